@@ -0,0 +1,42 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// captures build metadata for the version endpoint, see api::build_info.
+fn main() {
+    let commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .or_else(|| output_of("git", &["rev-parse", "--short", "HEAD"]));
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit);
+    }
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = output_of(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", version);
+    }
+
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", ts);
+
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=src");
+}
+
+fn output_of(cmd: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(cmd).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let v = String::from_utf8(output.stdout).ok()?;
+    let v = v.trim();
+    if v.is_empty() {
+        None
+    } else {
+        Some(v.to_string())
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    http::{header, HeaderMap, Request},
+    http::{header, HeaderMap, HeaderValue, Request},
     middleware::Next,
     response::Response,
 };
@@ -10,10 +10,22 @@ use uuid::Uuid;
 
 pub use structured_logger::unix_ms;
 
+tokio::task_local! {
+    // the id of the request being handled, echoed in response envelopes.
+    static REQUEST_ID: String;
+}
+
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|rid| rid.clone()).ok()
+}
+
 pub struct ReqContext {
-    pub rid: String,   // from x-request-id header
-    pub user: xid::Id, // from x-auth-user header
-    pub rating: i8,    // from x-auth-user-rating header, 0 if not present
+    pub rid: String,           // from x-request-id header
+    pub user: xid::Id,         // from x-auth-user header
+    pub rating: i8,            // from x-auth-user-rating header, 0 if not present
+    pub tenant: String,        // from x-tenant header, empty if not present
+    pub role: String,          // from x-auth-role header, empty if not present
+    pub deadline: Option<u64>, // unix ms, from x-request-deadline header
     pub unix_ms: u64,
     pub start: Instant,
     pub kv: RwLock<BTreeMap<String, Value>>,
@@ -25,12 +37,20 @@ impl ReqContext {
             rid: rid.to_string(),
             user,
             rating,
+            tenant: "".to_string(),
+            role: "".to_string(),
+            deadline: None,
             unix_ms: unix_ms(),
             start: Instant::now(),
             kv: RwLock::new(BTreeMap::new()),
         }
     }
 
+    // the milliseconds left before the client's deadline.
+    pub fn remaining_ms(&self) -> Option<u64> {
+        self.deadline.map(|d| d.saturating_sub(unix_ms()))
+    }
+
     pub async fn set(&self, key: &str, value: Value) {
         let mut kv = self.kv.write().await;
         kv.insert(key.to_string(), value);
@@ -52,13 +72,23 @@ pub async fn middleware<B>(mut req: Request<B>, next: Next<B>) -> Response {
     let app = extract_header(req.headers(), "x-auth-app", || "".to_string());
     let rating = extract_header(req.headers(), "x-auth-user-rating", || "0".to_string());
     let rating = i8::from_str(&rating).unwrap_or(0);
+    let tenant = extract_header(req.headers(), "x-tenant", || "".to_string());
+    let role = extract_header(req.headers(), "x-auth-role", || "".to_string());
+    let deadline = extract_header(req.headers(), "x-request-deadline", || "".to_string());
 
     let uid = xid::Id::from_str(&user).unwrap_or_default();
 
-    let ctx = Arc::new(ReqContext::new(&rid, uid, rating));
+    let mut ctx = ReqContext::new(&rid, uid, rating);
+    ctx.tenant = tenant.clone();
+    ctx.role = role;
+    ctx.deadline = u64::from_str(&deadline).ok();
+    let ctx = Arc::new(ctx);
     req.extensions_mut().insert(ctx.clone());
 
-    let res = next.run(req).await;
+    let mut res = REQUEST_ID.scope(rid.clone(), next.run(req)).await;
+    if let Ok(v) = HeaderValue::from_str(&rid) {
+        res.headers_mut().insert("x-request-id", v);
+    }
     let kv = ctx.kv.read().await;
     let status = res.status().as_u16();
     let headers = res.headers();
@@ -75,6 +105,7 @@ pub async fn middleware<B>(mut req: Request<B>, next: Next<B>) -> Response {
         user = user,
         app = app,
         rating = rating,
+        tenant = tenant,
         status = status,
         start = ctx.unix_ms,
         elapsed = ctx.start.elapsed().as_millis() as u64,
@@ -96,3 +127,69 @@ pub fn extract_header(hm: &HeaderMap, key: &str, or: impl FnOnce() -> String) ->
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{Body, HttpBody},
+        routing, Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::erring::{ErrorResponse, HTTPError, SuccessResponse};
+    use crate::object::PackObject;
+
+    async fn call(app: Router, rid: Option<&str>, uri: &str) -> (String, serde_json::Value) {
+        let mut req = Request::builder().uri(uri);
+        if let Some(rid) = rid {
+            req = req.header("x-request-id", rid);
+        }
+        let res = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        let header = res
+            .headers()
+            .get("x-request-id")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let mut body = res.into_body();
+        let mut data: Vec<u8> = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        (header, serde_json::from_slice(&data).unwrap())
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn request_id_works() {
+        assert!(request_id().is_none());
+
+        let app = Router::new()
+            .route(
+                "/ok",
+                routing::get(|| async { PackObject::Json(SuccessResponse::new(1)) }),
+            )
+            .route(
+                "/err",
+                routing::get(|| async { Err::<(), _>(HTTPError::new(404, "not found".into())) }),
+            )
+            .layer(axum::middleware::from_fn(middleware));
+
+        let (header, body) = call(app.clone(), Some("rid-1"), "/ok").await;
+        assert_eq!(header, "rid-1");
+        let res: SuccessResponse<i32> = serde_json::from_value(body).unwrap();
+        assert_eq!(res.request_id.unwrap(), "rid-1");
+
+        let (header, body) = call(app.clone(), None, "/ok").await;
+        assert!(!header.is_empty());
+        let res: SuccessResponse<i32> = serde_json::from_value(body).unwrap();
+        assert_eq!(res.request_id.unwrap(), header);
+
+        let (header, body) = call(app, Some("rid-2"), "/err").await;
+        assert_eq!(header, "rid-2");
+        let res: ErrorResponse = serde_json::from_value(body).unwrap();
+        assert_eq!(res.request_id.unwrap(), "rid-2");
+        assert_eq!(res.error.code, 404);
+    }
+}
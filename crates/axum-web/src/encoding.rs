@@ -88,10 +88,70 @@ impl Encoding {
     }
 }
 
+// picks the encoding with the highest q-value from an Accept-Encoding header value,
+// ties are broken by the order of `preferred`, which also lists the enabled encodings.
+pub fn negotiate(accept_encoding: &str, preferred: &[String]) -> Option<String> {
+    let mut best: Option<(u16, usize)> = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.splitn(2, ';');
+        let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let q = match parts.next() {
+            None => 1000,
+            Some(q) => match q.trim().strip_prefix("q=").map(|v| v.parse::<f32>()) {
+                Some(Ok(v)) if (0.0..=1.0).contains(&v) => (v * 1000.0) as u16,
+                _ => continue,
+            },
+        };
+        if q == 0 {
+            continue;
+        }
+
+        for (i, p) in preferred.iter().enumerate() {
+            if name == "*" || &name == p {
+                let better = match best {
+                    None => true,
+                    Some((bq, bi)) => q > bq || (q == bq && i < bi),
+                };
+                if better {
+                    best = Some((q, i));
+                }
+            }
+        }
+    }
+    best.map(|(_, i)| preferred[i].clone())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn negotiate_works() {
+        let preferred: Vec<String> = vec!["zstd".to_string(), "br".to_string(), "gzip".to_string()];
+        assert_eq!(negotiate("gzip, br", &preferred), Some("br".to_string()));
+        assert_eq!(
+            negotiate("gzip, br, zstd", &preferred),
+            Some("zstd".to_string())
+        );
+        assert_eq!(
+            negotiate("gzip, br;q=0.8", &preferred),
+            Some("gzip".to_string())
+        );
+        assert_eq!(
+            negotiate("zstd;q=0, br", &preferred),
+            Some("br".to_string())
+        );
+        assert_eq!(negotiate("*", &preferred), Some("zstd".to_string()));
+        assert_eq!(negotiate("deflate", &preferred), None);
+        assert_eq!(negotiate("", &preferred), None);
+
+        let preferred: Vec<String> = vec!["br".to_string(), "gzip".to_string()];
+        assert_eq!(
+            negotiate("gzip, zstd, br", &preferred),
+            Some("br".to_string())
+        );
+    }
+
     #[test]
     fn gzip_encode_decode() {
         let enc = Encoding::from_header_value(Some(&Encoding::Gzip.header_value()));
@@ -5,14 +5,17 @@ use axum::{
 };
 use scylla::transport::query_result::SingleRowError;
 use serde::{Deserialize, Serialize};
-use std::{convert::From, error::Error, fmt, fmt::Debug};
+use std::{collections::BTreeMap, convert::From, error::Error, fmt, fmt::Debug};
 use validator::{ValidationError, ValidationErrors};
 
+use crate::context;
 use crate::object::PackObject;
 
 /// ErrorResponse is the response body for error.
 #[derive(Deserialize, Serialize)]
 pub struct ErrorResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
     pub error: HTTPError,
 }
 
@@ -23,6 +26,16 @@ pub struct SuccessResponse<T> {
     pub total_size: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub next_page_token: Option<PackObject<Vec<u8>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_page_token: Option<PackObject<Vec<u8>>>, // pages back towards the previous page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub has_more: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warnings: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>, // same as the x-request-id response header
     pub result: T,
 }
 
@@ -31,9 +44,43 @@ impl<T> SuccessResponse<T> {
         SuccessResponse {
             total_size: None,
             next_page_token: None,
+            prev_page_token: None,
+            count: None,
+            has_more: None,
+            warnings: None,
+            request_id: context::request_id(),
             result,
         }
     }
+
+    // has_more is true iff the returned count equals the requested page size.
+    pub fn with_page(
+        mut self,
+        count: usize,
+        page_size: usize,
+        next_page_token: Option<PackObject<Vec<u8>>>,
+    ) -> Self {
+        let has_more = count > 0 && count == page_size;
+        self.count = Some(count as u64);
+        self.has_more = Some(has_more);
+        self.next_page_token = if has_more { next_page_token } else { None };
+        self
+    }
+
+    pub fn with_prev_page(mut self, prev_page_token: Option<PackObject<Vec<u8>>>) -> Self {
+        self.prev_page_token = prev_page_token;
+        self
+    }
+
+    // non-fatal advisories, omitted from the body when empty.
+    pub fn with_warnings(mut self, warnings: Vec<String>) -> Self {
+        self.warnings = if warnings.is_empty() {
+            None
+        } else {
+            Some(warnings)
+        };
+        self
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -74,7 +121,10 @@ impl IntoResponse for HTTPError {
             StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
         };
 
-        let body = Json(ErrorResponse { error: self });
+        let body = Json(ErrorResponse {
+            request_id: context::request_id(),
+            error: self,
+        });
         (status, body).into_response()
     }
 }
@@ -99,7 +149,21 @@ impl From<ValidationError> for HTTPError {
 
 impl From<ValidationErrors> for HTTPError {
     fn from(err: ValidationErrors) -> Self {
-        HTTPError::new(400, format!("{:?}", err))
+        // collect all field problems, so that clients can fix them at once.
+        let mut fields: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (field, errs) in err.field_errors() {
+            fields.insert(
+                field.to_string(),
+                errs.iter().map(|e| e.to_string()).collect(),
+            );
+        }
+
+        let names: Vec<&str> = fields.keys().map(|k| k.as_str()).collect();
+        HTTPError {
+            code: 400,
+            message: format!("Invalid fields: {}", names.join(", ")),
+            data: serde_json::to_value(&fields).ok(),
+        }
     }
 }
 
@@ -109,3 +173,27 @@ pub fn valid_user(uid: xid::Id) -> Result<(), HTTPError> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_page_works() {
+        let token = PackObject::Json(vec![1u8, 2, 3]);
+        let res = SuccessResponse::new(vec![1, 2]).with_page(2, 2, Some(token.clone()));
+        assert_eq!(res.count, Some(2));
+        assert_eq!(res.has_more, Some(true));
+        assert_eq!(res.next_page_token, Some(token.clone()));
+
+        let res = SuccessResponse::new(vec![1]).with_page(1, 2, Some(token));
+        assert_eq!(res.count, Some(1));
+        assert_eq!(res.has_more, Some(false));
+        assert_eq!(res.next_page_token, None);
+        assert_eq!(res.prev_page_token, None);
+
+        let token = PackObject::Json(vec![4u8]);
+        let res = SuccessResponse::new(vec![1]).with_prev_page(Some(token.clone()));
+        assert_eq!(res.prev_page_token, Some(token));
+    }
+}
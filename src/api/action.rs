@@ -1,3 +1,15 @@
+use axum::{
+    extract::{Path, Query},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use axum_web::erring::{HTTPError, SuccessResponse};
+use axum_web::object::PackObject;
+
+use crate::db;
+
 const ACTIONS: [&str; 88] = [
     "sys.create.user",
     "sys.update.user",
@@ -89,18 +101,346 @@ const ACTIONS: [&str; 88] = [
     "reserved",
 ];
 
+// actions that are meaningless without a payload.
+const PAYLOAD_REQUIRED: [&str; 3] = [
+    "creation.update.content",
+    "publication.update.content",
+    "collection.update.children",
+];
+
+// actions whose ip and payload may carry personal data.
+const PII: [&str; 6] = [
+    "sys.create.user",
+    "sys.update.user",
+    "user.login",
+    "user.authz",
+    "user.update",
+    "user.update.cn",
+];
+
+// actions kept to read old logs, new logs should not use them.
+const DEPRECATED: [&str; 0] = [];
+
+// actions that are complete once logged, they are created with status 1.
+const TERMINAL: [&str; 8] = [
+    "user.logout",
+    "user.bookmark",
+    "user.follow",
+    "group.delete",
+    "creation.delete",
+    "publication.delete",
+    "message.delete",
+    "collection.delete",
+];
+
+// the names of all non-reserved actions, in code order.
+pub fn names() -> Vec<&'static str> {
+    ACTIONS
+        .iter()
+        .filter(|&&a| a != "reserved")
+        .copied()
+        .collect()
+}
+
+// the name of codes outside the action table, unlike "reserved" slots
+// inside it.
+pub const UNKNOWN: &str = "unknown";
+
 pub fn from_action(a: i8) -> String {
     if a < 0 || a as usize >= ACTIONS.len() {
-        "reserved".to_string()
+        UNKNOWN.to_string()
     } else {
         ACTIONS[a as usize].to_string()
     }
 }
 
+// resolves an action name, or a stringified action code, to the action code.
 pub fn to_action(a: &str) -> Option<i8> {
     if a == "reserved" {
-        None
+        return None;
+    }
+    if let Ok(i) = a.parse::<i8>() {
+        return if i >= 0 && (i as usize) < ACTIONS.len() && ACTIONS[i as usize] != "reserved" {
+            Some(i)
+        } else {
+            None
+        };
+    }
+    ACTIONS.iter().position(|&x| x == a).map(|x| x as i8)
+}
+
+// trims and lowercases an action name from the caller, a name with inner
+// whitespace is rejected rather than reported as an unknown action.
+pub fn normalize_name(a: &str) -> Result<String, HTTPError> {
+    let name = a.trim();
+    if name.chars().any(char::is_whitespace) {
+        return Err(HTTPError::new(
+            400,
+            format!("invalid action {:?}, it contains whitespace", a),
+        ));
+    }
+    Ok(name.to_lowercase())
+}
+
+// resolves action names or codes to distinct action codes in their first
+// order, every invalid name is reported in one error.
+pub fn resolve_actions(names: &[String]) -> Result<Vec<i8>, HTTPError> {
+    let mut actions: Vec<i8> = Vec::with_capacity(names.len());
+    let mut invalid: Vec<&str> = Vec::new();
+    for a in names {
+        match to_action(a) {
+            Some(i) if !actions.contains(&i) => actions.push(i),
+            None if !invalid.contains(&a.as_str()) => invalid.push(a),
+            _ => {}
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(HTTPError::new(
+            400,
+            format!("invalid actions {}", invalid.join(", ")),
+        ));
+    }
+    Ok(actions)
+}
+
+pub fn is_pii(a: &str) -> bool {
+    PII.contains(&a)
+}
+
+pub fn requires_payload(a: &str) -> bool {
+    PAYLOAD_REQUIRED.contains(&a)
+}
+
+// the status of a created log when the caller doesn't set one.
+pub fn default_status(a: &str) -> db::Status {
+    if TERMINAL.contains(&a) {
+        db::Status::Frozen
     } else {
-        ACTIONS.iter().position(|&x| x == a).map(|x| x as i8)
+        db::Status::Open
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ActionInfo {
+    pub name: String,
+    pub code: i8,
+    pub category: String,
+    pub deprecated: bool,
+    pub requires_payload: bool,
+    pub default_status: db::Status,
+}
+
+pub fn action_info(a: &str) -> Option<ActionInfo> {
+    let code = to_action(a)?;
+    let name = from_action(code);
+    Some(ActionInfo {
+        code,
+        category: name.split('.').next().unwrap_or_default().to_string(),
+        deprecated: DEPRECATED.contains(&name.as_str()),
+        requires_payload: requires_payload(&name),
+        default_status: default_status(&name),
+        name,
+    })
+}
+
+pub async fn get(
+    to: PackObject<()>,
+    Path(name): Path<String>,
+) -> Result<PackObject<SuccessResponse<ActionInfo>>, HTTPError> {
+    let info = action_info(&name)
+        .ok_or_else(|| HTTPError::new(404, format!("unknown action {}", name)))?;
+    Ok(to.with(SuccessResponse::new(info)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SourceInput {
+    pub lang: Option<String>, // "rs" (default) or "ts"
+}
+
+// emits the non-reserved actions as source code for SDK generation.
+pub async fn source(Query(input): Query<SourceInput>) -> Result<Response, HTTPError> {
+    let lang = input.lang.unwrap_or_else(|| "rs".to_string());
+    let src =
+        codegen(&lang).ok_or_else(|| HTTPError::new(400, format!("unsupported lang {}", lang)))?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], src).into_response())
+}
+
+// "user.update.cn" -> "UserUpdateCn"
+fn ident(a: &str) -> String {
+    a.split('.')
+        .map(|s| {
+            let mut cs = s.chars();
+            match cs.next() {
+                Some(c) => c.to_ascii_uppercase().to_string() + cs.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+pub fn codegen(lang: &str) -> Option<String> {
+    let actions = ACTIONS.iter().enumerate().filter(|(_, &a)| a != "reserved");
+    let mut src = String::new();
+    match lang {
+        "rs" => {
+            src.push_str(
+                "#[derive(Debug, Clone, Copy, PartialEq, Eq)]\n#[repr(i8)]\npub enum Action {\n",
+            );
+            for (i, a) in actions {
+                src.push_str(&format!("    {} = {}, // {}\n", ident(a), i, a));
+            }
+            src.push_str("}\n");
+        }
+        "ts" => {
+            src.push_str("export const Action = {\n");
+            for (i, a) in actions {
+                src.push_str(&format!("  {}: {}, // {}\n", ident(a), i, a));
+            }
+            src.push_str("} as const\n");
+        }
+        _ => return None,
+    }
+    Some(src)
+}
+
+pub fn check_payload(a: &str, payload: &[u8]) -> Result<(), HTTPError> {
+    if payload.is_empty() && requires_payload(a) {
+        return Err(HTTPError::new(
+            400,
+            format!("payload is required for action {}", a),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_action_works() {
+        assert_eq!(from_action(8), "user.login");
+        assert_eq!(from_action(4), "reserved");
+        assert_eq!(from_action(-1), UNKNOWN);
+        assert_eq!(from_action(ACTIONS.len() as i8), UNKNOWN);
+        assert_eq!(from_action(i8::MAX), UNKNOWN);
+        assert_eq!(to_action(UNKNOWN), None);
+    }
+
+    #[test]
+    fn normalize_name_works() {
+        assert_eq!(normalize_name(" user.login ").unwrap(), "user.login");
+        assert_eq!(normalize_name("\tUser.Login\n").unwrap(), "user.login");
+        assert_eq!(to_action(&normalize_name(" user.login ").unwrap()), Some(8));
+        assert_eq!(normalize_name(" 8 ").unwrap(), "8");
+
+        let err = normalize_name("user login").unwrap_err();
+        assert_eq!(err.code, 400);
+        assert_eq!(
+            err.message,
+            "invalid action \"user login\", it contains whitespace"
+        );
+    }
+
+    #[test]
+    fn resolve_actions_works() {
+        let names = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert!(resolve_actions(&[]).unwrap().is_empty());
+        assert_eq!(
+            resolve_actions(&names(&[
+                "creation.create",
+                "user.login",
+                "40",
+                "user.login"
+            ]))
+            .unwrap(),
+            vec![40, 8]
+        );
+
+        let err =
+            resolve_actions(&names(&["user.fly", "user.login", "4", "user.fly"])).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert_eq!(err.message, "invalid actions user.fly, 4");
+    }
+
+    #[test]
+    fn to_action_works() {
+        assert_eq!(to_action("user.login"), Some(8));
+        assert_eq!(to_action("8"), Some(8));
+        assert_eq!(to_action("0"), Some(0));
+        assert_eq!(to_action("4"), None); // reserved
+        assert_eq!(to_action("88"), None);
+        assert_eq!(to_action("-1"), None);
+        assert_eq!(to_action("1000"), None);
+        assert_eq!(to_action("reserved"), None);
+        assert_eq!(to_action("user.unknown"), None);
+    }
+
+    #[test]
+    fn default_status_works() {
+        assert_eq!(default_status("user.logout"), db::Status::Frozen);
+        assert_eq!(default_status("group.delete"), db::Status::Frozen);
+        assert_eq!(default_status("creation.create"), db::Status::Open);
+        assert_eq!(default_status("user.login"), db::Status::Open);
+    }
+
+    #[test]
+    fn action_info_works() {
+        assert_eq!(
+            action_info("creation.update.content").unwrap(),
+            ActionInfo {
+                name: "creation.update.content".to_string(),
+                code: 44,
+                category: "creation".to_string(),
+                deprecated: false,
+                requires_payload: true,
+                default_status: db::Status::Open,
+            }
+        );
+
+        let info = action_info("28").unwrap();
+        assert_eq!(info.name, "group.delete");
+        assert_eq!(info.category, "group");
+        assert_eq!(info.default_status, db::Status::Frozen);
+
+        assert!(action_info("reserved").is_none());
+        assert!(action_info("user.unknown").is_none());
+    }
+
+    #[test]
+    fn codegen_works() {
+        assert_eq!(ident("user.update.cn"), "UserUpdateCn");
+
+        let src = codegen("rs").unwrap();
+        assert!(src.contains("pub enum Action {"));
+        assert!(src.contains("    UserLogin = 8, // user.login\n"));
+        assert!(!src.contains("Reserved"));
+        assert!(!src.contains("reserved"));
+        let variants: Vec<&str> = src
+            .lines()
+            .filter_map(|l| l.trim().split_once(" = "))
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(variants.len(), names().len());
+        for v in variants {
+            assert!(v.chars().next().unwrap().is_ascii_uppercase());
+            assert!(v.chars().all(|c| c.is_ascii_alphanumeric()));
+        }
+
+        let src = codegen("ts").unwrap();
+        assert!(src.contains("  UserLogin: 8, // user.login\n"));
+        assert!(!src.contains("reserved"));
+        assert!(codegen("go").is_none());
+    }
+
+    #[test]
+    fn check_payload_works() {
+        assert!(requires_payload("creation.update.content"));
+        let err = check_payload("creation.update.content", &[]).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(check_payload("creation.update.content", &[0x80]).is_ok());
+
+        assert!(!requires_payload("user.login"));
+        assert!(check_payload("user.login", &[]).is_ok());
     }
 }
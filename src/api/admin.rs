@@ -0,0 +1,159 @@
+use axum::{extract::State, Extension};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use axum_web::context::ReqContext;
+use axum_web::erring::{HTTPError, SuccessResponse};
+use axum_web::object::PackObject;
+
+use crate::api::AppState;
+
+// the read-only mode of this instance, writes fail with 503 while it is on
+// and reads keep working.
+#[derive(Default)]
+pub struct ReadOnly(AtomicBool);
+
+impl ReadOnly {
+    pub fn new(enabled: bool) -> Self {
+        Self(AtomicBool::new(enabled))
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn check(&self) -> Result<(), HTTPError> {
+        if self.get() {
+            return Err(HTTPError::new(
+                503,
+                "logbase is in read-only mode".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReadOnlyInput {
+    pub read_only: bool,
+}
+
+pub async fn get_read_only(
+    to: PackObject<()>,
+    State(app): State<Arc<AppState>>,
+) -> PackObject<SuccessResponse<ReadOnlyInput>> {
+    to.with(SuccessResponse::new(ReadOnlyInput {
+        read_only: app.read_only.get(),
+    }))
+}
+
+// switches the read-only mode of this instance until the next switch or
+// restart, the read_only config sets the mode on start. The admin routes are
+// limited to admin roles, see router::require_admin.
+pub async fn set_read_only(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<ReadOnlyInput>,
+) -> PackObject<SuccessResponse<ReadOnlyInput>> {
+    let (to, input) = to.unpack();
+    ctx.set_kvs(vec![
+        ("action", "set_read_only".into()),
+        ("read_only", input.read_only.into()),
+    ])
+    .await;
+
+    app.read_only.set(input.read_only);
+    log::warn!("read-only mode set to {}", input.read_only);
+    to.with(SuccessResponse::new(input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::testing::{put_log, test_conf, TestApp};
+    use axum::http::StatusCode;
+    use scylla_orm::ColumnsMap;
+
+    #[test]
+    fn read_only_works() {
+        let read_only = ReadOnly::default();
+        assert!(read_only.check().is_ok());
+
+        read_only.set(true);
+        assert!(read_only.get());
+        assert_eq!(read_only.check().unwrap_err().code, 503);
+
+        read_only.set(false);
+        assert!(read_only.check().is_ok());
+        assert!(ReadOnly::new(true).check().is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn set_read_only_works() {
+        let app = TestApp::new(test_conf());
+        let uid = xid::new();
+        let doc = put_log(app.store.as_ref(), uid, 1, ColumnsMap::new()).await;
+        let switch = |read_only: bool| serde_json::json!({ "read_only": read_only });
+        let admin = [("x-auth-role", "admin")];
+
+        // only admin roles can switch it
+        for headers in [&[][..], &[("x-auth-role", "guest")][..]] {
+            let (status, _) = app
+                .call("PUT", "/v1/admin/read_only", headers, Some(switch(true)))
+                .await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        assert!(!app.state.read_only.get());
+
+        let (status, res) = app
+            .call("PUT", "/v1/admin/read_only", &admin, Some(switch(true)))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        let (status, _) = app.call("GET", "/v1/admin/read_only", &[], None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (_, res) = app.call("GET", "/v1/admin/read_only", &admin, None).await;
+        assert_eq!(res["result"]["read_only"], true);
+
+        // writes are rejected
+        let create = serde_json::json!({
+            "uid": uid.to_string(),
+            "gid": xid::new().to_string(),
+            "action": "user.spend",
+            "payload": "",
+            "tokens": 1,
+        });
+        let (status, res) = app.call("POST", "/v1/log", &[], Some(create.clone())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res["error"]["message"], "logbase is in read-only mode");
+        let update = serde_json::json!({
+            "uid": uid.to_string(),
+            "id": doc.id.to_string(),
+            "status": 1,
+        });
+        let (status, res) = app.call("PATCH", "/v1/log", &[], Some(update)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", res);
+
+        // reads keep working
+        let uri = format!("/v1/log?uid={}&id={}", uid, doc.id);
+        let (status, res) = app.call("GET", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        let list = serde_json::json!({ "uid": uid.to_string() });
+        let (status, res) = app.call("POST", "/v1/log/list", &[], Some(list)).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"].as_array().unwrap().len(), 1);
+
+        let (status, _) = app
+            .call("PUT", "/v1/admin/read_only", &admin, Some(switch(false)))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, res) = app.call("POST", "/v1/log", &[], Some(create)).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+    }
+}
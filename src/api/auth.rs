@@ -0,0 +1,71 @@
+use crate::conf;
+
+// paths served without an API key, for probes and version checks.
+pub const EXEMPT_PATHS: [&str; 3] = ["/", "/healthz", "/readyz"];
+
+pub fn is_exempt(path: &str) -> bool {
+    EXEMPT_PATHS.contains(&path)
+}
+
+// the API key of an "Authorization: Bearer <key>" or "Authorization: ApiKey
+// <key>" header, None if the header is malformed or the key unknown.
+pub fn find_key<'a>(keys: &'a [conf::ApiKey], authorization: &str) -> Option<&'a conf::ApiKey> {
+    let (scheme, key) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") && !scheme.eq_ignore_ascii_case("apikey") {
+        return None;
+    }
+    let key = key.trim();
+    if key.is_empty() {
+        return None;
+    }
+    // compares with every key, so the timing doesn't tell which one is close
+    let mut found = None;
+    for k in keys {
+        if constant_time_eq(k.key.as_bytes(), key.as_bytes()) && found.is_none() {
+            found = Some(k);
+        }
+    }
+    found
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_key_works() {
+        let keys = vec![
+            conf::ApiKey {
+                key: "k1".to_string(),
+                name: "console".to_string(),
+                ..Default::default()
+            },
+            conf::ApiKey {
+                key: "k2".to_string(),
+                name: "worker".to_string(),
+                tenant: "a".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(find_key(&keys, "Bearer k1").unwrap().name, "console");
+        assert_eq!(find_key(&keys, "ApiKey k2").unwrap().name, "worker");
+        assert_eq!(find_key(&keys, "bearer  k2 ").unwrap().tenant, "a");
+        assert!(find_key(&keys, "Bearer k3").is_none());
+        assert!(find_key(&keys, "Bearer k").is_none());
+        assert!(find_key(&keys, "Bearer ").is_none());
+        assert!(find_key(&keys, "Basic k1").is_none());
+        assert!(find_key(&keys, "k1").is_none());
+        assert!(find_key(&[], "Bearer k1").is_none());
+
+        assert!(is_exempt("/healthz"));
+        assert!(!is_exempt("/v1/log"));
+    }
+}
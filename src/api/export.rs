@@ -0,0 +1,327 @@
+use axum::{
+    body::StreamBody,
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use validator::Validate;
+
+use axum_web::context::{unix_ms, ReqContext};
+use axum_web::erring::{HTTPError, SuccessResponse};
+use axum_web::object::{cbor_to_vec, PackObject};
+
+use crate::api::{action, get_fields, log::LogOutput, AppState};
+use crate::db;
+
+pub const EXPORT_BIN_SCHEMA: &str = "logbase.log";
+pub const EXPORT_BIN_VERSION: u16 = 1;
+
+// the first frame of a binary export.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportHeader {
+    pub schema: String,
+    pub version: u16,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ExportInput {
+    pub uid: PackObject<xid::Id>,
+    pub fields: Option<String>,
+    pub page_size: Option<u16>,
+    pub scrub: Option<bool>, // redacts personal data of PII actions
+    pub since: Option<u32>,  // unix seconds, inclusive
+    pub until: Option<u32>,  // unix seconds, exclusive
+}
+
+pub const REDACTED: &str = "[redacted]";
+
+// redacts ip and omits payload of PII actions.
+pub fn scrub(doc: &mut db::Log) {
+    if !action::is_pii(&action::from_action(doc.action)) {
+        return;
+    }
+    if !doc.ip.is_empty() {
+        doc.ip = REDACTED.to_string();
+    }
+    doc.payload.clear();
+    doc._fields.retain(|f| f != "payload");
+}
+
+// streams a header frame followed by one frame per log, every frame is a
+// 4 bytes big-endian length prefix and the CBOR encoded value.
+pub async fn export_bin(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    Query(input): Query<ExportInput>,
+) -> Result<Response, HTTPError> {
+    input.validate()?;
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "export_bin".into())]).await;
+
+    let uid = input.uid.unwrap();
+    if let (Some(since), Some(until)) = (input.since, input.until) {
+        if since >= until {
+            return Err(HTTPError::new(
+                400,
+                format!("since {} must be before until {}", since, until),
+            ));
+        }
+    }
+    let since = input.since;
+    // the upper bound of the window is the first page token
+    let until = input.until.map(db::xid_from_unix);
+    let fields = get_fields(input.fields);
+    let scrubbing = input.scrub.unwrap_or(false);
+    let redacted = app.redacted_fields(&ctx).to_vec();
+    let page_size = app.cfg.pagination.page_size(Some(
+        input.page_size.unwrap_or(app.cfg.pagination.max_page_size),
+    ));
+    let head = encode_frame(&ExportHeader {
+        schema: EXPORT_BIN_SCHEMA.to_string(),
+        version: EXPORT_BIN_VERSION,
+    })?;
+
+    let pages = stream::unfold(Some(until), move |state: Option<Option<xid::Id>>| {
+        let scylla = scylla.clone();
+        let fields = fields.clone();
+        let redacted = redacted.clone();
+        async move {
+            let page_token = state?;
+            let res = match db::Log::list(
+                &scylla, uid, fields, page_size, page_token, None, since, None,
+            )
+            .await
+            {
+                Ok(res) => res,
+                Err(err) => return Some((Err(err.into()), None)),
+            };
+
+            let next = if res.len() < page_size as usize {
+                None
+            } else {
+                res.last().map(|r| Some(r.id))
+            };
+            let to = PackObject::Cbor(());
+            let mut buf: Vec<u8> = Vec::new();
+            for mut doc in res {
+                if scrubbing {
+                    scrub(&mut doc);
+                }
+                match encode_frame(&LogOutput::redacted(doc, &to, &redacted)) {
+                    Ok(frame) => buf.extend_from_slice(&frame),
+                    Err(err) => return Some((Err(err.into()), None)),
+                }
+            }
+            Some((Ok(Bytes::from(buf)), next))
+        }
+    });
+
+    let body =
+        stream::once(async move { Ok::<Bytes, anyhow::Error>(Bytes::from(head)) }).chain(pages);
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        StreamBody::new(body),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ExportBucketsInput {
+    pub uid: PackObject<xid::Id>,
+    pub since: Option<u32>, // unix seconds, defaults to the earliest log
+    pub until: Option<u32>, // unix seconds, defaults to now
+    #[validate(range(min = 60))]
+    pub bucket_seconds: Option<u32>, // defaults to an hour
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ExportBucket {
+    pub since: u32, // inclusive
+    pub until: u32, // exclusive
+}
+
+pub const EXPORT_MAX_BUCKETS: u32 = 10000;
+
+// splits the export of a user into time buckets that can be downloaded in
+// parallel, by passing each bucket's since and until to export_bin.
+pub async fn export_buckets(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<ExportBucketsInput>,
+) -> Result<PackObject<SuccessResponse<Vec<ExportBucket>>>, HTTPError> {
+    input.validate()?;
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "export_buckets".into())]).await;
+
+    let uid = input.uid.unwrap();
+    let until = input.until.unwrap_or_else(|| (unix_ms() / 1000) as u32 + 1);
+    let since = match input.since {
+        Some(since) => since,
+        None => match db::Log::earliest(&scylla, uid, vec!["id".to_string()]).await {
+            Ok(doc) => db::xid_unix(&doc.id),
+            Err(db::LogError::NotFound) => until,
+            Err(err) => return Err(err.into()),
+        },
+    };
+    let buckets = time_buckets(since, until, input.bucket_seconds.unwrap_or(3600))?;
+    Ok(to.with(SuccessResponse::new(buckets)))
+}
+
+// contiguous buckets of bucket_seconds covering [since, until), the last
+// one may be shorter.
+pub fn time_buckets(
+    since: u32,
+    until: u32,
+    bucket_seconds: u32,
+) -> Result<Vec<ExportBucket>, HTTPError> {
+    let n = until.saturating_sub(since) as u64;
+    let bucket_seconds = bucket_seconds.max(1);
+    if n / bucket_seconds as u64 >= EXPORT_MAX_BUCKETS as u64 {
+        return Err(HTTPError::new(
+            400,
+            format!(
+                "too many buckets, expected at most {}, use larger bucket_seconds",
+                EXPORT_MAX_BUCKETS
+            ),
+        ));
+    }
+
+    let mut buckets: Vec<ExportBucket> = Vec::new();
+    let mut start = since;
+    while start < until {
+        let end = start.saturating_add(bucket_seconds).min(until);
+        buckets.push(ExportBucket {
+            since: start,
+            until: end,
+        });
+        start = end;
+    }
+    Ok(buckets)
+}
+
+pub fn encode_frame<T: Serialize>(value: &T) -> Result<Vec<u8>, HTTPError> {
+    let data = cbor_to_vec(value)?;
+    let mut frame: Vec<u8> = Vec::with_capacity(data.len() + 4);
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&data);
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum_web::object::cbor_from_slice;
+
+    // splits the frames of encode_frame, as a client reads them.
+    fn decode_frames(mut data: &[u8]) -> Result<Vec<&[u8]>, HTTPError> {
+        let mut frames: Vec<&[u8]> = Vec::new();
+        while !data.is_empty() {
+            if data.len() < 4 {
+                return Err(HTTPError::new(400, "Invalid frame header".to_string()));
+            }
+            let mut size = [0u8; 4];
+            size.copy_from_slice(&data[0..4]);
+            let size = u32::from_be_bytes(size) as usize;
+            if data.len() < size + 4 {
+                return Err(HTTPError::new(400, "Invalid frame length".to_string()));
+            }
+            frames.push(&data[4..size + 4]);
+            data = &data[size + 4..];
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn frames_works() {
+        let to = PackObject::Cbor(());
+        let mut doc = db::Log::with_pk(xid::new(), xid::new());
+        doc.action = 8;
+        doc.status = db::Status::Frozen;
+        doc.payload = vec![0x80];
+        doc._fields = vec!["payload".to_string()];
+
+        let mut data = encode_frame(&ExportHeader {
+            schema: EXPORT_BIN_SCHEMA.to_string(),
+            version: EXPORT_BIN_VERSION,
+        })
+        .unwrap();
+        data.extend(encode_frame(&LogOutput::from(doc.clone(), &to)).unwrap());
+        data.extend(encode_frame(&LogOutput::from(doc.clone(), &to)).unwrap());
+
+        let frames = decode_frames(&data).unwrap();
+        assert_eq!(frames.len(), 3);
+        let header: ExportHeader = cbor_from_slice(frames[0]).unwrap();
+        assert_eq!(header.schema, EXPORT_BIN_SCHEMA);
+        assert_eq!(header.version, EXPORT_BIN_VERSION);
+
+        for frame in &frames[1..] {
+            let output: LogOutput = cbor_from_slice(frame).unwrap();
+            assert_eq!(output.uid.unwrap(), doc.uid);
+            assert_eq!(output.id.unwrap(), doc.id);
+            assert_eq!(output.action, "user.login");
+            assert_eq!(output.payload.unwrap().unwrap(), vec![0x80]);
+        }
+
+        assert!(decode_frames(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn time_buckets_works() {
+        let since = 1690000000u32;
+        let until = since + 3 * 3600 + 100;
+        let buckets = time_buckets(since, until, 3600).unwrap();
+        assert_eq!(buckets.len(), 4);
+        assert_eq!(buckets[0].since, since);
+        assert_eq!(buckets[3].until, until);
+        assert_eq!(buckets[3].until - buckets[3].since, 100);
+        // contiguous, so neither a gap nor an overlap
+        for w in buckets.windows(2) {
+            assert_eq!(w[0].until, w[1].since);
+            assert!(w[0].since < w[0].until);
+        }
+
+        assert!(time_buckets(since, since, 3600).unwrap().is_empty());
+        assert!(time_buckets(until, since, 3600).unwrap().is_empty());
+        assert_eq!(
+            time_buckets(since, since + 10, 3600).unwrap(),
+            vec![ExportBucket {
+                since,
+                until: since + 10
+            }]
+        );
+        let err = time_buckets(0, u32::MAX, 60).unwrap_err();
+        assert_eq!(err.code, 400);
+    }
+
+    #[test]
+    fn scrub_works() {
+        let to = PackObject::Cbor(());
+        let mut doc = db::Log::with_pk(xid::new(), xid::new());
+        doc.action = 8; // user.login
+        doc.ip = "1.2.3.4".to_string();
+        doc.payload = vec![0x80];
+        doc._fields = vec!["ip".to_string(), "payload".to_string()];
+
+        let mut other = doc.clone();
+        other.action = 40; // creation.create
+
+        scrub(&mut doc);
+        let data = encode_frame(&LogOutput::from(doc, &to)).unwrap();
+        let output: LogOutput = cbor_from_slice(decode_frames(&data).unwrap()[0]).unwrap();
+        assert_eq!(output.ip.unwrap(), REDACTED);
+        assert!(output.payload.is_none());
+
+        scrub(&mut other);
+        let output = LogOutput::from(other, &to);
+        assert_eq!(output.ip.unwrap(), "1.2.3.4");
+        assert_eq!(output.payload.unwrap().unwrap(), vec![0x80]);
+    }
+}
@@ -1,26 +1,39 @@
 use axum::{
-    extract::{Query, State},
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Extension,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use validator::Validate;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use validator::{Validate, ValidationError};
 
-use axum_web::context::ReqContext;
+use axum_web::context::{unix_ms, ReqContext};
+use axum_web::encoding::Encoding;
 use axum_web::erring::{HTTPError, SuccessResponse};
 use axum_web::object::PackObject;
+use base64::{engine::general_purpose, Engine as _};
 use scylla_orm::ColumnsMap;
 
+use crate::conf;
 use crate::db;
 
-use crate::api::{action, get_fields, AppState};
+use crate::api::{action, client_ip, decode_page_token, encode_page_token, get_fields, AppState};
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct LogOutput {
     pub uid: PackObject<xid::Id>,
     pub id: PackObject<xid::Id>,
     pub action: String,
-    pub status: i8,
+    pub status: db::Status,
+    #[serde(default)]
+    pub created_at: i64, // unix ms, from the id's timestamp
     #[serde(skip_serializing_if = "Option::is_none")]
     pub gid: Option<PackObject<xid::Id>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -28,18 +41,39 @@ pub struct LogOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub payload: Option<PackObject<Vec<u8>>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_truncated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overridden: Option<bool>, // true if written after freezing, see freeze_bypass_actions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampled: Option<bool>, // true if the create was sampled out and not stored
 }
 
 impl LogOutput {
     pub fn from<T>(val: db::Log, to: &PackObject<T>) -> Self {
+        Self::redacted(val, to, &[])
+    }
+
+    // omits the redacted fields even if they were selected.
+    pub fn redacted<T>(mut val: db::Log, to: &PackObject<T>, redacted: &[String]) -> Self {
+        redact_fields(&mut val, redacted);
         let mut rt = Self {
             uid: to.with(val.uid),
             id: to.with(val.id),
             action: action::from_action(val.action),
             status: val.status,
+            created_at: db::xid_unix(&val.id) as i64 * 1000,
             ..Default::default()
         };
 
@@ -48,6 +82,13 @@ impl LogOutput {
                 "gid" => rt.gid = Some(to.with(val.gid)),
                 "ip" => rt.ip = Some(val.ip.to_owned()),
                 "payload" => rt.payload = Some(to.with(val.payload.to_owned())),
+                "payload_type" => {
+                    rt.payload_type = if val.payload_type.is_empty() {
+                        None
+                    } else {
+                        Some(val.payload_type.to_owned())
+                    }
+                }
                 "tokens" => rt.tokens = Some(val.tokens as u32),
                 "error" => {
                     rt.error = if val.error.is_empty() {
@@ -56,6 +97,27 @@ impl LogOutput {
                         Some(val.error.to_owned())
                     }
                 }
+                "trace_id" => {
+                    rt.trace_id = if val.trace_id.is_empty() {
+                        None
+                    } else {
+                        Some(val.trace_id.to_owned())
+                    }
+                }
+                "updated_at" => rt.updated_at = Some(val.updated_at),
+                "overridden" => rt.overridden = if val.overridden { Some(true) } else { None },
+                "labels" => {
+                    rt.labels = if val.labels.is_empty() {
+                        None
+                    } else {
+                        Some(
+                            val.labels
+                                .iter()
+                                .map(|(k, v)| (k.clone(), v.clone()))
+                                .collect(),
+                        )
+                    }
+                }
                 _ => {}
             }
         }
@@ -64,147 +126,4320 @@ impl LogOutput {
     }
 }
 
+pub fn redact_fields(val: &mut db::Log, redacted: &[String]) {
+    if !redacted.is_empty() {
+        val._fields.retain(|f| !redacted.contains(f));
+    }
+}
+
 #[derive(Debug, Deserialize, Validate)]
 pub struct QueryLog {
     pub uid: PackObject<xid::Id>,
+    #[validate(custom = "validate_id_not_future")]
     pub id: PackObject<xid::Id>,
     pub fields: Option<String>,
+    pub payload_limit: Option<usize>, // returns at most the first N bytes of payload
+    pub flat: Option<bool>,
 }
 
 pub async fn get(
     State(app): State<Arc<AppState>>,
     Extension(ctx): Extension<Arc<ReqContext>>,
+    headers: HeaderMap,
     to: PackObject<()>,
     Query(input): Query<QueryLog>,
-) -> Result<PackObject<SuccessResponse<LogOutput>>, HTTPError> {
+) -> Result<Response, HTTPError> {
     input.validate()?;
 
+    let store = app.store_for(&ctx)?;
     ctx.set_kvs(vec![("action", "get_log".into())]).await;
 
+    let mut fields = list_fields(&app.cfg.pagination, get_fields(input.fields), true)?;
+    if !fields.is_empty() && !fields.contains(&"updated_at".to_string()) {
+        fields.push("updated_at".to_string());
+    }
     let mut doc = db::Log::with_pk(input.uid.unwrap(), input.id.unwrap());
-    doc.get_one(&app.scylla, get_fields(input.fields)).await?;
+    store.get_one(&mut doc, fields).await?;
+    apply_status_history(store.as_ref(), doc.uid, std::slice::from_mut(&mut doc)).await?;
 
-    Ok(to.with(SuccessResponse::new(LogOutput::from(doc, &to))))
+    // the ETag is computed over what the caller gets to see.
+    redact_fields(&mut doc, app.redacted_fields(&ctx));
+    let variant = [
+        doc._fields.join(","),
+        input
+            .payload_limit
+            .map(|l| l.to_string())
+            .unwrap_or_default(),
+        input.flat.unwrap_or(false).to_string(),
+        match to {
+            PackObject::Json(_) => "json".to_string(),
+            PackObject::Cbor(_) => "cbor".to_string(),
+        },
+        ctx.role.clone(),
+    ];
+    let etag = weak_etag(doc.updated_at, doc.status, &variant);
+    if let Some(inm) = headers.get(header::IF_NONE_MATCH) {
+        if etag_matches(inm.to_str().unwrap_or_default(), &etag) {
+            return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+        }
+    }
+
+    let truncated = input
+        .payload_limit
+        .map(|limit| truncate_payload(&mut doc.payload, limit));
+    if input.flat.unwrap_or(false) {
+        let mut output = flat_log(doc);
+        if let (Some(truncated), true) = (truncated, output.contains_key("payload")) {
+            output.insert("payload_truncated".to_string(), truncated.into());
+        }
+        return Ok((
+            [(header::ETAG, etag)],
+            to.with(SuccessResponse::new(output)),
+        )
+            .into_response());
+    }
+
+    let mut output = LogOutput::from(doc, &to);
+    if output.payload.is_some() {
+        output.payload_truncated = truncated;
+    }
+    Ok((
+        [(header::ETAG, etag)],
+        to.with(SuccessResponse::new(output)),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct PresentFieldsInput {
+    pub uid: PackObject<xid::Id>,
+    #[validate(custom = "validate_id_not_future")]
+    pub id: PackObject<xid::Id>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PresentFieldsOutput {
+    pub fields: Vec<String>,
+}
+
+// names the optional fields of a log that hold a value, so sparse logs can
+// be fetched with just those fields.
+pub async fn present(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<PresentFieldsInput>,
+) -> Result<PackObject<SuccessResponse<PresentFieldsOutput>>, HTTPError> {
+    input.validate()?;
+
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "present_log_fields".into())])
+        .await;
+    let mut doc = db::Log::with_pk(input.uid.unwrap(), input.id.unwrap());
+    store.get_one(&mut doc, vec![]).await?;
+    redact_fields(&mut doc, app.redacted_fields(&ctx));
+    Ok(to.with(SuccessResponse::new(PresentFieldsOutput {
+        fields: present_fields(&doc),
+    })))
+}
+
+// the selected optional fields of the log that are not empty or zero, in
+// column order.
+pub fn present_fields(doc: &db::Log) -> Vec<String> {
+    doc._fields
+        .iter()
+        .filter(|f| match f.as_str() {
+            "gid" => doc.gid != xid::Id::default(),
+            "ip" => !doc.ip.is_empty(),
+            "payload" => !doc.payload.is_empty(),
+            "payload_type" => !doc.payload_type.is_empty(),
+            "tokens" => doc.tokens != 0,
+            "error" => !doc.error.is_empty(),
+            "trace_id" => !doc.trace_id.is_empty(),
+            "updated_at" => doc.updated_at != 0,
+            "labels" => !doc.labels.is_empty(),
+            "overridden" => doc.overridden,
+            _ => false,
+        })
+        .cloned()
+        .collect()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FlatQuery {
+    pub flat: Option<bool>,
+}
+
+pub type FlatLog = BTreeMap<String, serde_json::Value>;
+
+// a single-level form of the log for BI tools: ids are strings, action is
+// both name and code, and payload is base64url.
+pub fn flat_log(val: db::Log) -> FlatLog {
+    let mut rt = FlatLog::new();
+    rt.insert("uid".to_string(), val.uid.to_string().into());
+    rt.insert("id".to_string(), val.id.to_string().into());
+    rt.insert("action".to_string(), action::from_action(val.action).into());
+    rt.insert("action_code".to_string(), val.action.into());
+    rt.insert("status".to_string(), i8::from(val.status).into());
+
+    for v in val._fields {
+        match v.as_str() {
+            "gid" => {
+                rt.insert("gid".to_string(), val.gid.to_string().into());
+            }
+            "ip" => {
+                rt.insert("ip".to_string(), val.ip.to_owned().into());
+            }
+            "payload" => {
+                rt.insert(
+                    "payload".to_string(),
+                    general_purpose::URL_SAFE_NO_PAD.encode(&val.payload).into(),
+                );
+            }
+            "payload_type" => {
+                rt.insert(
+                    "payload_type".to_string(),
+                    val.payload_type.to_owned().into(),
+                );
+            }
+            "tokens" => {
+                rt.insert("tokens".to_string(), val.tokens.into());
+            }
+            "error" => {
+                rt.insert("error".to_string(), val.error.to_owned().into());
+            }
+            "trace_id" => {
+                rt.insert("trace_id".to_string(), val.trace_id.to_owned().into());
+            }
+            "labels" => {
+                let labels: serde_json::Map<String, serde_json::Value> = val
+                    .labels
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone().into()))
+                    .collect();
+                rt.insert("labels".to_string(), labels.into());
+            }
+            _ => {}
+        }
+    }
+
+    rt
+}
+
+// keeps the first `limit` bytes, returns true if the payload was cut.
+fn truncate_payload(payload: &mut Vec<u8>, limit: usize) -> bool {
+    if payload.len() <= limit {
+        return false;
+    }
+    payload.truncate(limit);
+    true
+}
+
+// only status, error and tokens are mutable, and every write bumps updated_at.
+// The variant holds the request options that shape the representation (fields,
+// payload_limit, flat, encoding and role), so that another projection of the
+// same version never matches.
+fn weak_etag(updated_at: i64, status: db::Status, variant: &[String]) -> String {
+    // FNV-1a, stable across builds unlike the std hashers.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in variant {
+        for b in part.bytes().chain(std::iter::once(0)) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("W/\"{:x}-{}-{:x}\"", updated_at, status, hash)
+}
+
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v.trim_start_matches("W/") == etag)
 }
 
 #[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
 pub struct CreateLogInput {
     pub uid: PackObject<xid::Id>,
     pub gid: PackObject<xid::Id>,
     pub action: String,
-    #[validate(range(min = -1, max = 1))]
-    pub status: i8,
-    pub ip: String,
+    #[validate(custom = "validate_status")]
+    pub status: Option<db::Status>,
+    #[serde(default)]
+    pub ip: String, // captured from the request if empty
     pub payload: PackObject<Vec<u8>>,
     #[validate(range(min = 0))]
     pub tokens: i32,
+    #[serde(default)]
+    #[validate(custom = "validate_labels")]
+    pub labels: HashMap<String, String>,
+    #[validate(length(min = 1, max = 128))]
+    pub external_id: Option<String>, // client-supplied id, unique per uid
+    #[validate(custom = "validate_payload_type")]
+    pub payload_type: Option<String>, // "cbor", "json", "text" or "bytes", as sent
+}
+
+pub const PAYLOAD_TYPES: [&str; 4] = ["cbor", "json", "text", "bytes"];
+
+fn validate_payload_type(payload_type: &str) -> Result<(), ValidationError> {
+    if !PAYLOAD_TYPES.contains(&payload_type) {
+        let mut err = ValidationError::new("payload_type");
+        err.message = Some(format!("expected one of {}", PAYLOAD_TYPES.join(", ")).into());
+        return Err(err);
+    }
+    Ok(())
+}
+
+// the payload must parse as its declared type if check_type is enabled, an
+// empty payload or "bytes" is never checked. A gzip payload is checked after
+// decoding.
+fn check_payload_type(
+    cfg: &conf::Payload,
+    payload_type: Option<&str>,
+    payload: &[u8],
+) -> Result<(), HTTPError> {
+    if !cfg.check_type || payload.is_empty() || matches!(payload_type, None | Some("bytes")) {
+        return Ok(());
+    }
+    let raw;
+    let payload = if payload.starts_with(&GZIP_MAGIC) {
+        raw = gunzip(cfg, payload)?;
+        &raw[..]
+    } else {
+        payload
+    };
+    let res = match payload_type {
+        Some("cbor") => {
+            let mut rd = payload;
+            ciborium::de::from_reader::<ciborium::value::Value, _>(&mut rd)
+                .map_err(|err| err.to_string())
+                .and_then(|_| {
+                    if rd.is_empty() {
+                        Ok(())
+                    } else {
+                        Err(format!("{} trailing bytes", rd.len()))
+                    }
+                })
+        }
+        Some("json") => serde_json::from_slice::<serde_json::Value>(payload)
+            .map(|_| ())
+            .map_err(|err| err.to_string()),
+        Some("text") => std::str::from_utf8(payload)
+            .map(|_| ())
+            .map_err(|err| err.to_string()),
+        _ => Ok(()),
+    };
+    res.map_err(|err| {
+        HTTPError::new(
+            400,
+            format!(
+                "payload is not valid {}, {}",
+                payload_type.unwrap_or_default(),
+                err
+            ),
+        )
+    })
+}
+
+pub const MAX_LABELS: usize = 20;
+
+// at most MAX_LABELS labels, keys are 1 to 64 chars of [a-z0-9_.-] and values
+// at most 256 chars.
+fn validate_labels(labels: &HashMap<String, String>) -> Result<(), ValidationError> {
+    let invalid = |msg: String| {
+        let mut err = ValidationError::new("labels");
+        err.message = Some(msg.into());
+        Err(err)
+    };
+    if labels.len() > MAX_LABELS {
+        return invalid(format!(
+            "too many labels, expected at most {}, got {}",
+            MAX_LABELS,
+            labels.len()
+        ));
+    }
+    for (k, v) in labels {
+        let valid_key = !k.is_empty()
+            && k.len() <= 64
+            && k.chars().all(|c| {
+                c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '.' || c == '-'
+            });
+        if !valid_key {
+            return invalid(format!("invalid label key {:?}", k));
+        }
+        if v.chars().count() > 256 {
+            return invalid(format!("label {} is too long", k));
+        }
+    }
+    Ok(())
 }
 
 pub async fn create(
     State(app): State<Arc<AppState>>,
     Extension(ctx): Extension<Arc<ReqContext>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
     to: PackObject<CreateLogInput>,
 ) -> Result<PackObject<SuccessResponse<LogOutput>>, HTTPError> {
-    let (to, input) = to.unpack();
-    input.validate()?;
-
-    let i = action::to_action(&input.action)
-        .ok_or_else(|| HTTPError::new(400, format!("invalid action {}", input.action)))?;
+    let (to, mut input) = to.unpack();
+    app.check_writable()?;
+    // the ip stays empty when the server runs without connect info, since
+    // X-Forwarded-For can't be trusted without a peer.
+    if let (true, Some(ConnectInfo(peer))) = (input.ip.is_empty(), peer) {
+        input.ip = client_ip(peer.ip(), &headers, &app.cfg.trusted_proxies).to_string();
+    }
+    let i = check_create(&app.cfg, &ctx, &headers, &mut input)
+        .map_err(|mut failures| failures.remove(0).1)?;
+    let name = action::from_action(i);
 
+    let store = app.store_for(&ctx)?;
     ctx.set_kvs(vec![("action", "create_log".into())]).await;
 
     let mut doc = db::Log::with_pk(input.uid.unwrap(), xid::new());
-    let mut cols: ColumnsMap = ColumnsMap::with_capacity(5);
+    let mut cols: ColumnsMap = ColumnsMap::with_capacity(7);
     doc.action = i;
     cols.set_as("action", &i);
-    cols.set_as("status", &input.status);
+    // explicit status always wins over the action's default.
+    doc.status = input
+        .status
+        .unwrap_or_else(|| action::default_status(&name));
+    cols.set_as("status", &doc.status);
     cols.set_as("gid", &input.gid.unwrap());
     cols.set_as("ip", &input.ip);
-    cols.set_as("payload", &input.payload.unwrap());
+    let payload = input.payload.unwrap();
+    cols.set_as("payload", &payload);
+    if let Some(payload_type) = input.payload_type {
+        doc.payload_type = payload_type;
+        cols.set_as("payload_type", &doc.payload_type);
+    }
     cols.set_as("tokens", &input.tokens);
+    if !input.labels.is_empty() {
+        doc.labels = input.labels;
+        cols.set_as("labels", &doc.labels);
+    }
+    // the request id is generated by the context middleware if absent.
+    doc.trace_id = ctx.rid.clone();
+    cols.set_as("trace_id", &doc.trace_id);
 
-    doc.upsert_fields(&app.scylla, cols).await?;
-    Ok(to.with(SuccessResponse::new(LogOutput::from(doc, &to))))
+    let warnings = soft_warnings(
+        &app.cfg.warning,
+        Some(&name),
+        Some(&input.ip),
+        Some(input.tokens),
+    );
+    doc._fields.push("trace_id".to_string());
+    if !doc.labels.is_empty() {
+        doc._fields.push("labels".to_string());
+    }
+    if !doc.payload_type.is_empty() {
+        doc._fields.push("payload_type".to_string());
+    }
+    let sampled_out = !keep_sampled(app.cfg.sampling.get(&name), sample_roll());
+    if sampled_out {
+        // answers as if stored, but writes nothing.
+        ctx.set_kvs(vec![("sampled_out", true.into())]).await;
+    } else {
+        // the quota counts stored logs, a failed write gives its count back.
+        let quota_ms = unix_ms();
+        app.quotas.check(doc.uid, &name, quota_ms)?;
+        let written: Result<(), HTTPError> = async {
+            match app.buffer_for(&ctx) {
+                Some(buffer) => {
+                    if let Some(ref external_id) = input.external_id {
+                        claim_external_id(store.as_ref(), doc.uid, external_id, doc.id).await?;
+                    }
+                    // a new id can't be frozen, so the buffered insert skips the check.
+                    // The buffer notifies once the log is written.
+                    doc.fill(&cols);
+                    doc.updated_at = unix_ms() as i64;
+                    if let Err(err) = buffer.push(doc.clone()).await {
+                        release_external_id(store.as_ref(), doc.uid, &input.external_id, doc.id)
+                            .await;
+                        return Err(err.into());
+                    }
+                }
+                None => {
+                    if let Some(ref external_id) = input.external_id {
+                        claim_external_id(store.as_ref(), doc.uid, external_id, doc.id).await?;
+                    }
+                    let claimed = doc.id;
+                    if let Err(err) = insert_unique(store.as_ref(), &mut doc, &cols, xid::new).await
+                    {
+                        release_external_id(store.as_ref(), doc.uid, &input.external_id, claimed)
+                            .await;
+                        return Err(err);
+                    }
+                    if let (Some(ref external_id), true) = (&input.external_id, doc.id != claimed) {
+                        // the id collided, points the external id to the regenerated one.
+                        store
+                            .put_external_id(doc.uid, external_id, doc.id, false)
+                            .await?;
+                    }
+                    app.notifier
+                        .notify(&name, LogOutput::from(doc.clone(), &PackObject::Json(())));
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = written {
+            app.quotas.refund(doc.uid, &name, quota_ms);
+            return Err(err);
+        }
+        app.payload_sizes.observe(payload.len());
+    }
+    let mut output = LogOutput::from(doc, &to);
+    if sampled_out {
+        output.sampled = Some(true);
+    }
+    Ok(to.with(SuccessResponse::new(output).with_warnings(warnings)))
+}
+
+// whether a log of an action with the sampling rate is stored, roll is
+// uniform in [0, 1). Actions without a rate are always stored.
+fn keep_sampled(rate: Option<&f64>, roll: f64) -> bool {
+    match rate {
+        None => true,
+        Some(rate) => roll < *rate,
+    }
+}
+
+fn is_sampled(rate: Option<&f64>) -> bool {
+    matches!(rate, Some(rate) if *rate < 1.0)
+}
+
+// fails on an unknown action or a rate out of [0, 1], so a bad config stops
+// the startup.
+pub fn check_sampling(sampling: &HashMap<String, f64>) -> anyhow::Result<()> {
+    for (name, rate) in sampling {
+        if action::to_action(name).is_none() {
+            anyhow::bail!("invalid sampling action {}", name);
+        }
+        if !(0.0..=1.0).contains(rate) {
+            anyhow::bail!(
+                "invalid sampling rate of {}: expected 0 to 1, got {}",
+                name,
+                rate
+            );
+        }
+    }
+    Ok(())
+}
+
+fn sample_roll() -> f64 {
+    // the low 62 bits of a v4 uuid are random, 53 of them fill the f64 mantissa.
+    let bits = uuid::Uuid::new_v4().as_u128() as u64 & ((1u64 << 53) - 1);
+    bits as f64 / (1u64 << 53) as f64
+}
+
+// an external id maps to one log only, a second create with it fails with 409.
+async fn claim_external_id(
+    store: &dyn db::LogStore,
+    uid: xid::Id,
+    external_id: &str,
+    id: xid::Id,
+) -> Result<(), HTTPError> {
+    if store.put_external_id(uid, external_id, id, true).await? {
+        return Ok(());
+    }
+    let existing = store.get_external_id(uid, external_id).await?;
+    Err(HTTPError::new(
+        409,
+        format!("external id {} is taken by log {}", external_id, existing),
+    ))
+}
+
+// logs whose status changed through append_status take the status of the
+// newest change, which also covers histories written before append_status
+// wrote the status to the log.
+async fn apply_status_history(
+    store: &dyn db::LogStore,
+    uid: xid::Id,
+    docs: &mut [db::Log],
+) -> Result<(), HTTPError> {
+    if docs.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<xid::Id> = docs.iter().map(|r| r.id).collect();
+    let latest = store.latest_statuses(uid, &ids).await?;
+    for doc in docs {
+        if let Some(entry) = latest.get(&doc.id) {
+            doc.apply_status(entry);
+        }
+    }
+    Ok(())
+}
+
+// frees an external id claimed by a create that then failed, so a retry of
+// the create can claim it again. A failed release is only logged, the create
+// fails anyway.
+async fn release_external_id(
+    store: &dyn db::LogStore,
+    uid: xid::Id,
+    external_id: &Option<String>,
+    id: xid::Id,
+) {
+    if let Some(external_id) = external_id {
+        if let Err(err) = store.release_external_id(uid, external_id, id).await {
+            log::warn!(
+                "release external id {} of {} failed: {}",
+                external_id,
+                uid,
+                err
+            );
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Validate)]
-pub struct UpdateLogInput {
+pub struct QueryByExternal {
     pub uid: PackObject<xid::Id>,
-    pub id: PackObject<xid::Id>,
-    pub status: i8,
-    pub payload: Option<PackObject<Vec<u8>>>,
-    #[validate(range(min = 0))]
-    pub tokens: Option<i32>,
-    pub error: Option<String>,
+    #[validate(length(min = 1, max = 128))]
+    pub external_id: String,
+    pub fields: Option<String>,
 }
 
-pub async fn update(
+// reads a log by the external id given on create. Deleting a log keeps its
+// external id, which then resolves to 404.
+pub async fn get_by_external(
     State(app): State<Arc<AppState>>,
     Extension(ctx): Extension<Arc<ReqContext>>,
-    to: PackObject<UpdateLogInput>,
+    to: PackObject<()>,
+    Query(input): Query<QueryByExternal>,
 ) -> Result<PackObject<SuccessResponse<LogOutput>>, HTTPError> {
-    let (to, input) = to.unpack();
     input.validate()?;
 
-    if input.status != -1 && input.status != 1 {
-        return Err(HTTPError::new(
-            400,
-            format!("invalid status, expected -1 or 1, got {}", input.status),
-        ));
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "get_log_by_external".into())])
+        .await;
+
+    let fields = list_fields(&app.cfg.pagination, get_fields(input.fields), true)?;
+    let doc = get_by_external_id(
+        store.as_ref(),
+        input.uid.unwrap(),
+        &input.external_id,
+        fields,
+    )
+    .await?;
+    Ok(to.with(SuccessResponse::new(LogOutput::redacted(
+        doc,
+        &to,
+        app.redacted_fields(&ctx),
+    ))))
+}
+
+async fn get_by_external_id(
+    store: &dyn db::LogStore,
+    uid: xid::Id,
+    external_id: &str,
+    fields: Vec<String>,
+) -> Result<db::Log, HTTPError> {
+    let id = store.get_external_id(uid, external_id).await?;
+    let mut doc = db::Log::with_pk(uid, id);
+    store.get_one(&mut doc, fields).await?;
+    Ok(doc)
+}
+
+const INSERT_ATTEMPTS: usize = 3;
+
+// inserts a new log without clobbering an existing one, a colliding id is
+// regenerated with new_id and retried.
+async fn insert_unique(
+    store: &dyn db::LogStore,
+    doc: &mut db::Log,
+    cols: &ColumnsMap,
+    new_id: impl Fn() -> xid::Id,
+) -> Result<(), HTTPError> {
+    for _ in 0..INSERT_ATTEMPTS {
+        if store.insert(doc, cols).await? {
+            return Ok(());
+        }
+        log::warn!("log id {} of {} collided, retrying", doc.id, doc.uid);
+        doc.id = new_id();
     }
+    Err(HTTPError::new(
+        409,
+        format!("log id collided {} times", INSERT_ATTEMPTS),
+    ))
+}
 
-    ctx.set_kvs(vec![("action", "update_log".into())]).await;
-    let mut doc = db::Log::with_pk(input.uid.unwrap(), input.id.unwrap());
-    let mut cols: ColumnsMap = ColumnsMap::with_capacity(3);
-    cols.set_as("status", &input.status);
-    if input.payload.is_some() {
-        cols.set_as("payload", &input.payload.unwrap().unwrap());
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ValidateOutput {
+    pub valid: bool,
+    pub failures: Vec<ValidateFailure>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ValidateFailure {
+    pub rule: String, // input, ip, action, payload_required, payload_type, token_cap, anonymous, required_context, sampling or payload_encoding
+    pub message: String,
+}
+
+// runs the create-time validations without writing.
+pub async fn validate(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    headers: HeaderMap,
+    to: PackObject<CreateLogInput>,
+) -> Result<PackObject<SuccessResponse<ValidateOutput>>, HTTPError> {
+    let (to, mut input) = to.unpack();
+    ctx.set_kvs(vec![("action", "validate_log".into())]).await;
+
+    let failures = match check_create(&app.cfg, &ctx, &headers, &mut input) {
+        Ok(_) => Vec::new(),
+        Err(failures) => failures
+            .into_iter()
+            .map(|(rule, err)| ValidateFailure {
+                rule: rule.to_string(),
+                message: err.message,
+            })
+            .collect(),
+    };
+    let res = ValidateOutput {
+        valid: failures.is_empty(),
+        failures,
+    };
+    ctx.set_kvs(vec![("valid", res.valid.into())]).await;
+    Ok(to.with(SuccessResponse::new(res)))
+}
+
+// the checks of create, shared with validate. Returns the action, with the
+// action, ip and payload of the input normalized, or every failed check
+// with its rule. An empty ip is captured from the request by create.
+fn check_create(
+    cfg: &conf::Conf,
+    ctx: &ReqContext,
+    headers: &HeaderMap,
+    input: &mut CreateLogInput,
+) -> Result<i8, Vec<(&'static str, HTTPError)>> {
+    let mut failures: Vec<(&'static str, HTTPError)> = Vec::new();
+    if let Err(err) = input.validate() {
+        failures.push(("input", err.into()));
     }
-    if input.tokens.is_some() {
-        cols.set_as("tokens", &input.tokens.unwrap());
+    if !input.ip.is_empty() {
+        match normalize_ip(&input.ip, cfg.keep_ipv6_zone) {
+            Ok(ip) => input.ip = ip,
+            Err(err) => failures.push(("ip", err)),
+        }
+    }
+
+    let i = action::normalize_name(&input.action).and_then(|name| {
+        action::to_action(&name)
+            .ok_or_else(|| HTTPError::new(400, format!("invalid action {}", input.action)))
+    });
+    let i = match i {
+        Ok(i) => {
+            let name = action::from_action(i);
+            input.action = name.clone();
+            if let Err(err) = action::check_payload(&name, &input.payload) {
+                failures.push(("payload_required", err));
+            }
+            if let Err(err) =
+                check_payload_type(&cfg.payload, input.payload_type.as_deref(), &input.payload)
+            {
+                failures.push(("payload_type", err));
+            }
+            if let Err(err) = check_token_cap(&cfg.token_caps, &name, input.tokens) {
+                failures.push(("token_cap", err));
+            }
+            if let Err(err) = check_anonymous(&cfg.anonymous_actions, &input.uid, &name) {
+                failures.push(("anonymous", err));
+            }
+            if let Err(err) = check_required_context(&cfg.required_context, ctx, headers, &name) {
+                failures.push(("required_context", err));
+            }
+            // a sampled-out log can't be found by its external id, so retries
+            // would be sampled again rather than deduplicated.
+            if input.external_id.is_some() && is_sampled(cfg.sampling.get(&name)) {
+                failures.push((
+                    "sampling",
+                    HTTPError::new(
+                        400,
+                        format!("external_id can't be used with the sampled action {}", name),
+                    ),
+                ));
+            }
+            Some(i)
+        }
+        Err(err) => {
+            failures.push(("action", err));
+            None
+        }
+    };
+
+    match normalize_payload(&cfg.payload, input.payload.to_vec()) {
+        Ok(payload) => input.payload = input.payload.with(payload),
+        Err(err) => failures.push(("payload_encoding", err)),
     }
-    if input.error.is_some() {
-        cols.set_as("error", &input.error.unwrap());
+
+    match i {
+        Some(i) if failures.is_empty() => Ok(i),
+        _ => Err(failures),
     }
+}
 
-    doc.upsert_fields(&app.scylla, cols).await?;
-    Ok(to.with(SuccessResponse::new(LogOutput::from(doc, &to))))
+#[derive(Debug, Deserialize, Serialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ImportLog {
+    pub uid: PackObject<xid::Id>,
+    pub id: PackObject<xid::Id>,
+    pub action: String,
+    #[validate(custom = "validate_status")]
+    pub status: db::Status,
+    pub gid: PackObject<xid::Id>,
+    #[serde(default)]
+    pub ip: String,
+    pub payload: PackObject<Vec<u8>>,
+    #[validate(range(min = 0))]
+    pub tokens: i32,
+    #[serde(default)]
+    #[validate(length(max = 2000))]
+    pub error: String,
+    #[serde(default)]
+    pub trace_id: String,
+    pub updated_at: Option<i64>, // defaults to the id's timestamp
+    #[serde(default)]
+    #[validate(custom = "validate_labels")]
+    pub labels: HashMap<String, String>,
+    #[validate(custom = "validate_payload_type")]
+    pub payload_type: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Validate)]
-pub struct ListRecentlyInput {
-    pub uid: PackObject<xid::Id>,
-    #[validate(length(min = 0, max = 10))]
-    pub actions: Vec<String>,
-    pub fields: Option<Vec<String>>,
+#[serde(deny_unknown_fields)]
+pub struct ImportLogInput {
+    #[validate(length(min = 1, max = 1000))]
+    pub logs: Vec<ImportLog>,
 }
 
-pub async fn list_recently(
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImportOutput {
+    pub imported: u64,
+    pub skipped: Vec<PackObject<xid::Id>>, // ids of logs that already exist, left as they are
+}
+
+// unlike create, the ids and timestamps of the records are kept as-is. The
+// action, ip and payload are normalized as create does.
+pub async fn import(
     State(app): State<Arc<AppState>>,
     Extension(ctx): Extension<Arc<ReqContext>>,
-    to: PackObject<ListRecentlyInput>,
-) -> Result<PackObject<SuccessResponse<Vec<LogOutput>>>, HTTPError> {
+    to: PackObject<ImportLogInput>,
+) -> Result<PackObject<SuccessResponse<ImportOutput>>, HTTPError> {
     let (to, input) = to.unpack();
     input.validate()?;
+    app.check_writable()?;
+
+    let mut logs: Vec<db::Log> = Vec::with_capacity(input.logs.len());
+    for item in input.logs {
+        item.validate()?;
+        let i = action::to_action(&action::normalize_name(&item.action)?)
+            .ok_or_else(|| HTTPError::new(400, format!("invalid action {}", item.action)))?;
+        let name = action::from_action(i);
+        check_anonymous(&app.cfg.anonymous_actions, &item.uid, &name)?;
+        check_token_cap(&app.cfg.token_caps, &name, item.tokens)?;
 
-    let mut actions: Vec<i8> = Vec::with_capacity(input.actions.len());
-    for a in input.actions.iter() {
-        let i = action::to_action(a)
-            .ok_or_else(|| HTTPError::new(400, format!("invalid action {}", a)))?;
-        actions.push(i);
+        let mut doc = db::Log::with_pk(item.uid.unwrap(), item.id.unwrap());
+        doc.action = i;
+        doc.status = item.status;
+        doc.gid = item.gid.unwrap();
+        if !item.ip.is_empty() {
+            doc.ip = normalize_ip(&item.ip, app.cfg.keep_ipv6_zone)?;
+        }
+        doc.payload = normalize_payload(&app.cfg.payload, item.payload.unwrap())?;
+        doc.payload_type = item.payload_type.unwrap_or_default();
+        doc.tokens = item.tokens;
+        doc.error = item.error;
+        doc.trace_id = item.trace_id;
+        doc.labels = item.labels;
+        doc.updated_at = item
+            .updated_at
+            .unwrap_or_else(|| db::xid_unix(&doc.id) as i64 * 1000);
+        logs.push(doc);
     }
 
-    ctx.set_kvs(vec![("action", "list_recently".into())]).await;
-    let res = db::Log::list_recently(
-        &app.scylla,
-        input.uid.unwrap(),
-        input.fields.unwrap_or_default(),
-        actions,
-    )
-    .await?;
-    Ok(to.with(SuccessResponse::new(
-        res.iter()
-            .map(|r| LogOutput::from(r.to_owned(), &to))
-            .collect(),
-    )))
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![
+        ("action", "import_logs".into()),
+        ("count", logs.len().into()),
+    ])
+    .await;
+    let (imported, skipped) = store.import_batch(&logs).await?;
+    Ok(to.with(SuccessResponse::new(ImportOutput {
+        imported,
+        skipped: skipped.into_iter().map(|id| to.with(id)).collect(),
+    })))
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// applies the configured policy to gzip payloads. The stored form is told
+// apart by its magic bytes, so keeping the gzip one needs no other marker.
+fn normalize_payload(cfg: &conf::Payload, payload: Vec<u8>) -> Result<Vec<u8>, HTTPError> {
+    if cfg.gzip != "zstd" || !payload.starts_with(&GZIP_MAGIC) {
+        return Ok(payload);
+    }
+
+    let raw = gunzip(cfg, &payload)?;
+    let zstd = Encoding::Zstd
+        .encode_all(&raw[..])
+        .map_err(|err| HTTPError::new(500, format!("{:?}", err)))?;
+    // already-compressed content doesn't shrink, keep what was sent
+    let saving = 1.0 - zstd.len() as f64 / payload.len() as f64;
+    if saving < cfg.zstd_min_saving {
+        return Ok(payload);
+    }
+    Ok(zstd)
+}
+
+// decompresses a gzip payload, at most cfg.max_gunzip_size bytes of it.
+fn gunzip(cfg: &conf::Payload, payload: &[u8]) -> Result<Vec<u8>, HTTPError> {
+    let mut raw: Vec<u8> = Vec::new();
+    libflate::gzip::Decoder::new(payload)
+        .and_then(|decoder| {
+            decoder
+                .take(cfg.max_gunzip_size as u64 + 1)
+                .read_to_end(&mut raw)
+        })
+        .map_err(|err| HTTPError::new(400, format!("invalid gzip payload, {}", err)))?;
+    if raw.len() > cfg.max_gunzip_size {
+        return Err(HTTPError::new(
+            400,
+            format!(
+                "gzip payload decompresses to more than {} bytes",
+                cfg.max_gunzip_size
+            ),
+        ));
+    }
+    Ok(raw)
+}
+
+fn check_token_cap(
+    caps: &HashMap<String, i32>,
+    action: &str,
+    tokens: i32,
+) -> Result<(), HTTPError> {
+    match caps.get(action) {
+        Some(cap) if tokens > *cap => Err(HTTPError::new(
+            400,
+            format!(
+                "tokens {} exceeds the cap {} of action {}",
+                tokens, cap, action
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_anonymous(allowed: &[String], uid: &xid::Id, action: &str) -> Result<(), HTTPError> {
+    if *uid == db::ANONYMOUS_UID && !allowed.iter().any(|a| a == action) {
+        return Err(HTTPError::new(
+            400,
+            format!("action {} can't be logged under the anonymous uid", action),
+        ));
+    }
+    Ok(())
+}
+
+// the action's required context keys must be present in the request.
+// "user", "tenant", "role" and "deadline" are read by the context middleware,
+// "caller" is the x-auth-app header set by authenticate, other keys are
+// x-<key> headers.
+fn check_required_context(
+    required: &HashMap<String, Vec<String>>,
+    ctx: &ReqContext,
+    headers: &HeaderMap,
+    action: &str,
+) -> Result<(), HTTPError> {
+    let keys = match required.get(action) {
+        Some(keys) => keys,
+        None => return Ok(()),
+    };
+    let has_header = |name: &str| {
+        headers
+            .get(name)
+            .map_or(false, |v| !v.as_bytes().iter().all(u8::is_ascii_whitespace))
+    };
+    let missing: Vec<&str> = keys
+        .iter()
+        .filter(|key| match key.as_str() {
+            "user" => ctx.user == xid::Id::default(),
+            "tenant" => ctx.tenant.is_empty(),
+            "role" => ctx.role.is_empty(),
+            "deadline" => ctx.deadline.is_none(),
+            "caller" => !has_header("x-auth-app"),
+            key => !has_header(&format!("x-{}", key)),
+        })
+        .map(|key| key.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(HTTPError::new(
+            400,
+            format!("action {} requires context {}", action, missing.join(",")),
+        ));
+    }
+    Ok(())
+}
+
+// non-fatal advisories on inputs that are valid but suspicious.
+fn soft_warnings(
+    cfg: &conf::Warning,
+    action: Option<&str>,
+    ip: Option<&str>,
+    tokens: Option<i32>,
+) -> Vec<String> {
+    let mut warnings: Vec<String> = Vec::new();
+    if !cfg.enabled {
+        return warnings;
+    }
+
+    if let (Some(action), Some(ip)) = (action, ip) {
+        // "sys.*" actions are internal, others are triggered by public requests.
+        if !action.starts_with("sys.") {
+            if let Ok(addr) = IpAddr::from_str(ip) {
+                if is_private_ip(&addr) {
+                    warnings.push(format!("private ip {} for public action {}", ip, action));
+                }
+            }
+        }
+    }
+
+    if let Some(tokens) = tokens {
+        if tokens > cfg.max_tokens {
+            warnings.push(format!(
+                "tokens {} is above the threshold {}",
+                tokens, cfg.max_tokens
+            ));
+        }
+    }
+
+    warnings
+}
+
+// the canonical stored form of an ip: dotted IPv4 or RFC 5952 IPv6, like
+// "2001:db8::1". The zone of a scoped IPv6 address, "%eth0" of "fe80::1%eth0",
+// is stripped unless keep_zone is set, then it's kept as-is after the "%".
+fn normalize_ip(ip: &str, keep_zone: bool) -> Result<String, HTTPError> {
+    let invalid = || HTTPError::new(400, format!("invalid ip {:?}", ip));
+    let (addr, zone) = match ip.split_once('%') {
+        Some((addr, zone)) => (addr, Some(zone)),
+        None => (ip, None),
+    };
+    let addr = IpAddr::from_str(addr).map_err(|_| invalid())?;
+    match zone {
+        None => Ok(addr.to_string()),
+        Some(zone) => {
+            let valid = addr.is_ipv6()
+                && !zone.is_empty()
+                && zone
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+            if !valid {
+                return Err(invalid());
+            }
+            if keep_zone {
+                Ok(format!("{}%{}", addr, zone))
+            } else {
+                Ok(addr.to_string())
+            }
+        }
+    }
+}
+
+fn is_private_ip(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
+        IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || (v6.segments()[0] & 0xfe00) == 0xfc00 // unique local
+                || (v6.segments()[0] & 0xffc0) == 0xfe80 // link local
+        }
+    }
+}
+
+// seconds an id's timestamp may run ahead of the server clock.
+const ID_CLOCK_SKEW_SECS: u32 = 60;
+
+// rejects ids from the future, they can only come from a skewed clock or a
+// crafted request and break the time ordered queries.
+fn validate_id_not_future(id: &PackObject<xid::Id>) -> Result<(), ValidationError> {
+    let ts = db::xid_unix(id.unwrap_ref());
+    let now = (unix_ms() / 1000) as u32;
+    if ts > now + ID_CLOCK_SKEW_SECS {
+        let mut err = ValidationError::new("id");
+        err.message = Some(format!("invalid id, its timestamp {} is in the future", ts).into());
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn validate_final_status(status: &db::Status) -> Result<(), ValidationError> {
+    if !matches!(status, db::Status::Failed | db::Status::Frozen) {
+        let mut err = ValidationError::new("status");
+        err.message = Some(format!("invalid status, expected -1 or 1, got {}", status).into());
+        return Err(err);
+    }
+    Ok(())
+}
+
+// a deleted status is only set by deleting the log.
+fn validate_status(status: &db::Status) -> Result<(), ValidationError> {
+    if *status == db::Status::Deleted {
+        let mut err = ValidationError::new("status");
+        err.message = Some(format!("invalid status, expected -1, 0 or 1, got {}", status).into());
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateLogInput {
+    pub uid: PackObject<xid::Id>,
+    #[validate(custom = "validate_id_not_future")]
+    pub id: PackObject<xid::Id>,
+    #[validate(custom = "validate_final_status")]
+    pub status: db::Status,
+    pub payload: Option<PackObject<Vec<u8>>>,
+    #[validate(range(min = 0))]
+    pub tokens: Option<i32>,
+    #[validate(length(max = 2000))]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub clear_fields: Vec<String>, // fields reset to empty, one of CLEARABLE_FIELDS
+    pub override_freeze: Option<bool>, // corrects a frozen log of freeze_bypass_actions, admin only
+}
+
+pub const CLEARABLE_FIELDS: [&str; 2] = ["error", "payload"];
+
+// resets the named fields to empty values rather than null, which would
+// leave a tombstone. The payload of an action that requires one can't be
+// cleared.
+fn clear_cols(
+    cols: &mut ColumnsMap,
+    clear_fields: &[String],
+    action: &str,
+) -> Result<(), HTTPError> {
+    for field in clear_fields {
+        if !CLEARABLE_FIELDS.contains(&field.as_str()) {
+            return Err(HTTPError::new(
+                400,
+                format!("field {} can't be cleared", field),
+            ));
+        }
+        if cols.has(field) {
+            return Err(HTTPError::new(
+                400,
+                format!("field {} is both set and cleared", field),
+            ));
+        }
+        match field.as_str() {
+            "error" => cols.set_as(field, &"".to_string()),
+            _ => {
+                action::check_payload(action, &[])?;
+                cols.set_as(field, &Vec::<u8>::new())
+            }
+        }
+    }
+    Ok(())
+}
+
+// fails on an unknown action, so a typo doesn't silently keep the freeze.
+pub fn check_freeze_bypass_actions(actions: &[String]) -> anyhow::Result<()> {
+    if let Some(name) = actions.iter().find(|a| action::to_action(a).is_none()) {
+        anyhow::bail!("invalid freeze_bypass_actions action {}", name);
+    }
+    Ok(())
+}
+
+pub async fn update(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<UpdateLogInput>,
+) -> Result<PackObject<SuccessResponse<LogOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.check_writable()?;
+
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "update_log".into())]).await;
+    let mut doc = db::Log::with_pk(input.uid.unwrap(), input.id.unwrap());
+    // the token and payload checks of create need the action of the log
+    let checks_tokens = input.tokens.is_some() && !app.cfg.token_caps.is_empty();
+    let checks_payload =
+        input.payload.is_some() || input.clear_fields.iter().any(|f| f == "payload");
+    if checks_tokens || checks_payload {
+        store
+            .get_one(
+                &mut doc,
+                vec!["action".to_string(), "payload_type".to_string()],
+            )
+            .await?;
+    }
+    let name = action::from_action(doc.action);
+    if let Some(tokens) = input.tokens {
+        check_token_cap(&app.cfg.token_caps, &name, tokens)?;
+    }
+    let mut cols: ColumnsMap = ColumnsMap::with_capacity(3);
+    cols.set_as("status", &input.status);
+    if let Some(payload) = input.payload {
+        let payload = payload.unwrap();
+        action::check_payload(&name, &payload)?;
+        let payload_type = Some(doc.payload_type.as_str()).filter(|t| !t.is_empty());
+        check_payload_type(&app.cfg.payload, payload_type, &payload)?;
+        cols.set_as("payload", &normalize_payload(&app.cfg.payload, payload)?);
+    }
+    if let Some(tokens) = input.tokens {
+        cols.set_as("tokens", &tokens);
+    }
+    if let Some(error) = input.error {
+        cols.set_as("error", &error);
+    }
+    clear_cols(&mut cols, &input.clear_fields, &name)?;
+
+    let warnings = soft_warnings(&app.cfg.warning, None, None, input.tokens);
+    if input.override_freeze.unwrap_or(false) {
+        app.check_admin(&ctx)?;
+        let bypass_actions: Vec<i8> = app
+            .cfg
+            .freeze_bypass_actions
+            .iter()
+            .filter_map(|a| action::to_action(a))
+            .collect();
+        store
+            .upsert_fields_overriding(
+                &mut doc,
+                cols,
+                &app.cfg.mutable_after_freeze,
+                &bypass_actions,
+            )
+            .await?;
+        if doc.overridden {
+            ctx.set_kvs(vec![("freeze_overridden", true.into())]).await;
+            doc._fields.push("overridden".to_string());
+        }
+    } else {
+        store
+            .upsert_fields(&mut doc, cols, &app.cfg.mutable_after_freeze)
+            .await?;
+    }
+    Ok(to.with(SuccessResponse::new(LogOutput::from(doc, &to)).with_warnings(warnings)))
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct BatchUpsertItem {
+    #[validate(custom = "validate_id_not_future")]
+    pub id: PackObject<xid::Id>,
+    #[validate(custom = "validate_final_status")]
+    pub status: Option<db::Status>,
+    pub payload: Option<PackObject<Vec<u8>>>,
+    #[validate(range(min = 0))]
+    pub tokens: Option<i32>,
+    #[validate(length(max = 2000))]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct BatchUpsertInput {
+    pub uid: PackObject<xid::Id>,
+    #[validate(length(min = 1, max = 100))] // db::BATCH_UPSERT_MAX
+    pub logs: Vec<BatchUpsertItem>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchUpsertOutput {
+    pub updated: u64,
+}
+
+// writes different fields to many logs of a uid, for bulk corrections. The
+// logs are written as one batch, it fails as a whole if any log is frozen or
+// fails the payload and token checks of create, see db::Log::batch_upsert.
+pub async fn batch_upsert(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<BatchUpsertInput>,
+) -> Result<PackObject<SuccessResponse<BatchUpsertOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.check_writable()?;
+    for item in &input.logs {
+        item.validate()?;
+    }
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![
+        ("action", "batch_upsert_logs".into()),
+        ("count", input.logs.len().into()),
+    ])
+    .await;
+    let checked: Vec<xid::Id> = input
+        .logs
+        .iter()
+        .filter(|item| item.payload.is_some() || item.tokens.is_some())
+        .map(|item| *item.id)
+        .collect();
+    if !checked.is_empty() {
+        let docs = db::Log::get_many(
+            &scylla,
+            *input.uid,
+            &checked,
+            vec!["action".to_string(), "payload_type".to_string()],
+            ctx.remaining_ms(),
+        )
+        .await?;
+        for doc in docs {
+            if let Some(item) = input.logs.iter().find(|item| *item.id == doc.id) {
+                let name = action::from_action(doc.action);
+                if let Some(payload) = &item.payload {
+                    action::check_payload(&name, payload)?;
+                    let payload_type = Some(doc.payload_type.as_str()).filter(|t| !t.is_empty());
+                    check_payload_type(&app.cfg.payload, payload_type, payload)?;
+                }
+                if let Some(tokens) = item.tokens {
+                    check_token_cap(&app.cfg.token_caps, &name, tokens)?;
+                }
+            }
+        }
+    }
+
+    let mut rows: Vec<(xid::Id, ColumnsMap)> = Vec::with_capacity(input.logs.len());
+    for item in input.logs {
+        let mut cols: ColumnsMap = ColumnsMap::with_capacity(4);
+        if let Some(status) = item.status {
+            cols.set_as("status", &status);
+        }
+        if let Some(payload) = item.payload {
+            cols.set_as(
+                "payload",
+                &normalize_payload(&app.cfg.payload, payload.unwrap())?,
+            );
+        }
+        if let Some(tokens) = item.tokens {
+            cols.set_as("tokens", &tokens);
+        }
+        if let Some(error) = item.error {
+            cols.set_as("error", &error);
+        }
+        rows.push((item.id.unwrap(), cols));
+    }
+    check_batch_bytes(&rows, app.cfg.write_buffer.max_batch_bytes)?;
+
+    let updated = db::Log::batch_upsert(
+        &scylla,
+        input.uid.unwrap(),
+        rows,
+        &app.cfg.mutable_after_freeze,
+    )
+    .await?;
+    Ok(to.with(SuccessResponse::new(BatchUpsertOutput { updated })))
+}
+
+// the payloads and errors of a batch of more than one log must fit in
+// max_batch_bytes, the batch limit of the write buffer.
+fn check_batch_bytes(rows: &[(xid::Id, ColumnsMap)], max_bytes: usize) -> Result<(), HTTPError> {
+    if rows.len() < 2 {
+        return Ok(());
+    }
+    let size: usize = rows
+        .iter()
+        .map(|(_, cols)| {
+            let payload: Vec<u8> = cols.get_as("payload").unwrap_or_default();
+            let error: String = cols.get_as("error").unwrap_or_default();
+            payload.len() + error.len()
+        })
+        .sum();
+    if size > max_bytes {
+        return Err(HTTPError::new(
+            400,
+            format!(
+                "batch of {} bytes is larger than {} bytes, send fewer logs",
+                size, max_bytes
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct AddTokensInput {
+    pub uid: PackObject<xid::Id>,
+    pub id: PackObject<xid::Id>,
+    #[validate(range(min = 1))]
+    pub delta: i32,
+}
+
+pub async fn add_tokens(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<AddTokensInput>,
+) -> Result<PackObject<SuccessResponse<LogOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.check_writable()?;
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![
+        ("action", "add_tokens".into()),
+        ("delta", input.delta.into()),
+    ])
+    .await;
+    let mut doc = db::Log::with_pk(input.uid.unwrap(), input.id.unwrap());
+    let mut cap: Option<i32> = None;
+    if !app.cfg.token_caps.is_empty() {
+        doc.get_one(&scylla, vec!["action".to_string()]).await?;
+        cap = app
+            .cfg
+            .token_caps
+            .get(&action::from_action(doc.action))
+            .copied();
+    }
+    doc.add_tokens(&scylla, input.delta, cap).await?;
+    Ok(to.with(SuccessResponse::new(LogOutput::from(doc, &to))))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ListInput {
+    pub uid: PackObject<xid::Id>,
+    pub page_size: Option<u16>,
+    pub page_token: Option<PackObject<Vec<u8>>>,
+    pub before: Option<PackObject<Vec<u8>>>, // a prev_page_token, lists the newer page instead, not with action or label
+    pub action: Option<String>,
+    pub since: Option<u32>, // unix timestamp (seconds), lower bound of the window
+    pub fields: Option<Vec<String>>,
+    pub with_payload: Option<bool>, // required to explicitly select payload
+    pub label: Option<String>,      // "key=value", only logs with the label
+}
+
+// sets the cursors of a list page, next_page_token pages to older logs and
+// prev_page_token to newer ones. A page read back from `before` always has
+// the older page it came from, and is the newest page if it is short.
+fn with_cursors<T>(
+    res: SuccessResponse<T>,
+    count: usize,
+    page_size: u16,
+    next: Option<PackObject<Vec<u8>>>,
+    prev: Option<PackObject<Vec<u8>>>,
+    backwards: bool,
+) -> SuccessResponse<T> {
+    if !backwards {
+        return res
+            .with_page(count, page_size as usize, next)
+            .with_prev_page(prev);
+    }
+    let full = count == page_size as usize;
+    let mut res = res
+        .with_page(count, page_size as usize, None)
+        .with_prev_page(if full { prev } else { None });
+    res.has_more = Some(count > 0);
+    res.next_page_token = next;
+    res
+}
+
+// bounds the projection size, and lists only return payload when it is
+// explicitly selected together with with_payload. An empty projection, e.g.
+// "full", is every field but payload then. Returns the fields to select.
+fn list_fields(
+    cfg: &conf::Pagination,
+    fields: Vec<String>,
+    with_payload: bool,
+) -> Result<Vec<String>, HTTPError> {
+    let fields = db::Log::expand_presets(fields);
+    if cfg.max_fields > 0 && fields.len() > cfg.max_fields {
+        return Err(HTTPError::new(
+            400,
+            format!(
+                "too many fields, expected at most {}, got {}",
+                cfg.max_fields,
+                fields.len()
+            ),
+        ));
+    }
+    if !with_payload && fields.iter().any(|f| f == "payload") {
+        return Err(HTTPError::new(
+            400,
+            "selecting payload in a list requires with_payload".to_string(),
+        ));
+    }
+    if fields.is_empty() && !with_payload {
+        return Ok(db::Log::fields()
+            .into_iter()
+            .filter(|f| f != "payload")
+            .collect());
+    }
+    Ok(fields)
+}
+
+// splits a "key=value" label filter.
+fn parse_label(label: &str) -> Result<(&str, &str), HTTPError> {
+    match label.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k, v)),
+        _ => Err(HTTPError::new(
+            400,
+            format!("invalid label {:?}, expected key=value", label),
+        )),
+    }
+}
+
+// filtered scans read with ALLOW FILTERING, so they must be bounded by a
+// time window or an explicit page size.
+fn check_scan_bounds(
+    filtered: bool,
+    since: Option<u32>,
+    page_size: Option<u16>,
+) -> Result<(), HTTPError> {
+    if filtered && since.is_none() && page_size.is_none() {
+        return Err(HTTPError::new(
+            400,
+            "filtered list requires a time window (since) or a page_size".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub async fn list(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    Query(flat): Query<FlatQuery>,
+    to: PackObject<ListInput>,
+) -> Result<Response, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let page_size = app.cfg.pagination.page_size(input.page_size);
+    let action = match input.action {
+        None => None,
+        Some(ref a) => Some(
+            action::to_action(a)
+                .ok_or_else(|| HTTPError::new(400, format!("invalid action {}", a)))?,
+        ),
+    };
+    let page_token = match input.page_token {
+        None => None,
+        Some(t) => Some(decode_page_token(&t.unwrap(), action, input.since)?),
+    };
+    let before = match input.before {
+        None => None,
+        Some(_) if action.is_some() || input.label.is_some() => {
+            return Err(HTTPError::new(
+                400,
+                "before can't be used with action or label".to_string(),
+            ));
+        }
+        Some(t) => Some(decode_page_token(&t.unwrap(), action, input.since)?),
+    };
+    if page_token.is_some() && before.is_some() {
+        return Err(HTTPError::new(
+            400,
+            "page_token and before can't be both set".to_string(),
+        ));
+    }
+    let label = match input.label {
+        None => None,
+        Some(ref l) => Some(parse_label(l)?),
+    };
+    // pages filtered by action or label can't be read back
+    let pages_back = action.is_none() && label.is_none();
+    check_scan_bounds(
+        action.is_some() || label.is_some(),
+        input.since,
+        input.page_size,
+    )?;
+    let fields = list_fields(
+        &app.cfg.pagination,
+        input.fields.unwrap_or_default(),
+        input.with_payload.unwrap_or(false),
+    )?;
+
+    let uid = *input.uid;
+    let store = app.store_for(&ctx)?;
+    let mut res = match label {
+        Some((key, value)) => {
+            ctx.set_kvs(vec![("action", "list_log".into())]).await;
+            store
+                .list_by_label(
+                    input.uid.unwrap(),
+                    fields,
+                    page_size,
+                    page_token,
+                    action,
+                    (key, value),
+                    input.since,
+                    ctx.remaining_ms(),
+                )
+                .await?
+        }
+        None => {
+            ctx.set_kvs(vec![("action", "list_log".into())]).await;
+            match before {
+                Some(before) => {
+                    store
+                        .list_before(
+                            input.uid.unwrap(),
+                            fields,
+                            page_size,
+                            before,
+                            input.since,
+                            ctx.remaining_ms(),
+                        )
+                        .await?
+                }
+                None => {
+                    store
+                        .list(
+                            input.uid.unwrap(),
+                            fields,
+                            page_size,
+                            page_token,
+                            action,
+                            input.since,
+                            ctx.remaining_ms(),
+                        )
+                        .await?
+                }
+            }
+        }
+    };
+    apply_status_history(store.as_ref(), uid, &mut res).await?;
+    let next_page_token = match res.last() {
+        Some(r) => Some(to.with(encode_page_token(r.id, action, input.since)?)),
+        None => None,
+    };
+    // the first page has no newer page
+    let prev_page_token = match res.first() {
+        Some(r) if pages_back && (page_token.is_some() || before.is_some()) => {
+            Some(to.with(encode_page_token(r.id, action, input.since)?))
+        }
+        _ => None,
+    };
+    let backwards = before.is_some();
+    let count = res.len();
+    let redacted = app.redacted_fields(&ctx);
+    if flat.flat.unwrap_or(false) {
+        return Ok(to
+            .with(with_cursors(
+                SuccessResponse::new(
+                    res.into_iter()
+                        .map(|mut r| {
+                            redact_fields(&mut r, redacted);
+                            flat_log(r)
+                        })
+                        .collect::<Vec<FlatLog>>(),
+                ),
+                count,
+                page_size,
+                next_page_token,
+                prev_page_token,
+                backwards,
+            ))
+            .into_response());
+    }
+
+    Ok(to
+        .with(with_cursors(
+            SuccessResponse::new(
+                res.into_iter()
+                    .map(|r| LogOutput::redacted(r, &to, redacted))
+                    .collect::<Vec<LogOutput>>(),
+            ),
+            count,
+            page_size,
+            next_page_token,
+            prev_page_token,
+            backwards,
+        ))
+        .into_response())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ListRecentlyMultiInput {
+    #[validate(length(min = 1, max = 100))]
+    pub uids: Vec<PackObject<xid::Id>>,
+    #[validate(length(min = 0, max = 10))]
+    pub actions: Vec<String>,
+    pub fields: Option<Vec<String>>,
+    pub with_payload: Option<bool>, // required to explicitly select payload
+    pub page_size: Option<u16>,
+}
+
+// admin tooling, recent logs across several users.
+pub async fn list_recently_multi(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<ListRecentlyMultiInput>,
+) -> Result<PackObject<SuccessResponse<Vec<LogOutput>>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let actions = recent_actions(&input.actions)?;
+    let uids: Vec<xid::Id> = input.uids.into_iter().map(|u| u.unwrap()).collect();
+    let page_size = app.cfg.pagination.page_size(input.page_size);
+    let fields = list_fields(
+        &app.cfg.pagination,
+        input.fields.unwrap_or_default(),
+        input.with_payload.unwrap_or(false),
+    )?;
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![
+        ("action", "list_recently_multi".into()),
+        ("uids", uids.len().into()),
+    ])
+    .await;
+    let res = db::Log::list_recently_multi(
+        &scylla,
+        &uids,
+        fields,
+        actions,
+        page_size,
+        ctx.remaining_ms(),
+    )
+    .await?;
+    let redacted = app.redacted_fields(&ctx);
+    Ok(to.with(SuccessResponse::new(
+        res.into_iter()
+            .map(|r| LogOutput::redacted(r, &to, redacted))
+            .collect(),
+    )))
+}
+
+// resolves the action filter of recent lists, ["all"] and [] both mean
+// every action.
+fn recent_actions(names: &[String]) -> Result<Vec<i8>, HTTPError> {
+    if names.iter().any(|a| a == "all") {
+        if names.len() > 1 {
+            return Err(HTTPError::new(
+                400,
+                "\"all\" can't be mixed with other actions".to_string(),
+            ));
+        }
+        return Ok(vec![]);
+    }
+    action::resolve_actions(names)
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ListRecentlyInput {
+    pub uid: PackObject<xid::Id>,
+    #[validate(length(min = 0, max = 10))]
+    pub actions: Vec<String>,
+    pub fields: Option<Vec<String>>,
+    pub with_payload: Option<bool>, // required to explicitly select payload
+    #[validate(range(min = 1, max = 1000))]
+    pub limit: Option<u16>, // max rows, defaults to max_page_size
+}
+
+pub async fn list_recently(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<ListRecentlyInput>,
+) -> Result<PackObject<SuccessResponse<Vec<LogOutput>>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let actions = recent_actions(&input.actions)?;
+    let fields = list_fields(
+        &app.cfg.pagination,
+        input.fields.unwrap_or_default(),
+        input.with_payload.unwrap_or(false),
+    )?;
+
+    let limit = app.cfg.pagination.page_size(Some(
+        input.limit.unwrap_or(app.cfg.pagination.max_page_size),
+    ));
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "list_recently".into())]).await;
+    let res = db::Log::list_recently(
+        &scylla,
+        input.uid.unwrap(),
+        fields,
+        actions,
+        limit,
+        ctx.remaining_ms(),
+    )
+    .await?;
+    let redacted = app.redacted_fields(&ctx);
+    Ok(to.with(SuccessResponse::new(
+        res.into_iter()
+            .map(|r| LogOutput::redacted(r, &to, redacted))
+            .collect(),
+    )))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TopTokensInput {
+    pub uid: PackObject<xid::Id>,
+    #[validate(length(min = 0, max = 10))]
+    pub actions: Vec<String>,
+    pub fields: Option<Vec<String>>,
+    pub with_payload: Option<bool>, // required to explicitly select payload
+    #[validate(range(min = 1, max = 100))]
+    pub top: Option<usize>,
+}
+
+// the top token-consuming logs of the recent window list_recently scans,
+// bounded to the last 3 days and at most max_page_size logs. Scylla can't
+// order by tokens, so they are sorted in memory.
+pub async fn top_tokens(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<TopTokensInput>,
+) -> Result<PackObject<SuccessResponse<Vec<LogOutput>>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let actions = recent_actions(&input.actions)?;
+    let mut fields = list_fields(
+        &app.cfg.pagination,
+        input.fields.unwrap_or_default(),
+        input.with_payload.unwrap_or(false),
+    )?;
+    if !fields.is_empty() && !fields.contains(&"tokens".to_string()) {
+        fields.push("tokens".to_string());
+    }
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "top_tokens".into())]).await;
+    let res = db::Log::list_recently(
+        &scylla,
+        input.uid.unwrap(),
+        fields,
+        actions,
+        app.cfg.pagination.max_page_size,
+        ctx.remaining_ms(),
+    )
+    .await?;
+    let redacted = app.redacted_fields(&ctx);
+    Ok(to.with(SuccessResponse::new(
+        top_by_tokens(res, input.top.unwrap_or(10))
+            .into_iter()
+            .map(|r| LogOutput::redacted(r, &to, redacted))
+            .collect(),
+    )))
+}
+
+// tokens descending, the newest first on ties.
+fn top_by_tokens(mut logs: Vec<db::Log>, n: usize) -> Vec<db::Log> {
+    logs.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| b.id.0.cmp(&a.id.0)));
+    logs.truncate(n);
+    logs
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ChangedSinceInput {
+    pub uid: PackObject<xid::Id>,
+    pub updated_after: i64, // unix ms
+    #[validate(range(min = 1, max = 90))]
+    pub window_days: Option<u32>, // only logs created in the window are scanned
+    pub fields: Option<String>,
+}
+
+// logs updated after updated_after, the oldest update first, for incremental
+// sync of mutable fields. It scans the logs created in the last window_days
+// (7 by default), at most pagination.max_scan() of them.
+pub async fn changed_since(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<ChangedSinceInput>,
+) -> Result<PackObject<SuccessResponse<Vec<LogOutput>>>, HTTPError> {
+    input.validate()?;
+
+    let fields = list_fields(&app.cfg.pagination, get_fields(input.fields), false)?;
+    let since = (unix_ms() / 1000) as u32 - 3600 * 24 * input.window_days.unwrap_or(7);
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "changed_since".into())]).await;
+    let (res, truncated) = db::Log::list_changed_since(
+        &scylla,
+        input.uid.unwrap(),
+        fields,
+        input.updated_after,
+        since,
+        app.cfg.pagination.scan_page_size(),
+        app.cfg.pagination.max_scan(),
+    )
+    .await?;
+
+    let mut warnings: Vec<String> = Vec::new();
+    if truncated {
+        warnings.push(format!(
+            "scanned the newest {} logs only, narrow window_days",
+            app.cfg.pagination.max_scan()
+        ));
+    }
+    let redacted = app.redacted_fields(&ctx);
+    Ok(to.with(
+        SuccessResponse::new(
+            res.into_iter()
+                .map(|r| LogOutput::redacted(r, &to, redacted))
+                .collect(),
+        )
+        .with_warnings(warnings),
+    ))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SyncCursor {
+    pub updated_at: i64, // unix ms
+    pub id: PackObject<xid::Id>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SyncInput {
+    pub uid: PackObject<xid::Id>,
+    pub cursor: Option<SyncCursor>, // from the start if absent
+    pub page_size: Option<u16>,
+    #[validate(range(min = 1, max = 90))]
+    pub window_days: Option<u32>, // only logs created in the window are scanned
+    pub fields: Option<Vec<String>>,
+    pub with_payload: Option<bool>, // required to explicitly select payload
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncOutput {
+    pub logs: Vec<LogOutput>,
+    pub cursor: SyncCursor, // of the last log, or the input cursor if none
+    pub has_more: bool,
+}
+
+// created or updated logs after the cursor, the oldest write first, for
+// replicas to stay in sync. Every log carries updated_at, the next request
+// passes the returned cursor.
+pub async fn sync(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<SyncInput>,
+) -> Result<PackObject<SuccessResponse<SyncOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let fields = list_fields(
+        &app.cfg.pagination,
+        input.fields.unwrap_or_default(),
+        input.with_payload.unwrap_or(false),
+    )?;
+    let page_size = app.cfg.pagination.page_size(input.page_size);
+    let cursor = match input.cursor {
+        Some(c) => (c.updated_at, c.id.unwrap()),
+        None => (0, xid::Id::default()),
+    };
+    let since = (unix_ms() / 1000) as u32 - 3600 * 24 * input.window_days.unwrap_or(7);
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "sync_log".into())]).await;
+    let page: db::SyncPage = db::Log::sync_page(
+        &scylla,
+        input.uid.unwrap(),
+        fields,
+        cursor,
+        since,
+        app.cfg.pagination.sync_lag_ms,
+        app.cfg.pagination.scan_page_size(),
+        app.cfg.pagination.max_scan(),
+        page_size as usize,
+        ctx.remaining_ms(),
+    )
+    .await?;
+
+    let mut warnings: Vec<String> = Vec::new();
+    if page.truncated {
+        warnings.push(format!(
+            "scanned the newest {} logs only, narrow window_days",
+            app.cfg.pagination.max_scan()
+        ));
+    }
+    let next = page.cursor;
+    let redacted = app.redacted_fields(&ctx);
+    let logs = page
+        .logs
+        .into_iter()
+        .map(|mut r| {
+            if !r._fields.iter().any(|f| f == "updated_at") {
+                r._fields.push("updated_at".to_string());
+            }
+            LogOutput::redacted(r, &to, redacted)
+        })
+        .collect();
+    Ok(to.with(
+        SuccessResponse::new(SyncOutput {
+            logs,
+            cursor: SyncCursor {
+                updated_at: next.0,
+                id: to.with(next.1),
+            },
+            has_more: page.has_more,
+        })
+        .with_warnings(warnings),
+    ))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct BatchGetInput {
+    pub uid: PackObject<xid::Id>,
+    #[validate(length(min = 1, max = 1000))]
+    pub ids: Vec<PackObject<xid::Id>>,
+    pub fields: Option<Vec<String>>,
+    pub with_payload: Option<bool>, // required to explicitly select payload
+}
+
+// the logs of the ids that exist, newest first.
+pub async fn batch_get(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<BatchGetInput>,
+) -> Result<PackObject<SuccessResponse<Vec<LogOutput>>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let fields = list_fields(
+        &app.cfg.pagination,
+        input.fields.unwrap_or_default(),
+        input.with_payload.unwrap_or(false),
+    )?;
+    let ids: Vec<xid::Id> = input.ids.into_iter().map(|id| id.unwrap()).collect();
+
+    let scylla = app.scylla_for(&ctx)?;
+    let requested = ids.len();
+    let ids = db::Log::drop_absent(&scylla, *input.uid, ids).await?;
+    ctx.set_kvs(vec![
+        ("action", "batch_get_log".into()),
+        ("ids", requested.into()),
+        ("ids_skipped", (requested - ids.len()).into()),
+    ])
+    .await;
+    let res = db::Log::get_many(
+        &scylla,
+        input.uid.unwrap(),
+        &ids,
+        fields,
+        ctx.remaining_ms(),
+    )
+    .await?;
+    let redacted = app.redacted_fields(&ctx);
+    Ok(to.with(SuccessResponse::new(
+        res.into_iter()
+            .map(|r| LogOutput::redacted(r, &to, redacted))
+            .collect(),
+    )))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct LatestInput {
+    pub uid: PackObject<xid::Id>,
+    pub fields: Option<String>,
+}
+
+pub async fn latest(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<LatestInput>,
+) -> Result<PackObject<SuccessResponse<Vec<LogOutput>>>, HTTPError> {
+    input.validate()?;
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "latest_log".into())]).await;
+    let res = db::Log::latest_per_action(
+        &scylla,
+        input.uid.unwrap(),
+        get_fields(input.fields),
+        app.cfg.pagination.scan_page_size(),
+        app.cfg.pagination.max_scan(),
+    )
+    .await?;
+    let redacted = app.redacted_fields(&ctx);
+    Ok(to.with(SuccessResponse::new(
+        res.into_iter()
+            .map(|r| LogOutput::redacted(r, &to, redacted))
+            .collect(),
+    )))
+}
+
+// the oldest log of a user, 404 if the user has no logs.
+pub async fn earliest(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<LatestInput>,
+) -> Result<PackObject<SuccessResponse<LogOutput>>, HTTPError> {
+    input.validate()?;
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "earliest_log".into())]).await;
+    let doc = db::Log::earliest(&scylla, input.uid.unwrap(), get_fields(input.fields)).await?;
+    Ok(to.with(SuccessResponse::new(LogOutput::redacted(
+        doc,
+        &to,
+        app.redacted_fields(&ctx),
+    ))))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct SummaryInput {
+    pub uid: PackObject<xid::Id>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ActionSummary {
+    pub count: u64,
+    pub tokens: i64,
+    pub errors: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SummaryOutput {
+    pub since: u32,
+    pub count: u64,
+    pub tokens: i64,
+    pub errors: u64,
+    pub actions: BTreeMap<String, ActionSummary>,
+    pub truncated: bool, // true if the scan hit its limit
+}
+
+// counts, token sums and error counts of the last 24 hours.
+pub async fn summary(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<SummaryInput>,
+) -> Result<PackObject<SuccessResponse<SummaryOutput>>, HTTPError> {
+    input.validate()?;
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "summary_log".into())]).await;
+    let since = (unix_ms() / 1000) as u32 - 3600 * 24;
+    let mut res = db::Log::list_since(
+        &scylla,
+        input.uid.unwrap(),
+        vec!["tokens".to_string(), "error".to_string()],
+        since,
+        app.cfg.pagination.scan_page_size(),
+        app.cfg.pagination.max_scan(),
+    )
+    .await?;
+    let truncated = res.len() > app.cfg.pagination.max_scan();
+    res.truncate(app.cfg.pagination.max_scan());
+
+    let mut output = summarize(&res);
+    output.since = since;
+    output.truncated = truncated;
+    Ok(to.with(SuccessResponse::new(output)))
+}
+
+fn summarize(logs: &[db::Log]) -> SummaryOutput {
+    let mut output = SummaryOutput::default();
+    for doc in logs {
+        let failed = doc.status == db::Status::Failed || !doc.error.is_empty();
+        let entry = output
+            .actions
+            .entry(action::from_action(doc.action))
+            .or_default();
+        entry.count += 1;
+        entry.tokens += doc.tokens as i64;
+        output.count += 1;
+        output.tokens += doc.tokens as i64;
+        if failed {
+            entry.errors += 1;
+            output.errors += 1;
+        }
+    }
+    output
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct PurgeInput {
+    pub uid: PackObject<xid::Id>,
+    #[validate(range(min = 1, max = 3650))]
+    pub days: Option<u32>,
+    pub before: Option<u32>,
+    pub force: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PurgeOutput {
+    pub deleted: u64,
+}
+
+pub async fn purge(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<PurgeInput>,
+) -> Result<PackObject<SuccessResponse<PurgeOutput>>, HTTPError> {
+    input.validate()?;
+    app.check_writable()?;
+
+    let before = match (input.days, input.before) {
+        (Some(days), None) => (unix_ms() / 1000) as u32 - days * 3600 * 24,
+        (None, Some(before)) => before,
+        _ => {
+            return Err(HTTPError::new(
+                400,
+                "expected one of days or before".to_string(),
+            ))
+        }
+    };
+
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![
+        ("action", "purge_log".into()),
+        ("before", before.into()),
+    ])
+    .await;
+    let deleted = store
+        .delete_before(
+            input.uid.unwrap(),
+            None,
+            before,
+            input.force.unwrap_or(false),
+            app.cfg.pagination.scan_page_size(),
+        )
+        .await?;
+    Ok(to.with(SuccessResponse::new(PurgeOutput { deleted })))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct DeleteByActionInput {
+    pub uid: PackObject<xid::Id>,
+    pub action: String,
+    pub before: Option<u32>,
+    pub force: Option<bool>,
+}
+
+pub async fn delete_by_action(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<DeleteByActionInput>,
+) -> Result<PackObject<SuccessResponse<PurgeOutput>>, HTTPError> {
+    input.validate()?;
+    app.check_writable()?;
+
+    let action = action::to_action(&input.action)
+        .ok_or_else(|| HTTPError::new(400, format!("invalid action {}", input.action)))?;
+    let before = input
+        .before
+        .unwrap_or_else(|| (unix_ms() / 1000) as u32 + 1);
+
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![
+        ("action", "delete_log_by_action".into()),
+        ("log_action", input.action.clone().into()),
+        ("before", before.into()),
+    ])
+    .await;
+    let deleted = store
+        .delete_before(
+            input.uid.unwrap(),
+            Some(action),
+            before,
+            input.force.unwrap_or(false),
+            app.cfg.pagination.scan_page_size(),
+        )
+        .await?;
+    Ok(to.with(SuccessResponse::new(PurgeOutput { deleted })))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct TokenUsageInput {
+    pub uid: PackObject<xid::Id>,
+    pub action: Option<String>,
+    pub since: u32,
+    pub until: Option<u32>,
+    pub streamed: Option<bool>, // pages the logs instead of a SUM query
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TokenUsageOutput {
+    pub since: u32,
+    pub until: u32,
+    pub tokens: i64,
+    pub truncated: bool, // true if the streamed sum hit TOKEN_USAGE_MAX_PAGES or the deadline
+}
+
+const TOKEN_USAGE_MAX_PAGES: usize = 100;
+
+// the sum of tokens of logs created in [since, until).
+pub async fn token_usage(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<TokenUsageInput>,
+) -> Result<PackObject<SuccessResponse<TokenUsageOutput>>, HTTPError> {
+    input.validate()?;
+
+    let action = match input.action {
+        None => None,
+        Some(ref a) => Some(
+            action::to_action(a)
+                .ok_or_else(|| HTTPError::new(400, format!("invalid action {}", a)))?,
+        ),
+    };
+    let until = input.until.unwrap_or_else(|| (unix_ms() / 1000) as u32 + 1);
+    if input.since >= until {
+        return Err(HTTPError::new(
+            400,
+            format!("invalid window, since {} >= until {}", input.since, until),
+        ));
+    }
+
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "token_usage".into())]).await;
+    let uid = input.uid.unwrap();
+    let (tokens, truncated) = if input.streamed.unwrap_or(false) {
+        store
+            .sum_tokens_streamed(
+                uid,
+                action,
+                input.since,
+                until,
+                app.cfg.pagination.scan_page_size(),
+                TOKEN_USAGE_MAX_PAGES,
+                ctx.remaining_ms(),
+            )
+            .await?
+    } else {
+        (
+            store
+                .sum_tokens(uid, action, input.since, until, ctx.remaining_ms())
+                .await?,
+            false,
+        )
+    };
+    Ok(to.with(SuccessResponse::new(TokenUsageOutput {
+        since: input.since,
+        until,
+        tokens,
+        truncated,
+    })))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct StatusCountInput {
+    pub uid: PackObject<xid::Id>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct StatusCountOutput {
+    pub failed: u64,
+    pub processing: u64,
+    pub success: u64,
+    pub truncated: bool, // true if the scan hit its limit, the counts are of the newest logs
+}
+
+impl From<db::StatusCount> for StatusCountOutput {
+    fn from(c: db::StatusCount) -> Self {
+        Self {
+            failed: c.failed,
+            processing: c.processing,
+            success: c.success,
+            truncated: false,
+        }
+    }
+}
+
+// counts of failed, processing and success logs of a user.
+pub async fn status_count(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<StatusCountInput>,
+) -> Result<PackObject<SuccessResponse<StatusCountOutput>>, HTTPError> {
+    input.validate()?;
+
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "status_count".into())]).await;
+    let (counts, truncated) = db::Log::count_by_status(
+        &scylla,
+        input.uid.unwrap(),
+        app.cfg.pagination.scan_page_size(),
+        app.cfg.pagination.max_scan(),
+        ctx.remaining_ms(),
+    )
+    .await?;
+    let mut output = StatusCountOutput::from(counts);
+    output.truncated = truncated;
+    Ok(to.with(SuccessResponse::new(output)))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct AppendStatusInput {
+    pub uid: PackObject<xid::Id>,
+    #[validate(custom = "validate_id_not_future")]
+    pub id: PackObject<xid::Id>,
+    #[validate(custom = "validate_status")]
+    pub status: db::Status,
+    #[serde(default)]
+    pub error: String,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct StatusEntryOutput {
+    pub at: i64, // unix ms
+    pub status: db::Status,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub error: String,
+}
+
+impl From<db::StatusEntry> for StatusEntryOutput {
+    fn from(e: db::StatusEntry) -> Self {
+        Self {
+            at: e.at,
+            status: e.status,
+            error: e.error,
+        }
+    }
+}
+
+// appends a status change to the history of a log, the log itself is only
+// written when its status changes, see db::Log::append_status.
+pub async fn append_status(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<AppendStatusInput>,
+) -> Result<PackObject<SuccessResponse<StatusEntryOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+    app.check_writable()?;
+
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "append_log_status".into())])
+        .await;
+
+    let mut doc = db::Log::with_pk(input.uid.unwrap(), input.id.unwrap());
+    store
+        .append_status(&mut doc, input.status, &input.error)
+        .await?;
+    Ok(to.with(SuccessResponse::new(StatusEntryOutput {
+        at: doc.updated_at,
+        status: doc.status,
+        error: doc.error,
+    })))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct StatusHistoryInput {
+    pub uid: PackObject<xid::Id>,
+    pub id: PackObject<xid::Id>,
+    #[validate(range(min = 1, max = 1000))]
+    pub limit: Option<u16>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct StatusHistoryOutput {
+    pub current: Option<StatusEntryOutput>, // the newest change, null if none
+    pub history: Vec<StatusEntryOutput>,    // newest first
+}
+
+pub async fn status_history(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    Query(input): Query<StatusHistoryInput>,
+) -> Result<PackObject<SuccessResponse<StatusHistoryOutput>>, HTTPError> {
+    input.validate()?;
+
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "log_status_history".into())])
+        .await;
+
+    let history = store
+        .status_history(
+            input.uid.unwrap(),
+            input.id.unwrap(),
+            input.limit.unwrap_or(app.cfg.pagination.default_page_size),
+        )
+        .await?;
+    let current = db::StatusEntry::latest(&history).cloned().map(Into::into);
+    Ok(to.with(SuccessResponse::new(StatusHistoryOutput {
+        current,
+        history: history.into_iter().map(Into::into).collect(),
+    })))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct HistogramInput {
+    pub uid: PackObject<xid::Id>,
+    pub action: Option<String>,
+    #[validate(range(min = 1))]
+    pub bucket_seconds: u32,
+    pub since: u32,
+    pub until: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HistogramOutput {
+    pub since: u32,
+    pub until: u32,
+    pub bucket_seconds: u32,
+    pub counts: Vec<u64>,
+    pub truncated: bool, // true if the scan hit its limit, the counts are of the newest logs
+}
+
+pub async fn histogram(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<HistogramInput>,
+) -> Result<PackObject<SuccessResponse<HistogramOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let action = match input.action {
+        None => None,
+        Some(ref a) => Some(
+            action::to_action(a)
+                .ok_or_else(|| HTTPError::new(400, format!("invalid action {}", a)))?,
+        ),
+    };
+    let until = input.until.unwrap_or_else(|| (unix_ms() / 1000) as u32 + 1);
+    if input.since >= until {
+        return Err(HTTPError::new(
+            400,
+            format!("invalid window, since {} >= until {}", input.since, until),
+        ));
+    }
+    if db::Log::histogram_buckets(input.since, until, input.bucket_seconds) > 1000 {
+        return Err(HTTPError::new(
+            400,
+            "too many buckets, expected at most 1000".to_string(),
+        ));
+    }
+
+    let store = app.store_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "histogram".into())]).await;
+    let (counts, truncated) = store
+        .action_histogram(
+            input.uid.unwrap(),
+            action,
+            input.bucket_seconds,
+            input.since,
+            until,
+            app.cfg.pagination.scan_page_size(),
+            app.cfg.pagination.max_scan(),
+            ctx.remaining_ms(),
+        )
+        .await?;
+    Ok(to.with(SuccessResponse::new(HistogramOutput {
+        since: input.since,
+        until,
+        bucket_seconds: input.bucket_seconds,
+        counts,
+        truncated,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::testing::{put_log, test_conf, TestApp};
+    use crate::db::LogStore;
+    use axum::{body::Body, extract::FromRequest, http::Request};
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn unknown_fields_are_rejected() {
+        let body = serde_json::json!({
+            "uid": xid::new().to_string(),
+            "id": xid::new().to_string(),
+            "toknes": 10,
+        });
+        let req = Request::builder()
+            .method("PATCH")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap();
+        let err = PackObject::<UpdateLogInput>::from_request(req, &())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(err.message.contains("unknown field `toknes`"));
+    }
+
+    #[test]
+    fn validation_errors_are_aggregated() {
+        let input = UpdateLogInput {
+            uid: PackObject::Json(xid::new()),
+            id: PackObject::Json(xid::new()),
+            status: db::Status::Open,
+            payload: None,
+            tokens: Some(-1),
+            error: None,
+            clear_fields: vec![],
+            override_freeze: None,
+        };
+
+        let err: HTTPError = input.validate().unwrap_err().into();
+        assert_eq!(err.code, 400);
+        let data = err.data.unwrap();
+        let fields = data.as_object().unwrap();
+        assert_eq!(fields.len(), 2);
+        assert!(fields["status"][0]
+            .as_str()
+            .unwrap()
+            .contains("expected -1 or 1"));
+        assert_eq!(fields["tokens"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn future_ids_are_rejected() {
+        let now = (unix_ms() / 1000) as u32;
+        let mut input = QueryLog {
+            uid: PackObject::Json(xid::new()),
+            id: PackObject::Json(db::xid_from_unix(now + 3600)),
+            fields: None,
+            payload_limit: None,
+            flat: None,
+        };
+        let err: HTTPError = input.validate().unwrap_err().into();
+        assert_eq!(err.code, 400);
+        assert!(err.data.unwrap()["id"][0]
+            .as_str()
+            .unwrap()
+            .contains("in the future"));
+
+        input.id = PackObject::Json(db::xid_from_unix(now + 10));
+        assert!(input.validate().is_ok());
+        input.id = PackObject::Json(xid::new());
+        assert!(input.validate().is_ok());
+
+        let input = UpdateLogInput {
+            uid: PackObject::Json(xid::new()),
+            id: PackObject::Json(db::xid_from_unix(now + 3600)),
+            status: db::Status::Frozen,
+            payload: None,
+            tokens: None,
+            error: None,
+            clear_fields: vec![],
+            override_freeze: None,
+        };
+        let err: HTTPError = input.validate().unwrap_err().into();
+        assert_eq!(err.code, 400);
+    }
+
+    #[test]
+    fn error_length_is_bounded() {
+        let mut input = UpdateLogInput {
+            uid: PackObject::Json(xid::new()),
+            id: PackObject::Json(xid::new()),
+            status: db::Status::Failed,
+            payload: None,
+            tokens: None,
+            error: Some("e".repeat(2000)),
+            clear_fields: vec![],
+            override_freeze: None,
+        };
+        assert!(input.validate().is_ok());
+
+        input.error = Some("e".repeat(2001));
+        let err: HTTPError = input.validate().unwrap_err().into();
+        assert_eq!(err.code, 400);
+        assert!(err.data.unwrap().get("error").is_some());
+    }
+
+    #[test]
+    fn etag_works() {
+        let variant = |fields: &str, limit: &str, flat: &str, encoding: &str, role: &str| {
+            [fields, limit, flat, encoding, role].map(|v| v.to_string())
+        };
+        let base = variant("", "", "false", "json", "");
+        let etag = weak_etag(1700000000000, db::Status::Frozen, &base);
+        assert!(etag.starts_with("W/\""));
+        assert_eq!(etag, weak_etag(1700000000000, db::Status::Frozen, &base));
+        assert_ne!(etag, weak_etag(1700000000001, db::Status::Frozen, &base));
+        assert_ne!(etag, weak_etag(1700000000000, db::Status::Failed, &base));
+        for other in [
+            variant("action", "", "false", "json", ""),
+            variant("", "10", "false", "json", ""),
+            variant("", "", "true", "json", ""),
+            variant("", "", "false", "cbor", ""),
+            variant("", "", "false", "json", "viewer"),
+            // parts don't run into each other
+            variant("", "", "false", "jso", "n"),
+        ] {
+            assert_ne!(etag, weak_etag(1700000000000, db::Status::Frozen, &other));
+        }
+
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(etag.trim_start_matches("W/"), &etag));
+        assert!(etag_matches(&format!("W/\"abc\", {}", etag), &etag));
+        assert!(etag_matches("*", &etag));
+        assert!(!etag_matches("W/\"abc\"", &etag));
+        assert!(!etag_matches("", &etag));
+    }
+
+    #[test]
+    fn log_output_trace_id() {
+        let mut doc = db::Log::with_pk(xid::new(), xid::new());
+        doc.trace_id = "7c4d3a2e-0b5b-4d5e-9d3c-1a2b3c4d5e6f".to_string();
+
+        let output = LogOutput::from(doc.clone(), &PackObject::Json(()));
+        assert_eq!(output.trace_id, None);
+
+        doc._fields = vec!["trace_id".to_string()];
+        let output = LogOutput::from(doc.clone(), &PackObject::Json(()));
+        assert_eq!(output.trace_id, Some(doc.trace_id));
+    }
+
+    #[test]
+    fn log_output_action() {
+        let to = PackObject::Json(());
+        let mut doc = db::Log::with_pk(xid::new(), xid::new());
+        for (code, name) in [
+            (8, "user.login"),
+            (-1, "unknown"),
+            (4, "reserved"),
+            (120, "unknown"),
+        ] {
+            doc.action = code;
+            assert_eq!(LogOutput::from(doc.clone(), &to).action, name);
+        }
+    }
+
+    #[test]
+    fn log_output_created_at() {
+        let now = unix_ms() as i64;
+        let doc = db::Log::with_pk(xid::new(), xid::new());
+        let output = LogOutput::from(doc, &PackObject::Json(()));
+        // xid has second precision
+        assert!((output.created_at - now).abs() <= 1000);
+        assert_eq!(output.created_at % 1000, 0);
+    }
+
+    #[test]
+    fn log_output_redacted() {
+        let mut doc = db::Log::with_pk(xid::new(), xid::new());
+        doc.ip = "1.2.3.4".to_string();
+        doc.payload = vec![0x80];
+        doc.tokens = 10;
+        doc._fields = vec![
+            "ip".to_string(),
+            "payload".to_string(),
+            "tokens".to_string(),
+        ];
+
+        let to = PackObject::Json(());
+        let restricted = vec!["ip".to_string(), "payload".to_string()];
+        let output = LogOutput::redacted(doc.clone(), &to, &restricted);
+        assert!(output.ip.is_none());
+        assert!(output.payload.is_none());
+        assert_eq!(output.tokens, Some(10));
+
+        let output = LogOutput::redacted(doc, &to, &[]);
+        assert_eq!(output.ip.unwrap(), "1.2.3.4");
+        assert_eq!(output.payload.unwrap().unwrap(), vec![0x80]);
+        assert_eq!(output.tokens, Some(10));
+    }
+
+    #[test]
+    fn soft_warnings_works() {
+        let mut cfg = conf::Warning::default();
+        let res = soft_warnings(&cfg, Some("user.login"), Some("192.168.1.2"), Some(10));
+        assert!(res.is_empty());
+
+        cfg.enabled = true;
+        let res = soft_warnings(&cfg, Some("user.login"), Some("192.168.1.2"), Some(10));
+        assert_eq!(res.len(), 1);
+        assert!(res[0].contains("private ip"));
+
+        let res = soft_warnings(&cfg, Some("sys.create.user"), Some("10.0.0.1"), Some(10));
+        assert!(res.is_empty());
+        let res = soft_warnings(&cfg, Some("user.login"), Some("8.8.8.8"), Some(10));
+        assert!(res.is_empty());
+        let res = soft_warnings(&cfg, None, None, Some(cfg.max_tokens + 1));
+        assert_eq!(res.len(), 1);
+    }
+
+    #[test]
+    fn flat_log_works() {
+        let mut doc = db::Log::with_pk(xid::new(), xid::new());
+        doc.action = 8;
+        doc.status = db::Status::Frozen;
+        doc.gid = xid::new();
+        doc.ip = "1.2.3.4".to_string();
+        doc.payload = vec![0x80, 0xff];
+        doc.tokens = 10;
+        doc._fields = vec![
+            "gid".to_string(),
+            "ip".to_string(),
+            "payload".to_string(),
+            "tokens".to_string(),
+        ];
+
+        let output = flat_log(doc.clone());
+        for v in output.values() {
+            assert!(!v.is_object() && !v.is_array());
+        }
+        assert_eq!(output["uid"], doc.uid.to_string());
+        assert_eq!(output["id"], doc.id.to_string());
+        assert_eq!(output["gid"], doc.gid.to_string());
+        assert_eq!(output["action"], "user.login");
+        assert_eq!(output["action_code"], 8);
+        assert_eq!(output["payload"], "gP8");
+        assert_eq!(output["tokens"], 10);
+        assert!(!output.contains_key("error"));
+
+        let data = serde_json::to_value(&output).unwrap();
+        assert!(data.as_object().unwrap().values().all(|v| !v.is_object()));
+    }
+
+    #[test]
+    fn truncate_payload_works() {
+        let mut doc = db::Log::with_pk(xid::new(), xid::new());
+        doc.payload = vec![1, 2, 3, 4, 5];
+        doc._fields = vec!["payload".to_string()];
+
+        assert!(!truncate_payload(&mut doc.payload, 5));
+        assert_eq!(doc.payload.len(), 5);
+        assert!(truncate_payload(&mut doc.payload, 2));
+        assert_eq!(doc.payload, vec![1, 2]);
+        assert!(!truncate_payload(&mut doc.payload, 10));
+
+        let output = LogOutput::from(doc, &PackObject::Json(()));
+        assert_eq!(output.payload.unwrap().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn summarize_works() {
+        let mut logs: Vec<db::Log> = Vec::new();
+        for (action, tokens, status, error) in [
+            (8i8, 0i32, 1i8, ""),
+            (8, 0, -1, ""),
+            (15, 100, 1, ""),
+            (15, 50, 0, "insufficient balance"),
+            (40, 10, 0, ""),
+        ] {
+            let mut doc = db::Log::with_pk(xid::new(), xid::new());
+            doc.action = action;
+            doc.tokens = tokens;
+            doc.status = db::Status::try_from(status).unwrap();
+            doc.error = error.to_string();
+            logs.push(doc);
+        }
+
+        let output = summarize(&logs);
+        assert_eq!(output.count, 5);
+        assert_eq!(output.tokens, 160);
+        assert_eq!(output.errors, 2);
+        assert_eq!(output.actions.len(), 3);
+        assert_eq!(
+            output.actions["user.login"],
+            ActionSummary {
+                count: 2,
+                tokens: 0,
+                errors: 1,
+            }
+        );
+        assert_eq!(
+            output.actions["user.spend"],
+            ActionSummary {
+                count: 2,
+                tokens: 150,
+                errors: 1,
+            }
+        );
+        assert_eq!(output.actions["creation.create"].count, 1);
+
+        let output = summarize(&[]);
+        assert_eq!(output.count, 0);
+        assert!(output.actions.is_empty());
+    }
+
+    #[test]
+    fn normalize_payload_works() {
+        let raw = b"hello hello hello hello".to_vec();
+        let gz = Encoding::Gzip.encode_all(&raw[..]).unwrap();
+        assert!(gz.starts_with(&GZIP_MAGIC));
+
+        let mut cfg = conf::Payload::default();
+        assert_eq!(normalize_payload(&cfg, gz.clone()).unwrap(), gz);
+        assert_eq!(normalize_payload(&cfg, raw.clone()).unwrap(), raw);
+
+        cfg.gzip = "zstd".to_string();
+        let res = normalize_payload(&cfg, gz).unwrap();
+        assert_eq!(Encoding::Zstd.decode_all(&res[..]).unwrap(), raw);
+        assert_eq!(normalize_payload(&cfg, raw.clone()).unwrap(), raw);
+
+        let err = normalize_payload(&cfg, vec![0x1f, 0x8b, 0x00]).unwrap_err();
+        assert_eq!(err.code, 400);
+
+        // a gzip bomb is cut off at max_gunzip_size
+        let zeros = vec![0u8; 1024 * 1024];
+        let bomb = Encoding::Gzip.encode_all(&zeros[..]).unwrap();
+        assert!(bomb.len() < 10 * 1024);
+        cfg.max_gunzip_size = zeros.len();
+        assert!(normalize_payload(&cfg, bomb.clone()).is_ok());
+        cfg.max_gunzip_size = zeros.len() - 1;
+        let err = normalize_payload(&cfg, bomb).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(err.message.contains("more than"));
+        cfg.max_gunzip_size = conf::Payload::default().max_gunzip_size;
+
+        // xorshift bytes don't compress, the gzip form is stored as sent
+        let mut x = 0x2545f4914f6cdd1du64;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                (x >> 56) as u8
+            })
+            .collect();
+        let gz = Encoding::Gzip.encode_all(&noise[..]).unwrap();
+        assert_eq!(normalize_payload(&cfg, gz.clone()).unwrap(), gz);
+
+        cfg.zstd_min_saving = -1.0;
+        let res = normalize_payload(&cfg, gz).unwrap();
+        assert_eq!(Encoding::Zstd.decode_all(&res[..]).unwrap(), noise);
+    }
+
+    #[test]
+    fn check_payload_type_works() {
+        use axum_web::object::cbor_to_vec;
+
+        let mut cfg = conf::Payload::default();
+        let cbor = cbor_to_vec(&vec!["hello", "world"]).unwrap();
+        let malformed = vec![0x82, 0x65, b'h'];
+        assert!(check_payload_type(&cfg, Some("cbor"), &malformed).is_ok());
+
+        cfg.check_type = true;
+        assert!(check_payload_type(&cfg, Some("cbor"), &cbor).is_ok());
+        let err = check_payload_type(&cfg, Some("cbor"), &malformed).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(err.message.starts_with("payload is not valid cbor"));
+        let mut trailing = cbor.clone();
+        trailing.push(0x00);
+        assert!(check_payload_type(&cfg, Some("cbor"), &trailing).is_err());
+
+        assert!(check_payload_type(&cfg, Some("json"), br#"{"a":1}"#).is_ok());
+        assert!(check_payload_type(&cfg, Some("json"), &cbor).is_err());
+        assert!(check_payload_type(&cfg, Some("text"), b"hello").is_ok());
+        assert!(check_payload_type(&cfg, Some("text"), &[0xff, 0xfe]).is_err());
+        assert!(check_payload_type(&cfg, Some("bytes"), &malformed).is_ok());
+        assert!(check_payload_type(&cfg, None, &malformed).is_ok());
+        assert!(check_payload_type(&cfg, Some("cbor"), &[]).is_ok());
+
+        // gzip payloads are checked decoded
+        let gz = Encoding::Gzip.encode_all(&cbor[..]).unwrap();
+        assert!(check_payload_type(&cfg, Some("cbor"), &gz).is_ok());
+        let gz = Encoding::Gzip.encode_all(&malformed[..]).unwrap();
+        let err = check_payload_type(&cfg, Some("cbor"), &gz).unwrap_err();
+        assert!(err.message.starts_with("payload is not valid cbor"));
+        assert!(check_payload_type(&cfg, Some("bytes"), &gz).is_ok());
+        let err = check_payload_type(&cfg, Some("json"), &[0x1f, 0x8b, 0x00]).unwrap_err();
+        assert!(err.message.starts_with("invalid gzip payload"));
+
+        assert!(validate_payload_type("cbor").is_ok());
+        assert!(validate_payload_type("xml").is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn keep_sampled_works() {
+        for (rate, stored) in [(0.0f64, 0usize), (1.0, 20)] {
+            let mut cfg = test_conf();
+            cfg.sampling = HashMap::from([("user.login".to_string(), rate)]);
+            let app = TestApp::new(cfg);
+            let uid = xid::new();
+            let create = serde_json::json!({
+                "uid": uid.to_string(),
+                "gid": xid::new().to_string(),
+                "action": "user.login",
+                "payload": "",
+                "tokens": 0,
+            });
+            for _ in 0..20 {
+                let (status, res) = app.call("POST", "/v1/log", &[], Some(create.clone())).await;
+                assert_eq!(status, StatusCode::OK, "{}", res);
+                // sampled out creates are answered as if stored
+                assert_eq!(
+                    res["result"]["sampled"].as_bool().unwrap_or(false),
+                    stored == 0
+                );
+            }
+            let res = app
+                .store
+                .list(uid, vec![], 1000, None, None, None, None)
+                .await
+                .unwrap();
+            assert_eq!(res.len(), stored, "rate {}", rate);
+
+            let mut create = create;
+            create["external_id"] = "order-1".into();
+            let (status, res) = app.call("POST", "/v1/log", &[], Some(create)).await;
+            if stored == 0 {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert!(res["error"]["message"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("external_id can't be used"));
+            } else {
+                assert_eq!(status, StatusCode::OK, "{}", res);
+            }
+        }
+
+        assert!(keep_sampled(None, 0.99));
+        assert!(keep_sampled(Some(&0.5), 0.49));
+        assert!(!keep_sampled(Some(&0.5), 0.5));
+        for _ in 0..100 {
+            let roll = sample_roll();
+            assert!((0.0..1.0).contains(&roll));
+        }
+
+        let sampling = |name: &str, rate: f64| HashMap::from([(name.to_string(), rate)]);
+        assert!(check_sampling(&sampling("user.authz", 0.0)).is_ok());
+        assert!(check_sampling(&sampling("user.authz", 1.0)).is_ok());
+        for (name, rate) in [
+            ("user.authz", -0.1),
+            ("user.authz", 1.5),
+            ("user.authz", f64::NAN),
+            ("user.fly", 0.5),
+        ] {
+            assert!(check_sampling(&sampling(name, rate)).is_err(), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn check_batch_bytes_works() {
+        let row = |payload: usize, error: &str| {
+            let mut cols = ColumnsMap::with_capacity(2);
+            cols.set_as("payload", &vec![0u8; payload]);
+            cols.set_as("error", &error.to_string());
+            (xid::new(), cols)
+        };
+        assert!(check_batch_bytes(&[row(60, "1234"), row(0, "")], 64).is_ok());
+        let err = check_batch_bytes(&[row(60, "12345"), row(0, "")], 64).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(err.message.contains("larger than 64 bytes"));
+        // a single log is written alone whatever its size
+        assert!(check_batch_bytes(&[row(1000, "")], 64).is_ok());
+    }
+
+    #[test]
+    fn check_token_cap_works() {
+        let caps = HashMap::from([("user.spend".to_string(), 1000)]);
+        assert!(check_token_cap(&caps, "user.spend", 1000).is_ok());
+        let err = check_token_cap(&caps, "user.spend", 1001).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(check_token_cap(&caps, "user.topup", i32::MAX).is_ok());
+        assert!(check_token_cap(&HashMap::new(), "user.spend", i32::MAX).is_ok());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn insert_unique_works() {
+        let store = db::MemoryStore::default();
+        let uid = xid::new();
+        let taken = xid::new();
+        let fresh = xid::new();
+
+        let mut doc = db::Log::with_pk(uid, taken);
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &8i8);
+        cols.set_as("tokens", &1i32);
+        insert_unique(&store, &mut doc, &cols, || unreachable!())
+            .await
+            .unwrap();
+
+        // a forced collision keeps the first log and retries with a new id
+        let mut other = db::Log::with_pk(uid, taken);
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &40i8);
+        cols.set_as("tokens", &2i32);
+        insert_unique(&store, &mut other, &cols, || fresh)
+            .await
+            .unwrap();
+        assert_eq!(other.id, fresh);
+
+        let mut doc = db::Log::with_pk(uid, taken);
+        store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(doc.action, 8);
+        assert_eq!(doc.tokens, 1);
+        let mut doc = db::Log::with_pk(uid, fresh);
+        store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(doc.action, 40);
+        assert_eq!(doc.tokens, 2);
+
+        // gives up if every id collides
+        let mut other = db::Log::with_pk(uid, taken);
+        let err = insert_unique(&store, &mut other, &cols, || taken)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, 409);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn external_id_works() {
+        let store = db::MemoryStore::default();
+        let uid = xid::new();
+
+        // create with an external id, as the create handler does
+        let mut doc = db::Log::with_pk(uid, xid::new());
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &8i8);
+        cols.set_as("tokens", &1i32);
+        claim_external_id(&store, uid, "order-1", doc.id)
+            .await
+            .unwrap();
+        insert_unique(&store, &mut doc, &cols, xid::new)
+            .await
+            .unwrap();
+
+        let got = get_by_external_id(&store, uid, "order-1", vec![])
+            .await
+            .unwrap();
+        assert_eq!(got.id, doc.id);
+        assert_eq!(got.action, 8);
+        assert_eq!(got.tokens, 1);
+        let output = LogOutput::from(got, &PackObject::Json(()));
+        assert_eq!(output.action, "user.login");
+
+        let err = claim_external_id(&store, uid, "order-1", xid::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, 409);
+        assert!(err.message.contains(&doc.id.to_string()));
+
+        // external ids are scoped by uid
+        let err = get_by_external_id(&store, xid::new(), "order-1", vec![])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, 404);
+        let err = get_by_external_id(&store, uid, "order-2", vec![])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, 404);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn status_history_works() {
+        let app = TestApp::new(test_conf());
+        let uid = xid::new();
+        let doc = put_log(app.store.as_ref(), uid, 8, ColumnsMap::new()).await;
+        let append = |id: xid::Id, status: i8, error: &str| {
+            serde_json::json!({
+                "uid": uid.to_string(),
+                "id": id.to_string(),
+                "status": status,
+                "error": error,
+            })
+        };
+
+        let (status, _) = app
+            .call(
+                "POST",
+                "/v1/log/status_history",
+                &[],
+                Some(append(xid::new(), 0, "")),
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        for (s, error) in [(0, "retrying"), (1, "")] {
+            let (status, res) = app
+                .call(
+                    "POST",
+                    "/v1/log/status_history",
+                    &[],
+                    Some(append(doc.id, s, error)),
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK, "{}", res);
+            assert_eq!(res["result"]["status"], s);
+        }
+        let (status, _) = app
+            .call(
+                "POST",
+                "/v1/log/status_history",
+                &[],
+                Some(append(doc.id, -1, "late")),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // get and list derive the status from the history
+        let uri = format!("/v1/log?uid={}&id={}", uid, doc.id);
+        let (status, res) = app.call("GET", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["status"], 1);
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log/list",
+                &[],
+                Some(serde_json::json!({"uid": uid.to_string()})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"][0]["status"], 1);
+
+        let uri = format!("/v1/log/status_history?uid={}&id={}", uid, doc.id);
+        let (status, res) = app.call("GET", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["current"]["status"], 1);
+        assert_eq!(res["result"]["history"].as_array().unwrap().len(), 2);
+        assert_eq!(res["result"]["history"][1]["error"], "retrying");
+
+        // the freeze of the history holds for updates too
+        let (status, res) = app
+            .call(
+                "PATCH",
+                "/v1/log",
+                &[],
+                Some(serde_json::json!({
+                    "uid": uid.to_string(),
+                    "id": doc.id.to_string(),
+                    "status": 1,
+                    "tokens": 5,
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(res["error"]["message"], "log is frozen");
+
+        // a log frozen in place has no history but is frozen still
+        let mut cols = ColumnsMap::new();
+        cols.set_as("status", &1i8);
+        let frozen = put_log(app.store.as_ref(), uid, 8, cols).await;
+        let (status, _) = app
+            .call(
+                "POST",
+                "/v1/log/status_history",
+                &[],
+                Some(append(frozen.id, 0, "")),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn create_payload_type_works() {
+        use axum_web::object::cbor_to_vec;
+
+        let mut cfg = test_conf();
+        cfg.payload.check_type = true;
+        let app = TestApp::new(cfg);
+        let uid = xid::new().to_string();
+        let create = |payload: &[u8]| {
+            serde_json::json!({
+                "uid": uid,
+                "gid": xid::new().to_string(),
+                "action": "user.spend",
+                "payload": general_purpose::URL_SAFE_NO_PAD.encode(payload),
+                "payload_type": "cbor",
+                "tokens": 1,
+            })
+        };
+
+        let cbor = cbor_to_vec(&vec!["hello", "world"]).unwrap();
+        let gz = Encoding::Gzip.encode_all(&cbor[..]).unwrap();
+        let (status, res) = app.call("POST", "/v1/log", &[], Some(create(&gz))).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["payload_type"], "cbor");
+
+        let uri = format!(
+            "/v1/log?uid={}&id={}&fields=payload_type",
+            uid,
+            res["result"]["id"].as_str().unwrap()
+        );
+        let (status, res) = app.call("GET", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["payload_type"], "cbor");
+
+        let gz = Encoding::Gzip
+            .encode_all(&[0x82u8, 0x65, b'h'][..])
+            .unwrap();
+        let (status, res) = app.call("POST", "/v1/log", &[], Some(create(&gz))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(res["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("payload is not valid cbor"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn quota_counts_stored_logs() {
+        let mut cfg = test_conf();
+        for name in ["user.login", "user.spend"] {
+            cfg.quotas.insert(
+                name.to_string(),
+                conf::Quota {
+                    limit: 1,
+                    window_secs: 3600,
+                },
+            );
+        }
+        cfg.sampling.insert("user.login".to_string(), 0.0);
+        let app = TestApp::new(cfg);
+        let uid = xid::new();
+        let create = |action: &str| {
+            serde_json::json!({
+                "uid": uid.to_string(),
+                "gid": xid::new().to_string(),
+                "action": action,
+                "payload": "",
+                "tokens": 1,
+            })
+        };
+
+        // sampled-out logs aren't stored, so they don't count
+        for _ in 0..3 {
+            let (status, res) = app
+                .call("POST", "/v1/log", &[], Some(create("user.login")))
+                .await;
+            assert_eq!(status, StatusCode::OK, "{}", res);
+            assert_eq!(res["result"]["sampled"], true);
+        }
+        assert!(app.state.quotas.check(uid, "user.login", unix_ms()).is_ok());
+
+        // nor do failed inserts
+        app.store.fail_inserts.store(true, Ordering::Relaxed);
+        let (status, res) = app
+            .call("POST", "/v1/log", &[], Some(create("user.spend")))
+            .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", res);
+        app.store.fail_inserts.store(false, Ordering::Relaxed);
+        let (status, res) = app
+            .call("POST", "/v1/log", &[], Some(create("user.spend")))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        let (status, _) = app
+            .call("POST", "/v1/log", &[], Some(create("user.spend")))
+            .await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn create_external_id_works() {
+        let app = TestApp::new(test_conf());
+        let uid = xid::new().to_string();
+        let create = |tokens: i32| {
+            serde_json::json!({
+                "uid": uid,
+                "gid": xid::new().to_string(),
+                "action": "user.spend",
+                "payload": "",
+                "tokens": tokens,
+                "external_id": "order-1",
+            })
+        };
+
+        // a failed insert releases the external id
+        app.store.fail_inserts.store(true, Ordering::Relaxed);
+        let (status, res) = app.call("POST", "/v1/log", &[], Some(create(1))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", res);
+        let uri = format!("/v1/log/by_external?uid={}&external_id=order-1", uid);
+        let (status, _) = app.call("GET", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // so the retry claims it
+        app.store.fail_inserts.store(false, Ordering::Relaxed);
+        let (status, res) = app.call("POST", "/v1/log", &[], Some(create(2))).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        let id = res["result"]["id"].clone();
+        let (status, res) = app.call("GET", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["id"], id);
+        assert_eq!(res["result"]["tokens"], 2);
+
+        // a taken external id isn't released by the create it fails
+        let (status, _) = app.call("POST", "/v1/log", &[], Some(create(3))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, res) = app.call("GET", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(res["result"]["id"], id);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn override_freeze_works() {
+        let store = db::MemoryStore::default();
+        let frozen = || {
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("status", &1i8);
+            cols
+        };
+        // user.spend and creation.create
+        let uid = xid::new();
+        let mut spend = put_log(&store, uid, 16, frozen()).await;
+        let mut create = put_log(&store, uid, 40, frozen()).await;
+        let correction = || {
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("error", &"audit correction".to_string());
+            cols
+        };
+
+        assert!(matches!(
+            store.upsert_fields(&mut spend, correction(), &[]).await,
+            Err(db::LogError::Frozen)
+        ));
+        assert!(matches!(
+            store
+                .upsert_fields_overriding(&mut create, correction(), &[], &[16])
+                .await,
+            Err(db::LogError::Frozen)
+        ));
+
+        store
+            .upsert_fields_overriding(&mut spend, correction(), &[], &[16])
+            .await
+            .unwrap();
+        assert!(spend.overridden);
+        store.get_one(&mut spend, vec![]).await.unwrap();
+        assert_eq!(spend.status, db::Status::Frozen);
+        assert_eq!(spend.error, "audit correction");
+        assert!(spend.overridden);
+        let output = LogOutput::from(spend, &PackObject::Json(()));
+        assert_eq!(output.overridden, Some(true));
+
+        store.get_one(&mut create, vec![]).await.unwrap();
+        assert_eq!(create.error, "");
+        assert!(!create.overridden);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn update_override_freeze_works() {
+        let mut cfg = test_conf();
+        cfg.freeze_bypass_actions = vec!["user.spend".to_string()];
+        let app = TestApp::new(cfg);
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("status", &db::Status::Frozen);
+        let uid = xid::new();
+        let doc = put_log(app.store.as_ref(), uid, 15, cols).await; // user.spend
+        let update = |fields: serde_json::Value| {
+            let mut input = serde_json::json!({
+                "uid": uid.to_string(),
+                "id": doc.id.to_string(),
+                "status": 1,
+                "override_freeze": true,
+            });
+            input
+                .as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            input
+        };
+        let correction = serde_json::json!({"error": "audit correction"});
+        let admin = [("x-auth-role", "admin")];
+
+        // admin only
+        for headers in [&[][..], &[("x-auth-role", "guest")][..]] {
+            let (status, _) = app
+                .call(
+                    "PATCH",
+                    "/v1/log",
+                    headers,
+                    Some(update(correction.clone())),
+                )
+                .await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        // correction fields only
+        let (status, res) = app
+            .call(
+                "PATCH",
+                "/v1/log",
+                &admin,
+                Some(update(serde_json::json!({"tokens": 1}))),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            res["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("tokens of a frozen log can't be overridden"),
+            "{}",
+            res
+        );
+
+        let (status, res) = app
+            .call("PATCH", "/v1/log", &admin, Some(update(correction)))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["overridden"], true);
+        let mut doc = doc;
+        app.store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(doc.error, "audit correction");
+
+        assert!(check_freeze_bypass_actions(&["user.spend".to_string()]).is_ok());
+        assert!(check_freeze_bypass_actions(&["user.fly".to_string()]).is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn update_checks_payload() {
+        use axum_web::object::cbor_to_vec;
+
+        let mut cfg = test_conf();
+        cfg.payload.check_type = true;
+        let app = TestApp::new(cfg);
+        let uid = xid::new();
+        let cbor = cbor_to_vec(&vec!["hello", "world"]).unwrap();
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("payload", &cbor);
+        cols.set_as("payload_type", &"cbor".to_string());
+        let action = action::to_action("creation.update.content").unwrap();
+        let doc = put_log(app.store.as_ref(), uid, action, cols).await;
+        let update = |patch: serde_json::Value| {
+            let mut input = serde_json::json!({
+                "uid": uid.to_string(),
+                "id": doc.id.to_string(),
+                "status": 1,
+            });
+            input
+                .as_object_mut()
+                .unwrap()
+                .extend(patch.as_object().unwrap().clone());
+            app.call("PATCH", "/v1/log", &[], Some(input))
+        };
+
+        let (status, res) = update(serde_json::json!({"payload": ""})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            res["error"]["message"],
+            "payload is required for action creation.update.content"
+        );
+        let (status, res) = update(serde_json::json!({"clear_fields": ["payload"]})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            res["error"]["message"],
+            "payload is required for action creation.update.content"
+        );
+        let malformed = general_purpose::URL_SAFE_NO_PAD.encode([0x82u8, 0x65, b'h']);
+        let (status, res) = update(serde_json::json!({ "payload": malformed })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(res["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("payload is not valid cbor"));
+
+        let mut stored = db::Log::with_pk(uid, doc.id);
+        app.store.get_one(&mut stored, vec![]).await.unwrap();
+        assert_eq!(stored.payload, cbor);
+        assert_eq!(stored.status, db::Status::Open);
+
+        let cbor = cbor_to_vec(&vec!["hello"]).unwrap();
+        let payload = general_purpose::URL_SAFE_NO_PAD.encode(&cbor);
+        let (status, res) = update(serde_json::json!({ "payload": payload })).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        app.store.get_one(&mut stored, vec![]).await.unwrap();
+        assert_eq!(stored.payload, cbor);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn present_fields_works() {
+        let store = db::MemoryStore::default();
+        let mut cols = ColumnsMap::with_capacity(3);
+        cols.set_as("ip", &"1.2.3.4".to_string());
+        cols.set_as("tokens", &10i32);
+        cols.set_as("error", &"".to_string());
+        let mut doc = put_log(&store, xid::new(), 8, cols).await;
+
+        store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(present_fields(&doc), vec!["ip", "tokens", "updated_at"]);
+
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("payload", &vec![0x80u8]);
+        cols.set_as(
+            "labels",
+            &HashMap::from([("env".to_string(), "prod".to_string())]),
+        );
+        store.upsert_fields(&mut doc, cols, &[]).await.unwrap();
+        store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(
+            present_fields(&doc),
+            vec!["ip", "payload", "tokens", "updated_at", "labels"]
+        );
+
+        redact_fields(&mut doc, &["ip".to_string(), "payload".to_string()]);
+        assert_eq!(present_fields(&doc), vec!["tokens", "updated_at", "labels"]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn clear_cols_works() {
+        let store = db::MemoryStore::default();
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("error", &"timeout".to_string());
+        cols.set_as("payload", &vec![0x80u8]);
+        let mut doc = put_log(&store, xid::new(), 8, cols).await;
+
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("status", &-1i8);
+        clear_cols(&mut cols, &["error".to_string()], "user.login").unwrap();
+        store.upsert_fields(&mut doc, cols, &[]).await.unwrap();
+
+        store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(doc.status, db::Status::Failed);
+        assert_eq!(doc.error, "");
+        assert_eq!(doc.payload, vec![0x80]);
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        assert_eq!(
+            clear_cols(&mut cols, &["tokens".to_string()], "user.login")
+                .unwrap_err()
+                .code,
+            400
+        );
+        cols.set_as("error", &"boom".to_string());
+        assert!(clear_cols(&mut cols, &["error".to_string()], "user.login").is_err());
+        let mut cols = ColumnsMap::with_capacity(1);
+        clear_cols(&mut cols, &["payload".to_string()], "user.login").unwrap();
+        assert_eq!(cols.get_as::<Vec<u8>>("payload").unwrap(), Vec::<u8>::new());
+
+        // a required payload can't be cleared
+        let mut cols = ColumnsMap::with_capacity(1);
+        let err = clear_cols(
+            &mut cols,
+            &["payload".to_string()],
+            "creation.update.content",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.message,
+            "payload is required for action creation.update.content"
+        );
+        assert!(!cols.has("payload"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn list_before_works() {
+        let app = TestApp::new(test_conf());
+        let uid = xid::new();
+        for _ in 0..5 {
+            put_log(app.store.as_ref(), uid, 8, ColumnsMap::new()).await;
+        }
+        let list = |input: serde_json::Value| app.call("POST", "/v1/log/list", &[], Some(input));
+        let ids = |res: &serde_json::Value| {
+            res["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| r["id"].as_str().unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        // forward two pages, then back one
+        let (status, page1) =
+            list(serde_json::json!({"uid": uid.to_string(), "page_size": 2})).await;
+        assert_eq!(status, StatusCode::OK, "{}", page1);
+        let (_, page2) = list(serde_json::json!({
+            "uid": uid.to_string(),
+            "page_size": 2,
+            "page_token": page1["next_page_token"],
+        }))
+        .await;
+        assert_eq!(ids(&page2).len(), 2);
+        let (status, back) = list(serde_json::json!({
+            "uid": uid.to_string(),
+            "page_size": 2,
+            "before": page2["prev_page_token"],
+        }))
+        .await;
+        assert_eq!(status, StatusCode::OK, "{}", back);
+        assert_eq!(ids(&back), ids(&page1));
+
+        // pages of an action can't be read back
+        let (_, res) = list(serde_json::json!({
+            "uid": uid.to_string(),
+            "page_size": 2,
+            "action": "user.login",
+        }))
+        .await;
+        let (status, res) = list(serde_json::json!({
+            "uid": uid.to_string(),
+            "page_size": 2,
+            "action": "user.login",
+            "page_token": res["next_page_token"],
+        }))
+        .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert!(res.get("prev_page_token").is_none(), "{}", res);
+        let (status, res) = list(serde_json::json!({
+            "uid": uid.to_string(),
+            "page_size": 2,
+            "action": "user.login",
+            "before": page2["prev_page_token"],
+        }))
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            res["error"]["message"],
+            "before can't be used with action or label"
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn handlers_work() {
+        let app = TestApp::new(test_conf());
+        let uid = xid::new().to_string();
+
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log",
+                &[],
+                Some(serde_json::json!({
+                    "uid": uid,
+                    "gid": xid::new().to_string(),
+                    "action": "user.spend",
+                    "payload": "",
+                    "tokens": 10,
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        let id = res["result"]["id"].as_str().unwrap().to_string();
+        assert_eq!(res["result"]["action"], "user.spend");
+
+        let (status, res) = app
+            .call(
+                "PATCH",
+                "/v1/log",
+                &[],
+                Some(serde_json::json!({
+                    "uid": uid,
+                    "id": id,
+                    "status": 1,
+                    "tokens": 12,
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+
+        let uri = format!("/v1/log?uid={}&id={}", uid, id);
+        let (status, res) = app.call("GET", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["status"], 1);
+        assert_eq!(res["result"]["tokens"], 12);
+
+        // the store is the one of the app
+        let mut doc = db::Log::with_pk(
+            xid::Id::from_str(&uid).unwrap(),
+            xid::Id::from_str(&id).unwrap(),
+        );
+        app.store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(doc.tokens, 12);
+
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log/list",
+                &[],
+                Some(serde_json::json!({"uid": uid})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"].as_array().unwrap().len(), 1);
+        assert_eq!(app.state.in_flight.get(), 0);
+
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log/list",
+                &[],
+                Some(serde_json::json!({"uid": uid, "fields": ["compact"]})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        let item = &res["result"][0];
+        assert!(item.get("tokens").is_some(), "{}", item);
+        assert!(item.get("ip").is_none(), "{}", item);
+
+        let uri = format!("/v1/log?uid={}&id={}", uid, xid::new());
+        let (status, _) = app.call("GET", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = app.call("GET", &uri, &[("x-tenant", "nope")], None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn import_normalizes_logs() {
+        let app = TestApp::new(test_conf());
+        let (uid, id) = (xid::new(), xid::new());
+        let log = serde_json::json!({
+            "uid": uid.to_string(),
+            "id": id.to_string(),
+            "action": " User.Login ",
+            "status": 0,
+            "gid": xid::new().to_string(),
+            "ip": "fe80::0001%eth0",
+            "payload": "",
+            "tokens": 1,
+            "labels": {"env": "prod"},
+            "payload_type": "json",
+        });
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log/import",
+                &[],
+                Some(serde_json::json!({ "logs": [log] })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["imported"], 1);
+
+        let mut doc = db::Log::with_pk(uid, id);
+        app.store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(doc.action, 8);
+        assert_eq!(doc.ip, "fe80::1");
+        assert_eq!(doc.labels.get("env").unwrap(), "prod");
+        assert_eq!(doc.payload_type, "json");
+
+        let mut log = log;
+        log["id"] = xid::new().to_string().into();
+        log["payload_type"] = "xml".into();
+        let (status, _) = app
+            .call(
+                "POST",
+                "/v1/log/import",
+                &[],
+                Some(serde_json::json!({ "logs": [log.clone()] })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        log["payload_type"] = "json".into();
+        log["ip"] = "not an ip".into();
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log/import",
+                &[],
+                Some(serde_json::json!({ "logs": [log] })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(res["error"]["message"], "invalid ip \"not an ip\"");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn scan_handlers_work() {
+        let app = TestApp::new(test_conf());
+        let uid = xid::new();
+        let since = 1_700_000_000u32;
+        let log = |at: u32, action: &str, status: i8, tokens: i32| {
+            serde_json::json!({
+                "uid": uid.to_string(),
+                "id": db::xid_from_unix(at).to_string(),
+                "action": action,
+                "status": status,
+                "gid": xid::new().to_string(),
+                "payload": "",
+                "tokens": tokens,
+            })
+        };
+        let logs = vec![
+            log(since, "user.spend", 0, 1),
+            log(since + 30, "user.spend", 1, 2),
+            log(since + 90, "user.login", 0, 3),
+        ];
+
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log/import",
+                &[],
+                Some(serde_json::json!({ "logs": logs })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["imported"], 3);
+        let (_, res) = app
+            .call(
+                "POST",
+                "/v1/log/import",
+                &[],
+                Some(serde_json::json!({ "logs": [logs[0]] })),
+            )
+            .await;
+        assert_eq!(res["result"]["imported"], 0);
+        assert_eq!(res["result"]["skipped"][0], logs[0]["id"]);
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as(
+            "labels",
+            &HashMap::from([("env".to_string(), "prod".to_string())]),
+        );
+        let labeled = put_log(app.store.as_ref(), uid, 8, cols).await;
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log/list",
+                &[],
+                Some(serde_json::json!({
+                    "uid": uid.to_string(),
+                    "label": "env=prod",
+                    "page_size": 10,
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"].as_array().unwrap().len(), 1);
+        assert_eq!(res["result"][0]["id"], labeled.id.to_string());
+
+        let tokens = |query: &str| {
+            format!(
+                "/v1/log/tokens?uid={}&since={}&until={}{}",
+                uid,
+                since,
+                since + 120,
+                query
+            )
+        };
+        let (status, res) = app.call("GET", &tokens(""), &[], None).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["tokens"], 6);
+        let (_, res) = app
+            .call("GET", &tokens("&action=user.spend"), &[], None)
+            .await;
+        assert_eq!(res["result"]["tokens"], 3);
+        let (_, res) = app.call("GET", &tokens("&streamed=true"), &[], None).await;
+        assert_eq!(res["result"]["tokens"], 6);
+        assert_eq!(res["result"]["truncated"], false);
+
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log/histogram",
+                &[],
+                Some(serde_json::json!({
+                    "uid": uid.to_string(),
+                    "bucket_seconds": 60,
+                    "since": since,
+                    "until": since + 120,
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["counts"], serde_json::json!([2, 1]));
+        assert_eq!(res["result"]["truncated"], false);
+
+        let uri = format!(
+            "/v1/log/by_action?uid={}&action=user.login&before={}",
+            uid,
+            since + 120
+        );
+        let (status, res) = app.call("DELETE", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["deleted"], 1);
+
+        // the frozen log is kept unless forced, the labeled one is newer
+        let uri = format!("/v1/log/purge?uid={}&before={}", uid, since + 120);
+        let (status, res) = app.call("DELETE", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+        assert_eq!(res["result"]["deleted"], 1);
+        let (_, res) = app
+            .call("DELETE", &format!("{}&force=true", uri), &[], None)
+            .await;
+        assert_eq!(res["result"]["deleted"], 1);
+        let (_, res) = app.call("GET", &tokens(""), &[], None).await;
+        assert_eq!(res["result"]["tokens"], 0);
+    }
+
+    #[test]
+    fn check_anonymous_works() {
+        let allowed = vec!["user.login".to_string()];
+        assert!(check_anonymous(&allowed, &db::ANONYMOUS_UID, "user.login").is_ok());
+        let err = check_anonymous(&allowed, &db::ANONYMOUS_UID, "user.spend").unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(check_anonymous(&[], &db::ANONYMOUS_UID, "user.login").is_err());
+        assert!(check_anonymous(&[], &xid::new(), "user.spend").is_ok());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn check_required_context_works() {
+        let required = HashMap::from([(
+            "user.spend".to_string(),
+            vec![
+                "tenant".to_string(),
+                "caller".to_string(),
+                "region".to_string(),
+            ],
+        )]);
+        let mut ctx = ReqContext::new("rid", xid::new(), 0);
+        let mut headers = HeaderMap::new();
+        assert!(check_required_context(&required, &ctx, &headers, "user.login").is_ok());
+        let err = check_required_context(&required, &ctx, &headers, "user.spend").unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(err.message.contains("tenant,caller,region"));
+
+        ctx.tenant = "example".to_string();
+        headers.insert("x-region", " ".parse().unwrap());
+        let err = check_required_context(&required, &ctx, &headers, "user.spend").unwrap_err();
+        assert!(err.message.ends_with("requires context caller,region"));
+
+        // context kvs set by handlers don't count
+        ctx.set_kvs(vec![("caller", "billing".into())]).await;
+        let err = check_required_context(&required, &ctx, &headers, "user.spend").unwrap_err();
+        assert!(err.message.ends_with("requires context caller,region"));
+
+        headers.insert("x-auth-app", "billing".parse().unwrap());
+        headers.insert("x-region", "eu".parse().unwrap());
+        assert!(check_required_context(&required, &ctx, &headers, "user.spend").is_ok());
+
+        // through create
+        let mut cfg = test_conf();
+        cfg.required_context = HashMap::from([(
+            "user.spend".to_string(),
+            vec!["caller".to_string(), "region".to_string()],
+        )]);
+        let app = TestApp::new(cfg);
+        let body = serde_json::json!({
+            "uid": xid::new().to_string(),
+            "gid": xid::new().to_string(),
+            "action": "user.spend",
+            "payload": "",
+            "tokens": 1,
+        });
+        let (status, res) = app
+            .call("POST", "/v1/log", &[("x-region", "eu")], Some(body.clone()))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(res["error"]["message"]
+            .as_str()
+            .unwrap()
+            .ends_with("requires context caller"));
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log",
+                &[("x-region", "eu"), ("x-auth-app", "billing")],
+                Some(body),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+    }
+
+    #[test]
+    fn check_create_works() {
+        let mut cfg = test_conf();
+        cfg.token_caps = HashMap::from([("user.spend".to_string(), 1000)]);
+        let ctx = ReqContext::new("rid", xid::new(), 0);
+        let headers = HeaderMap::new();
+        let check = |cfg: &conf::Conf, input: &mut CreateLogInput| {
+            check_create(cfg, &ctx, &headers, input)
+                .err()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(rule, err)| (rule, err.message))
+                .collect::<Vec<(&str, String)>>()
+        };
+        let mut input = CreateLogInput {
+            uid: PackObject::Cbor(xid::new()),
+            gid: PackObject::Cbor(xid::new()),
+            action: "user.spend".to_string(),
+            status: None,
+            ip: "".to_string(),
+            payload: PackObject::Cbor(vec![0x80]),
+            tokens: 100,
+            labels: HashMap::new(),
+            external_id: None,
+            payload_type: None,
+        };
+        assert_eq!(check_create(&cfg, &ctx, &headers, &mut input).unwrap(), 15);
+
+        input.tokens = 1001;
+        let res = check(&cfg, &mut input);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, "token_cap");
+
+        input.action = "creation.update.content".to_string();
+        input.payload = PackObject::Cbor(vec![]);
+        input.status = Some(db::Status::Deleted);
+        let res = check(&cfg, &mut input);
+        let rules: Vec<&str> = res.iter().map(|f| f.0).collect();
+        assert_eq!(rules, vec!["input", "payload_required"]);
+
+        input.action = "user.fly".to_string();
+        input.status = None;
+        let res = check(&cfg, &mut input);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, "action");
+        assert!(res[0].1.contains("user.fly"));
+
+        input.action = "user login".to_string();
+        let res = check(&cfg, &mut input);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, "action");
+        assert!(res[0].1.contains("whitespace"));
+
+        input.uid = PackObject::Cbor(db::ANONYMOUS_UID);
+        input.action = " user.login ".to_string();
+        let res = check(&cfg, &mut input);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, "anonymous");
+        cfg.anonymous_actions = vec!["user.login".to_string()];
+        assert!(check(&cfg, &mut input).is_empty());
+        assert_eq!(input.action, "user.login");
+
+        input.ip = "fe80::1%eth0".to_string();
+        assert!(check(&cfg, &mut input).is_empty());
+        assert_eq!(input.ip, "fe80::1");
+        input.ip = "fe80::1%eth0".to_string();
+        cfg.keep_ipv6_zone = true;
+        assert!(check(&cfg, &mut input).is_empty());
+        assert_eq!(input.ip, "fe80::1%eth0");
+        input.ip = "not an ip".to_string();
+        let res = check(&cfg, &mut input);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, "ip");
+        input.ip = "".to_string();
+
+        // the rules of create that depend on the request and the config
+        cfg.required_context =
+            HashMap::from([("user.login".to_string(), vec!["tenant".to_string()])]);
+        let res = check(&cfg, &mut input);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, "required_context");
+        cfg.required_context = HashMap::new();
+
+        cfg.sampling = HashMap::from([("user.login".to_string(), 0.5)]);
+        input.external_id = Some("order-1".to_string());
+        let res = check(&cfg, &mut input);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, "sampling");
+        input.external_id = None;
+        assert!(check(&cfg, &mut input).is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn histogram_buckets_are_bounded() {
+        let app = TestApp::new(test_conf());
+        let body = |until: u32| {
+            serde_json::json!({
+                "uid": xid::new().to_string(),
+                "bucket_seconds": 60,
+                "since": 1_700_000_000u32,
+                "until": until,
+            })
+        };
+        // 1000 buckets and a second, which rounds up to 1001
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log/histogram",
+                &[],
+                Some(body(1_700_000_000 + 60_001)),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            res["error"]["message"],
+            "too many buckets, expected at most 1000"
+        );
+    }
+
+    #[test]
+    fn list_recently_limit_is_bounded() {
+        let mut input = ListRecentlyInput {
+            uid: PackObject::Json(xid::new()),
+            actions: vec![],
+            fields: None,
+            with_payload: None,
+            limit: None,
+        };
+        assert!(input.validate().is_ok());
+        input.limit = Some(5);
+        assert!(input.validate().is_ok());
+        input.limit = Some(0);
+        assert!(input.validate().is_err());
+        input.limit = Some(1001);
+        assert!(input.validate().is_err());
+
+        let cfg = conf::Pagination::default();
+        assert_eq!(cfg.page_size(Some(5)), 5);
+        assert_eq!(cfg.page_size(Some(cfg.max_page_size)), 1000);
+    }
+
+    #[test]
+    fn labels_works() {
+        let labels = |v: &[(&str, &str)]| {
+            v.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<String, String>>()
+        };
+        assert!(validate_labels(&labels(&[])).is_ok());
+        assert!(validate_labels(&labels(&[("env", "prod"), ("region.az", "us-1")])).is_ok());
+        assert!(validate_labels(&labels(&[("Env", "prod")])).is_err());
+        assert!(validate_labels(&labels(&[("", "prod")])).is_err());
+        assert!(validate_labels(&labels(&[("env", &"x".repeat(257))])).is_err());
+        let many: Vec<(String, String)> = (0..=MAX_LABELS)
+            .map(|i| (format!("k{}", i), "v".to_string()))
+            .collect();
+        assert!(validate_labels(&many.into_iter().collect()).is_err());
+
+        assert_eq!(parse_label("env=prod").unwrap(), ("env", "prod"));
+        assert_eq!(parse_label("env=").unwrap(), ("env", ""));
+        assert_eq!(parse_label("env").unwrap_err().code, 400);
+        assert_eq!(parse_label("=prod").unwrap_err().code, 400);
+
+        let to = PackObject::Json(());
+        let mut doc = db::Log::with_pk(xid::new(), xid::new());
+        doc.labels = labels(&[("region", "us"), ("env", "prod")]);
+        let output = LogOutput::from(doc.clone(), &to);
+        assert!(output.labels.is_none());
+        doc._fields = vec!["labels".to_string()];
+        let output = serde_json::to_value(LogOutput::from(doc.clone(), &to)).unwrap();
+        assert_eq!(output["labels"]["env"], "prod");
+        assert_eq!(output["labels"]["region"], "us");
+        let output = flat_log(doc);
+        assert_eq!(output["labels"]["env"], "prod");
+    }
+
+    #[test]
+    fn normalize_ip_works() {
+        assert_eq!(normalize_ip("1.2.3.4", false).unwrap(), "1.2.3.4");
+        assert_eq!(
+            normalize_ip("2001:0DB8:0000:0000:0000:0000:0000:0001", false).unwrap(),
+            "2001:db8::1"
+        );
+        assert_eq!(normalize_ip("fe80::1%eth0", false).unwrap(), "fe80::1");
+        assert_eq!(normalize_ip("fe80::1%eth0", true).unwrap(), "fe80::1%eth0");
+        assert_eq!(
+            normalize_ip("FE80:0::0001%en0.1", true).unwrap(),
+            "fe80::1%en0.1"
+        );
+
+        for ip in [
+            "",
+            "1.2.3",
+            "localhost",
+            "1.2.3.4%eth0",
+            "fe80::1%",
+            "fe80::1%a b",
+        ] {
+            assert_eq!(normalize_ip(ip, true).unwrap_err().code, 400, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn top_by_tokens_works() {
+        let uid = xid::new();
+        let logs: Vec<db::Log> = [5, 100, 0, 42, 100, 7]
+            .iter()
+            .enumerate()
+            .map(|(i, tokens)| {
+                let mut doc = db::Log::with_pk(uid, db::xid_from_unix(1690000000 + i as u32));
+                doc.tokens = *tokens;
+                doc
+            })
+            .collect();
+
+        let res = top_by_tokens(logs.clone(), 3);
+        assert_eq!(
+            res.iter().map(|r| r.tokens).collect::<Vec<i32>>(),
+            vec![100, 100, 42]
+        );
+        assert_eq!(res[0].id, logs[4].id);
+        assert_eq!(res[1].id, logs[1].id);
+
+        let res = top_by_tokens(logs, 100);
+        assert_eq!(
+            res.iter().map(|r| r.tokens).collect::<Vec<i32>>(),
+            vec![100, 100, 42, 7, 5, 0]
+        );
+        assert!(top_by_tokens(vec![], 3).is_empty());
+    }
+
+    #[test]
+    fn list_fields_works() {
+        let mut cfg = conf::Pagination::default();
+        let fields = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            list_fields(&cfg, fields(&["tokens", "error"]), false).unwrap(),
+            fields(&["tokens", "error"])
+        );
+
+        // the default projection, "full" included, leaves payload out of lists
+        for empty in [vec![], get_fields(Some("full".to_string()))] {
+            let res = list_fields(&cfg, empty, false).unwrap();
+            assert!(!res.contains(&"payload".to_string()));
+            assert!(res.contains(&"tokens".to_string()));
+            assert_eq!(res.len(), db::Log::fields().len() - 1);
+        }
+        assert!(list_fields(&cfg, vec![], true).unwrap().is_empty());
+        assert!(list_fields(&cfg, fields(&["full"]), true)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            list_fields(&cfg, fields(&["compact"]), false).unwrap(),
+            db::Log::COMPACT_FIELDS.to_vec()
+        );
+
+        let err = list_fields(&cfg, fields(&["tokens", "payload"]), false).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(err.message.contains("with_payload"));
+        assert!(list_fields(&cfg, fields(&["tokens", "payload"]), true).is_ok());
+
+        cfg.max_fields = 2;
+        let err = list_fields(&cfg, fields(&["gid", "ip", "tokens"]), false).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert_eq!(
+            list_fields(&cfg, vec![], false).unwrap().len(),
+            db::Log::fields().len() - 1
+        );
+        cfg.max_fields = 0;
+        assert!(list_fields(&cfg, fields(&["gid", "ip", "tokens"]), false).is_ok());
+    }
+
+    #[test]
+    fn with_cursors_works() {
+        let token = |b: u8| Some(PackObject::Json(vec![b]));
+
+        // forward pages
+        let res = with_cursors(SuccessResponse::new(()), 3, 3, token(1), None, false);
+        assert_eq!(res.has_more, Some(true));
+        assert_eq!(res.next_page_token, token(1));
+        assert!(res.prev_page_token.is_none());
+        let res = with_cursors(SuccessResponse::new(()), 2, 3, token(1), token(2), false);
+        assert_eq!(res.has_more, Some(false));
+        assert!(res.next_page_token.is_none());
+        assert_eq!(res.prev_page_token, token(2));
+
+        // pages read back keep the way to older logs
+        let res = with_cursors(SuccessResponse::new(()), 3, 3, token(1), token(2), true);
+        assert_eq!(res.has_more, Some(true));
+        assert_eq!(res.next_page_token, token(1));
+        assert_eq!(res.prev_page_token, token(2));
+        let res = with_cursors(SuccessResponse::new(()), 2, 3, token(1), token(2), true);
+        assert_eq!(res.has_more, Some(true));
+        assert_eq!(res.next_page_token, token(1));
+        assert!(res.prev_page_token.is_none());
+        let res = with_cursors(SuccessResponse::new(()), 0, 3, None, None, true);
+        assert_eq!(res.has_more, Some(false));
+    }
+
+    #[test]
+    fn recent_actions_works() {
+        let names = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert!(recent_actions(&[]).unwrap().is_empty());
+        assert!(recent_actions(&names(&["all"])).unwrap().is_empty());
+        assert_eq!(
+            recent_actions(&names(&["user.login", "creation.create"])).unwrap(),
+            vec![8, 40]
+        );
+
+        let err = recent_actions(&names(&["all", "user.login"])).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(recent_actions(&names(&["user.fly"])).is_err());
+    }
+
+    #[test]
+    fn check_scan_bounds_works() {
+        assert!(check_scan_bounds(false, None, None).is_ok());
+        assert!(check_scan_bounds(true, Some(1690000000), None).is_ok());
+        assert!(check_scan_bounds(true, None, Some(100)).is_ok());
+
+        let err = check_scan_bounds(true, None, None).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(err.message.contains("time window"));
+    }
 }
@@ -0,0 +1,366 @@
+use axum::{extract::State, http::header, response::IntoResponse};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::api::AppState;
+
+// upper bounds of the latency buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; LATENCY_BUCKETS.len()], // cumulative
+    count: u64,
+    sum: f64,
+}
+
+// upper bounds of the payload size buckets, in bytes.
+pub const PAYLOAD_SIZE_BUCKETS: [u64; 8] = [64, 256, 1024, 4096, 16384, 65536, 262144, 1048576];
+
+#[derive(Default)]
+struct SizeHistogram {
+    counts: [u64; PAYLOAD_SIZE_BUCKETS.len()], // cumulative
+    count: u64,
+    sum: u64,
+}
+
+// the payload sizes of stored creates.
+#[derive(Default)]
+pub struct PayloadSizes(Mutex<SizeHistogram>);
+
+impl PayloadSizes {
+    pub fn observe(&self, size: usize) {
+        let size = size as u64;
+        let mut h = self.0.lock().unwrap();
+        for (i, le) in PAYLOAD_SIZE_BUCKETS.iter().enumerate() {
+            if size <= *le {
+                h.counts[i] += 1;
+            }
+        }
+        h.count += 1;
+        h.sum += size;
+    }
+
+    // renders the histogram in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        let name = "logbase_payload_size_bytes";
+        let _ = writeln!(out, "# HELP {} Payload size of created logs.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let h = self.0.lock().unwrap();
+        for (i, le) in PAYLOAD_SIZE_BUCKETS.iter().enumerate() {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, h.counts[i]);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, h.count);
+        let _ = writeln!(out, "{}_sum {}", name, h.sum);
+        let _ = writeln!(out, "{}_count {}", name, h.count);
+    }
+}
+
+// request latency histograms keyed by method and matched route.
+#[derive(Default)]
+pub struct RouteLatency {
+    routes: Mutex<BTreeMap<(String, String), Histogram>>,
+}
+
+impl RouteLatency {
+    pub fn observe(&self, method: &str, route: &str, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let mut routes = self.routes.lock().unwrap();
+        let h = routes
+            .entry((method.to_string(), route.to_string()))
+            .or_default();
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *le {
+                h.counts[i] += 1;
+            }
+        }
+        h.count += 1;
+        h.sum += secs;
+    }
+
+    // renders the histograms in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        let name = "logbase_http_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Request latency by route.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let routes = self.routes.lock().unwrap();
+        for ((method, route), h) in routes.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, route);
+            for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, labels, le, h.counts[i]
+                );
+            }
+            let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, h.count);
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, h.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, h.count);
+        }
+    }
+}
+
+// the number of requests being handled.
+#[derive(Default)]
+pub struct InFlight(AtomicU64);
+
+// the Scylla errors and queries of the recent window, so an error burst
+// long ago doesn't weigh on the health status forever. The counts cover the
+// previous full window and the current one.
+pub struct ScyllaWindow {
+    window: Duration,
+    state: Mutex<WindowState>,
+}
+
+struct WindowState {
+    started_at: Instant,
+    start: (u64, u64),    // cumulative (errors, queries) when the window started
+    previous: (u64, u64), // (errors, queries) of the previous window
+}
+
+impl ScyllaWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            state: Mutex::new(WindowState {
+                started_at: Instant::now(),
+                start: (0, 0),
+                previous: (0, 0),
+            }),
+        }
+    }
+
+    // takes the cumulative counts, returns the (errors, queries) of the
+    // recent window.
+    pub fn observe(&self, now: Instant, errors: u64, queries: u64) -> (u64, u64) {
+        let mut s = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(s.started_at);
+        if elapsed >= self.window {
+            s.previous = if elapsed >= self.window * 2 {
+                (0, 0) // no sample in the previous window
+            } else {
+                (
+                    errors.saturating_sub(s.start.0),
+                    queries.saturating_sub(s.start.1),
+                )
+            };
+            s.started_at = now;
+            s.start = (errors, queries);
+        }
+        (
+            s.previous.0 + errors.saturating_sub(s.start.0),
+            s.previous.1 + queries.saturating_sub(s.start.1),
+        )
+    }
+}
+
+// decrements the in-flight gauge when dropped, so cancelled or panicked
+// requests are released too.
+pub struct InFlightGuard(Arc<InFlight>);
+
+impl InFlight {
+    pub fn enter(self: &Arc<Self>) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self.clone())
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn metrics(State(app): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut out = String::new();
+    app.latency.render(&mut out);
+    app.payload_sizes.render(&mut out);
+
+    let name = "logbase_http_requests_in_flight";
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, app.in_flight.get());
+
+    let m = app.scylla.metrics();
+    for (name, value) in [
+        ("logbase_scylla_queries_total", m.get_queries_num()),
+        ("logbase_scylla_errors_total", m.get_errors_num()),
+        (
+            "logbase_scylla_queries_iter_total",
+            m.get_queries_iter_num(),
+        ),
+        ("logbase_scylla_errors_iter_total", m.get_errors_iter_num()),
+        ("logbase_scylla_retries_total", m.get_retries_num()),
+    ] {
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    let name = "logbase_write_buffer_dropped_total";
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let dropped: u64 = app.buffers.values().map(|b| b.dropped()).sum();
+    let _ = writeln!(out, "{} {}", name, dropped);
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scylla_window_works() {
+        let w = ScyllaWindow::new(Duration::from_secs(60));
+        let t0 = Instant::now();
+        assert_eq!(w.observe(t0, 50, 100), (50, 100));
+        assert_eq!(w.observe(t0 + Duration::from_secs(30), 50, 200), (50, 200));
+
+        // the burst is in the previous window
+        let t1 = t0 + Duration::from_secs(60);
+        assert_eq!(w.observe(t1, 50, 300), (50, 300));
+        assert_eq!(
+            w.observe(t1 + Duration::from_secs(30), 51, 1300),
+            (51, 1300)
+        );
+
+        // the burst has left the window
+        let t2 = t1 + Duration::from_secs(60);
+        assert_eq!(w.observe(t2, 51, 1300), (1, 1000));
+        assert_eq!(w.observe(t2 + Duration::from_secs(1), 51, 1400), (1, 1100));
+
+        // an idle gap of two windows forgets both
+        let t3 = t2 + Duration::from_secs(180);
+        assert_eq!(w.observe(t3, 60, 1500), (0, 0));
+        assert_eq!(w.observe(t3 + Duration::from_secs(1), 61, 1510), (1, 10));
+    }
+
+    #[test]
+    fn in_flight_works() {
+        let in_flight = Arc::new(InFlight::default());
+        let a = in_flight.enter();
+        let b = in_flight.enter();
+        assert_eq!(in_flight.get(), 2);
+        drop(a);
+        assert_eq!(in_flight.get(), 1);
+        drop(b);
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    #[test]
+    fn payload_sizes_works() {
+        let sizes = PayloadSizes::default();
+        sizes.observe(0);
+        sizes.observe(1000);
+        sizes.observe(2 << 20);
+
+        let mut out = String::new();
+        sizes.render(&mut out);
+        let name = "logbase_payload_size_bytes";
+        for line in [
+            format!("{}_bucket{{le=\"64\"}} 1", name),
+            format!("{}_bucket{{le=\"256\"}} 1", name),
+            format!("{}_bucket{{le=\"1024\"}} 2", name),
+            format!("{}_bucket{{le=\"1048576\"}} 2", name),
+            format!("{}_bucket{{le=\"+Inf\"}} 3", name),
+            format!("{}_sum {}", name, 1000 + (2 << 20)),
+            format!("{}_count 3", name),
+        ] {
+            assert!(out.contains(&line), "missing {}", line);
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn create_observes_payload_size() {
+        use crate::api::testing::{test_conf, TestApp};
+        use axum::{
+            body::Body,
+            http::{Request, StatusCode},
+        };
+        use base64::{engine::general_purpose, Engine as _};
+        use tower::ServiceExt;
+
+        let app = TestApp::new(test_conf());
+        let (status, res) = app
+            .call(
+                "POST",
+                "/v1/log",
+                &[],
+                Some(serde_json::json!({
+                    "uid": xid::new().to_string(),
+                    "gid": xid::new().to_string(),
+                    "action": "user.spend",
+                    "payload": general_purpose::URL_SAFE_NO_PAD.encode([7u8; 100]),
+                    "tokens": 1,
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", res);
+
+        let req = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let out = String::from_utf8(body.to_vec()).unwrap();
+        let name = "logbase_payload_size_bytes";
+        for line in [
+            format!("{}_bucket{{le=\"64\"}} 0", name),
+            format!("{}_bucket{{le=\"256\"}} 1", name),
+            format!("{}_sum 100", name),
+            format!("{}_count 1", name),
+        ] {
+            assert!(out.contains(&line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn route_latency_works() {
+        let latency = RouteLatency::default();
+        latency.observe("GET", "/v1/log", Duration::from_millis(3));
+        latency.observe("GET", "/v1/log", Duration::from_millis(300));
+        latency.observe("POST", "/v1/log/list", Duration::from_secs(30));
+
+        let mut out = String::new();
+        latency.render(&mut out);
+        let name = "logbase_http_request_duration_seconds";
+        for line in [
+            format!(
+                "{}_bucket{{method=\"GET\",route=\"/v1/log\",le=\"0.005\"}} 1",
+                name
+            ),
+            format!(
+                "{}_bucket{{method=\"GET\",route=\"/v1/log\",le=\"0.25\"}} 1",
+                name
+            ),
+            format!(
+                "{}_bucket{{method=\"GET\",route=\"/v1/log\",le=\"0.5\"}} 2",
+                name
+            ),
+            format!(
+                "{}_bucket{{method=\"GET\",route=\"/v1/log\",le=\"+Inf\"}} 2",
+                name
+            ),
+            format!("{}_count{{method=\"GET\",route=\"/v1/log\"}} 2", name),
+            format!(
+                "{}_bucket{{method=\"POST\",route=\"/v1/log/list\",le=\"10\"}} 0",
+                name
+            ),
+            format!("{}_count{{method=\"POST\",route=\"/v1/log/list\"}} 1", name),
+        ] {
+            assert!(out.contains(&line), "missing {}", line);
+        }
+    }
+}
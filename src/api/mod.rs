@@ -1,30 +1,138 @@
-use axum::extract::State;
+use axum::{extract::State, http::HeaderMap, Extension};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{collections::HashMap, net::IpAddr, str::FromStr, sync::Arc, time::Instant};
 
-use axum_web::object::PackObject;
+use axum_web::context::ReqContext;
+use axum_web::erring::HTTPError;
+use axum_web::object::{cbor_from_slice, cbor_to_vec, PackObject};
 
+use crate::conf;
 use crate::db::{self};
 
 pub mod action;
+pub mod admin;
+pub mod auth;
+pub mod export;
 pub mod log;
+pub mod metrics;
+pub mod notify;
+pub mod quota;
+pub mod replay;
+#[cfg(test)]
+pub mod testing;
 
 pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Clone)]
 pub struct AppState {
+    pub cfg: Arc<conf::Conf>,
     pub scylla: Arc<db::scylladb::ScyllaDB>,
+    pub tenants: HashMap<String, Arc<db::scylladb::ScyllaDB>>,
+    pub stores: HashMap<String, Arc<dyn db::LogStore>>, // tenant -> log store, "" for the default
+    pub buffers: HashMap<String, Arc<db::WriteBuffer>>, // tenant -> buffer, "" for the default
+    pub latency: Arc<metrics::RouteLatency>,
+    pub in_flight: Arc<metrics::InFlight>,
+    pub payload_sizes: Arc<metrics::PayloadSizes>,
+    pub scylla_window: Arc<metrics::ScyllaWindow>,
+    pub quotas: Arc<quota::ActionQuotas>,
+    pub read_only: Arc<admin::ReadOnly>,
+    pub notifier: Arc<notify::Notifier>,
+}
+
+impl AppState {
+    // selects the Scylla handle of the request's tenant.
+    pub fn scylla_for(&self, ctx: &ReqContext) -> Result<Arc<db::scylladb::ScyllaDB>, HTTPError> {
+        select_tenant(&self.tenants, &self.scylla, &ctx.tenant).cloned()
+    }
+
+    // the log store of the request's tenant, the tenant's Scylla handle
+    // unless another store is injected.
+    pub fn store_for(&self, ctx: &ReqContext) -> Result<Arc<dyn db::LogStore>, HTTPError> {
+        self.stores
+            .get(&ctx.tenant)
+            .cloned()
+            .ok_or_else(|| HTTPError::new(400, format!("unknown tenant {}", ctx.tenant)))
+    }
+
+    // the write buffer of the request's tenant, if buffering is enabled.
+    pub fn buffer_for(&self, ctx: &ReqContext) -> Option<Arc<db::WriteBuffer>> {
+        self.buffers.get(&ctx.tenant).cloned()
+    }
+
+    // flushes the write buffers.
+    pub async fn shutdown(&self) {
+        for buffer in self.buffers.values() {
+            buffer.shutdown().await;
+        }
+    }
+
+    // fails writes with 503 in read-only mode.
+    pub fn check_writable(&self) -> Result<(), HTTPError> {
+        self.read_only.check()
+    }
+
+    // fails with 403 unless the request's role is one of admin_roles. Pin the
+    // role with an API key, the X-Auth-Role header is the caller's own claim
+    // when authentication is off.
+    pub fn check_admin(&self, ctx: &ReqContext) -> Result<(), HTTPError> {
+        if ctx.role.is_empty() || !self.cfg.admin_roles.contains(&ctx.role) {
+            return Err(HTTPError::new(
+                403,
+                format!("role {:?} is not an admin role", ctx.role),
+            ));
+        }
+        Ok(())
+    }
+
+    // the fields the request's role is not allowed to read.
+    pub fn redacted_fields(&self, ctx: &ReqContext) -> &[String] {
+        role_redaction(&self.cfg.redaction, &ctx.role)
+    }
+}
+
+// the redacted fields of a role. Callers without a role or with a role not
+// listed get the "default" entry, so a missing role header can't read more
+// than a listed role.
+fn role_redaction<'a>(redaction: &'a HashMap<String, Vec<String>>, role: &str) -> &'a [String] {
+    redaction
+        .get(role)
+        .filter(|_| !role.is_empty())
+        .or_else(|| redaction.get(DEFAULT_ROLE))
+        .map(|fields| fields.as_slice())
+        .unwrap_or(&[])
+}
+
+const DEFAULT_ROLE: &str = "default";
+
+fn select_tenant<'a, T>(
+    tenants: &'a HashMap<String, T>,
+    default: &'a T,
+    tenant: &str,
+) -> Result<&'a T, HTTPError> {
+    if tenant.is_empty() {
+        return Ok(default);
+    }
+    tenants
+        .get(tenant)
+        .ok_or_else(|| HTTPError::new(400, format!("unknown tenant {}", tenant)))
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct AppVersion {
     pub name: String,
     pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build_timestamp: Option<u64>, // unix seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rustc_version: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct AppInfo {
+    pub status: String, // "healthy", "degraded" or "unhealthy", see health_status
     // https://docs.rs/scylla/latest/scylla/struct.Metrics.html
     pub scylla_latency_avg_ms: u64,
     pub scylla_latency_p99_ms: u64,
@@ -34,18 +142,43 @@ pub struct AppInfo {
     pub scylla_errors_iter_num: u64,
     pub scylla_queries_iter_num: u64,
     pub scylla_retries_num: u64,
+    pub scylla_window_errors_num: u64, // errors and queries of the recent window
+    pub scylla_window_queries_num: u64,
+    pub scylla_connected: bool,
+    pub scylla_breaker_open: bool,
+    pub scylla_tombstone_failures: u64,
+    pub schema_ready: bool,
+    pub in_flight_requests: u64,
+    pub read_only: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AppReady {
+    pub scylla_connected: bool,
+    pub schema_ready: bool, // the log table exists in the keyspace
 }
 
 pub async fn version(to: PackObject<()>, State(_): State<Arc<AppState>>) -> PackObject<AppVersion> {
-    to.with(AppVersion {
+    to.with(build_info())
+}
+
+// the build metadata set by build.rs, absent if it couldn't be captured.
+pub fn build_info() -> AppVersion {
+    AppVersion {
         name: APP_NAME.to_string(),
         version: APP_VERSION.to_string(),
-    })
+        git_commit: option_env!("BUILD_GIT_COMMIT").map(|v| v.to_string()),
+        build_timestamp: option_env!("BUILD_TIMESTAMP").and_then(|v| v.parse().ok()),
+        rustc_version: option_env!("BUILD_RUSTC_VERSION").map(|v| v.to_string()),
+    }
 }
 
 pub async fn healthz(to: PackObject<()>, State(app): State<Arc<AppState>>) -> PackObject<AppInfo> {
     let m = app.scylla.metrics();
-    to.with(AppInfo {
+    let (window_errors, window_queries) =
+        app.scylla_window
+            .observe(Instant::now(), m.get_errors_num(), m.get_queries_num());
+    let mut info = AppInfo {
         scylla_latency_avg_ms: m.get_latency_avg_ms().unwrap_or(0),
         scylla_latency_p99_ms: m.get_latency_percentile_ms(99.0f64).unwrap_or(0),
         scylla_latency_p90_ms: m.get_latency_percentile_ms(90.0f64).unwrap_or(0),
@@ -54,9 +187,216 @@ pub async fn healthz(to: PackObject<()>, State(app): State<Arc<AppState>>) -> Pa
         scylla_errors_iter_num: m.get_errors_iter_num(),
         scylla_queries_iter_num: m.get_queries_iter_num(),
         scylla_retries_num: m.get_retries_num(),
+        scylla_window_errors_num: window_errors,
+        scylla_window_queries_num: window_queries,
+        scylla_connected: app.scylla.is_ready(),
+        scylla_breaker_open: app.scylla.is_breaker_open(),
+        scylla_tombstone_failures: app.scylla.tombstone_failures(),
+        schema_ready: app
+            .scylla
+            .table_exists(app.scylla.keyspace(), "log")
+            .await
+            .unwrap_or(false),
+        in_flight_requests: app.in_flight.get(),
+        read_only: app.read_only.get(),
+        ..Default::default()
+    };
+    info.status = health_status(&app.cfg.health, &info).to_string();
+    to.with(info)
+}
+
+// unhealthy if Scylla can't be queried or a metric crosses its unhealthy
+// threshold, degraded if one crosses its degraded threshold.
+pub fn health_status(cfg: &conf::Health, info: &AppInfo) -> &'static str {
+    let error_ratio = if info.scylla_window_queries_num == 0 {
+        0.0
+    } else {
+        info.scylla_window_errors_num as f64 / info.scylla_window_queries_num as f64
+    };
+    let p99 = info.scylla_latency_p99_ms;
+
+    if !info.scylla_connected
+        || info.scylla_breaker_open
+        || error_ratio >= cfg.unhealthy_error_ratio
+        || p99 >= cfg.unhealthy_p99_ms
+    {
+        "unhealthy"
+    } else if !info.schema_ready
+        || error_ratio >= cfg.degraded_error_ratio
+        || p99 >= cfg.degraded_p99_ms
+    {
+        "degraded"
+    } else {
+        "healthy"
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WhoAmI {
+    pub rid: String,
+    pub user: PackObject<xid::Id>,
+    pub rating: i8,
+    pub tenant: String,
+    pub keyspace: String,
+    pub actions: Vec<String>, // there is no per-caller allowlist, every caller may use all actions
+}
+
+// echoes the caller identity resolved from the request headers.
+pub async fn whoami(
+    to: PackObject<()>,
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+) -> Result<PackObject<WhoAmI>, HTTPError> {
+    let scylla = app.scylla_for(&ctx)?;
+    Ok(to.with(whoami_of(&to, &ctx, scylla.keyspace())))
+}
+
+fn whoami_of<T>(to: &PackObject<T>, ctx: &ReqContext, keyspace: &str) -> WhoAmI {
+    WhoAmI {
+        rid: ctx.rid.clone(),
+        user: to.with(ctx.user),
+        rating: ctx.rating,
+        tenant: ctx.tenant.clone(),
+        keyspace: keyspace.to_string(),
+        actions: action::names().into_iter().map(String::from).collect(),
+    }
+}
+
+pub async fn readyz(
+    to: PackObject<()>,
+    State(app): State<Arc<AppState>>,
+) -> Result<PackObject<AppReady>, HTTPError> {
+    if !app.scylla.is_ready() {
+        return Err(HTTPError::new(503, "scylla is not ready".to_string()));
+    }
+    let schema_ready = app
+        .scylla
+        .table_exists(app.scylla.keyspace(), "log")
+        .await
+        .unwrap_or(false);
+    if !schema_ready {
+        return Err(HTTPError::new(
+            503,
+            format!("table log not found in keyspace {}", app.scylla.keyspace()),
+        ));
+    }
+    Ok(to.with(AppReady {
+        scylla_connected: true,
+        schema_ready,
+    }))
+}
+
+// resolves the client ip, X-Forwarded-For is only honored when the peer is a trusted proxy.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[String]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|cidr| ip_in_cidr(ip, cidr));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|s| IpAddr::from_str(s.trim()).ok())
+        .collect();
+    // the rightmost untrusted hop is the client.
+    let mut client = peer;
+    for ip in forwarded.into_iter().rev() {
+        client = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+// a bare address matches only itself, invalid CIDRs match nothing.
+pub fn ip_in_cidr(ip: &IpAddr, cidr: &str) -> bool {
+    let (net, bits) = match cidr.trim().split_once('/') {
+        Some((net, bits)) => match u8::from_str(bits) {
+            Ok(bits) => (net, Some(bits)),
+            Err(_) => return false,
+        },
+        None => (cidr.trim(), None),
+    };
+    let net = match IpAddr::from_str(net) {
+        Ok(net) => net,
+        Err(_) => return false,
+    };
+
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let bits = bits.unwrap_or(32);
+            if bits > 32 {
+                return false;
+            }
+            let mask = u32::MAX.checked_shl(32 - bits as u32).unwrap_or(0);
+            u32::from(*ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let bits = bits.unwrap_or(128);
+            if bits > 128 {
+                return false;
+            }
+            let mask = u128::MAX.checked_shl(128 - bits as u32).unwrap_or(0);
+            u128::from(*ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+pub const PAGE_TOKEN_VERSION: u8 = 1;
+
+// the opaque page token, it carries the query's filters so that it can't be
+// reused on an incompatible query.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct PageToken {
+    v: u8,
+    id: [u8; 12],
+    desc: bool,
+    action: Option<i8>,
+    since: Option<u32>,
+}
+
+pub fn encode_page_token(
+    id: xid::Id,
+    action: Option<i8>,
+    since: Option<u32>,
+) -> Result<Vec<u8>, HTTPError> {
+    cbor_to_vec(&PageToken {
+        v: PAGE_TOKEN_VERSION,
+        id: id.0,
+        desc: true,
+        action,
+        since,
     })
 }
 
+pub fn decode_page_token(
+    data: &[u8],
+    action: Option<i8>,
+    since: Option<u32>,
+) -> Result<xid::Id, HTTPError> {
+    let token: PageToken = cbor_from_slice(data)
+        .map_err(|err| HTTPError::new(400, format!("invalid page_token, {}", err.message)))?;
+    if token.v != PAGE_TOKEN_VERSION {
+        return Err(HTTPError::new(
+            400,
+            format!("unsupported page_token version {}", token.v),
+        ));
+    }
+    if !token.desc || token.action != action || token.since != since {
+        return Err(HTTPError::new(
+            400,
+            "page_token does not match the query".to_string(),
+        ));
+    }
+    Ok(xid::Id(token.id))
+}
+
+// the comma separated fields of a query, with the presets of
+// db::Log::expand_presets expanded.
 pub fn get_fields(fields: Option<String>) -> Vec<String> {
     if fields.is_none() {
         return vec![];
@@ -66,5 +406,237 @@ pub fn get_fields(fields: Option<String>) -> Vec<String> {
     if fields.is_empty() {
         return vec![];
     }
-    fields.split(',').map(|s| s.trim().to_string()).collect()
+
+    db::Log::expand_presets(fields.split(',').map(|s| s.trim().to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info_works() {
+        let info = build_info();
+        assert_eq!(info.name, "logbase");
+        assert_eq!(info.version, APP_VERSION);
+        assert_eq!(info.git_commit.as_deref(), option_env!("BUILD_GIT_COMMIT"));
+        assert!(info.build_timestamp.unwrap() > 1690000000);
+        assert!(info.rustc_version.unwrap().starts_with("rustc "));
+    }
+
+    #[test]
+    fn role_redaction_works() {
+        let redaction = HashMap::from([
+            ("admin".to_string(), vec![]),
+            (
+                "guest".to_string(),
+                vec!["ip".to_string(), "payload".to_string()],
+            ),
+            (DEFAULT_ROLE.to_string(), vec!["ip".to_string()]),
+        ]);
+        assert!(role_redaction(&redaction, "admin").is_empty());
+        assert_eq!(role_redaction(&redaction, "guest"), ["ip", "payload"]);
+        assert_eq!(role_redaction(&redaction, ""), ["ip"]);
+        assert_eq!(role_redaction(&redaction, "unknown"), ["ip"]);
+
+        let redaction = HashMap::from([("guest".to_string(), vec!["ip".to_string()])]);
+        assert!(role_redaction(&redaction, "").is_empty());
+        assert!(role_redaction(&HashMap::new(), "guest").is_empty());
+    }
+
+    #[test]
+    fn get_fields_works() {
+        assert!(get_fields(None).is_empty());
+        assert!(get_fields(Some(" ".to_string())).is_empty());
+        assert_eq!(
+            get_fields(Some("tokens, error".to_string())),
+            vec!["tokens", "error"]
+        );
+        assert!(get_fields(Some("full".to_string())).is_empty());
+        assert!(get_fields(Some("compact,full".to_string())).is_empty());
+        assert_eq!(
+            get_fields(Some("compact,tokens,error".to_string())),
+            vec!["action", "status", "tokens", "error"]
+        );
+
+        let fields = get_fields(Some("compact".to_string()));
+        assert_eq!(fields, db::Log::COMPACT_FIELDS.to_vec());
+        assert_eq!(
+            db::Log::select_fields(fields, false).unwrap(),
+            db::Log::COMPACT_FIELDS.to_vec()
+        );
+        // lists of fields, as POSTed, expand too
+        for fields in [vec!["compact"], vec!["compact", "status"]] {
+            let fields = fields.into_iter().map(String::from).collect();
+            assert_eq!(
+                db::Log::select_fields(fields, false).unwrap(),
+                db::Log::COMPACT_FIELDS.to_vec()
+            );
+        }
+        assert_eq!(
+            db::Log::select_fields(vec!["full".to_string()], false).unwrap(),
+            db::Log::fields()
+        );
+    }
+
+    #[test]
+    fn select_tenant_works() {
+        let tenants = HashMap::from([
+            ("a".to_string(), "logbase_a"),
+            ("b".to_string(), "logbase_b"),
+        ]);
+        let default = "logbase";
+
+        assert_eq!(*select_tenant(&tenants, &default, "").unwrap(), "logbase");
+        assert_eq!(
+            *select_tenant(&tenants, &default, "a").unwrap(),
+            "logbase_a"
+        );
+        assert_eq!(
+            *select_tenant(&tenants, &default, "b").unwrap(),
+            "logbase_b"
+        );
+        let err = select_tenant(&tenants, &default, "c").unwrap_err();
+        assert_eq!(err.code, 400);
+    }
+
+    #[test]
+    fn health_status_works() {
+        let cfg = conf::Health::default();
+        let mut info = AppInfo {
+            scylla_latency_p99_ms: 20,
+            scylla_window_errors_num: 5,
+            scylla_window_queries_num: 1000,
+            scylla_connected: true,
+            schema_ready: true,
+            ..Default::default()
+        };
+        assert_eq!(health_status(&cfg, &info), "healthy");
+
+        info.scylla_window_errors_num = 10;
+        assert_eq!(health_status(&cfg, &info), "degraded");
+        info.scylla_window_errors_num = 100;
+        assert_eq!(health_status(&cfg, &info), "unhealthy");
+
+        // the lifetime counts don't count
+        info.scylla_window_errors_num = 0;
+        info.scylla_errors_num = 500;
+        info.scylla_queries_num = 1000;
+        assert_eq!(health_status(&cfg, &info), "healthy");
+
+        info.scylla_latency_p99_ms = 500;
+        assert_eq!(health_status(&cfg, &info), "degraded");
+        info.scylla_latency_p99_ms = 2000;
+        assert_eq!(health_status(&cfg, &info), "unhealthy");
+
+        info.scylla_latency_p99_ms = 20;
+        info.scylla_breaker_open = true;
+        assert_eq!(health_status(&cfg, &info), "unhealthy");
+
+        // no queries yet is not an error ratio
+        let info = AppInfo {
+            scylla_connected: true,
+            schema_ready: true,
+            ..Default::default()
+        };
+        assert_eq!(health_status(&cfg, &info), "healthy");
+    }
+
+    #[test]
+    fn whoami_works() {
+        let user = xid::new();
+        let mut ctx = ReqContext::new("some-rid", user, 2);
+        ctx.tenant = "a".to_string();
+
+        let res = whoami_of(&PackObject::Json(()), &ctx, "logbase_a");
+        assert_eq!(res.rid, "some-rid");
+        assert_eq!(res.user.unwrap(), user);
+        assert_eq!(res.rating, 2);
+        assert_eq!(res.tenant, "a");
+        assert_eq!(res.keyspace, "logbase_a");
+        assert!(res.actions.contains(&"user.login".to_string()));
+        assert!(!res.actions.contains(&"reserved".to_string()));
+    }
+
+    #[test]
+    fn page_token_works() {
+        let id = xid::new();
+        let token = encode_page_token(id, None, None).unwrap();
+        assert_eq!(decode_page_token(&token, None, None).unwrap(), id);
+
+        let token = encode_page_token(id, Some(8), Some(1690000000)).unwrap();
+        assert_eq!(
+            decode_page_token(&token, Some(8), Some(1690000000)).unwrap(),
+            id
+        );
+        // reused on an unfiltered query
+        let err = decode_page_token(&token, None, Some(1690000000)).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert!(err.message.contains("does not match"));
+        assert!(decode_page_token(&token, Some(9), Some(1690000000)).is_err());
+        assert!(decode_page_token(&token, Some(8), None).is_err());
+
+        // raw xid bytes are not a token
+        assert!(decode_page_token(id.as_bytes(), None, None).is_err());
+        let token = cbor_to_vec(&PageToken {
+            v: 2,
+            id: id.0,
+            desc: true,
+            action: None,
+            since: None,
+        })
+        .unwrap();
+        let err = decode_page_token(&token, None, None).unwrap_err();
+        assert!(err.message.contains("version"));
+    }
+
+    #[test]
+    fn ip_in_cidr_works() {
+        let ip = IpAddr::from_str("10.1.2.3").unwrap();
+        assert!(ip_in_cidr(&ip, "10.0.0.0/8"));
+        assert!(ip_in_cidr(&ip, "10.1.2.3"));
+        assert!(ip_in_cidr(&ip, "0.0.0.0/0"));
+        assert!(!ip_in_cidr(&ip, "10.1.2.4"));
+        assert!(!ip_in_cidr(&ip, "192.168.0.0/16"));
+        assert!(!ip_in_cidr(&ip, "10.0.0.0/33"));
+        assert!(!ip_in_cidr(&ip, "10.0.0.0/x"));
+        assert!(!ip_in_cidr(&ip, "::/0"));
+
+        let ip = IpAddr::from_str("fd00::1").unwrap();
+        assert!(ip_in_cidr(&ip, "fd00::/8"));
+        assert!(!ip_in_cidr(&ip, "fe80::/10"));
+    }
+
+    #[test]
+    fn client_ip_works() {
+        let trusted = vec!["10.0.0.0/8".to_string()];
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4, 10.0.0.2".parse().unwrap());
+
+        // trusted proxy, the forwarded client is honored
+        let peer = IpAddr::from_str("10.0.0.1").unwrap();
+        assert_eq!(
+            client_ip(peer, &headers, &trusted),
+            IpAddr::from_str("1.2.3.4").unwrap()
+        );
+
+        // untrusted peer, the header is ignored
+        let peer = IpAddr::from_str("5.6.7.8").unwrap();
+        assert_eq!(client_ip(peer, &headers, &trusted), peer);
+        assert_eq!(client_ip(peer, &headers, &[]), peer);
+
+        // a spoofed leftmost entry is skipped past the first untrusted hop
+        headers.insert(
+            "x-forwarded-for",
+            "9.9.9.9, 1.2.3.4, 10.0.0.2".parse().unwrap(),
+        );
+        let peer = IpAddr::from_str("10.0.0.1").unwrap();
+        assert_eq!(
+            client_ip(peer, &headers, &trusted),
+            IpAddr::from_str("1.2.3.4").unwrap()
+        );
+
+        // no header
+        assert_eq!(client_ip(peer, &HeaderMap::new(), &trusted), peer);
+    }
 }
@@ -0,0 +1,222 @@
+use anyhow::{anyhow, bail};
+use axum::http::{header, Method, Request, Uri};
+use axum_web::object::PackObject;
+use hyper::{Body, Client};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+use tokio::{sync::Semaphore, task::JoinHandle};
+
+use crate::api::{action, log::LogOutput};
+use crate::db;
+
+const DELIVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// POSTs created logs as JSON to the webhook of their action. Deliveries run
+// in the background, at most max_in_flight at once, notifications beyond
+// that are dropped. Failures are only logged.
+pub struct Notifier {
+    webhooks: HashMap<String, Uri>, // action name -> webhook
+    permits: Arc<Semaphore>,
+}
+
+impl Notifier {
+    // fails on an unknown action or a webhook that isn't an absolute http URL,
+    // so a bad config stops the startup rather than every notification.
+    pub fn new(webhooks: &HashMap<String, String>, max_in_flight: usize) -> anyhow::Result<Self> {
+        let mut uris = HashMap::with_capacity(webhooks.len());
+        for (name, url) in webhooks {
+            if action::to_action(name).is_none() {
+                bail!("invalid notify action {}", name);
+            }
+            let uri = Uri::from_str(url)
+                .map_err(|err| anyhow!("invalid notify webhook of {}: {}", name, err))?;
+            if uri.scheme_str() != Some("http") || uri.host().is_none() {
+                bail!(
+                    "invalid notify webhook of {}: expected an http URL, got {:?}",
+                    name,
+                    url
+                );
+            }
+            uris.insert(name.to_owned(), uri);
+        }
+        Ok(Self {
+            webhooks: uris,
+            permits: Arc::new(Semaphore::new(max_in_flight.max(1))),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty()
+    }
+
+    // notifies the webhook of the action if any, without waiting for it.
+    pub fn notify(&self, action: &str, output: LogOutput) -> Option<JoinHandle<()>> {
+        let uri = self.webhooks.get(action)?.clone();
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::warn!(
+                    "notify webhook of {} skipped, too many notifications in flight",
+                    action
+                );
+                return None;
+            }
+        };
+        let body = match serde_json::to_vec(&output) {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("encode notification of {} failed: {}", action, err);
+                return None;
+            }
+        };
+
+        let action = action.to_string();
+        let headers = vec![
+            ("x-log-uid", output.uid.unwrap().to_string()),
+            ("x-log-id", output.id.unwrap().to_string()),
+            ("x-log-action", action.clone()),
+        ];
+        Some(tokio::spawn(async move {
+            match post(uri, headers, body).await {
+                Ok(status) if status >= 300 => {
+                    log::warn!("notify webhook of {} returned {}", action, status);
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("notify webhook of {} failed: {}", action, err),
+            }
+            drop(permit);
+        }))
+    }
+}
+
+// notifies each buffered log once the write buffer has written it.
+pub fn on_written(notifier: Arc<Notifier>) -> db::OnWritten {
+    Arc::new(move |doc: &db::Log| {
+        notifier.notify(
+            &action::from_action(doc.action),
+            LogOutput::from(doc.clone(), &PackObject::Json(())),
+        );
+    })
+}
+
+async fn post(uri: Uri, headers: Vec<(&str, String)>, body: Vec<u8>) -> Result<u16, String> {
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json");
+    for (k, v) in headers {
+        req = req.header(k, v);
+    }
+    let req = req.body(Body::from(body)).map_err(|err| err.to_string())?;
+
+    let res = tokio::time::timeout(DELIVER_TIMEOUT, Client::new().request(req))
+        .await
+        .map_err(|_| "timeout".to_string())?
+        .map_err(|err| err.to_string())?;
+    Ok(res.status().as_u16())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing, Router};
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn new_works() {
+        let webhooks =
+            |action: &str, url: &str| HashMap::from([(action.to_string(), url.to_string())]);
+        assert!(Notifier::new(&HashMap::new(), 8).unwrap().is_empty());
+        assert!(
+            Notifier::new(&webhooks("group.delete", "http://127.0.0.1:8081/notify"), 8).is_ok()
+        );
+
+        let err = Notifier::new(&webhooks("group.fly", "http://127.0.0.1/notify"), 8)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("invalid notify action"));
+        for url in [
+            "127.0.0.1/notify",
+            "ftp://example.com/notify",
+            "https://example.com/notify",
+            "http://",
+        ] {
+            assert!(
+                Notifier::new(&webhooks("group.delete", url), 8).is_err(),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn notify_works() {
+        let (tx, mut rx) = mpsc::channel::<(String, serde_json::Value)>(2);
+        let app = Router::new().route(
+            "/notify",
+            routing::post(move |headers: axum::http::HeaderMap, body: bytes::Bytes| {
+                let tx = tx.clone();
+                async move {
+                    let action = headers
+                        .get("x-log-action")
+                        .map(|v| v.to_str().unwrap().to_string())
+                        .unwrap_or_default();
+                    tx.send((action, serde_json::from_slice(&body).unwrap()))
+                        .await
+                        .unwrap();
+                    "ok"
+                }
+            }),
+        );
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let mut webhooks = HashMap::new();
+        webhooks.insert(
+            "group.delete".to_string(),
+            format!("http://{}/notify", addr),
+        );
+        let notifier = Notifier::new(&webhooks, 1).unwrap();
+
+        let to = PackObject::Json(());
+        let mut doc = db::Log::with_pk(xid::new(), xid::new());
+        doc.action = 8; // user.login
+        assert!(notifier
+            .notify("user.login", LogOutput::from(doc.clone(), &to))
+            .is_none());
+
+        doc.action = 28; // group.delete
+        let delivery = notifier
+            .notify("group.delete", LogOutput::from(doc.clone(), &to))
+            .unwrap();
+        // one delivery at a time, the second is dropped
+        assert!(notifier
+            .notify("group.delete", LogOutput::from(doc.clone(), &to))
+            .is_none());
+        delivery.await.unwrap();
+        let (action, body) = rx.recv().await.unwrap();
+        assert_eq!(action, "group.delete");
+        assert_eq!(body["action"], "group.delete");
+        assert_eq!(body["id"], doc.id.to_string());
+        assert!(rx.try_recv().is_err());
+
+        // the permit is released once delivered
+        on_written(Arc::new(notifier))(&doc);
+        let (action, _) = rx.recv().await.unwrap();
+        assert_eq!(action, "group.delete");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn post_works() {
+        // nothing listens on the port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let uri = Uri::from_str(&format!("http://{}/notify", addr)).unwrap();
+        let err = post(uri, vec![], b"{}".to_vec()).await.unwrap_err();
+        assert!(!err.contains("replay"));
+    }
+}
@@ -0,0 +1,244 @@
+use axum_web::erring::HTTPError;
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::conf;
+
+// windows tracked at most. Past it the ended windows are dropped, then the
+// least recently counted ones, down to LOW_WATER_WINDOWS so the next eviction
+// is MAX_WINDOWS - LOW_WATER_WINDOWS new windows away.
+const MAX_WINDOWS: usize = 100_000;
+const LOW_WATER_WINDOWS: usize = MAX_WINDOWS * 3 / 4;
+
+type Windows = HashMap<([u8; 12], String), Window>; // (uid, action) -> window
+
+// counts logs per uid and action in fixed windows of this instance, a
+// quota is enforced per instance rather than across the cluster.
+#[derive(Default)]
+pub struct ActionQuotas {
+    quotas: HashMap<String, conf::Quota>,
+    windows: Mutex<Windows>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    window: u64,
+    count: u32,
+    last_ms: u64, // when the window last counted a log
+}
+
+impl ActionQuotas {
+    pub fn new(quotas: &HashMap<String, conf::Quota>) -> Self {
+        Self {
+            quotas: quotas.clone(),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // counts a log of the action by uid, fails with 429 if the action's
+    // quota of the current window is used up.
+    pub fn check(&self, uid: xid::Id, action: &str, now_ms: u64) -> Result<(), HTTPError> {
+        let quota = match self.quotas.get(action) {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+        let window_secs = quota.window_secs.max(1);
+        let window = now_ms / 1000 / window_secs;
+
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= MAX_WINDOWS {
+            evict(&mut windows, &self.quotas, now_ms, LOW_WATER_WINDOWS);
+        }
+
+        let entry = windows
+            .entry((uid.0, action.to_string()))
+            .or_insert(Window {
+                window,
+                count: 0,
+                last_ms: now_ms,
+            });
+        if entry.window != window {
+            entry.window = window;
+            entry.count = 0;
+        }
+        entry.last_ms = now_ms;
+        if entry.count >= quota.limit {
+            return Err(HTTPError::new(
+                429,
+                format!(
+                    "quota of {} exceeded, at most {} logs per {} seconds",
+                    action, quota.limit, window_secs
+                ),
+            ));
+        }
+        entry.count += 1;
+        Ok(())
+    }
+
+    // gives back a log counted by check at now_ms that wasn't stored, unless
+    // its window has ended since.
+    pub fn refund(&self, uid: xid::Id, action: &str, now_ms: u64) {
+        let quota = match self.quotas.get(action) {
+            Some(quota) => quota,
+            None => return,
+        };
+        let window = now_ms / 1000 / quota.window_secs.max(1);
+
+        let mut windows = self.windows.lock().unwrap();
+        if let Some(entry) = windows.get_mut(&(uid.0, action.to_string())) {
+            if entry.window == window && entry.count > 0 {
+                entry.count -= 1;
+            }
+        }
+    }
+}
+
+// drops the ended windows, then the least recently counted ones until at
+// most low_water are left. A dropped window that hadn't ended starts over
+// at its next log, the quota is lenient under memory pressure.
+fn evict(
+    windows: &mut Windows,
+    quotas: &HashMap<String, conf::Quota>,
+    now_ms: u64,
+    low_water: usize,
+) {
+    windows.retain(|(_, action), w| {
+        quotas
+            .get(action)
+            .map_or(false, |q| w.window == now_ms / 1000 / q.window_secs.max(1))
+    });
+    if windows.len() <= low_water {
+        return;
+    }
+
+    let mut last: Vec<u64> = windows.values().map(|w| w.last_ms).collect();
+    let n = last.len() - low_water;
+    let (_, cutoff, _) = last.select_nth_unstable(n - 1);
+    let cutoff = *cutoff;
+    // of the windows counted at the cutoff, as many as needed are dropped
+    let mut ties = n - last.iter().filter(|l| **l < cutoff).count();
+    windows.retain(|_, w| {
+        if w.last_ms < cutoff {
+            return false;
+        }
+        if w.last_ms == cutoff && ties > 0 {
+            ties -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_works() {
+        let quotas = ActionQuotas::new(&HashMap::from([(
+            "user.login".to_string(),
+            conf::Quota {
+                limit: 2,
+                window_secs: 60,
+            },
+        )]));
+        let uid = xid::new();
+        let now = 1690000000000u64;
+
+        assert!(quotas.check(uid, "user.login", now).is_ok());
+        assert!(quotas.check(uid, "user.login", now + 1000).is_ok());
+        let err = quotas.check(uid, "user.login", now + 2000).unwrap_err();
+        assert_eq!(err.code, 429);
+
+        // other actions and uids are unaffected
+        for _ in 0..10 {
+            assert!(quotas.check(uid, "creation.create", now).is_ok());
+        }
+        assert!(quotas.check(xid::new(), "user.login", now).is_ok());
+
+        // the next window starts over
+        assert!(quotas.check(uid, "user.login", now + 60000).is_ok());
+    }
+
+    #[test]
+    fn refund_works() {
+        let quotas = ActionQuotas::new(&HashMap::from([(
+            "user.login".to_string(),
+            conf::Quota {
+                limit: 1,
+                window_secs: 60,
+            },
+        )]));
+        let uid = xid::new();
+        let now = 1690000000000u64;
+
+        assert!(quotas.check(uid, "user.login", now).is_ok());
+        assert!(quotas.check(uid, "user.login", now).is_err());
+        quotas.refund(uid, "user.login", now);
+        assert!(quotas.check(uid, "user.login", now).is_ok());
+
+        // a refund of an ended window doesn't touch the next one
+        assert!(quotas.check(uid, "user.login", now + 60000).is_ok());
+        quotas.refund(uid, "user.login", now);
+        assert!(quotas.check(uid, "user.login", now + 60000).is_err());
+
+        // nor of an action without a quota or an unseen uid
+        quotas.refund(uid, "creation.create", now);
+        quotas.refund(xid::new(), "user.login", now);
+    }
+
+    #[test]
+    fn evict_works() {
+        let quotas = HashMap::from([(
+            "user.login".to_string(),
+            conf::Quota {
+                limit: 2,
+                window_secs: 60,
+            },
+        )]);
+        let now = 1690000000000u64;
+        let window = now / 1000 / 60;
+        let key = |i: u8| ([i; 12], "user.login".to_string());
+        let mut windows: Windows = HashMap::new();
+        for i in 0..10u8 {
+            windows.insert(
+                key(i),
+                Window {
+                    window,
+                    count: 1,
+                    last_ms: now + i as u64 / 2 * 10,
+                },
+            );
+        }
+        // ended windows and windows of actions without a quota
+        windows.insert(
+            key(100),
+            Window {
+                window: window - 1,
+                count: 1,
+                last_ms: now + 1000,
+            },
+        );
+        windows.insert(
+            ([101; 12], "user.logout".to_string()),
+            Window {
+                window,
+                count: 1,
+                last_ms: now + 1000,
+            },
+        );
+
+        evict(&mut windows, &quotas, now, 20);
+        assert_eq!(windows.len(), 10);
+
+        // the least recently counted go first, ties included
+        evict(&mut windows, &quotas, now, 5);
+        assert_eq!(windows.len(), 5);
+        for i in 6..10u8 {
+            assert!(windows.contains_key(&key(i)), "{}", i);
+        }
+        assert_eq!(
+            (4..6u8).filter(|i| windows.contains_key(&key(*i))).count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use axum::{
+    extract::State,
+    http::{header, Method, Request, Uri},
+    Extension,
+};
+use hyper::{Body, Client};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, sync::Arc, time::Duration};
+use validator::Validate;
+
+use axum_web::context::ReqContext;
+use axum_web::erring::{HTTPError, SuccessResponse};
+use axum_web::object::PackObject;
+
+use crate::api::{action, AppState};
+use crate::conf;
+use crate::db;
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ReplayInput {
+    pub uid: PackObject<xid::Id>,
+    pub id: PackObject<xid::Id>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReplayOutput {
+    pub status: u16, // status code returned by the webhook
+    pub size: usize,
+}
+
+// POSTs the payload of a log to the configured webhook.
+pub async fn replay(
+    State(app): State<Arc<AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<ReplayInput>,
+) -> Result<PackObject<SuccessResponse<ReplayOutput>>, HTTPError> {
+    let (to, input) = to.unpack();
+    input.validate()?;
+
+    let uri = webhook_uri(&app.cfg.replay)?;
+    let scylla = app.scylla_for(&ctx)?;
+    ctx.set_kvs(vec![("action", "replay_log".into())]).await;
+
+    let mut doc = db::Log::with_pk(input.uid.unwrap(), input.id.unwrap());
+    doc.get_one(&scylla, vec!["payload".to_string()]).await?;
+
+    let size = doc.payload.len();
+    let res = deliver(
+        uri,
+        vec![
+            ("x-log-uid", doc.uid.to_string()),
+            ("x-log-id", doc.id.to_string()),
+            ("x-log-action", action::from_action(doc.action)),
+        ],
+        "application/octet-stream",
+        doc.payload,
+    )
+    .await;
+    match res {
+        Ok(status) => {
+            ctx.set_kvs(vec![("webhook_status", status.into())]).await;
+            Ok(to.with(SuccessResponse::new(ReplayOutput { status, size })))
+        }
+        Err(err) => {
+            ctx.set_kvs(vec![("webhook_error", err.message.clone().into())])
+                .await;
+            Err(err)
+        }
+    }
+}
+
+// the webhook URL must be set and its host allowed.
+fn webhook_uri(cfg: &conf::Replay) -> Result<Uri, HTTPError> {
+    if cfg.webhook_url.is_empty() {
+        return Err(HTTPError::new(
+            400,
+            "replay webhook is not configured".to_string(),
+        ));
+    }
+
+    let uri = Uri::from_str(&cfg.webhook_url)
+        .map_err(|err| HTTPError::new(500, format!("invalid replay webhook, {}", err)))?;
+    let host = uri.host().unwrap_or_default();
+    if !cfg.allowed_hosts.iter().any(|h| h == host) {
+        return Err(HTTPError::new(
+            403,
+            format!("replay webhook host {} is not allowed", host),
+        ));
+    }
+    Ok(uri)
+}
+
+pub(crate) async fn deliver(
+    uri: Uri,
+    headers: Vec<(&str, String)>,
+    content_type: &str,
+    payload: Vec<u8>,
+) -> Result<u16, HTTPError> {
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, content_type);
+    for (k, v) in headers {
+        req = req.header(k, v);
+    }
+    let req = req
+        .body(Body::from(payload))
+        .map_err(|err| HTTPError::new(500, format!("{:?}", err)))?;
+
+    let res = tokio::time::timeout(Duration::from_secs(10), Client::new().request(req))
+        .await
+        .map_err(|_| HTTPError::new(504, "replay webhook timeout".to_string()))?
+        .map_err(|err| HTTPError::new(502, format!("replay webhook failed, {}", err)))?;
+    Ok(res.status().as_u16())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing, Router};
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn webhook_uri_works() {
+        let mut cfg = conf::Replay::default();
+        assert_eq!(webhook_uri(&cfg).unwrap_err().code, 400);
+
+        cfg.webhook_url = "http://hooks.example.com/replay".to_string();
+        assert_eq!(webhook_uri(&cfg).unwrap_err().code, 403);
+
+        cfg.allowed_hosts = vec!["hooks.example.com".to_string()];
+        assert_eq!(webhook_uri(&cfg).unwrap().path(), "/replay");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn deliver_works() {
+        let (tx, mut rx) = mpsc::channel::<(String, Vec<u8>)>(1);
+        let app = Router::new().route(
+            "/replay",
+            routing::post(move |headers: axum::http::HeaderMap, body: bytes::Bytes| {
+                let tx = tx.clone();
+                async move {
+                    let action = headers
+                        .get("x-log-action")
+                        .map(|v| v.to_str().unwrap().to_string())
+                        .unwrap_or_default();
+                    tx.send((action, body.to_vec())).await.unwrap();
+                    "ok"
+                }
+            }),
+        );
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let uri = Uri::from_str(&format!("http://{}/replay", addr)).unwrap();
+        let status = deliver(
+            uri,
+            vec![("x-log-action", "user.login".to_string())],
+            "application/octet-stream",
+            vec![0x80, 0x81],
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, 200);
+
+        let (action, payload) = rx.recv().await.unwrap();
+        assert_eq!(action, "user.login");
+        assert_eq!(payload, vec![0x80, 0x81]);
+    }
+}
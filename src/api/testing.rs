@@ -0,0 +1,106 @@
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    Router,
+};
+use scylla_orm::ColumnsMap;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tower::ServiceExt;
+
+use crate::api::{admin, metrics, notify, quota, AppState};
+use crate::conf;
+use crate::db::{self, scylladb::ScyllaDB};
+use crate::router;
+
+// the app over a MemoryStore, handler tests drive it through the routes and
+// middlewares as a server would.
+pub struct TestApp {
+    pub state: Arc<AppState>,
+    pub store: Arc<db::MemoryStore>,
+    pub router: Router,
+}
+
+impl TestApp {
+    pub fn new(cfg: conf::Conf) -> Self {
+        let store = Arc::new(db::MemoryStore::default());
+        let scylla = Arc::new(ScyllaDB::disconnected(cfg.scylla.clone(), "logbase_test"));
+        let stores: HashMap<String, Arc<dyn db::LogStore>> =
+            HashMap::from([("".to_string(), store.clone() as Arc<dyn db::LogStore>)]);
+        let state = Arc::new(AppState {
+            scylla,
+            tenants: HashMap::new(),
+            stores,
+            buffers: HashMap::new(),
+            latency: Arc::new(metrics::RouteLatency::default()),
+            in_flight: Arc::new(metrics::InFlight::default()),
+            payload_sizes: Arc::new(metrics::PayloadSizes::default()),
+            scylla_window: Arc::new(metrics::ScyllaWindow::new(Duration::from_secs(
+                cfg.health.error_window_secs,
+            ))),
+            quotas: Arc::new(quota::ActionQuotas::new(&cfg.quotas)),
+            read_only: Arc::new(admin::ReadOnly::new(cfg.read_only)),
+            notifier: Arc::new(
+                notify::Notifier::new(&cfg.notify, cfg.notify_max_in_flight).unwrap(),
+            ),
+            cfg: Arc::new(cfg),
+        });
+        let router = router::routes(state.clone()).unwrap();
+        Self {
+            state,
+            store,
+            router,
+        }
+    }
+
+    // sends a JSON request, returns the status and the JSON body, Null if
+    // the body is empty.
+    pub async fn call(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let body = match body {
+            Some(body) => Body::from(serde_json::to_vec(&body).unwrap()),
+            None => Body::empty(),
+        };
+        let res = self
+            .router
+            .clone()
+            .oneshot(req.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        if body.is_empty() {
+            return (status, serde_json::Value::Null);
+        }
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+}
+
+// the config of config/default.toml, tests adjust it before TestApp::new.
+pub fn test_conf() -> conf::Conf {
+    conf::Conf::new().unwrap_or_else(|err| panic!("config error: {}", err))
+}
+
+// writes a new log of uid with the action and the columns to the store.
+pub async fn put_log(
+    store: &dyn db::LogStore,
+    uid: xid::Id,
+    action: i8,
+    mut cols: ColumnsMap,
+) -> db::Log {
+    let mut doc = db::Log::with_pk(uid, xid::new());
+    cols.set_as("action", &action);
+    store.upsert_fields(&mut doc, cols, &[]).await.unwrap();
+    doc
+}
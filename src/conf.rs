@@ -1,5 +1,6 @@
 use config::{Config, ConfigError, File, FileFormat};
 use serde::Deserialize;
+use std::{collections::HashMap, time::Duration};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Log {
@@ -19,14 +20,350 @@ pub struct ScyllaDB {
     pub nodes: Vec<String>,
     pub username: String,
     pub password: String,
+    #[serde(default = "default_health_check_interval")]
+    pub health_check_interval: u64,
+    #[serde(default = "default_health_check_failures")]
+    pub health_check_failures: u32,
+    #[serde(default)]
+    pub pool_size: usize, // connections per shard, or per host if pool_per_host, 0 for the driver default
+    #[serde(default)]
+    pub pool_per_host: bool,
+    #[serde(default)]
+    pub keepalive_interval: u64, // seconds, 0 for the driver default
+    #[serde(default)]
+    pub bootstrap: bool, // creates the log table on startup if missing
+    #[serde(default = "default_breaker_threshold")]
+    pub breaker_threshold: u32, // consecutive failures that open the circuit breaker, 0 to disable
+    #[serde(default = "default_breaker_cooldown")]
+    pub breaker_cooldown: u64, // seconds
+}
+
+impl ScyllaDB {
+    // a 0 interval would probe in a busy loop, it is at least 1 second.
+    pub fn health_check_interval(&self) -> Duration {
+        Duration::from_secs(self.health_check_interval.max(1))
+    }
+}
+
+fn default_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_breaker_cooldown() -> u64 {
+    10
+}
+
+fn default_admin_roles() -> Vec<String> {
+    vec!["admin".to_string()]
+}
+
+fn default_notify_max_in_flight() -> usize {
+    64
+}
+
+fn default_health_check_interval() -> u64 {
+    10
+}
+
+fn default_health_check_failures() -> u32 {
+    3
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Warning {
+    pub enabled: bool,
+    pub max_tokens: i32,
+}
+
+impl Default for Warning {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_tokens: 100000,
+        }
+    }
+}
+
+// thresholds of the healthz status, a value at or above a threshold makes
+// the instance degraded or unhealthy.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Health {
+    pub degraded_error_ratio: f64, // Scylla errors per query in the recent window
+    pub unhealthy_error_ratio: f64,
+    pub degraded_p99_ms: u64, // Scylla p99 latency
+    pub unhealthy_p99_ms: u64,
+    pub error_window_secs: u64, // the error ratio covers one to two windows
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            degraded_error_ratio: 0.01,
+            unhealthy_error_ratio: 0.1,
+            degraded_p99_ms: 500,
+            unhealthy_p99_ms: 2000,
+            error_window_secs: 60,
+        }
+    }
+}
+
+pub const DEFAULT_PAGE_SIZE: u16 = 10;
+pub const MAX_PAGE_SIZE: u16 = 1000;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Pagination {
+    pub default_page_size: u16,
+    pub max_page_size: u16,
+    pub max_fields: usize, // max fields of a projection, 0 for unlimited
+    pub sync_lag_ms: u64,  // writes this recent are left to the next sync page
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            default_page_size: DEFAULT_PAGE_SIZE,
+            max_page_size: MAX_PAGE_SIZE,
+            max_fields: 10,
+            sync_lag_ms: 2000,
+        }
+    }
+}
+
+impl Pagination {
+    // clamps a user supplied page size into [1, max_page_size].
+    pub fn page_size(&self, page_size: Option<u16>) -> u16 {
+        page_size
+            .unwrap_or(self.default_page_size)
+            .clamp(1, self.max_page_size.max(1))
+    }
+
+    // page size of the scans that read many pages, such as counts and purges.
+    pub fn scan_page_size(&self) -> u16 {
+        self.max_page_size.max(1)
+    }
+
+    // the most logs a scan-style list endpoint reads, ten full pages.
+    pub fn max_scan(&self) -> usize {
+        10 * self.scan_page_size() as usize
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Compression {
+    // enabled response encodings, in order of preference.
+    pub preferred: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            preferred: vec!["zstd".to_string(), "br".to_string(), "gzip".to_string()],
+        }
+    }
+}
+
+impl Compression {
+    pub fn enabled(&self, encoding: &str) -> bool {
+        self.preferred.iter().any(|v| v == encoding)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(default)]
+pub struct ApiKey {
+    pub key: String,
+    pub name: String,   // the caller, logged as its app
+    pub tenant: String, // pins the X-Tenant header, removes it if empty
+    pub role: String,   // pins the X-Auth-Role header, removes it if empty
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Cors {
+    // origins allowed to call the API cross-origin, "*" for any. Empty
+    // keeps the API same-origin only.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age_secs: u64, // how long browsers may cache a preflight
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            allowed_headers: [
+                "accept",
+                "authorization",
+                "content-type",
+                "x-request-id",
+                "x-request-deadline",
+                "x-tenant",
+                "x-auth-role",
+            ]
+            .iter()
+            .map(|h| h.to_string())
+            .collect(),
+            max_age_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Payload {
+    // policy for gzip payloads: "keep" stores them as-is,
+    // "zstd" decompresses and recompresses them with zstd.
+    pub gzip: String,
+    // share of bytes the zstd form must save over the gzip form to be
+    // stored, the gzip form is kept otherwise.
+    pub zstd_min_saving: f64,
+    pub check_type: bool, // parses payloads as their declared payload_type on create
+    pub max_gunzip_size: usize, // bytes a gzip payload may decompress to
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self {
+            gzip: "keep".to_string(),
+            zstd_min_saving: 0.1,
+            check_type: false,
+            max_gunzip_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(default)]
+pub struct Replay {
+    pub webhook_url: String,
+    pub allowed_hosts: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Otel {
+    pub endpoint: String, // OTLP gRPC endpoint, spans are not exported if empty
+    pub service_name: String,
+}
+
+impl Default for Otel {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            service_name: "logbase".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Quota {
+    pub limit: u32, // logs per uid per window
+    pub window_secs: u64,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            limit: 0,
+            window_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WriteBuffer {
+    pub enabled: bool,
+    pub flush_interval_ms: u64,
+    pub max_entries: usize, // logs per batch
+    pub capacity: usize,    // creates wait when this many logs are buffered
+    pub max_batch_bytes: usize,
+    pub max_retries: u32,
+    pub retry_backoff_ms: u64,
+}
+
+impl Default for WriteBuffer {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            flush_interval_ms: 100,
+            max_entries: 100,
+            capacity: 10000,
+            max_batch_bytes: 65536,
+            max_retries: 5,
+            retry_backoff_ms: 100,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Conf {
     pub env: String,
+    #[serde(default)]
+    pub read_only: bool, // starts rejecting writes with 503, switchable via /v1/admin/read_only
     pub log: Log,
     pub server: Server,
     pub scylla: ScyllaDB,
+    #[serde(default)]
+    pub scylla_read: Option<ScyllaDB>, // reads use the scylla session if unset
+    #[serde(default)]
+    pub warning: Warning,
+    #[serde(default)]
+    pub health: Health,
+    #[serde(default)]
+    pub tenants: HashMap<String, String>, // tenant name -> keyspace
+    #[serde(default)]
+    pub pagination: Pagination,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub cors: Cors,
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>, // requests need one of the keys if any is set
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>, // CIDRs allowed to set X-Forwarded-For
+    #[serde(default)]
+    pub keep_ipv6_zone: bool, // stores "fe80::1%eth0" as-is rather than "fe80::1"
+    #[serde(default)]
+    pub replay: Replay,
+    #[serde(default)]
+    pub payload: Payload,
+    #[serde(default)]
+    pub token_caps: HashMap<String, i32>, // action name -> max tokens
+    #[serde(default)]
+    pub quotas: HashMap<String, Quota>, // action name -> logs allowed per uid per window
+    #[serde(default)]
+    pub sampling: HashMap<String, f64>, // action name -> share of creates stored, 0 to 1
+    #[serde(default)]
+    pub notify: HashMap<String, String>, // action name -> webhook URL notified on create
+    #[serde(default = "default_notify_max_in_flight")]
+    pub notify_max_in_flight: usize, // notifications delivered at once, more are dropped
+    #[serde(default)]
+    pub anonymous_actions: Vec<String>, // actions allowed to log under the anonymous uid
+    #[serde(default)]
+    pub required_context: HashMap<String, Vec<String>>, // action name -> context keys a create must carry
+    #[serde(default)]
+    pub redaction: HashMap<String, Vec<String>>, // caller role -> fields never returned
+    #[serde(default = "default_admin_roles")]
+    pub admin_roles: Vec<String>, // caller roles allowed to call the admin APIs
+    #[serde(default)]
+    pub mutable_after_freeze: Vec<String>, // fields the update API can still write on a frozen log
+    #[serde(default)]
+    pub freeze_bypass_actions: Vec<String>, // actions whose frozen logs an override_freeze update can write
+    #[serde(default)]
+    pub write_buffer: WriteBuffer,
+    #[serde(default)]
+    pub otel: Otel,
 }
 
 impl Conf {
@@ -41,3 +378,46 @@ impl Conf {
         builder.build()?.try_deserialize::<Conf>()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_check_interval_works() {
+        let mut cfg = ScyllaDB {
+            nodes: vec![],
+            username: "".to_string(),
+            password: "".to_string(),
+            health_check_interval: default_health_check_interval(),
+            health_check_failures: default_health_check_failures(),
+            pool_size: 0,
+            pool_per_host: false,
+            keepalive_interval: 0,
+            bootstrap: false,
+            breaker_threshold: default_breaker_threshold(),
+            breaker_cooldown: default_breaker_cooldown(),
+        };
+        assert_eq!(cfg.health_check_interval(), Duration::from_secs(10));
+        cfg.health_check_interval = 0;
+        assert_eq!(cfg.health_check_interval(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn page_size_works() {
+        let cfg = Pagination::default();
+        assert_eq!(cfg.page_size(None), DEFAULT_PAGE_SIZE);
+        assert_eq!(cfg.page_size(Some(0)), 1);
+        assert_eq!(cfg.page_size(Some(100)), 100);
+        assert_eq!(cfg.page_size(Some(MAX_PAGE_SIZE + 1)), MAX_PAGE_SIZE);
+        assert_eq!(cfg.scan_page_size(), MAX_PAGE_SIZE);
+        assert_eq!(cfg.max_scan(), 10 * MAX_PAGE_SIZE as usize);
+
+        let cfg = Pagination {
+            max_page_size: 0,
+            ..Pagination::default()
+        };
+        assert_eq!(cfg.scan_page_size(), 1);
+        assert_eq!(cfg.max_scan(), 10);
+    }
+}
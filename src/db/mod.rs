@@ -1,7 +1,56 @@
 mod model_log;
+mod store;
+mod write_buffer;
 
 pub mod scylladb;
 
-pub use model_log::Log;
+pub use model_log::{Log, LogError, Status, StatusCount, StatusEntry, SyncPage};
+pub use store::LogStore;
+#[cfg(test)]
+pub use store::MemoryStore;
+pub use write_buffer::{OnWritten, WriteBuffer};
 
 pub static MAX_ID: xid::Id = xid::Id([255; 12]);
+
+// the uid of events without a known user, only anonymous_actions may use it.
+pub static ANONYMOUS_UID: xid::Id = xid::Id([0; 12]);
+
+const SCHEMA_TABLE: &str = include_str!("../../cql/schema_table.cql");
+
+// creates the log tables and their indexes in the keyspace if they don't exist,
+// and adds the columns missing from tables created by earlier versions.
+pub async fn bootstrap(db: &scylladb::ScyllaDB, keyspace: &str) -> anyhow::Result<()> {
+    if keyspace.is_empty()
+        || !keyspace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        anyhow::bail!("invalid keyspace {:?}", keyspace);
+    }
+
+    let schema = SCHEMA_TABLE
+        .replace(
+            "TABLE IF NOT EXISTS ",
+            &format!("TABLE IF NOT EXISTS {}.", keyspace),
+        )
+        .replace(
+            "ALTER TABLE log ",
+            &format!("ALTER TABLE {}.log ", keyspace),
+        )
+        .replace(" ON log (", &format!(" ON {}.log (", keyspace));
+    scylladb::exec_cqls(db, &schema).await
+}
+
+// the smallest xid generated at the given unix timestamp (seconds).
+pub fn xid_from_unix(unix_ts: u32) -> xid::Id {
+    let mut id = xid::Id::default();
+    id.0[0..=3].copy_from_slice(&unix_ts.to_be_bytes());
+    id
+}
+
+// the unix timestamp (seconds) embedded in the xid.
+pub fn xid_unix(id: &xid::Id) -> u32 {
+    let mut ts = [0u8; 4];
+    ts.copy_from_slice(&id.0[0..=3]);
+    u32::from_be_bytes(ts)
+}
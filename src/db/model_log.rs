@@ -1,20 +1,105 @@
 use axum_web::{context::unix_ms, erring::HTTPError};
-use scylla_orm::{ColumnsMap, CqlValue, ToCqlVal};
+use scylla::{query::Query, transport::query_result::SingleRowError};
+use scylla_orm::{ColumnsMap, CqlValue, FromCqlVal, FromCqlValError, ToCqlVal};
 use scylla_orm_macros::CqlOrm;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::{Duration, Instant},
+};
 
-use crate::db::{scylladb, MAX_ID};
+use crate::db::{scylladb, xid_from_unix, xid_unix, MAX_ID};
+use crate::otel;
+
+const INSERT_QUERY: &str = "INSERT INTO log (uid,id,action,status,gid,ip,payload,payload_type,tokens,error,trace_id,updated_at,labels) VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?)";
+
+// failure modes of log model operations.
+#[derive(Debug)]
+pub enum LogError {
+    NotFound,
+    Frozen,
+    InvalidField(String),
+    InvalidInput(String),
+    Conflict(String),
+    Unavailable,
+    TooManyTombstones(String),
+    DeadlineExceeded,
+    Db(anyhow::Error),
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogError::NotFound => write!(f, "data not found"),
+            LogError::Frozen => write!(f, "log is frozen"),
+            LogError::InvalidField(field) => write!(f, "Invalid field: {}", field),
+            LogError::InvalidInput(msg) => write!(f, "{}", msg),
+            LogError::Conflict(msg) => write!(f, "{}", msg),
+            LogError::Unavailable => write!(f, "{}", scylladb::CircuitOpen),
+            LogError::TooManyTombstones(msg) => {
+                write!(f, "{}", scylladb::TooManyTombstones(msg.to_owned()))
+            }
+            LogError::DeadlineExceeded => write!(f, "request deadline exceeded"),
+            LogError::Db(err) => write!(f, "{:?}", err),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<anyhow::Error> for LogError {
+    fn from(err: anyhow::Error) -> Self {
+        if err.is::<scylladb::CircuitOpen>() {
+            return LogError::Unavailable;
+        }
+        let err = match err.downcast::<scylladb::TooManyTombstones>() {
+            Ok(err) => return LogError::TooManyTombstones(err.0),
+            Err(err) => err,
+        };
+        match err.downcast::<SingleRowError>() {
+            Ok(_) => LogError::NotFound,
+            Err(err) => LogError::Db(err),
+        }
+    }
+}
+
+impl From<SingleRowError> for LogError {
+    fn from(_: SingleRowError) -> Self {
+        LogError::NotFound
+    }
+}
+
+impl From<LogError> for HTTPError {
+    fn from(err: LogError) -> Self {
+        let code = match err {
+            LogError::NotFound => 404,
+            LogError::Frozen | LogError::InvalidField(_) | LogError::InvalidInput(_) => 400,
+            LogError::Conflict(_) => 409,
+            LogError::Unavailable => 503,
+            LogError::DeadlineExceeded => 504,
+            LogError::TooManyTombstones(_) | LogError::Db(_) => 500,
+        };
+        HTTPError::new(code, err.to_string())
+    }
+}
 
 #[derive(Debug, Default, Clone, CqlOrm)]
 pub struct Log {
     pub uid: xid::Id,
     pub id: xid::Id,
     pub action: i8,
-    pub status: i8,
+    pub status: Status,
     pub gid: xid::Id,
     pub ip: String,
     pub payload: Vec<u8>,
+    pub payload_type: String,
     pub tokens: i32,
     pub error: String,
+    pub trace_id: String,
+    pub updated_at: i64,
+    pub labels: HashMap<String, String>,
+    pub overridden: bool,
 
     pub _fields: Vec<String>, // selected fields，`_` 前缀字段会被 CqlOrm 忽略
 }
@@ -28,7 +113,39 @@ impl Log {
         }
     }
 
-    pub fn select_fields(select_fields: Vec<String>, with_pk: bool) -> anyhow::Result<Vec<String>> {
+    // named field sets accepted in place of field names, "full" selects all
+    // fields.
+    pub const COMPACT_FIELDS: [&'static str; 3] = ["action", "status", "tokens"];
+
+    // expands the "compact" and "full" presets in fields, without repeats.
+    // "full" is every field, that is an empty list.
+    pub fn expand_presets(fields: Vec<String>) -> Vec<String> {
+        let mut res: Vec<String> = Vec::with_capacity(fields.len());
+        for name in fields {
+            match name.as_str() {
+                "full" => return vec![],
+                "compact" => {
+                    for f in Self::COMPACT_FIELDS {
+                        if !res.iter().any(|r| r == f) {
+                            res.push(f.to_string());
+                        }
+                    }
+                }
+                _ => {
+                    if !res.contains(&name) {
+                        res.push(name);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    pub fn select_fields(
+        select_fields: Vec<String>,
+        with_pk: bool,
+    ) -> Result<Vec<String>, LogError> {
+        let select_fields = Self::expand_presets(select_fields);
         if select_fields.is_empty() {
             return Ok(Self::fields());
         }
@@ -36,7 +153,7 @@ impl Log {
         let fields = Self::fields();
         for field in &select_fields {
             if !fields.contains(field) {
-                return Err(HTTPError::new(400, format!("Invalid field: {}", field)).into());
+                return Err(LogError::InvalidField(field.to_owned()));
             }
         }
 
@@ -63,11 +180,104 @@ impl Log {
         Ok(select_fields)
     }
 
+    // the fields upsert_fields can write.
+    pub const UPSERT_FIELDS: [&'static str; 10] = [
+        "status",
+        "gid",
+        "action",
+        "ip",
+        "payload",
+        "payload_type",
+        "tokens",
+        "error",
+        "trace_id",
+        "labels",
+    ];
+
+    // the action is only written on create, an audit log can't be relabeled.
+    pub fn check_action_unchanged(action: i8, cols: &ColumnsMap) -> Result<(), LogError> {
+        match cols.get("action") {
+            Some(v) if *v != action.to_cql() => Err(LogError::InvalidInput(
+                "action can't be changed after create".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // a log is frozen once its status is not 0, then only the fields in
+    // mutable_after_freeze can be written. Restating the current status is
+    // not a change.
+    pub fn check_frozen(
+        status: Status,
+        cols: &ColumnsMap,
+        mutable_after_freeze: &[String],
+    ) -> Result<(), LogError> {
+        if status == Status::Open {
+            return Ok(());
+        }
+
+        let current = status.to_cql();
+        let allowed = cols
+            .iter()
+            .all(|(k, v)| mutable_after_freeze.contains(k) || (k == "status" && *v == current));
+        if !allowed || cols.is_empty() {
+            return Err(LogError::Frozen);
+        }
+        Ok(())
+    }
+
+    // writes to a frozen log append to its error, joined by a newline, so the
+    // recorded failure is kept. Clearing error or payload of a frozen log is
+    // rejected. Returns whether the error was appended to.
+    pub fn append_frozen(
+        status: Status,
+        error: &str,
+        cols: &mut ColumnsMap,
+    ) -> Result<bool, LogError> {
+        if status == Status::Open {
+            return Ok(false);
+        }
+
+        if cols.has("payload")
+            && cols
+                .get_as::<Vec<u8>>("payload")
+                .unwrap_or_default()
+                .is_empty()
+        {
+            return Err(LogError::InvalidInput(
+                "payload of a frozen log can't be cleared".to_string(),
+            ));
+        }
+        if !cols.has("error") {
+            return Ok(false);
+        }
+        let appended: String = cols.get_as("error").unwrap_or_default();
+        if appended.is_empty() {
+            return Err(LogError::InvalidInput(
+                "error of a frozen log can't be cleared".to_string(),
+            ));
+        }
+        if !error.is_empty() {
+            cols.set_as("error", &format!("{}\n{}", error, appended));
+        }
+        Ok(true)
+    }
+
     pub async fn get_one(
         &mut self,
         db: &scylladb::ScyllaDB,
         select_fields: Vec<String>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), LogError> {
+        otel::DbSpan::start("log.get_one", Some(&self.uid), QUERY_TIMEOUT_MS)
+            .run(async { self.fetch(db.read(), select_fields).await })
+            .await
+    }
+
+    async fn fetch(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        select_fields: Vec<String>,
+    ) -> Result<(), LogError> {
         let fields = Self::select_fields(select_fields, false)?;
         self._fields = fields.clone();
 
@@ -85,225 +295,3429 @@ impl Log {
         Ok(())
     }
 
+    // the handlers write through LogStore, with their mutable_after_freeze.
+    #[cfg(test)]
     pub async fn upsert_fields(
         &mut self,
         db: &scylladb::ScyllaDB,
         cols: ColumnsMap,
-    ) -> anyhow::Result<bool> {
-        let valid_fields = vec![
-            "status", "gid", "action", "ip", "payload", "tokens", "error",
-        ];
+    ) -> Result<bool, LogError> {
+        self.upsert_fields_with(db, cols, &[]).await
+    }
+
+    // like upsert_fields, but frozen logs still accept writes to the fields
+    // in mutable_after_freeze, see append_frozen.
+    pub async fn upsert_fields_with(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        mut cols: ColumnsMap,
+        mutable_after_freeze: &[String],
+    ) -> Result<bool, LogError> {
+        otel::DbSpan::start("log.upsert_fields", Some(&self.uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                let res = self
+                    .fetch(
+                        db.read_latest(),
+                        vec!["status".to_string(), "error".to_string()],
+                    )
+                    .await;
+                let mut if_error = None;
+                if res.is_ok() {
+                    Self::check_action_unchanged(self.action, &cols)?;
+                    Self::check_frozen(self.status, &cols, mutable_after_freeze)?;
+                    if Self::append_frozen(self.status, &self.error, &mut cols)? {
+                        if_error = Some(std::mem::replace(
+                            &mut self.error,
+                            cols.get_as("error").unwrap_or_default(),
+                        ));
+                    }
+                }
+                self.write_fields(db, cols, false, if_error).await
+            })
+            .await
+    }
+
+    // like upsert_fields_with, but writes a frozen log of one of
+    // bypass_actions anyway and marks it overridden.
+    pub async fn upsert_fields_overriding(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        mut cols: ColumnsMap,
+        mutable_after_freeze: &[String],
+        bypass_actions: &[i8],
+    ) -> Result<bool, LogError> {
+        otel::DbSpan::start("log.upsert_fields", Some(&self.uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                self.fetch(
+                    db.read_latest(),
+                    vec!["status".to_string(), "error".to_string()],
+                )
+                .await?;
+                Self::check_action_unchanged(self.action, &cols)?;
+                let overridden = Self::check_override(
+                    self.action,
+                    self.status,
+                    &cols,
+                    mutable_after_freeze,
+                    bypass_actions,
+                )?;
+                // an override corrects the log, other writes to a frozen log append.
+                let mut if_error = None;
+                if !overridden && Self::append_frozen(self.status, &self.error, &mut cols)? {
+                    if_error = Some(std::mem::replace(
+                        &mut self.error,
+                        cols.get_as("error").unwrap_or_default(),
+                    ));
+                }
+                self.write_fields(db, cols, overridden, if_error).await
+            })
+            .await
+    }
+
+    // the fields an override can correct on a frozen log.
+    pub const CORRECTION_FIELDS: [&'static str; 1] = ["error"];
 
-        let res = self.get_one(db, vec!["status".to_string()]).await;
-        if res.is_ok() && self.status != 0 {
-            return Err(HTTPError::new(400, "log is frozen".to_string()).into());
+    // returns whether the write overrides the freeze, only a frozen log of
+    // one of bypass_actions can be overridden, and only its CORRECTION_FIELDS.
+    // Restating the current status is not a change.
+    pub fn check_override(
+        action: i8,
+        status: Status,
+        cols: &ColumnsMap,
+        mutable_after_freeze: &[String],
+        bypass_actions: &[i8],
+    ) -> Result<bool, LogError> {
+        if Self::check_frozen(status, cols, mutable_after_freeze).is_ok() {
+            return Ok(false);
+        }
+        if !bypass_actions.contains(&action) {
+            return Err(LogError::Frozen);
+        }
+        let current = status.to_cql();
+        if let Some((k, _)) = cols.iter().find(|(k, v)| {
+            let restated = *k == "status" && **v == current;
+            !(Self::CORRECTION_FIELDS.contains(&k.as_str()) || restated)
+        }) {
+            return Err(LogError::InvalidInput(format!(
+                "{} of a frozen log can't be overridden, expected one of {}",
+                k,
+                Self::CORRECTION_FIELDS.join(", ")
+            )));
         }
+        Ok(true)
+    }
 
-        let mut set_fields: Vec<String> = Vec::with_capacity(cols.len());
+    // if_error makes the write conditional on the error read before an
+    // append, so concurrent appends don't lose one another.
+    async fn write_fields(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        cols: ColumnsMap,
+        overridden: bool,
+        if_error: Option<String>,
+    ) -> Result<bool, LogError> {
+        let mut set_fields: Vec<String> = Vec::with_capacity(cols.len() + 1);
         let mut params: Vec<CqlValue> = Vec::with_capacity(cols.len() + 4);
         for (k, v) in cols.iter() {
-            if !valid_fields.contains(&k.as_str()) {
-                return Err(HTTPError::new(400, format!("Invalid field: {}", k)).into());
+            if !Self::UPSERT_FIELDS.contains(&k.as_str()) {
+                return Err(LogError::InvalidField(k.to_owned()));
             }
             set_fields.push(format!("{}=?", k));
             params.push(v.to_owned());
         }
+        if overridden {
+            self.overridden = true;
+            set_fields.push("overridden=?".to_string());
+            params.push(true.to_cql());
+        }
+        self.updated_at = unix_ms() as i64;
+        set_fields.push("updated_at=?".to_string());
+        params.push(self.updated_at.to_cql());
 
-        let query = format!(
+        let mut query = format!(
             "UPDATE log SET {} WHERE uid=? AND id=?",
             set_fields.join(",")
         );
         params.push(self.uid.to_cql());
         params.push(self.id.to_cql());
+        if let Some(ref error) = if_error {
+            query.push_str(" IF error=?");
+            params.push(error.to_cql());
+        }
 
-        let _ = db.execute(query, params).await?;
+        let res = db.execute(query, params).await?;
+        if if_error.is_some() && !scylladb::extract_applied(res) {
+            return Err(LogError::Conflict(format!(
+                "log {} was updated concurrently, retry the update",
+                self.id
+            )));
+        }
         Ok(true)
     }
 
-    pub async fn list(
+    // inserts a new log with a LWT, returns false without writing if the id is taken.
+    pub async fn insert_if_absent(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        cols: &ColumnsMap,
+    ) -> Result<bool, LogError> {
+        otel::DbSpan::start("log.insert_if_absent", Some(&self.uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                let mut fields: Vec<String> = Vec::with_capacity(cols.len() + 3);
+                let mut params: Vec<CqlValue> = Vec::with_capacity(cols.len() + 3);
+                fields.push("uid".to_string());
+                params.push(self.uid.to_cql());
+                fields.push("id".to_string());
+                params.push(self.id.to_cql());
+                for (k, v) in cols.iter() {
+                    if !Self::UPSERT_FIELDS.contains(&k.as_str()) {
+                        return Err(LogError::InvalidField(k.to_owned()));
+                    }
+                    fields.push(k.to_owned());
+                    params.push(v.to_owned());
+                }
+                let updated_at = unix_ms() as i64;
+                fields.push("updated_at".to_string());
+                params.push(updated_at.to_cql());
+
+                let query = format!(
+                    "INSERT INTO log ({}) VALUES ({}) IF NOT EXISTS",
+                    fields.join(","),
+                    vec!["?"; fields.len()].join(",")
+                );
+                let res = db.execute(query, params).await?;
+                if !scylladb::extract_applied(res) {
+                    return Ok(false);
+                }
+                self.updated_at = updated_at;
+                Ok(true)
+            })
+            .await
+    }
+
+    // maps a client-supplied external id of uid to a log id, with a LWT if
+    // if_absent, returns false if the external id is taken then.
+    pub async fn put_external_id(
         db: &scylladb::ScyllaDB,
         uid: xid::Id,
-        select_fields: Vec<String>,
-        page_size: u16,
-        page_token: Option<xid::Id>,
-        action: Option<i8>,
-    ) -> anyhow::Result<Vec<Log>> {
-        let fields = Self::select_fields(select_fields, true)?;
-        let token = if page_token.is_none() {
-            MAX_ID
-        } else {
-            page_token.unwrap()
-        };
+        external_id: &str,
+        id: xid::Id,
+        if_absent: bool,
+    ) -> Result<bool, LogError> {
+        otel::DbSpan::start("log_external.put", Some(&uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                let query = if if_absent {
+                    "INSERT INTO log_external (uid,external_id,id) VALUES (?,?,?) IF NOT EXISTS"
+                } else {
+                    "INSERT INTO log_external (uid,external_id,id) VALUES (?,?,?)"
+                };
+                let params = (uid.to_cql(), external_id, id.to_cql());
+                let res = db.execute(query, params).await?;
+                Ok(!if_absent || scylladb::extract_applied(res))
+            })
+            .await
+    }
 
-        let rows = if action.is_none() {
-            let query = format!(
-                "SELECT {} FROM log WHERE uid=? AND id<? LIMIT ? USING TIMEOUT 3s",
-                fields.clone().join(",")
-            );
-            let params = (uid.to_cql(), token.to_cql(), page_size as i32);
-            db.execute_iter(query, params).await?
-        } else {
-            let query = format!(
-                "SELECT {} FROM log WHERE uid=? AND action=? AND id<? LIMIT ? USING TIMEOUT 3s",
-                fields.clone().join(",")
-            );
-            let params = (
-                uid.to_cql(),
-                token.to_cql(),
-                action.unwrap(),
-                page_size as i32,
-            );
-            db.execute_iter(query, params).await?
-        };
+    // resolves an external id of uid to its log id.
+    pub async fn get_external_id(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        external_id: &str,
+    ) -> Result<xid::Id, LogError> {
+        otel::DbSpan::start("log_external.get", Some(&uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                let query = "SELECT id FROM log_external WHERE uid=? AND external_id=? LIMIT 1";
+                let params = (uid.to_cql(), external_id);
+                let row = db.read().execute(query, params).await?.single_row()?;
+                let mut cols = ColumnsMap::with_capacity(1);
+                cols.fill(row, &vec!["id".to_string()])?;
+                Ok(cols.get_as("id")?)
+            })
+            .await
+    }
 
-        let mut res: Vec<Log> = Vec::with_capacity(rows.len());
-        for row in rows {
-            let mut doc = Log::default();
-            let mut cols = ColumnsMap::with_capacity(fields.len());
-            cols.fill(row, &fields)?;
-            doc.fill(&cols);
-            doc._fields = fields.clone();
-            res.push(doc);
-        }
+    // removes the mapping of an external id if it still maps to id.
+    pub async fn release_external_id(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        external_id: &str,
+        id: xid::Id,
+    ) -> Result<(), LogError> {
+        otel::DbSpan::start("log_external.release", Some(&uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                let query = "DELETE FROM log_external WHERE uid=? AND external_id=? IF id=?";
+                let params = (uid.to_cql(), external_id, id.to_cql());
+                db.execute(query, params).await?;
+                Ok(())
+            })
+            .await
+    }
 
-        Ok(res)
+    // appends a status change to log_status_history instead of updating the
+    // log in place, so frequent changes don't pile up cell versions on the
+    // row. The current status is the newest entry, a frozen status can only
+    // be restated. A change of the status is also written to the log first,
+    // conditionally on the status read, so the freeze checks of the other
+    // writes and the readers of the log see it. That happens at most once.
+    //
+    // The log must exist. Changes are keyed by a timeuuid, so changes in the
+    // same millisecond are all kept, and written with a LWT on the static
+    // current status, so of concurrent changes from Open only one freezes.
+    pub async fn append_status(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        status: Status,
+        error: &str,
+    ) -> Result<(), LogError> {
+        otel::DbSpan::start("log.append_status", Some(&self.uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                let fields = vec!["status".to_string()];
+                for _ in 0..5 {
+                    let query = "SELECT status FROM log WHERE uid=? AND id=? LIMIT 1";
+                    let params = (self.uid.to_cql(), self.id.to_cql());
+                    let row = db
+                        .read_latest()
+                        .execute(query, params)
+                        .await?
+                        .single_row()?;
+                    let mut cols = ColumnsMap::with_capacity(fields.len());
+                    cols.fill(row, &fields)?;
+                    let stored: Option<Status> = if cols.has("status") {
+                        Some(cols.get_as("status")?)
+                    } else {
+                        None
+                    };
+                    let log_status = stored.unwrap_or_default();
+
+                    let query =
+                        "SELECT current FROM log_status_history WHERE uid=? AND id=? LIMIT 1";
+                    let params = (self.uid.to_cql(), self.id.to_cql());
+                    let rows = db.read_latest().execute_iter(query, params).await?;
+                    let mut current: Option<Status> = None;
+                    if let Some(row) = rows.into_iter().next() {
+                        let mut cols = ColumnsMap::with_capacity(1);
+                        cols.fill(row, &vec!["current".to_string()])?;
+                        if cols.has("current") {
+                            current = Some(cols.get_as("current")?);
+                        }
+                    }
+                    Self::check_status_change(current.unwrap_or(log_status), status)?;
+
+                    let at = unix_ms() as i64;
+                    if log_status != status {
+                        let query =
+                            "UPDATE log SET status=?,updated_at=? WHERE uid=? AND id=? IF status=?";
+                        let params = (
+                            status.to_cql(),
+                            at,
+                            self.uid.to_cql(),
+                            self.id.to_cql(),
+                            stored.map(i8::from),
+                        );
+                        if !scylladb::extract_applied(db.execute(query, params).await?) {
+                            continue;
+                        }
+                    }
+                    let query = "UPDATE log_status_history SET current=?,at=?,status=?,error=? WHERE uid=? AND id=? AND change_id=now() IF current=?";
+                    let params = (
+                        status.to_cql(),
+                        at,
+                        status.to_cql(),
+                        error,
+                        self.uid.to_cql(),
+                        self.id.to_cql(),
+                        current.map(i8::from),
+                    );
+                    if scylladb::extract_applied(db.execute(query, params).await?) {
+                        self.status = status;
+                        self.error = error.to_string();
+                        self.updated_at = at;
+                        return Ok(());
+                    }
+                }
+                Err(LogError::Conflict(
+                    "status of the log changed concurrently".to_string(),
+                ))
+            })
+            .await
     }
 
-    pub async fn list_recently(
+    // a change from the current status, a frozen status can only be restated.
+    pub fn check_status_change(current: Status, status: Status) -> Result<(), LogError> {
+        if current != Status::Open && current != status {
+            return Err(LogError::Frozen);
+        }
+        Ok(())
+    }
+
+    // the newest status change of each of the logs of uid that has one.
+    pub async fn latest_statuses(
         db: &scylladb::ScyllaDB,
         uid: xid::Id,
-        select_fields: Vec<String>,
-        actions: Vec<i8>,
-    ) -> anyhow::Result<Vec<Log>> {
-        let fields = Self::select_fields(select_fields, true)?;
-
-        // from 3 days ago
-        let unix_ts = (unix_ms() / 1000 - 3600 * 24 * 3) as u32;
-        let mut id = xid::Id::default();
-        id.0[0..=3].copy_from_slice(&unix_ts.to_be_bytes());
-
-        let rows = if actions.is_empty() {
-            let query = format!(
-                "SELECT {} FROM log WHERE uid=? AND id>? LIMIT ? USING TIMEOUT 3s",
-                fields.clone().join(","),
-            );
+        ids: &[xid::Id],
+    ) -> Result<HashMap<xid::Id, StatusEntry>, LogError> {
+        otel::DbSpan::start("log.latest_statuses", Some(&uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                let fields = vec![
+                    "id".to_string(),
+                    "at".to_string(),
+                    "status".to_string(),
+                    "error".to_string(),
+                ];
+                let mut res: HashMap<xid::Id, StatusEntry> = HashMap::new();
+                for chunk in dedup_ids(ids).chunks(GET_MANY_CHUNK) {
+                    let query = format!(
+                        "SELECT id,at,status,error FROM log_status_history WHERE uid=? AND id IN ({}) PER PARTITION LIMIT 1",
+                        chunk.iter().map(|_| "?").collect::<Vec<&str>>().join(",")
+                    );
+                    let mut params: Vec<CqlValue> = Vec::with_capacity(chunk.len() + 1);
+                    params.push(uid.to_cql());
+                    for id in chunk {
+                        params.push(id.to_cql());
+                    }
 
-            let mut params: Vec<CqlValue> = Vec::with_capacity(3);
-            params.push(uid.to_cql());
-            params.push(id.to_cql());
-            params.push(1000_i32.to_cql());
-            db.execute_iter(query, params).await?
-        } else {
-            let query = format!(
-                "SELECT {} FROM log WHERE uid=? AND id>? AND action IN ({}) LIMIT ? ALLOW FILTERING USING TIMEOUT 3s",
-                fields.clone().join(","),
-                actions.iter().map(|_| "?").collect::<Vec<&str>>().join(",")
-            );
+                    for row in db.read().execute_iter(query, params).await? {
+                        let mut cols = ColumnsMap::with_capacity(fields.len());
+                        cols.fill(row, &fields)?;
+                        res.insert(cols.get_as("id")?, StatusEntry::from_cols(&cols)?);
+                    }
+                }
+                Ok(res)
+            })
+            .await
+    }
 
-            let mut params: Vec<CqlValue> = Vec::with_capacity(actions.len() + 3);
-            params.push(uid.to_cql());
-            params.push(id.to_cql());
-            for a in &actions {
-                params.push(a.to_cql());
-            }
-            params.push(1000_i32.to_cql());
-            db.execute_iter(query, params).await?
-        };
+    // takes the status of the newest change of the log's history, and its
+    // error if the error is selected.
+    pub fn apply_status(&mut self, entry: &StatusEntry) {
+        self.status = entry.status;
+        if self._fields.iter().any(|f| f == "error") {
+            self.error = entry.error.clone();
+        }
+        self.updated_at = self.updated_at.max(entry.at);
+    }
+
+    // the status changes of a log, newest first.
+    pub async fn status_history(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        id: xid::Id,
+        limit: u16,
+    ) -> Result<Vec<StatusEntry>, LogError> {
+        otel::DbSpan::start("log.status_history", Some(&uid), QUERY_TIMEOUT_MS)
+            .run(async { Self::query_status_history(db.read(), uid, id, limit).await })
+            .await
+    }
 
-        let mut res: Vec<Log> = Vec::with_capacity(rows.len());
+    async fn query_status_history(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        id: xid::Id,
+        limit: u16,
+    ) -> Result<Vec<StatusEntry>, LogError> {
+        let fields = vec!["at".to_string(), "status".to_string(), "error".to_string()];
+        let query = "SELECT at,status,error FROM log_status_history WHERE uid=? AND id=? LIMIT ?";
+        let params = (uid.to_cql(), id.to_cql(), limit as i32);
+        let rows = db.execute_iter(query, params).await?;
+        let mut res: Vec<StatusEntry> = Vec::with_capacity(rows.len());
         for row in rows {
-            let mut doc = Log::default();
             let mut cols = ColumnsMap::with_capacity(fields.len());
             cols.fill(row, &fields)?;
-            doc.fill(&cols);
-            doc._fields = fields.clone();
-            res.push(doc);
+            res.push(StatusEntry::from_cols(&cols)?);
         }
-
         Ok(res)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use tokio::sync::OnceCell;
+    // adds delta to tokens with a read-modify-write guarded by a LWT, retries on contention.
+    pub async fn add_tokens(
+        &mut self,
+        db: &scylladb::ScyllaDB,
+        delta: i32,
+        cap: Option<i32>, // the token cap of the log's action
+    ) -> Result<i32, LogError> {
+        otel::DbSpan::start("log.add_tokens", Some(&self.uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                let fields = vec!["tokens".to_string(), "status".to_string()];
+                for _ in 0..5 {
+                    let query = "SELECT tokens,status FROM log WHERE uid=? AND id=? LIMIT 1";
+                    let params = (self.uid.to_cql(), self.id.to_cql());
+                    let row = db
+                        .read_latest()
+                        .execute(query, params)
+                        .await?
+                        .single_row()?;
+                    let mut cols = ColumnsMap::with_capacity(fields.len());
+                    cols.fill(row, &fields)?;
 
-    use crate::conf;
+                    let status: Status = cols.get_as("status").unwrap_or_default();
+                    if status != Status::Open {
+                        return Err(LogError::Frozen);
+                    }
 
-    use super::*;
+                    let current: Option<i32> = cols.get_as("tokens").ok();
+                    let tokens = current
+                        .unwrap_or_default()
+                        .checked_add(delta)
+                        .ok_or_else(|| LogError::InvalidInput("tokens overflow".to_string()))?;
+                    if let Some(cap) = cap.filter(|cap| tokens > *cap) {
+                        return Err(LogError::InvalidInput(format!(
+                            "tokens {} exceeds the cap {}",
+                            tokens, cap
+                        )));
+                    }
+                    let updated_at = unix_ms() as i64;
+                    let query =
+                        "UPDATE log SET tokens=?,updated_at=? WHERE uid=? AND id=? IF tokens=? AND status=?";
+                    let params = (
+                        tokens,
+                        updated_at,
+                        self.uid.to_cql(),
+                        self.id.to_cql(),
+                        current,
+                        Status::Open.to_cql(),
+                    );
+                    let res = db.execute(query, params).await?;
+                    if scylladb::extract_applied(res) {
+                        self.tokens = tokens;
+                        self.status = status;
+                        self.updated_at = updated_at;
+                        self._fields = fields;
+                        return Ok(tokens);
+                    }
+                }
 
-    static DB: OnceCell<scylladb::ScyllaDB> = OnceCell::const_new();
+                Err(LogError::Conflict(
+                    "tokens updated concurrently, try again".to_string(),
+                ))
+            })
+            .await
+    }
 
-    async fn get_db() -> scylladb::ScyllaDB {
-        let cfg = conf::Conf::new().unwrap_or_else(|err| panic!("config error: {}", err));
-        let res = scylladb::ScyllaDB::new(cfg.scylla, "logbase_test").await;
-        res.unwrap()
+    // inserts logs as-is with their own ids and timestamps, ids minted in the future are rejected.
+    // A log that already exists is left untouched, frozen or not, and its id is
+    // returned with the skipped ones.
+    pub async fn import_batch(
+        db: &scylladb::ScyllaDB,
+        logs: &[Log],
+    ) -> Result<(u64, Vec<xid::Id>), LogError> {
+        otel::DbSpan::start("log.import_batch", None, QUERY_TIMEOUT_MS)
+            .run(async {
+                let now = (unix_ms() / 1000) as u32;
+                if let Some(doc) = logs.iter().find(|doc| xid_unix(&doc.id) > now) {
+                    return Err(LogError::InvalidInput(format!(
+                        "log id {} is in the future",
+                        doc.id
+                    )));
+                }
+
+                let query = format!("{} IF NOT EXISTS", INSERT_QUERY);
+                let mut skipped: Vec<xid::Id> = Vec::new();
+                for doc in logs {
+                    let res = db.execute(query.as_str(), doc.insert_params()).await?;
+                    if !scylladb::extract_applied(res) {
+                        skipped.push(doc.id);
+                    }
+                }
+                Ok(((logs.len() - skipped.len()) as u64, skipped))
+            })
+            .await
     }
 
-    #[tokio::test(flavor = "current_thread")]
-    #[ignore]
-    async fn log_model_works() {
-        let db = DB.get_or_init(get_db).await;
-        let uid = xid::new();
-        let id = xid::new();
-        let mut doc = Log::with_pk(uid, id);
+    // writes different fields to at most BATCH_UPSERT_MAX logs of a uid as
+    // one batch of UPDATEs within the uid partition, so it's written as a
+    // whole or not at all. Every cols map is checked against
+    // UPSERT_FIELDS and existing logs get the checks of upsert_fields_with
+    // before anything is written. Errors of frozen logs are appended to
+    // without a condition, unlike upsert_fields_with.
+    pub async fn batch_upsert(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        mut rows: Vec<(xid::Id, ColumnsMap)>,
+        mutable_after_freeze: &[String],
+    ) -> Result<u64, LogError> {
+        otel::DbSpan::start("log.batch_upsert", Some(&uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                if rows.len() > BATCH_UPSERT_MAX {
+                    return Err(LogError::InvalidInput(format!(
+                        "too many logs {}, expected at most {}",
+                        rows.len(),
+                        BATCH_UPSERT_MAX
+                    )));
+                }
+                let mut ids: Vec<xid::Id> = Vec::with_capacity(rows.len());
+                for (id, cols) in &rows {
+                    if cols.is_empty() {
+                        return Err(LogError::InvalidInput(format!(
+                            "no fields to write to log {}",
+                            id
+                        )));
+                    }
+                    if let Some((k, _)) = cols
+                        .iter()
+                        .find(|(k, _)| !Self::UPSERT_FIELDS.contains(&k.as_str()))
+                    {
+                        return Err(LogError::InvalidField(k.to_owned()));
+                    }
+                    if ids.contains(id) {
+                        return Err(LogError::InvalidInput(format!("duplicate log {}", id)));
+                    }
+                    ids.push(*id);
+                }
 
-        let res = doc.get_one(db, vec![]).await;
-        assert!(res.is_err());
-        let err: HTTPError = res.unwrap_err().into();
-        assert_eq!(err.code, 404);
+                let fields = vec![
+                    "id".to_string(),
+                    "action".to_string(),
+                    "status".to_string(),
+                    "error".to_string(),
+                ];
+                let mut existing: HashMap<[u8; 12], (i8, Status, String)> =
+                    HashMap::with_capacity(ids.len());
+                for chunk in ids.chunks(GET_MANY_CHUNK) {
+                    let query = format!(
+                        "SELECT id,action,status,error FROM log WHERE uid=? AND id IN ({})",
+                        chunk.iter().map(|_| "?").collect::<Vec<&str>>().join(",")
+                    );
+                    let mut params: Vec<CqlValue> = Vec::with_capacity(chunk.len() + 1);
+                    params.push(uid.to_cql());
+                    for id in chunk {
+                        params.push(id.to_cql());
+                    }
+                    for row in db.read_latest().execute_iter(query, params).await? {
+                        let mut cols = ColumnsMap::with_capacity(fields.len());
+                        cols.fill(row, &fields)?;
+                        let id: xid::Id = cols.get_as("id").unwrap_or_default();
+                        existing.insert(
+                            id.0,
+                            (
+                                cols.get_as("action").unwrap_or_default(),
+                                cols.get_as("status").unwrap_or_default(),
+                                cols.get_as("error").unwrap_or_default(),
+                            ),
+                        );
+                    }
+                }
+                for (id, cols) in rows.iter_mut() {
+                    if let Some((action, status, error)) = existing.get(&id.0) {
+                        Self::check_action_unchanged(*action, cols)?;
+                        Self::check_frozen(*status, cols, mutable_after_freeze)?;
+                        Self::append_frozen(*status, error, cols)?;
+                    }
+                }
 
-        let content: Vec<u8> = vec![0x80];
+                let updated_at = (unix_ms() as i64).to_cql();
+                let mut statements: Vec<String> = Vec::with_capacity(rows.len());
+                let mut values: Vec<Vec<CqlValue>> = Vec::with_capacity(rows.len());
+                for (id, cols) in &rows {
+                    let mut set_fields: Vec<String> = Vec::with_capacity(cols.len() + 1);
+                    let mut params: Vec<CqlValue> = Vec::with_capacity(cols.len() + 3);
+                    for (k, v) in cols.iter() {
+                        set_fields.push(format!("{}=?", k));
+                        params.push(v.to_owned());
+                    }
+                    set_fields.push("updated_at=?".to_string());
+                    params.push(updated_at.clone());
+                    params.push(uid.to_cql());
+                    params.push(id.to_cql());
+                    statements.push(format!(
+                        "UPDATE log SET {} WHERE uid=? AND id=?",
+                        set_fields.join(",")
+                    ));
+                    values.push(params);
+                }
+                db.batch(statements.iter().map(|s| s.as_str()).collect(), values)
+                    .await?;
+                Ok(rows.len() as u64)
+            })
+            .await
+    }
 
-        let mut cols = ColumnsMap::with_capacity(4);
-        cols.set_as("action", &1i8);
-        cols.set_as("ip", &"1.2.3.4".to_string());
-        cols.set_as("tokens", &(1000i32));
-        cols.set_as("payload", &content);
+    // writes logs as one unlogged batch, without the freeze check of upsert.
+    pub async fn insert_unlogged(db: &scylladb::ScyllaDB, logs: &[Log]) -> Result<(), LogError> {
+        otel::DbSpan::start("log.insert_unlogged", None, QUERY_TIMEOUT_MS)
+            .run(async {
+                if logs.is_empty() {
+                    return Ok(());
+                }
 
-        doc.upsert_fields(db, cols).await.unwrap();
+                let statements = vec![INSERT_QUERY; logs.len()];
+                let values: Vec<Vec<CqlValue>> =
+                    logs.iter().map(|doc| doc.insert_params()).collect();
+                let _ = db.unlogged_batch(statements, values).await?;
+                Ok(())
+            })
+            .await
+    }
 
-        let mut doc2 = Log::with_pk(uid, id);
-        doc2.get_one(db, vec![]).await.unwrap();
+    fn insert_params(&self) -> Vec<CqlValue> {
+        vec![
+            self.uid.to_cql(),
+            self.id.to_cql(),
+            self.action.to_cql(),
+            self.status.to_cql(),
+            self.gid.to_cql(),
+            self.ip.to_cql(),
+            self.payload.to_cql(),
+            self.payload_type.to_cql(),
+            self.tokens.to_cql(),
+            self.error.to_cql(),
+            self.trace_id.to_cql(),
+            self.updated_at.to_cql(),
+            self.labels.to_cql(),
+        ]
+    }
 
-        assert_eq!(doc2.action, 1i8);
-        assert_eq!(doc2.gid, xid::Id::default());
-        assert_eq!(doc2.ip, "1.2.3.4".to_string());
-        assert_eq!(doc2.tokens, 1000i32);
-        assert_eq!(doc2.payload, content);
-        assert_eq!(doc2.error, "".to_string());
+    // newest first, the table is clustered by id DESC so rows come back in
+    // storage order and the `id<?` predicate pages backwards in time.
+    // Ids are unique and compared as raw bytes, the timestamp first, then the
+    // machine, process and counter bytes, so logs of the same second still have
+    // a total and stable order. Passing the last id of a page as the next
+    // page_token neither skips nor repeats a log.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn list(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        page_token: Option<xid::Id>,
+        action: Option<i8>,
+        since: Option<u32>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError> {
+        otel::DbSpan::start("log.list", Some(&uid), timeout_of(timeout_ms))
+            .run(async {
+                let fields = Self::select_fields(select_fields, true)?;
+                let token = page_token.unwrap_or(MAX_ID);
+                // the window bounds how far a filtered scan can read.
+                let start = xid_from_unix(since.unwrap_or_default());
 
-        let mut doc3 = Log::with_pk(uid, id);
-        doc3.get_one(db, vec!["error".to_string()]).await.unwrap();
-        assert_eq!(doc3.tokens, 0i32);
-        assert_eq!(doc3.payload.len(), 0);
-        assert_eq!(doc3.error, "".to_string());
+                let rows = if let Some(action) = action {
+                    let query = timed_query(
+                        format!(
+                        "SELECT {} FROM log WHERE uid=? AND action=? AND id>=? AND id<? LIMIT ? ALLOW FILTERING",
+                        fields.clone().join(","),
+                        ),
+                        timeout_ms,
+                        )?;
+                    let params = (
+                        uid.to_cql(),
+                        action,
+                        start.to_cql(),
+                        token.to_cql(),
+                        page_size as i32,
+                    );
+                    db.read().execute_iter(query, params).await?
+                } else {
+                    let query = timed_query(
+                        format!(
+                            "SELECT {} FROM log WHERE uid=? AND id>=? AND id<? LIMIT ?",
+                            fields.clone().join(","),
+                        ),
+                        timeout_ms,
+                    )?;
+                    let params = (
+                        uid.to_cql(),
+                        start.to_cql(),
+                        token.to_cql(),
+                        page_size as i32,
+                    );
+                    db.read().execute_iter(query, params).await?
+                };
 
-        let mut cols = ColumnsMap::with_capacity(1);
-        cols.set_as("error", &"some error".to_string());
-        doc.upsert_fields(db, cols).await.unwrap();
+                let mut res: Vec<Log> = Vec::with_capacity(rows.len());
+                for row in rows {
+                    let mut doc = Log::default();
+                    let mut cols = ColumnsMap::with_capacity(fields.len());
+                    cols.fill(row, &fields)?;
+                    doc.fill(&cols);
+                    doc._fields = fields.clone();
+                    res.push(doc);
+                }
 
-        let mut doc3 = Log::with_pk(uid, id);
-        doc3.get_one(db, vec![]).await.unwrap();
-        assert_eq!(doc3.tokens, 1000i32);
-        assert_eq!(doc3.payload, content);
-        assert_eq!(doc3.error, "some error".to_string());
+                Ok(res)
+            })
+            .await
+    }
 
-        let mut doc = Log::with_pk(uid, xid::new());
-        let mut cols = ColumnsMap::with_capacity(1);
-        cols.set_as("action", &2i8);
-        cols.set_as("error", &"some error".to_string());
-        doc.upsert_fields(db, cols).await.unwrap();
-        doc.get_one(db, vec![]).await.unwrap();
-        assert_eq!(doc.tokens, 0i32);
-        assert_eq!(doc.payload.len(), 0);
-        assert_eq!(doc.error, "some error".to_string());
+    // the page of logs newer than `before`, the reverse of list: it reads the
+    // oldest ones first and returns them newest first, so a page read back
+    // from the first id of the next page equals the page listed forwards.
+    // There is no action filter, the action index can't be read in clustering
+    // order.
+    pub async fn list_before(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        before: xid::Id,
+        since: Option<u32>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError> {
+        otel::DbSpan::start("log.list_before", Some(&uid), timeout_of(timeout_ms))
+            .run(async {
+                let fields = Self::select_fields(select_fields, true)?;
+                // a clustering column takes one lower bound, the tighter one of the
+                // window and the cursor.
+                let start = xid_from_unix(since.unwrap_or_default());
+                let (bound, token) = if before.0 >= start.0 {
+                    ("id>?", before)
+                } else {
+                    ("id>=?", start)
+                };
+
+                let query = timed_query(
+                    format!(
+                        "SELECT {} FROM log WHERE uid=? AND {} ORDER BY id ASC LIMIT ?",
+                        fields.clone().join(","),
+                        bound,
+                    ),
+                    timeout_ms,
+                )?;
+                let params = (uid.to_cql(), token.to_cql(), page_size as i32);
+                let rows = db.read().execute_iter(query, params).await?;
 
-        let docs = Log::list_recently(db, uid, vec![], vec![1i8, 2i8])
+                let mut res: Vec<Log> = Vec::with_capacity(rows.len());
+                for row in rows {
+                    let mut doc = Log::default();
+                    let mut cols = ColumnsMap::with_capacity(fields.len());
+                    cols.fill(row, &fields)?;
+                    doc.fill(&cols);
+                    doc._fields = fields.clone();
+                    res.push(doc);
+                }
+                res.reverse();
+
+                Ok(res)
+            })
             .await
-            .unwrap();
-        assert_eq!(2, docs.len());
-        assert_eq!(docs[0].action, 2i8);
-        assert_eq!(docs[1].action, 1i8);
+    }
+
+    // like list, but only logs with the label `key=value`. Labels are not
+    // indexed, Scylla reads and filters every log of the window, so the scan
+    // should be bounded by `since` or a small page size.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn list_by_label(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        page_token: Option<xid::Id>,
+        action: Option<i8>,
+        label: (&str, &str),
+        since: Option<u32>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError> {
+        otel::DbSpan::start("log.list_by_label", Some(&uid), timeout_of(timeout_ms))
+            .run(async {
+                let fields = Self::select_fields(select_fields, true)?;
+                let token = page_token.unwrap_or(MAX_ID);
+                let start = xid_from_unix(since.unwrap_or_default());
+
+                let mut conds = vec!["uid=?", "id>=?", "id<?"];
+                let mut params: Vec<CqlValue> = vec![uid.to_cql(), start.to_cql(), token.to_cql()];
+                if let Some(action) = action {
+                    conds.push("action=?");
+                    params.push(action.to_cql());
+                }
+                conds.push("labels[?]=?");
+                params.push(label.0.to_string().to_cql());
+                params.push(label.1.to_string().to_cql());
+                params.push((page_size as i32).to_cql());
+
+                let query = timed_query(
+                    format!(
+                        "SELECT {} FROM log WHERE {} LIMIT ? ALLOW FILTERING",
+                        fields.join(","),
+                        conds.join(" AND "),
+                    ),
+                    timeout_ms,
+                )?;
+                let rows = db.read().execute_iter(query, params).await?;
+
+                let mut res: Vec<Log> = Vec::with_capacity(rows.len());
+                for row in rows {
+                    let mut doc = Log::default();
+                    let mut cols = ColumnsMap::with_capacity(fields.len());
+                    cols.fill(row, &fields)?;
+                    doc.fill(&cols);
+                    doc._fields = fields.clone();
+                    res.push(doc);
+                }
+
+                Ok(res)
+            })
+            .await
+    }
+
+    pub async fn list_recently(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        actions: Vec<i8>,
+        page_size: u16,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError> {
+        otel::DbSpan::start("log.list_recently", Some(&uid), timeout_of(timeout_ms))
+            .run(async {
+                let fields = Self::select_fields(select_fields, true)?;
+
+                // from 3 days ago
+                let id = xid_from_unix((unix_ms() / 1000 - 3600 * 24 * 3) as u32);
+
+                let rows = if actions.is_empty() {
+                    let query = timed_query(
+                        format!(
+                            "SELECT {} FROM log WHERE uid=? AND id>? LIMIT ?",
+                            fields.clone().join(","),
+                        ),
+                        timeout_ms,
+                    )?;
+
+                    let mut params: Vec<CqlValue> = Vec::with_capacity(3);
+                    params.push(uid.to_cql());
+                    params.push(id.to_cql());
+                    params.push((page_size as i32).to_cql());
+                    db.read().execute_iter(query, params).await?
+                } else {
+                    let query = timed_query(
+                        format!(
+                        "SELECT {} FROM log WHERE uid=? AND id>? AND action IN ({}) LIMIT ? ALLOW FILTERING",
+                        fields.clone().join(","),
+                        actions.iter().map(|_| "?").collect::<Vec<&str>>().join(","),
+                        ),
+                        timeout_ms,
+                        )?;
+
+                    let mut params: Vec<CqlValue> = Vec::with_capacity(actions.len() + 3);
+                    params.push(uid.to_cql());
+                    params.push(id.to_cql());
+                    for a in &actions {
+                        params.push(a.to_cql());
+                    }
+                    params.push((page_size as i32).to_cql());
+                    db.read().execute_iter(query, params).await?
+                };
+
+                let mut res: Vec<Log> = Vec::with_capacity(rows.len());
+                for row in rows {
+                    let mut doc = Log::default();
+                    let mut cols = ColumnsMap::with_capacity(fields.len());
+                    cols.fill(row, &fields)?;
+                    doc.fill(&cols);
+                    doc._fields = fields.clone();
+                    res.push(doc);
+                }
+
+                Ok(res)
+            })
+            .await
+    }
+
+    // the recent logs of several uids, queried concurrently and merged newest first.
+    pub async fn list_recently_multi(
+        db: &scylladb::ScyllaDB,
+        uids: &[xid::Id],
+        select_fields: Vec<String>,
+        actions: Vec<i8>,
+        limit: u16,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError> {
+        let res = futures::future::try_join_all(uids.iter().map(|uid| {
+            Self::list_recently(
+                db,
+                *uid,
+                select_fields.clone(),
+                actions.clone(),
+                limit,
+                timeout_ms,
+            )
+        }))
+        .await?;
+        Ok(merge_newest(res, limit as usize))
+    }
+
+    // drops the ids older than the oldest log of uid before a get_many, they
+    // can't exist. Refetched ids of purged or expired logs then cost no IN
+    // query, at the price of a one-row probe, so it only probes ids that take
+    // more than one IN query. Ids are minted at their creation time, so the
+    // probe never drops a log that exists, except one imported concurrently.
+    pub async fn drop_absent(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        ids: Vec<xid::Id>,
+    ) -> Result<Vec<xid::Id>, LogError> {
+        if ids.len() <= GET_MANY_CHUNK {
+            return Ok(ids);
+        }
+
+        let oldest = match Self::earliest(db, uid, vec!["id".to_string()]).await {
+            Ok(doc) => doc.id,
+            Err(LogError::NotFound) => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        Ok(ids.into_iter().filter(|id| id.0 >= oldest.0).collect())
+    }
+
+    // fetches the logs of the ids that exist, newest first. Scylla limits the
+    // size of IN lists, so ids are queried GET_MANY_CHUNK at a time, concurrently.
+    // Repeated ids are queried once.
+    pub async fn get_many(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        ids: &[xid::Id],
+        select_fields: Vec<String>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError> {
+        otel::DbSpan::start("log.get_many", Some(&uid), timeout_of(timeout_ms))
+            .run(async {
+                let fields = Self::select_fields(select_fields, true)?;
+                let ids = dedup_ids(ids);
+                let res = futures::future::try_join_all(ids.chunks(GET_MANY_CHUNK).map(|chunk| {
+                    let fields = fields.clone();
+                    async move {
+                        let query = timed_query(
+                            format!(
+                                "SELECT {} FROM log WHERE uid=? AND id IN ({})",
+                                fields.join(","),
+                                chunk.iter().map(|_| "?").collect::<Vec<&str>>().join(",")
+                            ),
+                            timeout_ms,
+                        )?;
+                        let mut params: Vec<CqlValue> = Vec::with_capacity(chunk.len() + 1);
+                        params.push(uid.to_cql());
+                        for id in chunk {
+                            params.push(id.to_cql());
+                        }
+
+                        let rows = db.read().execute_iter(query, params).await?;
+                        let mut res: Vec<Log> = Vec::with_capacity(rows.len());
+                        for row in rows {
+                            let mut doc = Log::default();
+                            let mut cols = ColumnsMap::with_capacity(fields.len());
+                            cols.fill(row, &fields)?;
+                            doc.fill(&cols);
+                            doc._fields = fields.clone();
+                            res.push(doc);
+                        }
+                        Ok::<Vec<Log>, LogError>(res)
+                    }
+                }))
+                .await?;
+                Ok(merge_newest(res, ids.len()))
+            })
+            .await
+    }
+
+    // the number of `bucket_seconds` windows covering [since, until), the last
+    // one may be cut short by until.
+    pub fn histogram_buckets(since: u32, until: u32, bucket_seconds: u32) -> usize {
+        if since >= until || bucket_seconds == 0 {
+            return 0;
+        }
+        ((until - since - 1) / bucket_seconds + 1) as usize
+    }
+
+    // counts logs in [since, until) per `bucket_seconds` window, computed from the
+    // xid timestamps over a paged scan of at most max_scan logs, newest first,
+    // rather than a server side aggregation. Every page gets the budget left of
+    // timeout_ms. The returned bool is true if the counts may be partial.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn action_histogram(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        action: Option<i8>,
+        bucket_seconds: u32,
+        since: u32,
+        until: u32,
+        page_size: u16,
+        max_scan: usize,
+        timeout_ms: Option<u64>,
+    ) -> Result<(Vec<u64>, bool), LogError> {
+        otel::DbSpan::start("log.action_histogram", Some(&uid), timeout_of(timeout_ms))
+            .run(async {
+                if bucket_seconds == 0 || since >= until {
+                    return Err(LogError::InvalidInput(
+                        "Invalid histogram window".to_string(),
+                    ));
+                }
+
+                let started = Instant::now();
+                let mut counts = vec![0u64; Self::histogram_buckets(since, until, bucket_seconds)];
+                let start = xid_from_unix(since);
+                let mut token = xid_from_unix(until);
+                let mut scanned = 0usize;
+                let fields = vec!["id".to_string()];
+                loop {
+                    if scanned >= max_scan {
+                        return Ok((counts, true));
+                    }
+                    let limit = (page_size as usize).min(max_scan - scanned);
+                    let remaining_ms = budget_left(timeout_ms, started);
+                    let rows = if let Some(action) = action {
+                        let query = timed_query(
+                            "SELECT id FROM log WHERE uid=? AND id>=? AND id<? AND action=? LIMIT ? ALLOW FILTERING".to_string(),
+                            remaining_ms,
+                        )?;
+                        let params = (
+                            uid.to_cql(),
+                            start.to_cql(),
+                            token.to_cql(),
+                            action,
+                            limit as i32,
+                        );
+                        db.read().execute_iter(query, params).await?
+                    } else {
+                        let query = timed_query(
+                            "SELECT id FROM log WHERE uid=? AND id>=? AND id<? LIMIT ?".to_string(),
+                            remaining_ms,
+                        )?;
+                        let params = (uid.to_cql(), start.to_cql(), token.to_cql(), limit as i32);
+                        db.read().execute_iter(query, params).await?
+                    };
+
+                    let n = rows.len();
+                    for row in rows {
+                        let mut cols = ColumnsMap::with_capacity(1);
+                        cols.fill(row, &fields)?;
+                        token = cols.get_as("id")?;
+                        fill_histogram(&mut counts, since, bucket_seconds, xid_unix(&token));
+                    }
+
+                    scanned += n;
+                    if n < limit {
+                        return Ok((counts, false));
+                    }
+                }
+            })
+            .await
+    }
+
+    // the sum of tokens of logs in [since, until), aggregated by Scylla. The
+    // tokens are summed as bigint, a sum of int would overflow on a large window.
+    pub async fn sum_tokens(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        action: Option<i8>,
+        since: u32,
+        until: u32,
+        timeout_ms: Option<u64>,
+    ) -> Result<i64, LogError> {
+        otel::DbSpan::start("log.sum_tokens", Some(&uid), timeout_of(timeout_ms))
+            .run(async {
+                let start = xid_from_unix(since);
+                let end = xid_from_unix(until);
+                let res = if let Some(action) = action {
+                    let query = timed_query(
+                        "SELECT SUM(CAST(tokens AS bigint)) AS sum FROM log WHERE uid=? AND id>=? AND id<? AND action=? ALLOW FILTERING".to_string(),
+                        timeout_ms,
+                    )?;
+                    let params = (uid.to_cql(), start.to_cql(), end.to_cql(), action);
+                    db.read().execute(query, params).await?
+                } else {
+                    let query = timed_query(
+                        "SELECT SUM(CAST(tokens AS bigint)) AS sum FROM log WHERE uid=? AND id>=? AND id<?"
+                            .to_string(),
+                        timeout_ms,
+                    )?;
+                    let params = (uid.to_cql(), start.to_cql(), end.to_cql());
+                    db.read().execute(query, params).await?
+                };
+
+                let mut cols = ColumnsMap::with_capacity(1);
+                cols.fill(res.single_row()?, &vec!["sum".to_string()])?;
+                // a null sum is an empty window
+                if !cols.has("sum") {
+                    return Ok(0);
+                }
+                Ok(cols.get_as::<i64>("sum")?)
+            })
+            .await
+    }
+
+    // like sum_tokens, but pages the logs and sums them here, so a large window
+    // isn't one long aggregation on a hot partition. It stops after `max_pages`
+    // pages or once timeout_ms is spent, every page gets the budget left. The
+    // returned bool is true if the sum may be partial.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn sum_tokens_streamed(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        action: Option<i8>,
+        since: u32,
+        until: u32,
+        page_size: u16,
+        max_pages: usize,
+        timeout_ms: Option<u64>,
+    ) -> Result<(i64, bool), LogError> {
+        let started = Instant::now();
+        let mut total = 0i64;
+        let mut token = xid_from_unix(until);
+        for _ in 0..max_pages {
+            let remaining_ms = budget_left(timeout_ms, started);
+            if remaining_ms == Some(0) {
+                return Ok((total, true));
+            }
+            let res = Self::list(
+                db,
+                uid,
+                vec!["tokens".to_string()],
+                page_size,
+                Some(token),
+                action,
+                Some(since),
+                remaining_ms,
+            )
+            .await?;
+
+            let n = res.len();
+            for doc in res {
+                token = doc.id;
+                total += doc.tokens as i64;
+            }
+            if n < page_size as usize {
+                return Ok((total, false));
+            }
+        }
+
+        Ok((total, true))
+    }
+
+    // counts the logs of a user by status. The status is not a key column so
+    // Scylla can't GROUP BY it, the counts are computed over a paged scan of
+    // at most max_scan logs, newest first. Every page gets the budget left of
+    // timeout_ms. The returned bool is true if the counts may be partial.
+    pub async fn count_by_status(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        page_size: u16,
+        max_scan: usize,
+        timeout_ms: Option<u64>,
+    ) -> Result<(StatusCount, bool), LogError> {
+        otel::DbSpan::start("log.count_by_status", Some(&uid), timeout_of(timeout_ms))
+            .run(async {
+                let started = Instant::now();
+                let mut counts = StatusCount::default();
+                let mut scanned = 0usize;
+                let mut token = MAX_ID;
+                let fields = vec!["id".to_string(), "status".to_string()];
+                loop {
+                    if scanned >= max_scan {
+                        return Ok((counts, true));
+                    }
+                    let limit = (page_size as usize).min(max_scan - scanned);
+                    let remaining_ms = budget_left(timeout_ms, started);
+                    let query = timed_query(
+                        "SELECT id,status FROM log WHERE uid=? AND id<? LIMIT ?".to_string(),
+                        remaining_ms,
+                    )?;
+                    let params = (uid.to_cql(), token.to_cql(), limit as i32);
+                    let rows = db.read().execute_iter(query, params).await?;
+                    let n = rows.len();
+                    for row in rows {
+                        let mut cols = ColumnsMap::with_capacity(2);
+                        cols.fill(row, &fields)?;
+                        token = cols.get_as("id")?;
+                        // a log created without status is still processing
+                        counts.add(cols.get_as("status").unwrap_or_default());
+                    }
+
+                    scanned += n;
+                    if n < limit {
+                        return Ok((counts, false));
+                    }
+                }
+            })
+            .await
+    }
+
+    // deletes logs created before the `before` unix timestamp (seconds),
+    // frozen logs are kept unless `force` is set.
+    pub async fn purge_before(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        before: u32,
+        force: bool,
+        page_size: u16,
+    ) -> Result<u64, LogError> {
+        Self::delete_before(db, uid, None, before, force, page_size).await
+    }
+
+    // deletes logs of the action created before the `before` unix timestamp (seconds),
+    // frozen logs are kept unless `force` is set.
+    pub async fn delete_by_action(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        action: i8,
+        before: u32,
+        force: bool,
+        page_size: u16,
+    ) -> Result<u64, LogError> {
+        Self::delete_before(db, uid, Some(action), before, force, page_size).await
+    }
+
+    async fn delete_before(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        action: Option<i8>,
+        before: u32,
+        force: bool,
+        page_size: u16,
+    ) -> Result<u64, LogError> {
+        let mut token = xid_from_unix(before);
+        let mut deleted = 0u64;
+        loop {
+            let res = Self::list(
+                db,
+                uid,
+                vec!["status".to_string()],
+                page_size,
+                Some(token),
+                action,
+                None,
+                None,
+            )
+            .await?;
+
+            let n = res.len();
+            let mut ids: Vec<xid::Id> = Vec::with_capacity(n);
+            for doc in res {
+                token = doc.id;
+                if force || doc.status == Status::Open {
+                    ids.push(doc.id);
+                }
+            }
+
+            deleted += Self::delete_many(db, uid, &ids).await?;
+            if n < page_size as usize {
+                break;
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    // the oldest log of a user, read against the clustering order.
+    pub async fn earliest(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+    ) -> Result<Log, LogError> {
+        otel::DbSpan::start("log.earliest", Some(&uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                let fields = Self::select_fields(select_fields, true)?;
+                let query = format!(
+                    "SELECT {} FROM log WHERE uid=? ORDER BY id ASC LIMIT 1",
+                    fields.join(",")
+                );
+                let params = (uid.to_cql(),);
+                let row = db.read().execute(query, params).await?.single_row()?;
+
+                let mut doc = Log::default();
+                let mut cols = ColumnsMap::with_capacity(fields.len());
+                cols.fill(row, &fields)?;
+                doc.fill(&cols);
+                doc._fields = fields;
+                Ok(doc)
+            })
+            .await
+    }
+
+    // the newest log of every action found in the latest `max_scan` logs. The
+    // scan reads the keys and action only, the selected fields are fetched for
+    // the newest logs.
+    pub async fn latest_per_action(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        max_scan: usize,
+    ) -> Result<Vec<Log>, LogError> {
+        let mut scanned: Vec<Log> = Vec::new();
+        let mut page_token: Option<xid::Id> = None;
+        while scanned.len() < max_scan {
+            let res = Self::list(
+                db,
+                uid,
+                vec!["action".to_string()],
+                page_size,
+                page_token,
+                None,
+                None,
+                None,
+            )
+            .await?;
+            let n = res.len();
+            page_token = res.last().map(|r| r.id);
+            scanned.extend(res);
+            if n < page_size as usize {
+                break;
+            }
+        }
+
+        let ids: Vec<xid::Id> = newest_per_action(scanned)
+            .into_iter()
+            .map(|doc| doc.id)
+            .collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        Self::get_many(db, uid, &ids, select_fields, None).await
+    }
+
+    // logs created since the `since` unix timestamp (seconds). It reads one log
+    // more than `max_scan`, so callers can tell a scan that hit the limit from
+    // one that read exactly max_scan logs.
+    pub async fn list_since(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        since: u32,
+        page_size: u16,
+        max_scan: usize,
+    ) -> Result<Vec<Log>, LogError> {
+        let mut res: Vec<Log> = Vec::new();
+        let mut page_token: Option<xid::Id> = None;
+        while res.len() <= max_scan {
+            let docs = Self::list(
+                db,
+                uid,
+                select_fields.clone(),
+                page_size,
+                page_token,
+                None,
+                Some(since),
+                None,
+            )
+            .await?;
+            let n = docs.len();
+            page_token = docs.last().map(|r| r.id);
+            res.extend(docs);
+            if n < page_size as usize {
+                break;
+            }
+        }
+
+        res.truncate(max_scan + 1);
+        Ok(res)
+    }
+
+    // logs updated after `updated_after` (unix ms), the oldest update first.
+    // updated_at is not a clustering column, so it scans the logs created
+    // since `since` (unix seconds), at most max_scan of them, and filters in
+    // memory. The bool is true if the scan hit max_scan.
+    pub async fn list_changed_since(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        updated_after: i64,
+        since: u32,
+        page_size: u16,
+        max_scan: usize,
+    ) -> Result<(Vec<Log>, bool), LogError> {
+        let mut select_fields = select_fields;
+        if !select_fields.is_empty() && !select_fields.contains(&"updated_at".to_string()) {
+            select_fields.push("updated_at".to_string());
+        }
+
+        let mut res = Self::list_since(db, uid, select_fields, since, page_size, max_scan).await?;
+        let truncated = res.len() > max_scan;
+        res.truncate(max_scan);
+        Ok((changed_after(res, updated_after), truncated))
+    }
+
+    // a page of logs created or updated after the (updated_at, id) cursor,
+    // ordered by (updated_at, id). Like list_changed_since, it scans the logs
+    // created since `since`, at most max_scan of them, but reads only their
+    // updated_at, the selected fields are read for the page only. Writes of
+    // the last lag_ms are left to the next page: a write in flight may still
+    // land with an updated_at behind them, the cursor must not pass it.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn sync_page(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        cursor: (i64, xid::Id),
+        since: u32,
+        lag_ms: u64,
+        page_size: u16,
+        max_scan: usize,
+        limit: usize,
+        timeout_ms: Option<u64>,
+    ) -> Result<SyncPage, LogError> {
+        let mut select_fields = select_fields;
+        if !select_fields.is_empty() && !select_fields.contains(&"updated_at".to_string()) {
+            select_fields.push("updated_at".to_string());
+        }
+
+        let mut res = Self::list_since(
+            db,
+            uid,
+            vec!["updated_at".to_string()],
+            since,
+            page_size,
+            max_scan,
+        )
+        .await?;
+        let truncated = res.len() > max_scan;
+        res.truncate(max_scan);
+        let settled_at = unix_ms() as i64 - lag_ms as i64;
+        let (keys, has_more) = after_cursor(res, cursor, settled_at, limit);
+        let next = keys.last().map_or(cursor, |r| (r.updated_at, r.id));
+        if keys.is_empty() {
+            return Ok(SyncPage {
+                logs: vec![],
+                cursor: next,
+                has_more,
+                truncated,
+            });
+        }
+
+        let ids: Vec<xid::Id> = keys.iter().map(|r| r.id).collect();
+        let mut logs = Self::get_many(db, uid, &ids, select_fields, timeout_ms).await?;
+        // the cursor order, a log deleted since the scan is left out
+        let order: HashMap<xid::Id, usize> =
+            ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        logs.sort_by_key(|r| order.get(&r.id).copied());
+        Ok(SyncPage {
+            logs,
+            cursor: next,
+            has_more,
+            truncated,
+        })
+    }
+
+    async fn delete_many(
+        db: &scylladb::ScyllaDB,
+        uid: xid::Id,
+        ids: &[xid::Id],
+    ) -> Result<u64, LogError> {
+        otel::DbSpan::start("log.delete_many", Some(&uid), QUERY_TIMEOUT_MS)
+            .run(async {
+                // BATCH operations are isolated within the uid partition.
+                for chunk in ids.chunks(100) {
+                    let statements = vec!["DELETE FROM log WHERE uid=? AND id=?"; chunk.len()];
+                    let values: Vec<(CqlValue, CqlValue)> =
+                        chunk.iter().map(|id| (uid.to_cql(), id.to_cql())).collect();
+                    db.batch(statements, values).await?;
+                }
+                Ok(ids.len() as u64)
+            })
+            .await
+    }
+}
+
+pub const QUERY_TIMEOUT_MS: u64 = 3000;
+
+// see sync_page.
+#[derive(Debug, Default)]
+pub struct SyncPage {
+    pub logs: Vec<Log>,
+    pub cursor: (i64, xid::Id), // of the last log as scanned, or the input cursor
+    pub has_more: bool,         // more changes follow the page
+    pub truncated: bool,        // the scan hit max_scan
+}
+
+// the status of a log, stored and sent as its i8 value. A log is frozen once
+// it leaves Open, see check_frozen.
+#[repr(i8)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "i8", into = "i8")]
+pub enum Status {
+    Failed = -1,
+    #[default]
+    Open = 0,
+    Frozen = 1,
+    Deleted = 2,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", *self as i8)
+    }
+}
+
+impl From<Status> for i8 {
+    fn from(s: Status) -> Self {
+        s as i8
+    }
+}
+
+impl TryFrom<i8> for Status {
+    type Error = LogError;
+
+    fn try_from(v: i8) -> Result<Self, Self::Error> {
+        match v {
+            -1 => Ok(Status::Failed),
+            0 => Ok(Status::Open),
+            1 => Ok(Status::Frozen),
+            2 => Ok(Status::Deleted),
+            _ => Err(LogError::InvalidInput(format!("invalid status {}", v))),
+        }
+    }
+}
+
+impl FromCqlVal for Status {
+    fn from_cql(cql_val: &CqlValue) -> Result<Self, FromCqlValError> {
+        Status::try_from(i8::from_cql(cql_val)?).map_err(|_| FromCqlValError::BadVal)
+    }
+}
+
+impl ToCqlVal for Status {
+    fn to_cql(&self) -> CqlValue {
+        CqlValue::TinyInt(*self as i8)
+    }
+}
+
+// a status change of a log, see append_status.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StatusEntry {
+    pub at: i64, // unix ms
+    pub status: Status,
+    pub error: String,
+}
+
+impl StatusEntry {
+    // the entry holding the current status, the newest one, None if the
+    // status never changed. Of changes in the same millisecond the first
+    // wins, the history is read newest first.
+    pub fn latest(history: &[StatusEntry]) -> Option<&StatusEntry> {
+        history.iter().rev().max_by_key(|e| e.at)
+    }
+
+    fn from_cols(cols: &ColumnsMap) -> Result<Self, LogError> {
+        Ok(Self {
+            at: cols.get_as("at")?,
+            status: cols.get_as("status")?,
+            error: if cols.has("error") {
+                cols.get_as("error")?
+            } else {
+                String::new()
+            },
+        })
+    }
+}
+
+// log counts by status, see count_by_status.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StatusCount {
+    pub failed: u64,     // -1
+    pub processing: u64, // 0
+    pub success: u64,    // 1
+}
+
+impl StatusCount {
+    pub fn add(&mut self, status: i8) {
+        match Status::try_from(status) {
+            Ok(Status::Failed) => self.failed += 1,
+            Ok(Status::Open) => self.processing += 1,
+            Ok(Status::Frozen) => self.success += 1,
+            _ => {}
+        }
+    }
+}
+
+// ids per IN query of get_many.
+pub const GET_MANY_CHUNK: usize = 100;
+
+// logs per batch_upsert, which writes them as one batch.
+pub const BATCH_UPSERT_MAX: usize = 100;
+
+// the query timeout, shrunk to the remaining request budget if any.
+fn timeout_of(budget_ms: Option<u64>) -> u64 {
+    budget_ms.map_or(QUERY_TIMEOUT_MS, |b| b.clamp(1, QUERY_TIMEOUT_MS))
+}
+
+// the budget of a paged scan left since it started.
+fn budget_left(budget_ms: Option<u64>, started: Instant) -> Option<u64> {
+    budget_ms.map(|b| b.saturating_sub(started.elapsed().as_millis() as u64))
+}
+
+// a query with the timeout of the remaining request budget. The timeout is
+// bound to the statement rather than written into the text, which would make
+// a distinct prepared statement per budget. A spent budget fails up front.
+fn timed_query(query: String, budget_ms: Option<u64>) -> Result<Query, LogError> {
+    if budget_ms == Some(0) {
+        return Err(LogError::DeadlineExceeded);
+    }
+    let mut query = Query::new(query);
+    query.set_request_timeout(Some(Duration::from_millis(timeout_of(budget_ms))));
+    Ok(query)
+}
+
+// the ids in their first order, without repeats.
+fn dedup_ids(ids: &[xid::Id]) -> Vec<xid::Id> {
+    let mut seen: HashSet<xid::Id> = HashSet::with_capacity(ids.len());
+    ids.iter().filter(|id| seen.insert(**id)).copied().collect()
+}
+
+fn merge_newest(lists: Vec<Vec<Log>>, limit: usize) -> Vec<Log> {
+    let mut res: Vec<Log> = lists.into_iter().flatten().collect();
+    res.sort_by_key(|doc| std::cmp::Reverse(doc.id.0));
+    res.truncate(limit);
+    res
+}
+
+fn changed_after(logs: Vec<Log>, updated_after: i64) -> Vec<Log> {
+    let mut res: Vec<Log> = logs
+        .into_iter()
+        .filter(|doc| doc.updated_at > updated_after)
+        .collect();
+    res.sort_by(|a, b| {
+        a.updated_at
+            .cmp(&b.updated_at)
+            .then_with(|| a.id.0.cmp(&b.id.0))
+    });
+    res
+}
+
+// at most `limit` logs after the cursor and updated at or before
+// settled_at, ordered by (updated_at, id). The bool is true if logs were left
+// out by the limit.
+fn after_cursor(
+    logs: Vec<Log>,
+    cursor: (i64, xid::Id),
+    settled_at: i64,
+    limit: usize,
+) -> (Vec<Log>, bool) {
+    let mut res: Vec<Log> = logs
+        .into_iter()
+        .filter(|doc| {
+            doc.updated_at <= settled_at && (doc.updated_at, doc.id.0) > (cursor.0, cursor.1 .0)
+        })
+        .collect();
+    res.sort_by(|a, b| {
+        a.updated_at
+            .cmp(&b.updated_at)
+            .then_with(|| a.id.0.cmp(&b.id.0))
+    });
+
+    let has_more = res.len() > limit;
+    res.truncate(limit);
+    (res, has_more)
+}
+
+// keeps the first log of every action, logs should be ordered newest first.
+fn newest_per_action(logs: Vec<Log>) -> Vec<Log> {
+    let mut seen: Vec<i8> = Vec::new();
+    let mut res: Vec<Log> = Vec::new();
+    for doc in logs {
+        if !seen.contains(&doc.action) {
+            seen.push(doc.action);
+            res.push(doc);
+        }
+    }
+    res
+}
+
+fn fill_histogram(counts: &mut [u64], since: u32, bucket_seconds: u32, unix_ts: u32) {
+    if unix_ts < since {
+        return;
+    }
+    let i = ((unix_ts - since) / bucket_seconds) as usize;
+    if i < counts.len() {
+        counts[i] += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::OnceCell;
+
+    use super::*;
+    use crate::conf;
+
+    static DB: OnceCell<scylladb::ScyllaDB> = OnceCell::const_new();
+
+    async fn get_db() -> scylladb::ScyllaDB {
+        let cfg = conf::Conf::new().unwrap_or_else(|err| panic!("config error: {}", err));
+        let res = scylladb::ScyllaDB::new(cfg.scylla, "logbase_test").await;
+        res.unwrap()
+    }
+
+    #[test]
+    fn fill_histogram_works() {
+        let since = 1_700_000_000u32;
+        let mut counts = vec![0u64; 3];
+        for ts in [
+            since,
+            since + 59,
+            since + 60,
+            since + 179,
+            since + 180,
+            since - 1,
+        ] {
+            let id = xid_from_unix(ts);
+            fill_histogram(&mut counts, since, 60, xid_unix(&id));
+        }
+        assert_eq!(counts, vec![2, 1, 1]);
+
+        assert_eq!(Log::histogram_buckets(since, since + 180, 60), 3);
+        assert_eq!(Log::histogram_buckets(since, since + 181, 60), 4);
+        assert_eq!(Log::histogram_buckets(since, since + 1, 60), 1);
+        assert_eq!(Log::histogram_buckets(since, since, 60), 0);
+    }
+
+    #[test]
+    fn log_error_works() {
+        let cases = vec![
+            (LogError::NotFound, 404),
+            (LogError::Frozen, 400),
+            (LogError::InvalidField("abc".to_string()), 400),
+            (LogError::InvalidInput("tokens overflow".to_string()), 400),
+            (LogError::Conflict("try again".to_string()), 409),
+            (LogError::Unavailable, 503),
+            (LogError::DeadlineExceeded, 504),
+            (LogError::Db(anyhow::anyhow!("connection reset")), 500),
+        ];
+        for (err, code) in cases {
+            let err: HTTPError = err.into();
+            assert_eq!(err.code, code);
+        }
+
+        let err: HTTPError = LogError::InvalidField("abc".to_string()).into();
+        assert_eq!(err.message, "Invalid field: abc");
+
+        let err: LogError = anyhow::Error::from(SingleRowError::BadNumberOfRows(0)).into();
+        assert!(matches!(err, LogError::NotFound));
+        let err: LogError = anyhow::Error::from(scylladb::CircuitOpen).into();
+        assert!(matches!(err, LogError::Unavailable));
+        let err: LogError =
+            anyhow::Error::from(scylladb::TooManyTombstones("scanned over".to_string())).into();
+        assert!(matches!(err, LogError::TooManyTombstones(_)));
+        let err: HTTPError = err.into();
+        assert_eq!(err.code, 500);
+        assert!(err.message.contains("too many tombstones"));
+        assert!(matches!(
+            Log::select_fields(vec!["abc".to_string()], false),
+            Err(LogError::InvalidField(_))
+        ));
+    }
+
+    #[test]
+    fn changed_after_works() {
+        let uid = xid::new();
+        let logs: Vec<Log> = [300i64, 100, 500, 200]
+            .iter()
+            .map(|updated_at| {
+                let mut doc = Log::with_pk(uid, xid::new());
+                doc.updated_at = *updated_at;
+                doc
+            })
+            .collect();
+
+        let res = changed_after(logs.clone(), 150);
+        assert_eq!(
+            res.iter().map(|d| d.updated_at).collect::<Vec<i64>>(),
+            vec![200, 300, 500]
+        );
+        assert!(changed_after(logs.clone(), 500).is_empty());
+        assert_eq!(changed_after(logs, 0).len(), 4);
+    }
+
+    #[test]
+    fn after_cursor_works() {
+        let uid = xid::new();
+        let doc = |id: xid::Id, updated_at: i64| {
+            let mut doc = Log::with_pk(uid, id);
+            doc.updated_at = updated_at;
+            doc
+        };
+        let (a, b, c) = (xid::new(), xid::new(), xid::new());
+        let logs = vec![doc(c, 300), doc(a, 100), doc(b, 200)];
+
+        let (res, has_more) = after_cursor(logs.clone(), (0, xid::Id::default()), i64::MAX, 2);
+        assert_eq!(res.iter().map(|d| d.id).collect::<Vec<_>>(), vec![a, b]);
+        assert!(has_more);
+        let cursor = (res[1].updated_at, res[1].id);
+        let (res, has_more) = after_cursor(logs.clone(), cursor, i64::MAX, 2);
+        assert_eq!(res.iter().map(|d| d.id).collect::<Vec<_>>(), vec![c]);
+        assert!(!has_more);
+
+        // writes after settled_at wait for a later page
+        let (res, has_more) = after_cursor(logs.clone(), cursor, 299, 2);
+        assert!(res.is_empty());
+        assert!(!has_more);
+        let (res, _) = after_cursor(logs, (0, xid::Id::default()), 200, 10);
+        assert_eq!(res.iter().map(|d| d.id).collect::<Vec<_>>(), vec![a, b]);
+
+        // a is updated after the sync, it comes back with a later cursor
+        let logs = vec![doc(c, 300), doc(a, 400), doc(b, 200)];
+        let (res, _) = after_cursor(logs, (300, c), i64::MAX, 10);
+        assert_eq!(res.len(), 1);
+        assert_eq!((res[0].updated_at, res[0].id), (400, a));
+
+        // ties on updated_at are ordered by id
+        let logs = vec![doc(b, 500), doc(a, 500)];
+        let (res, _) = after_cursor(logs.clone(), (0, xid::Id::default()), i64::MAX, 10);
+        assert_eq!(
+            res.iter().map(|d| (d.updated_at, d.id)).collect::<Vec<_>>(),
+            vec![(500, a), (500, b)]
+        );
+        let (res, _) = after_cursor(logs, (500, a), i64::MAX, 10);
+        assert_eq!(res.iter().map(|d| d.id).collect::<Vec<_>>(), vec![b]);
+    }
+
+    #[test]
+    fn status_entry_latest_works() {
+        assert!(StatusEntry::latest(&[]).is_none());
+
+        let entry = |at: i64, status: Status, error: &str| StatusEntry {
+            at,
+            status,
+            error: error.to_string(),
+        };
+        // in any order, the newest change wins
+        let history = vec![
+            entry(1690000001000, Status::Open, ""),
+            entry(1690000003000, Status::Failed, "timeout"),
+            entry(1690000002000, Status::Open, "retrying"),
+        ];
+        let latest = StatusEntry::latest(&history).unwrap();
+        assert_eq!(latest.status, Status::Failed);
+        assert_eq!(latest.error, "timeout");
+    }
+
+    #[test]
+    fn status_works() {
+        for s in [
+            Status::Failed,
+            Status::Open,
+            Status::Frozen,
+            Status::Deleted,
+        ] {
+            assert_eq!(Status::try_from(i8::from(s)).unwrap(), s);
+        }
+        assert_eq!(i8::from(Status::Failed), -1);
+        assert_eq!(Status::try_from(1).unwrap(), Status::Frozen);
+        assert!(matches!(
+            Status::try_from(3),
+            Err(LogError::InvalidInput(msg)) if msg == "invalid status 3"
+        ));
+        assert!(Status::try_from(-2).is_err());
+
+        // i8 on the wire and in Scylla
+        assert_eq!(serde_json::to_string(&Status::Failed).unwrap(), "-1");
+        assert_eq!(serde_json::from_str::<Status>("1").unwrap(), Status::Frozen);
+        let err = serde_json::from_str::<Status>("3").unwrap_err();
+        assert!(err.to_string().contains("invalid status 3"));
+        assert_eq!(Status::Deleted.to_cql(), CqlValue::TinyInt(2));
+        assert_eq!(
+            Status::from_cql(&CqlValue::TinyInt(-1)).unwrap(),
+            Status::Failed
+        );
+        assert!(Status::from_cql(&CqlValue::TinyInt(5)).is_err());
+        assert_eq!(Status::default(), Status::Open);
+    }
+
+    #[test]
+    fn status_count_works() {
+        let mut counts = StatusCount::default();
+        for status in [1i8, -1, 0, 1, 2, 0, 1] {
+            counts.add(status);
+        }
+        assert_eq!(
+            counts,
+            StatusCount {
+                failed: 1,
+                processing: 2,
+                success: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn check_action_unchanged_works() {
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("tokens", &1i32);
+        assert!(Log::check_action_unchanged(8, &cols).is_ok());
+        cols.set_as("action", &8i8);
+        assert!(Log::check_action_unchanged(8, &cols).is_ok());
+        cols.set_as("action", &40i8);
+        let err: HTTPError = Log::check_action_unchanged(8, &cols).unwrap_err().into();
+        assert_eq!(err.code, 400);
+    }
+
+    #[test]
+    fn check_frozen_works() {
+        let mutable = vec!["error".to_string()];
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("tokens", &1i32);
+        assert!(Log::check_frozen(Status::Open, &cols, &[]).is_ok());
+        assert!(matches!(
+            Log::check_frozen(Status::Frozen, &cols, &mutable),
+            Err(LogError::Frozen)
+        ));
+
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("error", &"retry failed".to_string());
+        assert!(Log::check_frozen(Status::Failed, &cols, &mutable).is_ok());
+        assert!(Log::check_frozen(Status::Failed, &cols, &[]).is_err());
+        cols.set_as("status", &-1i8);
+        assert!(Log::check_frozen(Status::Failed, &cols, &mutable).is_ok());
+        cols.set_as("status", &1i8);
+        assert!(Log::check_frozen(Status::Failed, &cols, &mutable).is_err());
+        assert!(Log::check_frozen(Status::Frozen, &ColumnsMap::new(), &mutable).is_err());
+    }
+
+    #[test]
+    fn append_frozen_works() {
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"retry failed".to_string());
+        assert!(!Log::append_frozen(Status::Open, "timeout", &mut cols).unwrap());
+        assert_eq!(cols.get_as::<String>("error").unwrap(), "retry failed");
+
+        assert!(Log::append_frozen(Status::Failed, "timeout", &mut cols).unwrap());
+        assert_eq!(
+            cols.get_as::<String>("error").unwrap(),
+            "timeout\nretry failed"
+        );
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"retry failed".to_string());
+        assert!(Log::append_frozen(Status::Frozen, "", &mut cols).unwrap());
+        assert_eq!(cols.get_as::<String>("error").unwrap(), "retry failed");
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("tokens", &1i32);
+        assert!(!Log::append_frozen(Status::Frozen, "timeout", &mut cols).unwrap());
+
+        // clears are rejected
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"".to_string());
+        assert!(matches!(
+            Log::append_frozen(Status::Frozen, "timeout", &mut cols),
+            Err(LogError::InvalidInput(_))
+        ));
+        assert!(Log::append_frozen(Status::Open, "timeout", &mut cols).is_ok());
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("payload", &Vec::<u8>::new());
+        assert!(matches!(
+            Log::append_frozen(Status::Failed, "", &mut cols),
+            Err(LogError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn check_override_works() {
+        let mutable = vec!["tokens".to_string()];
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"audit correction".to_string());
+
+        // open logs and mutable fields need no override
+        assert!(!Log::check_override(8, Status::Open, &cols, &mutable, &[8]).unwrap());
+        let mut tokens = ColumnsMap::with_capacity(1);
+        tokens.set_as("tokens", &1i32);
+        assert!(!Log::check_override(8, Status::Frozen, &tokens, &mutable, &[8]).unwrap());
+
+        assert!(Log::check_override(8, Status::Frozen, &cols, &mutable, &[8]).unwrap());
+        assert!(Log::check_override(8, Status::Failed, &cols, &mutable, &[8, 40]).unwrap());
+        assert!(matches!(
+            Log::check_override(40, Status::Frozen, &cols, &mutable, &[8]),
+            Err(LogError::Frozen)
+        ));
+        assert!(matches!(
+            Log::check_override(8, Status::Frozen, &cols, &mutable, &[]),
+            Err(LogError::Frozen)
+        ));
+
+        // only correction fields, the status can be restated but not changed
+        cols.set_as("status", &Status::Frozen);
+        assert!(Log::check_override(8, Status::Frozen, &cols, &mutable, &[8]).unwrap());
+        assert!(matches!(
+            Log::check_override(8, Status::Failed, &cols, &mutable, &[8]),
+            Err(LogError::InvalidInput(msg)) if msg.starts_with("status of a frozen log")
+        ));
+        cols.set_as("payload", &vec![1u8]);
+        assert!(matches!(
+            Log::check_override(8, Status::Frozen, &cols, &mutable, &[8]),
+            Err(LogError::InvalidInput(msg)) if msg.starts_with("payload of a frozen log")
+        ));
+    }
+
+    #[test]
+    fn merge_newest_works() {
+        let (a, b) = (xid::new(), xid::new());
+        let now = (unix_ms() / 1000) as u32;
+        let log = |uid: xid::Id, ago: u32| {
+            let mut id = xid_from_unix(now - ago);
+            id.0[11] = ago as u8;
+            Log::with_pk(uid, id)
+        };
+
+        let res = merge_newest(
+            vec![
+                vec![log(a, 1), log(a, 4), log(a, 5)],
+                vec![log(b, 2), log(b, 3), log(b, 6)],
+            ],
+            5,
+        );
+        let got: Vec<(xid::Id, u32)> = res.iter().map(|r| (r.uid, now - xid_unix(&r.id))).collect();
+        assert_eq!(got, vec![(a, 1), (b, 2), (b, 3), (a, 4), (a, 5)]);
+        assert!(merge_newest(vec![], 5).is_empty());
+    }
+
+    #[test]
+    fn dedup_ids_works() {
+        let (a, b, c) = (xid::new(), xid::new(), xid::new());
+        assert_eq!(dedup_ids(&[a, b, a, c, b, a]), vec![a, b, c]);
+        assert_eq!(dedup_ids(&[c, c]), vec![c]);
+        assert!(dedup_ids(&[]).is_empty());
+    }
+
+    #[test]
+    fn timed_query_works() {
+        let timeout = |budget_ms: Option<u64>| {
+            timed_query("SELECT id FROM log".to_string(), budget_ms)
+                .unwrap()
+                .get_request_timeout()
+        };
+        assert_eq!(timeout(None), Some(Duration::from_millis(3000)));
+        assert_eq!(timeout(Some(250)), Some(Duration::from_millis(250)));
+        assert_eq!(timeout(Some(1)), Some(Duration::from_millis(1)));
+        assert_eq!(timeout(Some(60_000)), Some(Duration::from_millis(3000)));
+
+        let query = timed_query("SELECT id FROM log".to_string(), Some(250)).unwrap();
+        assert_eq!(query.contents, "SELECT id FROM log");
+        assert!(matches!(
+            timed_query("SELECT id FROM log".to_string(), Some(0)),
+            Err(LogError::DeadlineExceeded)
+        ));
+    }
+
+    #[test]
+    fn budget_left_works() {
+        let started = Instant::now();
+        assert_eq!(budget_left(None, started), None);
+        assert!(budget_left(Some(60_000), started).unwrap() > 59_000);
+        let started = started - Duration::from_millis(500);
+        assert_eq!(budget_left(Some(200), started), Some(0));
+    }
+
+    #[test]
+    fn newest_per_action_works() {
+        let uid = xid::new();
+        let mut logs: Vec<Log> = (0..6)
+            .map(|i| {
+                let mut doc = Log::with_pk(uid, xid::new());
+                doc.action = i % 3;
+                doc
+            })
+            .collect();
+        // newest first
+        logs.sort_by_key(|d| std::cmp::Reverse(d.id.0));
+        let expected: Vec<(i8, xid::Id)> = logs[0..3].iter().map(|d| (d.action, d.id)).collect();
+
+        let res = newest_per_action(logs);
+        assert_eq!(res.len(), 3);
+        assert_eq!(
+            res.iter().map(|d| (d.action, d.id)).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn log_model_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let id = xid::new();
+        let mut doc = Log::with_pk(uid, id);
+
+        let res = doc.get_one(db, vec![]).await;
+        assert!(res.is_err());
+        let err: HTTPError = res.unwrap_err().into();
+        assert_eq!(err.code, 404);
+
+        let content: Vec<u8> = vec![0x80];
+
+        let mut cols = ColumnsMap::with_capacity(5);
+        cols.set_as("action", &1i8);
+        cols.set_as("ip", &"1.2.3.4".to_string());
+        cols.set_as("tokens", &(1000i32));
+        cols.set_as("payload", &content);
+        cols.set_as("trace_id", &"some-request-id".to_string());
+
+        doc.upsert_fields(db, cols).await.unwrap();
+
+        let mut doc2 = Log::with_pk(uid, id);
+        doc2.get_one(db, vec![]).await.unwrap();
+
+        assert_eq!(doc2.action, 1i8);
+        assert_eq!(doc2.gid, xid::Id::default());
+        assert_eq!(doc2.ip, "1.2.3.4".to_string());
+        assert_eq!(doc2.tokens, 1000i32);
+        assert_eq!(doc2.payload, content);
+        assert_eq!(doc2.error, "".to_string());
+        assert_eq!(doc2.trace_id, "some-request-id".to_string());
+
+        let mut doc3 = Log::with_pk(uid, id);
+        doc3.get_one(db, vec!["error".to_string()]).await.unwrap();
+        assert_eq!(doc3.tokens, 0i32);
+        assert_eq!(doc3.payload.len(), 0);
+        assert_eq!(doc3.error, "".to_string());
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"some error".to_string());
+        doc.upsert_fields(db, cols).await.unwrap();
+
+        let mut doc3 = Log::with_pk(uid, id);
+        doc3.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc3.tokens, 1000i32);
+        assert_eq!(doc3.payload, content);
+        assert_eq!(doc3.error, "some error".to_string());
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("action", &2i8);
+        let err: HTTPError = doc.upsert_fields(db, cols).await.unwrap_err().into();
+        assert_eq!(err.code, 400);
+
+        let mut doc = Log::with_pk(uid, xid::new());
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("action", &2i8);
+        cols.set_as("error", &"some error".to_string());
+        doc.upsert_fields(db, cols).await.unwrap();
+        doc.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc.tokens, 0i32);
+        assert_eq!(doc.payload.len(), 0);
+        assert_eq!(doc.error, "some error".to_string());
+
+        let docs = Log::list_recently(db, uid, vec![], vec![1i8, 2i8], 1000, None)
+            .await
+            .unwrap();
+        assert_eq!(2, docs.len());
+        assert_eq!(docs[0].action, 2i8);
+        assert_eq!(docs[1].action, 1i8);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn purge_before_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let now = (unix_ms() / 1000) as u32;
+
+        let mut old_id = xid_from_unix(now - 3600 * 24 * 10);
+        old_id.0[11] = 1;
+        let mut doc = Log::with_pk(uid, old_id);
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("action", &1i8);
+        doc.upsert_fields(db, cols).await.unwrap();
+
+        let new_id = xid::new();
+        let mut doc = Log::with_pk(uid, new_id);
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("action", &1i8);
+        doc.upsert_fields(db, cols).await.unwrap();
+
+        let deleted = Log::purge_before(db, uid, now - 3600 * 24 * 7, false, 1000)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+
+        let mut doc = Log::with_pk(uid, old_id);
+        assert!(doc.get_one(db, vec![]).await.is_err());
+        let mut doc = Log::with_pk(uid, new_id);
+        doc.get_one(db, vec![]).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn action_histogram_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let since = (unix_ms() / 1000) as u32 - 3600;
+        let until = since + 180;
+
+        // (seconds after since, action), the last two are outside the window.
+        let logs = [
+            (0i64, 1i8),
+            (30, 2),
+            (59, 1),
+            (60, 1),
+            (179, 2),
+            (180, 1),
+            (-1, 1),
+        ];
+        for (i, (offset, action)) in logs.iter().enumerate() {
+            let mut id = xid_from_unix((since as i64 + offset) as u32);
+            id.0[11] = i as u8 + 1;
+            let mut doc = Log::with_pk(uid, id);
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("action", action);
+            doc.upsert_fields(db, cols).await.unwrap();
+        }
+
+        let (counts, truncated) =
+            Log::action_histogram(db, uid, None, 60, since, until, 2, 100, None)
+                .await
+                .unwrap();
+        assert_eq!(counts, vec![3, 1, 1]);
+        assert!(!truncated);
+
+        let (counts, _) = Log::action_histogram(db, uid, Some(1), 60, since, until, 2, 100, None)
+            .await
+            .unwrap();
+        assert_eq!(counts, vec![2, 1, 0]);
+
+        let (counts, _) = Log::action_histogram(db, uid, Some(2), 100, since, until, 2, 100, None)
+            .await
+            .unwrap();
+        assert_eq!(counts, vec![1, 1]);
+
+        let (counts, _) = Log::action_histogram(db, uid, Some(3), 60, since, until, 2, 100, None)
+            .await
+            .unwrap();
+        assert_eq!(counts, vec![0, 0, 0]);
+
+        // the scan stops at max_scan, newest first
+        let (counts, truncated) =
+            Log::action_histogram(db, uid, None, 60, since, until, 2, 3, None)
+                .await
+                .unwrap();
+        assert_eq!(counts, vec![1, 1, 1]);
+        assert!(truncated);
+        assert!(matches!(
+            Log::action_histogram(db, uid, None, 60, since, until, 2, 100, Some(0)).await,
+            Err(LogError::DeadlineExceeded)
+        ));
+
+        assert!(
+            Log::action_histogram(db, uid, None, 0, since, until, 2, 100, None)
+                .await
+                .is_err()
+        );
+        assert!(
+            Log::action_histogram(db, uid, None, 60, until, since, 2, 100, None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn latest_per_action_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let now = (unix_ms() / 1000) as u32;
+
+        let mut newest: Vec<(i8, xid::Id)> = Vec::new();
+        for i in 0..6u32 {
+            let mut id = xid_from_unix(now - 60 + i);
+            id.0[11] = i as u8 + 1;
+            let action = (i % 3) as i8;
+            let mut doc = Log::with_pk(uid, id);
+            let mut cols = ColumnsMap::with_capacity(2);
+            cols.set_as("action", &action);
+            cols.set_as("tokens", &(i as i32));
+            doc.upsert_fields(db, cols).await.unwrap();
+            if i >= 3 {
+                newest.insert(0, (action, id));
+            }
+        }
+
+        let res = Log::latest_per_action(db, uid, vec!["tokens".to_string()], 2, 100)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.iter().map(|d| (d.action, d.id)).collect::<Vec<_>>(),
+            newest
+        );
+        assert_eq!(res[0].tokens, 5);
+        assert!(!res[0]._fields.contains(&"payload".to_string()));
+
+        let res = Log::latest_per_action(db, xid::new(), vec![], 2, 100)
+            .await
+            .unwrap();
+        assert!(res.is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn bootstrap_works() {
+        let cfg = conf::Conf::new().unwrap_or_else(|err| panic!("config error: {}", err));
+        let keyspace = "logbase_bootstrap_test";
+        let db = scylladb::ScyllaDB::new(cfg.scylla.clone(), "")
+            .await
+            .unwrap();
+        db.execute(format!("DROP KEYSPACE IF EXISTS {}", keyspace), &[])
+            .await
+            .unwrap();
+        db.execute(
+            format!("CREATE KEYSPACE {} WITH replication = {{ 'class': 'SimpleStrategy', 'replication_factor': '1' }}", keyspace),
+            &[],
+        )
+        .await
+        .unwrap();
+        assert!(!db.table_exists(keyspace, "log").await.unwrap());
+
+        crate::db::bootstrap(&db, keyspace).await.unwrap();
+        assert!(db.table_exists(keyspace, "log").await.unwrap());
+        assert!(db.table_exists(keyspace, "log_external").await.unwrap());
+        // idempotent
+        crate::db::bootstrap(&db, keyspace).await.unwrap();
+        assert!(crate::db::bootstrap(&db, "bad;keyspace").await.is_err());
+
+        let db = scylladb::ScyllaDB::new(cfg.scylla, keyspace).await.unwrap();
+        let mut doc = Log::with_pk(xid::new(), xid::new());
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &8i8);
+        cols.set_as("ip", &"1.2.3.4".to_string());
+        doc.upsert_fields(&db, cols).await.unwrap();
+
+        let mut doc2 = Log::with_pk(doc.uid, doc.id);
+        doc2.get_one(&db, vec![]).await.unwrap();
+        assert_eq!(doc2.action, 8);
+        assert_eq!(doc2.ip, "1.2.3.4");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn newest_first_works() {
+        let db = DB.get_or_init(get_db).await;
+
+        let query = "SELECT clustering_order FROM system_schema.columns WHERE keyspace_name=? AND table_name=? AND column_name=?";
+        let row = db
+            .execute(query, ("logbase_test", "log", "id"))
+            .await
+            .unwrap()
+            .single_row()
+            .unwrap();
+        let order = row.columns[0].as_ref().and_then(|v| v.as_text()).unwrap();
+        assert_eq!(order, "desc");
+
+        let uid = xid::new();
+        let now = (unix_ms() / 1000) as u32;
+        let mut ids: Vec<xid::Id> = Vec::new();
+        for i in 0..5u32 {
+            let mut id = xid_from_unix(now - 100 + i);
+            id.0[11] = i as u8;
+            let mut doc = Log::with_pk(uid, id);
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("action", &8i8);
+            doc.upsert_fields(db, cols).await.unwrap();
+            ids.push(id);
+        }
+        ids.reverse();
+
+        let docs = Log::list(db, uid, vec![], 3, None, None, None, None)
+            .await
+            .unwrap();
+        let mut got: Vec<xid::Id> = docs.iter().map(|d| d.id).collect();
+        let docs = Log::list(db, uid, vec![], 3, Some(got[2]), None, None, None)
+            .await
+            .unwrap();
+        got.extend(docs.iter().map(|d| d.id));
+        assert_eq!(got, ids);
+
+        let docs = Log::list_recently(db, uid, vec![], vec![], 10, None)
+            .await
+            .unwrap();
+        let got: Vec<xid::Id> = docs.iter().map(|d| d.id).collect();
+        assert_eq!(got, ids);
+
+        let docs = Log::list_recently(db, uid, vec![], vec![], 2, None)
+            .await
+            .unwrap();
+        let got: Vec<xid::Id> = docs.iter().map(|d| d.id).collect();
+        assert_eq!(got, ids[..2].to_vec());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn list_changed_since_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let since = (unix_ms() / 1000) as u32 - 60;
+
+        let mut ids: Vec<xid::Id> = Vec::new();
+        for _ in 0..3 {
+            let mut doc = Log::with_pk(uid, xid::new());
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("action", &40i8);
+            doc.upsert_fields(db, cols).await.unwrap();
+            ids.push(doc.id);
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let checkpoint = unix_ms() as i64;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let mut doc = Log::with_pk(uid, ids[1]);
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("tokens", &7i32);
+        cols.set_as("status", &1i8);
+        doc.upsert_fields(db, cols).await.unwrap();
+
+        let (res, truncated) = Log::list_changed_since(
+            db,
+            uid,
+            vec!["tokens".to_string()],
+            checkpoint,
+            since,
+            10,
+            100,
+        )
+        .await
+        .unwrap();
+        assert!(!truncated);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id, ids[1]);
+        assert_eq!(res[0].tokens, 7);
+        assert_eq!(res[0].status, Status::Frozen);
+        assert!(res[0].updated_at > checkpoint);
+
+        let (res, _) = Log::list_changed_since(db, uid, vec![], 0, since, 10, 100)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[2].id, ids[1]);
+
+        // a scan reading exactly max_scan logs is not truncated
+        let (res, truncated) = Log::list_changed_since(db, uid, vec![], 0, since, 2, 3)
+            .await
+            .unwrap();
+        assert!(!truncated);
+        assert_eq!(res.len(), 3);
+        let (res, truncated) = Log::list_changed_since(db, uid, vec![], 0, since, 2, 2)
+            .await
+            .unwrap();
+        assert!(truncated);
+        assert_eq!(res.len(), 2);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn get_many_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+
+        let mut ids: Vec<xid::Id> = Vec::new();
+        let mut present: Vec<xid::Id> = Vec::new();
+        for i in 0..(GET_MANY_CHUNK * 2 + 50) {
+            let id = xid::new();
+            ids.push(id);
+            if i % 3 != 0 {
+                let mut doc = Log::with_pk(uid, id);
+                let mut cols = ColumnsMap::with_capacity(1);
+                cols.set_as("action", &8i8);
+                doc.upsert_fields(db, cols).await.unwrap();
+                present.push(id);
+            }
+        }
+
+        let res = Log::get_many(db, uid, &ids, vec!["action".to_string()], None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), present.len());
+        present.reverse(); // newest first
+        assert_eq!(res.iter().map(|r| r.id).collect::<Vec<xid::Id>>(), present);
+        assert!(res.iter().all(|r| r.action == 8));
+
+        let twice = [present[0], present[1], present[0]];
+        let res = Log::get_many(db, uid, &twice, vec![], Some(1000))
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 2);
+        assert!(matches!(
+            Log::get_many(db, uid, &ids, vec![], Some(0)).await,
+            Err(LogError::DeadlineExceeded)
+        ));
+
+        assert!(Log::get_many(db, uid, &[], vec![], None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn drop_absent_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let now = (unix_ms() / 1000) as u32;
+
+        // ids of logs older than the oldest log, e.g. purged ones
+        let mut ids: Vec<xid::Id> = (0..GET_MANY_CHUNK)
+            .map(|i| {
+                let mut id = xid_from_unix(now - 3600);
+                id.0[10] = (i / 256) as u8;
+                id.0[11] = (i % 256) as u8;
+                id
+            })
+            .collect();
+        for i in 0..20 {
+            let id = xid::new();
+            ids.push(id);
+            if i % 2 == 0 {
+                let mut doc = Log::with_pk(uid, id);
+                let mut cols = ColumnsMap::with_capacity(1);
+                cols.set_as("action", &8i8);
+                doc.upsert_fields(db, cols).await.unwrap();
+            }
+        }
+
+        let kept = Log::drop_absent(db, uid, ids.clone()).await.unwrap();
+        assert_eq!(kept.len(), 20);
+        let with = Log::get_many(db, uid, &kept, vec![], None).await.unwrap();
+        let without = Log::get_many(db, uid, &ids, vec![], None).await.unwrap();
+        assert_eq!(with.len(), 10);
+        assert_eq!(
+            with.iter().map(|r| r.id).collect::<Vec<xid::Id>>(),
+            without.iter().map(|r| r.id).collect::<Vec<xid::Id>>()
+        );
+
+        // a single IN query is not worth the probe
+        let few = ids[0..GET_MANY_CHUNK].to_vec();
+        assert_eq!(Log::drop_absent(db, uid, few.clone()).await.unwrap(), few);
+        // no logs at all
+        assert!(Log::drop_absent(db, xid::new(), ids)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn sync_page_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let since = (unix_ms() / 1000) as u32 - 60;
+
+        let mut ids: Vec<xid::Id> = Vec::new();
+        for _ in 0..3 {
+            let mut doc = Log::with_pk(uid, xid::new());
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("action", &8i8);
+            doc.upsert_fields(db, cols).await.unwrap();
+            ids.push(doc.id);
+        }
+
+        // the writes are within the lag
+        let page = Log::sync_page(
+            db,
+            uid,
+            vec![],
+            (0, xid::Id::default()),
+            since,
+            60_000,
+            10,
+            100,
+            10,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(page.logs.is_empty());
+        assert_eq!(page.cursor, (0, xid::Id::default()));
+
+        let page = Log::sync_page(
+            db,
+            uid,
+            vec![],
+            (0, xid::Id::default()),
+            since,
+            0,
+            10,
+            100,
+            10,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.logs.iter().map(|r| r.id).collect::<Vec<_>>(), ids);
+        assert!(!page.has_more);
+        let last = page.logs.last().unwrap();
+        let cursor = (last.updated_at, last.id);
+        assert_eq!(page.cursor, cursor);
+
+        let page = Log::sync_page(db, uid, vec![], cursor, since, 0, 10, 100, 10, None)
+            .await
+            .unwrap();
+        assert!(page.logs.is_empty());
+
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let mut doc = Log::with_pk(uid, ids[0]);
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("tokens", &10i32);
+        doc.upsert_fields(db, cols).await.unwrap();
+
+        let page = Log::sync_page(
+            db,
+            uid,
+            vec!["tokens".to_string()],
+            cursor,
+            since,
+            0,
+            10,
+            100,
+            10,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.logs.len(), 1);
+        assert_eq!(page.logs[0].id, ids[0]);
+        assert_eq!(page.logs[0].tokens, 10);
+        assert!(page.logs[0].updated_at > cursor.0);
+        assert_eq!(page.cursor, (page.logs[0].updated_at, ids[0]));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn insert_if_absent_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let id = xid::new();
+
+        let mut doc = Log::with_pk(uid, id);
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &8i8);
+        cols.set_as("tokens", &1i32);
+        assert!(doc.insert_if_absent(db, &cols).await.unwrap());
+        assert!(doc.updated_at > 0);
+
+        let mut other = Log::with_pk(uid, id);
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &40i8);
+        cols.set_as("tokens", &2i32);
+        assert!(!other.insert_if_absent(db, &cols).await.unwrap());
+
+        let mut doc = Log::with_pk(uid, id);
+        doc.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc.action, 8);
+        assert_eq!(doc.tokens, 1);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn external_id_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let (id, other) = (xid::new(), xid::new());
+
+        assert!(matches!(
+            Log::get_external_id(db, uid, "order-1").await,
+            Err(LogError::NotFound)
+        ));
+        assert!(Log::put_external_id(db, uid, "order-1", id, true)
+            .await
+            .unwrap());
+        assert!(!Log::put_external_id(db, uid, "order-1", other, true)
+            .await
+            .unwrap());
+        assert_eq!(Log::get_external_id(db, uid, "order-1").await.unwrap(), id);
+
+        assert!(Log::put_external_id(db, uid, "order-1", other, false)
+            .await
+            .unwrap());
+        assert_eq!(
+            Log::get_external_id(db, uid, "order-1").await.unwrap(),
+            other
+        );
+
+        // released only while it maps to the id
+        Log::release_external_id(db, uid, "order-1", id)
+            .await
+            .unwrap();
+        assert_eq!(
+            Log::get_external_id(db, uid, "order-1").await.unwrap(),
+            other
+        );
+        Log::release_external_id(db, uid, "order-1", other)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Log::get_external_id(db, uid, "order-1").await,
+            Err(LogError::NotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn labels_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+
+        let mut ids: Vec<xid::Id> = Vec::new();
+        for env in ["prod", "dev", "prod"] {
+            let mut doc = Log::with_pk(uid, xid::new());
+            let mut cols = ColumnsMap::with_capacity(2);
+            cols.set_as("action", &8i8);
+            cols.set_as(
+                "labels",
+                &HashMap::from([
+                    ("env".to_string(), env.to_string()),
+                    ("region".to_string(), "us".to_string()),
+                ]),
+            );
+            doc.upsert_fields(db, cols).await.unwrap();
+            ids.push(doc.id);
+        }
+
+        let mut doc = Log::with_pk(uid, ids[1]);
+        doc.get_one(db, vec!["labels".to_string()]).await.unwrap();
+        assert_eq!(doc.labels.get("env").unwrap(), "dev");
+        assert_eq!(doc.labels.get("region").unwrap(), "us");
+
+        let res = Log::list_by_label(db, uid, vec![], 10, None, None, ("env", "prod"), None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.iter().map(|r| r.id).collect::<Vec<xid::Id>>(),
+            vec![ids[2], ids[0]]
+        );
+        let res = Log::list_by_label(
+            db,
+            uid,
+            vec![],
+            10,
+            None,
+            Some(40),
+            ("env", "prod"),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(res.is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn list_paging_is_stable() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let second = (unix_ms() / 1000) as u32 - 60;
+
+        // ids of the same second, differing only in the trailing bytes,
+        // inserted out of order.
+        let mut ids: Vec<xid::Id> = Vec::new();
+        for i in [7u8, 0, 255, 3, 128, 1, 64, 2, 200, 9] {
+            for j in [0u8, 1] {
+                let mut id = xid_from_unix(second);
+                id.0[4] = j;
+                id.0[11] = i;
+                ids.push(id);
+            }
+        }
+        for id in &ids {
+            let mut doc = Log::with_pk(uid, *id);
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("action", &8i8);
+            doc.upsert_fields(db, cols).await.unwrap();
+        }
+
+        let mut seen: Vec<xid::Id> = Vec::new();
+        let mut page_token: Option<xid::Id> = None;
+        loop {
+            let res = Log::list(db, uid, vec![], 1, page_token, None, None, None)
+                .await
+                .unwrap();
+            if res.is_empty() {
+                break;
+            }
+            assert_eq!(res.len(), 1);
+            page_token = Some(res[0].id);
+            seen.push(res[0].id);
+        }
+
+        ids.sort_by_key(|id| std::cmp::Reverse(id.0));
+        assert_eq!(seen, ids);
+
+        // the same pages again
+        let res = Log::list(db, uid, vec![], 5, Some(ids[4]), None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.iter().map(|r| r.id).collect::<Vec<xid::Id>>(),
+            ids[5..10].to_vec()
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn earliest_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        assert!(matches!(
+            Log::earliest(db, uid, vec![]).await,
+            Err(LogError::NotFound)
+        ));
+
+        let now = (unix_ms() / 1000) as u32;
+        // inserted out of order, the first one created is the oldest id
+        for (i, action) in [(30u32, 40i8), (10, 8), (20, 40)] {
+            let mut doc = Log::with_pk(uid, xid_from_unix(now - 100 + i));
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("action", &action);
+            doc.upsert_fields(db, cols).await.unwrap();
+        }
+
+        let doc = Log::earliest(db, uid, vec![]).await.unwrap();
+        assert_eq!(doc.uid, uid);
+        assert_eq!(doc.id, xid_from_unix(now - 90));
+        assert_eq!(doc.action, 8);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn list_before_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let now = (unix_ms() / 1000) as u32;
+        for i in 0..7u32 {
+            let mut doc = Log::with_pk(uid, xid_from_unix(now - 100 + i));
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("action", &(if i % 2 == 0 { 8i8 } else { 40i8 }));
+            doc.upsert_fields(db, cols).await.unwrap();
+        }
+
+        // forward two pages, then back one
+        let page1 = Log::list(db, uid, vec![], 3, None, None, None, None)
+            .await
+            .unwrap();
+        let page2 = Log::list(db, uid, vec![], 3, Some(page1[2].id), None, None, None)
+            .await
+            .unwrap();
+        let back = Log::list_before(db, uid, vec![], 3, page2[0].id, None, None)
+            .await
+            .unwrap();
+        let ids = |res: &[Log]| res.iter().map(|r| r.id).collect::<Vec<xid::Id>>();
+        assert_eq!(ids(&back), ids(&page1));
+        assert!(
+            Log::list_before(db, uid, vec![], 3, page1[0].id, None, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        let res = Log::list_before(db, uid, vec![], 10, page2[2].id, Some(now - 98), None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 5);
+        assert!(res[0].id.0 > res[1].id.0);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn sum_tokens_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let since = (unix_ms() / 1000) as u32 - 1;
+
+        let mut expected = 0i64;
+        for i in 0..(conf::MAX_PAGE_SIZE as i32 + 20) {
+            let mut doc = Log::with_pk(uid, xid::new());
+            let mut cols = ColumnsMap::with_capacity(2);
+            cols.set_as("action", &if i % 2 == 0 { 8i8 } else { 15i8 });
+            cols.set_as("tokens", &i);
+            doc.upsert_fields(db, cols).await.unwrap();
+            expected += i as i64;
+        }
+        let until = (unix_ms() / 1000) as u32 + 1;
+
+        let direct = Log::sum_tokens(db, uid, None, since, until, None)
+            .await
+            .unwrap();
+        assert_eq!(direct, expected);
+        let (streamed, truncated) =
+            Log::sum_tokens_streamed(db, uid, None, since, until, conf::MAX_PAGE_SIZE, 10, None)
+                .await
+                .unwrap();
+        assert_eq!(streamed, direct);
+        assert!(!truncated);
+
+        let direct = Log::sum_tokens(db, uid, Some(15), since, until, Some(1000))
+            .await
+            .unwrap();
+        let (streamed, _) = Log::sum_tokens_streamed(
+            db,
+            uid,
+            Some(15),
+            since,
+            until,
+            conf::MAX_PAGE_SIZE,
+            10,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(streamed, direct);
+
+        let (partial, truncated) =
+            Log::sum_tokens_streamed(db, uid, None, since, until, conf::MAX_PAGE_SIZE, 1, None)
+                .await
+                .unwrap();
+        assert!(partial < expected);
+        assert!(truncated);
+        // a spent deadline stops the scan with what was summed
+        let (partial, truncated) = Log::sum_tokens_streamed(
+            db,
+            uid,
+            None,
+            since,
+            until,
+            conf::MAX_PAGE_SIZE,
+            10,
+            Some(0),
+        )
+        .await
+        .unwrap();
+        assert_eq!(partial, 0);
+        assert!(truncated);
+
+        // a spent deadline fails before the query
+        let res = Log::sum_tokens(db, uid, None, since, until, Some(0)).await;
+        assert!(matches!(res, Err(LogError::DeadlineExceeded)));
+        // nothing in the window
+        let res = Log::sum_tokens(db, uid, None, since - 100, since - 10, None)
+            .await
+            .unwrap();
+        assert_eq!(res, 0);
+
+        // the sum of int columns past i32::MAX
+        let uid = xid::new();
+        for _ in 0..3 {
+            let mut doc = Log::with_pk(uid, xid::new());
+            let mut cols = ColumnsMap::with_capacity(2);
+            cols.set_as("action", &8i8);
+            cols.set_as("tokens", &i32::MAX);
+            doc.upsert_fields(db, cols).await.unwrap();
+        }
+        let until = (unix_ms() / 1000) as u32 + 1;
+        let res = Log::sum_tokens(db, uid, None, since, until, None)
+            .await
+            .unwrap();
+        assert_eq!(res, 3 * i32::MAX as i64);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn count_by_status_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+
+        for status in [-1i8, 0, 0, 1, 1, 1] {
+            let mut doc = Log::with_pk(uid, xid::new());
+            let mut cols = ColumnsMap::with_capacity(2);
+            cols.set_as("action", &8i8);
+            cols.set_as("status", &status);
+            doc.upsert_fields(db, cols).await.unwrap();
+        }
+
+        let (counts, truncated) = Log::count_by_status(db, uid, 1000, 1000, None)
+            .await
+            .unwrap();
+        assert!(!truncated);
+        assert_eq!(
+            counts,
+            StatusCount {
+                failed: 1,
+                processing: 2,
+                success: 3,
+            }
+        );
+        // pages of 2, the scan stops after the newest 4 logs
+        let (counts, truncated) = Log::count_by_status(db, uid, 2, 4, Some(1000))
+            .await
+            .unwrap();
+        assert!(truncated);
+        assert_eq!(counts.failed + counts.processing + counts.success, 4);
+        assert_eq!(counts.success, 3);
+        assert!(matches!(
+            Log::count_by_status(db, uid, 2, 4, Some(0)).await,
+            Err(LogError::DeadlineExceeded)
+        ));
+        assert_eq!(
+            Log::count_by_status(db, xid::new(), 1000, 1000, None)
+                .await
+                .unwrap(),
+            (StatusCount::default(), false)
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn batch_upsert_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let mut frozen = Log::with_pk(uid, xid::new());
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &8i8);
+        cols.set_as("status", &1i8);
+        frozen.upsert_fields(db, cols).await.unwrap();
+
+        let ids: Vec<xid::Id> = (0..3).map(|_| xid::new()).collect();
+        let mut rows: Vec<(xid::Id, ColumnsMap)> = Vec::new();
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("status", &-1i8);
+        cols.set_as("error", &"timeout".to_string());
+        rows.push((ids[0], cols));
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("tokens", &42i32);
+        rows.push((ids[1], cols));
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("status", &1i8);
+        cols.set_as("payload", &vec![0x80u8]);
+        rows.push((ids[2], cols));
+        assert_eq!(Log::batch_upsert(db, uid, rows, &[]).await.unwrap(), 3);
+
+        let mut doc = Log::with_pk(uid, ids[0]);
+        doc.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc.status, Status::Failed);
+        assert_eq!(doc.error, "timeout");
+        let mut doc = Log::with_pk(uid, ids[1]);
+        doc.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc.status, Status::Open);
+        assert_eq!(doc.tokens, 42);
+        let mut doc = Log::with_pk(uid, ids[2]);
+        doc.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc.status, Status::Frozen);
+        assert_eq!(doc.payload, vec![0x80]);
+        assert!(doc.updated_at > 0);
+
+        // one frozen log fails the whole batch before anything is written
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"late".to_string());
+        let mut other = ColumnsMap::with_capacity(1);
+        other.set_as("tokens", &7i32);
+        let rows = vec![(ids[1], other), (frozen.id, cols)];
+        assert!(matches!(
+            Log::batch_upsert(db, uid, rows, &[]).await,
+            Err(LogError::Frozen)
+        ));
+        let mut doc = Log::with_pk(uid, ids[1]);
+        doc.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc.tokens, 42);
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("uid", &uid);
+        assert!(matches!(
+            Log::batch_upsert(db, uid, vec![(ids[0], cols)], &[]).await,
+            Err(LogError::InvalidField(_))
+        ));
+        assert!(matches!(
+            Log::batch_upsert(db, uid, vec![(ids[0], ColumnsMap::new())], &[]).await,
+            Err(LogError::InvalidInput(_))
+        ));
+
+        // more than one batch is rejected rather than written in parts
+        let rows: Vec<(xid::Id, ColumnsMap)> = (0..=BATCH_UPSERT_MAX)
+            .map(|_| {
+                let mut cols = ColumnsMap::with_capacity(1);
+                cols.set_as("tokens", &1i32);
+                (xid::new(), cols)
+            })
+            .collect();
+        assert!(matches!(
+            Log::batch_upsert(db, uid, rows, &[]).await,
+            Err(LogError::InvalidInput(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn upsert_fields_overriding_works() {
+        let db = DB.get_or_init(get_db).await;
+        let mut doc = Log::with_pk(xid::new(), xid::new());
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &16i8);
+        cols.set_as("status", &1i8);
+        doc.upsert_fields(db, cols).await.unwrap();
+
+        let correction = || {
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("error", &"audit correction".to_string());
+            cols
+        };
+        assert!(matches!(
+            doc.upsert_fields(db, correction()).await,
+            Err(LogError::Frozen)
+        ));
+        assert!(matches!(
+            doc.upsert_fields_overriding(db, correction(), &[], &[8])
+                .await,
+            Err(LogError::Frozen)
+        ));
+        doc.upsert_fields_overriding(db, correction(), &[], &[16])
+            .await
+            .unwrap();
+
+        let mut doc2 = Log::with_pk(doc.uid, doc.id);
+        doc2.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc2.status, Status::Frozen);
+        assert_eq!(doc2.error, "audit correction");
+        assert!(doc2.overridden);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn upsert_fields_with_works() {
+        let db = DB.get_or_init(get_db).await;
+        let mutable = vec!["error".to_string()];
+        let mut doc = Log::with_pk(xid::new(), xid::new());
+        let mut cols = ColumnsMap::with_capacity(3);
+        cols.set_as("action", &8i8);
+        cols.set_as("status", &-1i8);
+        cols.set_as("error", &"timeout".to_string());
+        doc.upsert_fields(db, cols).await.unwrap();
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"retry failed".to_string());
+        doc.upsert_fields_with(db, cols, &mutable).await.unwrap();
+        assert_eq!(doc.error, "timeout\nretry failed");
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"".to_string());
+        assert!(matches!(
+            doc.upsert_fields_with(db, cols, &mutable).await,
+            Err(LogError::InvalidInput(_))
+        ));
+
+        let mut doc2 = Log::with_pk(doc.uid, doc.id);
+        doc2.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc2.status, Status::Failed);
+        assert_eq!(doc2.error, "timeout\nretry failed");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn append_status_works() {
+        let db = DB.get_or_init(get_db).await;
+        let mut doc = Log::with_pk(xid::new(), xid::new());
+        assert!(matches!(
+            doc.append_status(db, Status::Open, "").await,
+            Err(LogError::NotFound)
+        ));
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("action", &8i8);
+        doc.upsert_fields(db, cols).await.unwrap();
+        assert!(Log::status_history(db, doc.uid, doc.id, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // changes in the same millisecond are all kept
+        doc.append_status(db, Status::Open, "").await.unwrap();
+        doc.append_status(db, Status::Open, "retrying")
+            .await
+            .unwrap();
+        doc.append_status(db, Status::Frozen, "").await.unwrap();
+        assert!(matches!(
+            doc.append_status(db, Status::Failed, "late").await,
+            Err(LogError::Frozen)
+        ));
+        // restating a frozen status is allowed
+        doc.append_status(db, Status::Frozen, "").await.unwrap();
+
+        let history = Log::status_history(db, doc.uid, doc.id, 10).await.unwrap();
+        assert_eq!(
+            history.iter().map(|e| e.status).collect::<Vec<Status>>(),
+            vec![Status::Frozen, Status::Frozen, Status::Open, Status::Open]
+        );
+        assert_eq!(history[2].error, "retrying");
+        let latest = StatusEntry::latest(&history).unwrap();
+        assert_eq!(latest.status, Status::Frozen);
+        assert_eq!(latest.at, doc.updated_at);
+
+        let other = xid::new();
+        let latest = Log::latest_statuses(db, doc.uid, &[doc.id, other])
+            .await
+            .unwrap();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[&doc.id].status, Status::Frozen);
+
+        // a log frozen in place is frozen for the history too
+        let mut frozen = Log::with_pk(doc.uid, other);
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &8i8);
+        cols.set_as("status", &1i8);
+        frozen.upsert_fields(db, cols).await.unwrap();
+        assert!(matches!(
+            frozen.append_status(db, Status::Open, "").await,
+            Err(LogError::Frozen)
+        ));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn append_status_freezes_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let mut doc = Log::with_pk(uid, xid::new());
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("action", &15i8);
+        cols.set_as("tokens", &10i32);
+        doc.upsert_fields(db, cols).await.unwrap();
+        doc.append_status(db, Status::Frozen, "").await.unwrap();
+
+        // the log itself is frozen
+        let mut got = Log::with_pk(uid, doc.id);
+        got.get_one(db, vec![]).await.unwrap();
+        assert_eq!(got.status, Status::Frozen);
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("tokens", &20i32);
+        assert!(matches!(
+            Log::with_pk(uid, doc.id).upsert_fields(db, cols).await,
+            Err(LogError::Frozen)
+        ));
+        assert!(matches!(
+            Log::with_pk(uid, doc.id).add_tokens(db, 5, None).await,
+            Err(LogError::Frozen)
+        ));
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("tokens", &20i32);
+        assert!(matches!(
+            Log::batch_upsert(db, uid, vec![(doc.id, cols)], &[]).await,
+            Err(LogError::Frozen)
+        ));
+        let before = xid_unix(&doc.id) + 1;
+        assert_eq!(
+            Log::purge_before(db, uid, before, false, 10).await.unwrap(),
+            0
+        );
+
+        let mut got = Log::with_pk(uid, doc.id);
+        got.get_one(db, vec![]).await.unwrap();
+        assert_eq!(got.tokens, 10);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn read_session_works() {
+        let cfg = conf::Conf::new().unwrap_or_else(|err| panic!("config error: {}", err));
+        let reader = Arc::new(
+            scylladb::ScyllaDB::new(cfg.scylla.clone(), "logbase_test")
+                .await
+                .unwrap(),
+        );
+        let db = scylladb::ScyllaDB::new(cfg.scylla, "logbase_test")
+            .await
+            .unwrap()
+            .with_reader(reader.clone());
+        assert!(std::ptr::eq(db.read(), reader.as_ref()));
+
+        let uid = xid::new();
+        let mut doc = Log::with_pk(uid, xid::new());
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("action", &1i8);
+        doc.upsert_fields(&db, cols).await.unwrap();
+
+        let writes = db.metrics().get_queries_num();
+        let reads = reader.metrics().get_queries_iter_num();
+        let docs = Log::list(&db, uid, vec![], 10, None, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(reader.metrics().get_queries_iter_num(), reads + 1);
+        assert_eq!(db.metrics().get_queries_num(), writes);
+        assert_eq!(db.metrics().get_queries_iter_num(), 0);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn import_batch_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let now = (unix_ms() / 1000) as u32;
+
+        let mut id = xid_from_unix(now - 3600 * 24 * 30);
+        id.0[11] = 7;
+        let mut doc = Log::with_pk(uid, id);
+        doc.action = 3;
+        doc.status = Status::Frozen;
+        doc.gid = xid::new();
+        doc.ip = "1.2.3.4".to_string();
+        doc.payload = vec![0x80];
+        doc.tokens = 42;
+        doc.trace_id = "migrated".to_string();
+        doc.updated_at = (now as i64 - 3600 * 24 * 29) * 1000;
+        assert_eq!(
+            Log::import_batch(db, &[doc.clone()]).await.unwrap(),
+            (1, vec![])
+        );
+
+        let mut doc2 = Log::with_pk(uid, id);
+        doc2.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc2.id, id);
+        assert_eq!(doc2.action, doc.action);
+        assert_eq!(doc2.status, doc.status);
+        assert_eq!(doc2.gid, doc.gid);
+        assert_eq!(doc2.ip, doc.ip);
+        assert_eq!(doc2.payload, doc.payload);
+        assert_eq!(doc2.tokens, doc.tokens);
+        assert_eq!(doc2.trace_id, doc.trace_id);
+        assert_eq!(doc2.updated_at, doc.updated_at);
+
+        // an existing log is skipped, not overwritten
+        let mut other = doc.clone();
+        other.status = Status::Failed;
+        other.tokens = 1;
+        let mut id2 = id;
+        id2.0[11] = 8;
+        let new = Log::with_pk(uid, id2);
+        assert_eq!(
+            Log::import_batch(db, &[other, new]).await.unwrap(),
+            (1, vec![id])
+        );
+        let mut doc2 = Log::with_pk(uid, id);
+        doc2.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc2.status, doc.status);
+        assert_eq!(doc2.tokens, doc.tokens);
+
+        let future = Log::with_pk(uid, xid_from_unix(now + 3600));
+        let err: HTTPError = Log::import_batch(db, &[future]).await.unwrap_err().into();
+        assert_eq!(err.code, 400);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn add_tokens_works() {
+        let db = DB.get_or_init(get_db).await;
+        let mut doc = Log::with_pk(xid::new(), xid::new());
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("action", &1i8);
+        doc.upsert_fields(db, cols).await.unwrap();
+
+        assert_eq!(doc.add_tokens(db, 100, None).await.unwrap(), 100);
+        assert_eq!(doc.add_tokens(db, 50, Some(150)).await.unwrap(), 150);
+        let err: HTTPError = doc.add_tokens(db, 1, Some(150)).await.unwrap_err().into();
+        assert_eq!(err.code, 400);
+
+        let mut doc2 = Log::with_pk(doc.uid, doc.id);
+        doc2.get_one(db, vec![]).await.unwrap();
+        assert_eq!(doc2.tokens, 150);
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("status", &1i8);
+        doc.upsert_fields(db, cols).await.unwrap();
+        let err: HTTPError = doc.add_tokens(db, 1, None).await.unwrap_err().into();
+        assert_eq!(err.code, 400);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn delete_by_action_works() {
+        let db = DB.get_or_init(get_db).await;
+        let uid = xid::new();
+        let mut ids: Vec<xid::Id> = Vec::new();
+        for action in [1i8, 2i8, 1i8] {
+            let mut doc = Log::with_pk(uid, xid::new());
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("action", &action);
+            doc.upsert_fields(db, cols).await.unwrap();
+            ids.push(doc.id);
+        }
+
+        let before = (unix_ms() / 1000) as u32 + 1;
+        let deleted = Log::delete_by_action(db, uid, 1i8, before, false, 1000)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+
+        let docs = Log::list(db, uid, vec![], 10, None, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].id, ids[1]);
+        assert_eq!(docs[0].action, 2i8);
     }
 }
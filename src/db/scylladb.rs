@@ -2,26 +2,240 @@ use futures::{stream::StreamExt, Stream};
 use scylla::{
     frame::value::{BatchValues, ValueList},
     statement::{Consistency, SerialConsistency},
-    transport::{query_result::QueryResult, Compression, ExecutionProfile},
+    transport::{
+        errors::{DbError, QueryError},
+        query_result::QueryResult,
+        session::PoolSize,
+        Compression, ExecutionProfile,
+    },
     CachingSession, Metrics, Session, SessionBuilder,
 };
-use std::{sync::Arc, time::Duration};
+use std::{
+    fmt,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 
 pub use scylla::{
-    batch::Batch,
+    batch::{Batch, BatchType},
     frame::response::result::{ColumnType, Row},
     query::Query,
     Bytes,
 };
 
+use axum_web::context::unix_ms;
+
 use crate::conf;
 
+// tracks probe results of a session.
+pub struct Health {
+    ready: AtomicBool,
+    failures: AtomicU32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            ready: AtomicBool::new(true),
+            failures: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Health {
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    // records a probe result, returns true when the session should be rebuilt.
+    pub fn record(&self, ok: bool, threshold: u32) -> bool {
+        if ok {
+            self.failures.store(0, Ordering::Relaxed);
+            self.ready.store(true, Ordering::Relaxed);
+            return false;
+        }
+
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= threshold {
+            self.ready.store(false, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+}
+
+// returned instead of querying while the circuit breaker is open.
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "scylla is unavailable, circuit breaker is open")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+// a read aborted by the replica after scanning too many tombstones, such as
+// those left by deleted logs.
+#[derive(Debug)]
+pub struct TooManyTombstones(pub String);
+
+impl fmt::Display for TooManyTombstones {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "read failed on too many tombstones, narrow the page size or time window and retry, or compact the table: {}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for TooManyTombstones {}
+
+pub fn is_tombstone_error(err: &QueryError) -> bool {
+    match err {
+        QueryError::DbError(_, msg) => msg.to_lowercase().contains("tombstone"),
+        _ => false,
+    }
+}
+
+// whether a failed query hints at an outage: the replicas are unavailable,
+// overloaded or didn't answer in time. Other errors, such as invalid queries,
+// come from a cluster that answered and don't trip the breaker.
+pub fn is_outage_error(err: &QueryError) -> bool {
+    match err {
+        QueryError::DbError(err, _) => matches!(
+            err,
+            DbError::Unavailable { .. }
+                | DbError::Overloaded
+                | DbError::IsBootstrapping
+                | DbError::ReadTimeout { .. }
+                | DbError::WriteTimeout { .. }
+        ),
+        QueryError::TimeoutError | QueryError::RequestTimeout(_) => true,
+        _ => false,
+    }
+}
+
+// sheds queries for a cooldown after `threshold` consecutive failures. Once
+// the cooldown ends it is half-open: a single probe query passes, its outcome
+// closes the breaker or opens it for another cooldown. A probe that never
+// reports back, e.g. a cancelled request, frees its turn after a cooldown.
+#[derive(Default)]
+pub struct Breaker {
+    failures: AtomicU32,
+    open_until: AtomicU64,  // unix ms, 0 if closed
+    probe_until: AtomicU64, // unix ms, the turn of the half-open probe
+}
+
+impl Breaker {
+    pub fn allow(&self, cooldown_ms: u64, now_ms: u64) -> bool {
+        let open_until = self.open_until.load(Ordering::Relaxed);
+        if open_until == 0 {
+            return true;
+        }
+        if now_ms < open_until {
+            return false;
+        }
+
+        let probe_until = self.probe_until.load(Ordering::Relaxed);
+        now_ms >= probe_until
+            && self
+                .probe_until
+                .compare_exchange(
+                    probe_until,
+                    now_ms + cooldown_ms,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    // true while open or half-open.
+    pub fn is_open(&self) -> bool {
+        self.open_until.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn record(&self, ok: bool, threshold: u32, cooldown_ms: u64, now_ms: u64) {
+        if ok {
+            self.failures.store(0, Ordering::Relaxed);
+            self.open_until.store(0, Ordering::Relaxed);
+            self.probe_until.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if threshold > 0 && failures >= threshold {
+            self.open_until
+                .store(now_ms + cooldown_ms, Ordering::Relaxed);
+            self.probe_until.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
 pub struct ScyllaDB {
-    session: CachingSession,
+    cfg: conf::ScyllaDB,
+    keyspace: String,
+    session: RwLock<Option<Arc<CachingSession>>>, // None if never connected
+    health: Health,
+    breaker: Breaker,
+    tombstone_failures: AtomicU64,
+    reader: Option<Arc<ScyllaDB>>,
 }
 
 impl ScyllaDB {
     pub async fn new(cfg: conf::ScyllaDB, keyspace: &str) -> anyhow::Result<Self> {
+        let session = Self::connect(&cfg, keyspace).await?;
+        Ok(Self {
+            cfg,
+            keyspace: keyspace.to_string(),
+            session: RwLock::new(Some(Arc::new(session))),
+            health: Health::default(),
+            breaker: Breaker::default(),
+            tombstone_failures: AtomicU64::new(0),
+            reader: None,
+        })
+    }
+
+    // a handle without a session, every query fails. Tests use it to build an
+    // AppState whose logs live in a MemoryStore.
+    #[cfg(test)]
+    pub fn disconnected(cfg: conf::ScyllaDB, keyspace: &str) -> Self {
+        Self {
+            cfg,
+            keyspace: keyspace.to_string(),
+            session: RwLock::new(None),
+            health: Health::default(),
+            breaker: Breaker::default(),
+            tombstone_failures: AtomicU64::new(0),
+            reader: None,
+        }
+    }
+
+    // attaches a handle for reads, it may point to another datacenter or consistency.
+    pub fn with_reader(mut self, reader: Arc<ScyllaDB>) -> Self {
+        self.reader = Some(reader);
+        self
+    }
+
+    // the handle for reads, defaults to self.
+    pub fn read(&self) -> &ScyllaDB {
+        self.reader.as_deref().unwrap_or(self)
+    }
+
+    // the handle for reads that must see the latest writes, such as the freeze
+    // checks before a write. It skips the reader, which may lag and miss a
+    // freeze that was just written.
+    pub fn read_latest(&self) -> &ScyllaDB {
+        self
+    }
+
+    async fn connect(cfg: &conf::ScyllaDB, keyspace: &str) -> anyhow::Result<CachingSession> {
         // use tls https://github.com/scylladb/scylla-rust-driver/blob/main/examples/tls.rs
 
         let handle = ExecutionProfile::builder()
@@ -31,10 +245,7 @@ impl ScyllaDB {
             .build()
             .into_handle();
 
-        let session: Session = SessionBuilder::new()
-            .known_nodes(&cfg.nodes)
-            .user(cfg.username, cfg.password)
-            .compression(Some(Compression::Lz4))
+        let session: Session = Self::builder(cfg)
             .default_execution_profile_handle(handle)
             .build()
             .await?;
@@ -43,13 +254,124 @@ impl ScyllaDB {
             session.use_keyspace(keyspace, false).await?;
         }
 
-        Ok(Self {
-            session: CachingSession::from(session, 100000),
-        })
+        Ok(CachingSession::from(session, 100000))
+    }
+
+    fn builder(cfg: &conf::ScyllaDB) -> SessionBuilder {
+        let mut builder = SessionBuilder::new()
+            .known_nodes(&cfg.nodes)
+            .user(&cfg.username, &cfg.password)
+            .compression(Some(Compression::Lz4));
+
+        if let Some(size) = NonZeroUsize::new(cfg.pool_size) {
+            builder = builder.pool_size(if cfg.pool_per_host {
+                PoolSize::PerHost(size)
+            } else {
+                PoolSize::PerShard(size)
+            });
+        }
+        if cfg.keepalive_interval > 0 {
+            builder = builder.keepalive_interval(Duration::from_secs(cfg.keepalive_interval));
+        }
+        builder
+    }
+
+    fn session(&self) -> anyhow::Result<Arc<CachingSession>> {
+        self.session
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("scylla not connected, keyspace: {}", self.keyspace))
     }
 
     pub fn metrics(&self) -> Arc<Metrics> {
-        self.session.get_session().get_metrics()
+        match self.session() {
+            Ok(session) => session.get_session().get_metrics(),
+            Err(_) => Arc::new(Metrics::new()),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.health.is_ready()
+    }
+
+    // reads failed on tombstones, including those of the read session.
+    pub fn tombstone_failures(&self) -> u64 {
+        self.tombstone_failures.load(Ordering::Relaxed)
+            + self.reader.as_ref().map_or(0, |r| r.tombstone_failures())
+    }
+
+    pub fn is_breaker_open(&self) -> bool {
+        self.breaker.is_open()
+    }
+
+    fn guard(&self) -> anyhow::Result<()> {
+        if self
+            .breaker
+            .allow(self.cfg.breaker_cooldown * 1000, unix_ms())
+        {
+            Ok(())
+        } else {
+            Err(CircuitOpen.into())
+        }
+    }
+
+    // records the outcome of a query, only outage errors count as failures.
+    fn record(&self, err: Option<&QueryError>) {
+        self.breaker.record(
+            !err.map_or(false, is_outage_error),
+            self.cfg.breaker_threshold,
+            self.cfg.breaker_cooldown * 1000,
+            unix_ms(),
+        );
+    }
+
+    pub fn keyspace(&self) -> &str {
+        &self.keyspace
+    }
+
+    pub async fn table_exists(&self, keyspace: &str, table: &str) -> anyhow::Result<bool> {
+        let query =
+            "SELECT table_name FROM system_schema.tables WHERE keyspace_name=? AND table_name=?";
+        let res = self.execute(query, (keyspace, table)).await?;
+        Ok(res.rows_num().unwrap_or(0) > 0)
+    }
+
+    // probes the session periodically, and rebuilds it after `threshold` consecutive failures.
+    pub fn spawn_health_check(db: Arc<Self>, interval: Duration, threshold: u32) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // probes bypass the circuit breaker, and close it on success.
+                let ok = match db.session() {
+                    Ok(session) => session
+                        .execute("SELECT now() FROM system.local", &[])
+                        .await
+                        .is_ok(),
+                    Err(_) => false,
+                };
+                if ok {
+                    db.record(None);
+                }
+                if !db.health.record(ok, threshold) {
+                    continue;
+                }
+
+                match Self::connect(&db.cfg, &db.keyspace).await {
+                    Ok(session) => {
+                        *db.session.write().unwrap() = Some(Arc::new(session));
+                        log::warn!("scylla session rebuilt, keyspace: {}", db.keyspace);
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "scylla session rebuild failed, keyspace: {}, error: {}",
+                            db.keyspace,
+                            err
+                        );
+                    }
+                }
+            }
+        });
     }
 
     pub async fn execute(
@@ -57,8 +379,10 @@ impl ScyllaDB {
         query: impl Into<Query>,
         params: impl ValueList,
     ) -> anyhow::Result<QueryResult> {
-        let res = self.session.execute(query, params).await?;
-        Ok(res)
+        self.guard()?;
+        let res = self.session()?.execute(query, params).await;
+        self.record(res.as_ref().err());
+        Ok(res?)
     }
 
     pub async fn execute_iter(
@@ -66,7 +390,40 @@ impl ScyllaDB {
         query: impl Into<Query>,
         params: impl ValueList,
     ) -> anyhow::Result<Vec<Row>> {
-        let mut rows_stream = self.session.execute_iter(query, params).await?;
+        self.guard()?;
+        let res = self.collect_iter(query, params).await;
+        match res {
+            Err(err)
+                if err
+                    .downcast_ref::<QueryError>()
+                    .map_or(false, is_tombstone_error) =>
+            {
+                // the replicas answered, it's not an outage.
+                self.record(None);
+                self.tombstone_failures.fetch_add(1, Ordering::Relaxed);
+                log::warn!(
+                    "scylla read failed on tombstones, keyspace: {}",
+                    self.keyspace
+                );
+                Err(TooManyTombstones(err.to_string()).into())
+            }
+            res => {
+                self.record(
+                    res.as_ref()
+                        .err()
+                        .and_then(|err| err.downcast_ref::<QueryError>()),
+                );
+                res
+            }
+        }
+    }
+
+    async fn collect_iter(
+        &self,
+        query: impl Into<Query>,
+        params: impl ValueList,
+    ) -> anyhow::Result<Vec<Row>> {
+        let mut rows_stream = self.session()?.execute_iter(query, params).await?;
 
         let (capacity, _) = rows_stream.size_hint();
         let mut rows: Vec<Row> = Vec::with_capacity(capacity);
@@ -84,12 +441,33 @@ impl ScyllaDB {
         statements: Vec<&str>,
         values: impl BatchValues,
     ) -> anyhow::Result<QueryResult> {
-        let mut batch: Batch = Default::default();
+        self.run_batch(Batch::default(), statements, values).await
+    }
+
+    // unlogged batches skip the batchlog, trading atomicity across
+    // partitions for throughput.
+    pub async fn unlogged_batch(
+        &self,
+        statements: Vec<&str>,
+        values: impl BatchValues,
+    ) -> anyhow::Result<QueryResult> {
+        self.run_batch(Batch::new(BatchType::Unlogged), statements, values)
+            .await
+    }
+
+    async fn run_batch(
+        &self,
+        mut batch: Batch,
+        statements: Vec<&str>,
+        values: impl BatchValues,
+    ) -> anyhow::Result<QueryResult> {
         for statement in statements {
             batch.append_statement(statement);
         }
-        let res = self.session.batch(&batch, values).await?;
-        Ok(res)
+        self.guard()?;
+        let res = self.session()?.batch(&batch, values).await;
+        self.record(res.as_ref().err());
+        Ok(res?)
     }
 }
 
@@ -127,7 +505,7 @@ pub async fn exec_cqls(db: &ScyllaDB, cqls: &str) -> anyhow::Result<()> {
             let res = res.unwrap_err();
             if res.to_string().contains("Index already exists") {
                 println!("WARN: {}", res);
-            } else {
+            } else if !is_column_exists_error(&res.to_string()) {
                 return Err(res);
             }
         }
@@ -136,6 +514,12 @@ pub async fn exec_cqls(db: &ScyllaDB, cqls: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+// an ALTER TABLE ... ADD of a column that exists already, the migration was applied.
+fn is_column_exists_error(msg: &str) -> bool {
+    msg.contains("conflicts with an existing column") // scylla
+        || (msg.contains("Column with name") && msg.contains("already exists")) // cassandra
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -154,6 +538,178 @@ mod tests {
         .await
     }
 
+    #[test]
+    fn is_column_exists_error_works() {
+        assert!(is_column_exists_error(
+            "Invalid column name trace_id because it conflicts with an existing column"
+        ));
+        assert!(is_column_exists_error(
+            "Column with name 'trace_id' already exists"
+        ));
+        assert!(!is_column_exists_error("Index already exists"));
+        assert!(!is_column_exists_error("Undefined column name trace_id"));
+    }
+
+    #[test]
+    fn builder_works() {
+        let mut cfg = conf::ScyllaDB {
+            nodes: vec!["127.0.0.1:9042".to_string()],
+            username: "".to_string(),
+            password: "".to_string(),
+            health_check_interval: 10,
+            health_check_failures: 3,
+            pool_size: 0,
+            pool_per_host: false,
+            keepalive_interval: 0,
+            bootstrap: false,
+            breaker_threshold: 5,
+            breaker_cooldown: 10,
+        };
+        let builder = ScyllaDB::builder(&cfg);
+        assert!(matches!(
+            builder.config.connection_pool_size,
+            PoolSize::PerShard(n) if n.get() == 1
+        ));
+        assert_eq!(
+            builder.config.keepalive_interval,
+            SessionBuilder::new().config.keepalive_interval
+        );
+
+        cfg.pool_size = 4;
+        cfg.keepalive_interval = 30;
+        let builder = ScyllaDB::builder(&cfg);
+        assert!(matches!(
+            builder.config.connection_pool_size,
+            PoolSize::PerShard(n) if n.get() == 4
+        ));
+        assert_eq!(
+            builder.config.keepalive_interval,
+            Some(Duration::from_secs(30))
+        );
+
+        cfg.pool_per_host = true;
+        let builder = ScyllaDB::builder(&cfg);
+        assert!(matches!(
+            builder.config.connection_pool_size,
+            PoolSize::PerHost(n) if n.get() == 4
+        ));
+    }
+
+    #[test]
+    fn breaker_works() {
+        let breaker = Breaker::default();
+        let now = 1_000_000u64;
+        assert!(breaker.allow(10_000, now));
+        assert!(breaker.allow(10_000, now));
+
+        breaker.record(false, 3, 10_000, now);
+        breaker.record(false, 3, 10_000, now);
+        assert!(breaker.allow(10_000, now));
+        assert!(!breaker.is_open());
+        breaker.record(false, 3, 10_000, now);
+        // open, fails fast during the cooldown
+        assert!(breaker.is_open());
+        assert!(!breaker.allow(10_000, now));
+        assert!(!breaker.allow(10_000, now + 9_999));
+
+        // half-open after the cooldown, a single probe passes and its failure reopens it
+        assert!(breaker.allow(10_000, now + 10_000));
+        assert!(!breaker.allow(10_000, now + 10_000));
+        assert!(!breaker.allow(10_000, now + 10_500));
+        breaker.record(false, 3, 10_000, now + 10_500);
+        assert!(!breaker.allow(10_000, now + 10_501));
+        assert!(!breaker.allow(10_000, now + 20_499));
+
+        // a probe that never reports back frees its turn after a cooldown
+        assert!(breaker.allow(10_000, now + 20_500));
+        assert!(!breaker.allow(10_000, now + 30_499));
+        assert!(breaker.allow(10_000, now + 30_500));
+
+        // the probe succeeds, closed again
+        breaker.record(true, 3, 10_000, now + 30_600);
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(10_000, now + 30_600));
+        assert!(breaker.allow(10_000, now + 30_600));
+        breaker.record(false, 3, 10_000, now + 30_600);
+        assert!(breaker.allow(10_000, now + 30_600));
+
+        // disabled
+        let breaker = Breaker::default();
+        for _ in 0..10 {
+            breaker.record(false, 0, 10_000, now);
+        }
+        assert!(breaker.allow(10_000, now));
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn is_outage_error_works() {
+        for err in [
+            QueryError::DbError(DbError::Overloaded, "overloaded".to_string()),
+            QueryError::DbError(DbError::IsBootstrapping, "bootstrapping".to_string()),
+            QueryError::TimeoutError,
+            QueryError::RequestTimeout("request timed out".to_string()),
+        ] {
+            assert!(is_outage_error(&err), "{}", err);
+        }
+        for err in [
+            QueryError::DbError(DbError::SyntaxError, "line 1:0".to_string()),
+            QueryError::DbError(DbError::Invalid, "undefined column".to_string()),
+            QueryError::DbError(DbError::Unauthorized, "denied".to_string()),
+            QueryError::DbError(DbError::ServerError, "tombstones".to_string()),
+            QueryError::InvalidMessage("bad frame".to_string()),
+        ] {
+            assert!(!is_outage_error(&err), "{}", err);
+        }
+    }
+
+    #[test]
+    fn is_tombstone_error_works() {
+        let err = QueryError::DbError(
+            DbError::ServerError,
+            "TombstoneOverwhelmingException: Scanned over 100001 tombstones".to_string(),
+        );
+        assert!(is_tombstone_error(&err));
+        let err = QueryError::DbError(DbError::ServerError, "Operation timed out".to_string());
+        assert!(!is_tombstone_error(&err));
+        assert!(!is_tombstone_error(&QueryError::TimeoutError));
+
+        let err = TooManyTombstones("Scanned over 100001 tombstones".to_string());
+        assert!(err.to_string().contains("narrow the page size"));
+    }
+
+    #[test]
+    fn health_works() {
+        let health = Health::default();
+        assert!(health.is_ready());
+
+        // session dropped
+        assert!(!health.record(false, 3));
+        assert!(!health.record(false, 3));
+        assert!(health.is_ready());
+        assert!(health.record(false, 3));
+        assert!(!health.is_ready());
+        assert!(health.record(false, 3));
+
+        // recovered
+        assert!(!health.record(true, 3));
+        assert!(health.is_ready());
+        assert!(!health.record(false, 3));
+        assert!(health.is_ready());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn table_exists_works() {
+        let db = get_db().await;
+
+        assert!(db.table_exists("system_schema", "tables").await.unwrap());
+        assert!(!db
+            .table_exists("system_schema", "no_such_table")
+            .await
+            .unwrap());
+        assert!(!db.table_exists("no_such_keyspace", "log").await.unwrap());
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn exec_cqls_works() {
         let db = get_db().await;
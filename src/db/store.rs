@@ -0,0 +1,996 @@
+use async_trait::async_trait;
+use scylla_orm::ColumnsMap;
+use std::collections::HashMap;
+
+use crate::db::{scylladb::ScyllaDB, Log, LogError, Status, StatusEntry};
+
+// the log operations of the handlers that read and write logs, so they can run
+// against an in-memory store in tests.
+#[async_trait]
+pub trait LogStore: Send + Sync {
+    async fn get_one(&self, doc: &mut Log, select_fields: Vec<String>) -> Result<(), LogError>;
+
+    async fn upsert_fields(
+        &self,
+        doc: &mut Log,
+        cols: ColumnsMap,
+        mutable_after_freeze: &[String],
+    ) -> Result<bool, LogError>;
+
+    // writes an existing log even if frozen when its action is one of
+    // bypass_actions, see Log::upsert_fields_overriding.
+    async fn upsert_fields_overriding(
+        &self,
+        doc: &mut Log,
+        cols: ColumnsMap,
+        mutable_after_freeze: &[String],
+        bypass_actions: &[i8],
+    ) -> Result<bool, LogError>;
+
+    // inserts a new log, returns false without writing if the id is taken.
+    async fn insert(&self, doc: &mut Log, cols: &ColumnsMap) -> Result<bool, LogError>;
+
+    // maps an external id of uid to a log id, returns false if if_absent and
+    // the external id is taken.
+    async fn put_external_id(
+        &self,
+        uid: xid::Id,
+        external_id: &str,
+        id: xid::Id,
+        if_absent: bool,
+    ) -> Result<bool, LogError>;
+
+    async fn get_external_id(&self, uid: xid::Id, external_id: &str) -> Result<xid::Id, LogError>;
+
+    // removes the mapping of an external id if it still maps to id.
+    async fn release_external_id(
+        &self,
+        uid: xid::Id,
+        external_id: &str,
+        id: xid::Id,
+    ) -> Result<(), LogError>;
+
+    // appends a status change to the history of a log, see Log::append_status.
+    async fn append_status(
+        &self,
+        doc: &mut Log,
+        status: Status,
+        error: &str,
+    ) -> Result<(), LogError>;
+
+    // the status changes of a log, newest first.
+    async fn status_history(
+        &self,
+        uid: xid::Id,
+        id: xid::Id,
+        limit: u16,
+    ) -> Result<Vec<StatusEntry>, LogError>;
+
+    // the newest status change of each of the logs that has one.
+    async fn latest_statuses(
+        &self,
+        uid: xid::Id,
+        ids: &[xid::Id],
+    ) -> Result<HashMap<xid::Id, StatusEntry>, LogError>;
+
+    #[allow(clippy::too_many_arguments)]
+    async fn list(
+        &self,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        page_token: Option<xid::Id>,
+        action: Option<i8>,
+        since: Option<u32>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError>;
+
+    // the page of logs newer than `before`, newest first, see Log::list_before.
+    async fn list_before(
+        &self,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        before: xid::Id,
+        since: Option<u32>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError>;
+
+    // like list, but only logs with the label `key=value`, see Log::list_by_label.
+    #[allow(clippy::too_many_arguments)]
+    async fn list_by_label(
+        &self,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        page_token: Option<xid::Id>,
+        action: Option<i8>,
+        label: (&str, &str),
+        since: Option<u32>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError>;
+
+    // inserts logs with their own ids, existing ones are skipped, see
+    // Log::import_batch.
+    async fn import_batch(&self, logs: &[Log]) -> Result<(u64, Vec<xid::Id>), LogError>;
+
+    // the sum of tokens of logs in [since, until).
+    async fn sum_tokens(
+        &self,
+        uid: xid::Id,
+        action: Option<i8>,
+        since: u32,
+        until: u32,
+        timeout_ms: Option<u64>,
+    ) -> Result<i64, LogError>;
+
+    // like sum_tokens over at most max_pages pages, see Log::sum_tokens_streamed.
+    #[allow(clippy::too_many_arguments)]
+    async fn sum_tokens_streamed(
+        &self,
+        uid: xid::Id,
+        action: Option<i8>,
+        since: u32,
+        until: u32,
+        page_size: u16,
+        max_pages: usize,
+        timeout_ms: Option<u64>,
+    ) -> Result<(i64, bool), LogError>;
+
+    // see Log::action_histogram.
+    #[allow(clippy::too_many_arguments)]
+    async fn action_histogram(
+        &self,
+        uid: xid::Id,
+        action: Option<i8>,
+        bucket_seconds: u32,
+        since: u32,
+        until: u32,
+        page_size: u16,
+        max_scan: usize,
+        timeout_ms: Option<u64>,
+    ) -> Result<(Vec<u64>, bool), LogError>;
+
+    // deletes logs created before the `before` unix timestamp, of the action
+    // if one is given. Frozen logs are kept unless `force` is set.
+    async fn delete_before(
+        &self,
+        uid: xid::Id,
+        action: Option<i8>,
+        before: u32,
+        force: bool,
+        page_size: u16,
+    ) -> Result<u64, LogError>;
+}
+
+#[async_trait]
+impl LogStore for ScyllaDB {
+    async fn get_one(&self, doc: &mut Log, select_fields: Vec<String>) -> Result<(), LogError> {
+        doc.get_one(self, select_fields).await
+    }
+
+    async fn upsert_fields(
+        &self,
+        doc: &mut Log,
+        cols: ColumnsMap,
+        mutable_after_freeze: &[String],
+    ) -> Result<bool, LogError> {
+        doc.upsert_fields_with(self, cols, mutable_after_freeze)
+            .await
+    }
+
+    async fn upsert_fields_overriding(
+        &self,
+        doc: &mut Log,
+        cols: ColumnsMap,
+        mutable_after_freeze: &[String],
+        bypass_actions: &[i8],
+    ) -> Result<bool, LogError> {
+        doc.upsert_fields_overriding(self, cols, mutable_after_freeze, bypass_actions)
+            .await
+    }
+
+    async fn insert(&self, doc: &mut Log, cols: &ColumnsMap) -> Result<bool, LogError> {
+        doc.insert_if_absent(self, cols).await
+    }
+
+    async fn put_external_id(
+        &self,
+        uid: xid::Id,
+        external_id: &str,
+        id: xid::Id,
+        if_absent: bool,
+    ) -> Result<bool, LogError> {
+        Log::put_external_id(self, uid, external_id, id, if_absent).await
+    }
+
+    async fn get_external_id(&self, uid: xid::Id, external_id: &str) -> Result<xid::Id, LogError> {
+        Log::get_external_id(self, uid, external_id).await
+    }
+
+    async fn release_external_id(
+        &self,
+        uid: xid::Id,
+        external_id: &str,
+        id: xid::Id,
+    ) -> Result<(), LogError> {
+        Log::release_external_id(self, uid, external_id, id).await
+    }
+
+    async fn append_status(
+        &self,
+        doc: &mut Log,
+        status: Status,
+        error: &str,
+    ) -> Result<(), LogError> {
+        doc.append_status(self, status, error).await
+    }
+
+    async fn status_history(
+        &self,
+        uid: xid::Id,
+        id: xid::Id,
+        limit: u16,
+    ) -> Result<Vec<StatusEntry>, LogError> {
+        Log::status_history(self, uid, id, limit).await
+    }
+
+    async fn latest_statuses(
+        &self,
+        uid: xid::Id,
+        ids: &[xid::Id],
+    ) -> Result<HashMap<xid::Id, StatusEntry>, LogError> {
+        Log::latest_statuses(self, uid, ids).await
+    }
+
+    async fn list(
+        &self,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        page_token: Option<xid::Id>,
+        action: Option<i8>,
+        since: Option<u32>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError> {
+        Log::list(
+            self,
+            uid,
+            select_fields,
+            page_size,
+            page_token,
+            action,
+            since,
+            timeout_ms,
+        )
+        .await
+    }
+
+    async fn list_before(
+        &self,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        before: xid::Id,
+        since: Option<u32>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError> {
+        Log::list_before(
+            self,
+            uid,
+            select_fields,
+            page_size,
+            before,
+            since,
+            timeout_ms,
+        )
+        .await
+    }
+
+    async fn list_by_label(
+        &self,
+        uid: xid::Id,
+        select_fields: Vec<String>,
+        page_size: u16,
+        page_token: Option<xid::Id>,
+        action: Option<i8>,
+        label: (&str, &str),
+        since: Option<u32>,
+        timeout_ms: Option<u64>,
+    ) -> Result<Vec<Log>, LogError> {
+        Log::list_by_label(
+            self,
+            uid,
+            select_fields,
+            page_size,
+            page_token,
+            action,
+            label,
+            since,
+            timeout_ms,
+        )
+        .await
+    }
+
+    async fn import_batch(&self, logs: &[Log]) -> Result<(u64, Vec<xid::Id>), LogError> {
+        Log::import_batch(self, logs).await
+    }
+
+    async fn sum_tokens(
+        &self,
+        uid: xid::Id,
+        action: Option<i8>,
+        since: u32,
+        until: u32,
+        timeout_ms: Option<u64>,
+    ) -> Result<i64, LogError> {
+        Log::sum_tokens(self, uid, action, since, until, timeout_ms).await
+    }
+
+    async fn sum_tokens_streamed(
+        &self,
+        uid: xid::Id,
+        action: Option<i8>,
+        since: u32,
+        until: u32,
+        page_size: u16,
+        max_pages: usize,
+        timeout_ms: Option<u64>,
+    ) -> Result<(i64, bool), LogError> {
+        Log::sum_tokens_streamed(
+            self, uid, action, since, until, page_size, max_pages, timeout_ms,
+        )
+        .await
+    }
+
+    async fn action_histogram(
+        &self,
+        uid: xid::Id,
+        action: Option<i8>,
+        bucket_seconds: u32,
+        since: u32,
+        until: u32,
+        page_size: u16,
+        max_scan: usize,
+        timeout_ms: Option<u64>,
+    ) -> Result<(Vec<u64>, bool), LogError> {
+        Log::action_histogram(
+            self,
+            uid,
+            action,
+            bucket_seconds,
+            since,
+            until,
+            page_size,
+            max_scan,
+            timeout_ms,
+        )
+        .await
+    }
+
+    async fn delete_before(
+        &self,
+        uid: xid::Id,
+        action: Option<i8>,
+        before: u32,
+        force: bool,
+        page_size: u16,
+    ) -> Result<u64, LogError> {
+        match action {
+            Some(action) => {
+                Log::delete_by_action(self, uid, action, before, force, page_size).await
+            }
+            None => Log::purge_before(self, uid, before, force, page_size).await,
+        }
+    }
+}
+
+#[cfg(test)]
+pub use memory::MemoryStore;
+
+#[cfg(test)]
+mod memory {
+    use axum_web::context::unix_ms;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    };
+
+    use super::*;
+    use crate::db::{xid_from_unix, xid_unix, MAX_ID};
+
+    // (uid, id) -> the status history of a log, newest first.
+    type StatusHistories = HashMap<([u8; 12], [u8; 12]), Vec<StatusEntry>>;
+
+    // keeps logs in a Vec, with the same field checks, freezing and
+    // newest-first ordering as the Scylla table.
+    #[derive(Default)]
+    pub struct MemoryStore {
+        logs: Mutex<Vec<Log>>,
+        external_ids: Mutex<HashMap<([u8; 12], String), xid::Id>>,
+        statuses: Mutex<StatusHistories>,
+        pub fail_inserts: AtomicBool, // inserts fail as if Scylla were down
+    }
+
+    #[async_trait]
+    impl LogStore for MemoryStore {
+        async fn get_one(&self, doc: &mut Log, select_fields: Vec<String>) -> Result<(), LogError> {
+            let fields = Log::select_fields(select_fields, false)?;
+            let logs = self.logs.lock().unwrap();
+            let found = logs
+                .iter()
+                .find(|r| r.uid == doc.uid && r.id == doc.id)
+                .ok_or(LogError::NotFound)?;
+            *doc = found.clone();
+            doc._fields = fields;
+            Ok(())
+        }
+
+        async fn upsert_fields(
+            &self,
+            doc: &mut Log,
+            mut cols: ColumnsMap,
+            mutable_after_freeze: &[String],
+        ) -> Result<bool, LogError> {
+            for (k, _) in cols.iter() {
+                if !Log::UPSERT_FIELDS.contains(&k.as_str()) {
+                    return Err(LogError::InvalidField(k.to_owned()));
+                }
+            }
+
+            let mut logs = self.logs.lock().unwrap();
+            let i = match logs.iter().position(|r| r.uid == doc.uid && r.id == doc.id) {
+                Some(i) => {
+                    Log::check_action_unchanged(logs[i].action, &cols)?;
+                    Log::check_frozen(logs[i].status, &cols, mutable_after_freeze)?;
+                    Log::append_frozen(logs[i].status, &logs[i].error, &mut cols)?;
+                    i
+                }
+                None => {
+                    logs.push(Log::with_pk(doc.uid, doc.id));
+                    logs.len() - 1
+                }
+            };
+
+            logs[i].fill(&cols);
+            logs[i].updated_at = unix_ms() as i64;
+            doc.updated_at = logs[i].updated_at;
+            Ok(true)
+        }
+
+        async fn upsert_fields_overriding(
+            &self,
+            doc: &mut Log,
+            mut cols: ColumnsMap,
+            mutable_after_freeze: &[String],
+            bypass_actions: &[i8],
+        ) -> Result<bool, LogError> {
+            for (k, _) in cols.iter() {
+                if !Log::UPSERT_FIELDS.contains(&k.as_str()) {
+                    return Err(LogError::InvalidField(k.to_owned()));
+                }
+            }
+
+            let mut logs = self.logs.lock().unwrap();
+            let log = logs
+                .iter_mut()
+                .find(|r| r.uid == doc.uid && r.id == doc.id)
+                .ok_or(LogError::NotFound)?;
+            Log::check_action_unchanged(log.action, &cols)?;
+            if Log::check_override(
+                log.action,
+                log.status,
+                &cols,
+                mutable_after_freeze,
+                bypass_actions,
+            )? {
+                log.overridden = true;
+                doc.overridden = true;
+            } else {
+                Log::append_frozen(log.status, &log.error, &mut cols)?;
+            }
+            log.fill(&cols);
+            log.updated_at = unix_ms() as i64;
+            doc.updated_at = log.updated_at;
+            Ok(true)
+        }
+
+        async fn insert(&self, doc: &mut Log, cols: &ColumnsMap) -> Result<bool, LogError> {
+            if self.fail_inserts.load(Ordering::Relaxed) {
+                return Err(LogError::Unavailable);
+            }
+            for (k, _) in cols.iter() {
+                if !Log::UPSERT_FIELDS.contains(&k.as_str()) {
+                    return Err(LogError::InvalidField(k.to_owned()));
+                }
+            }
+
+            let mut logs = self.logs.lock().unwrap();
+            if logs.iter().any(|r| r.uid == doc.uid && r.id == doc.id) {
+                return Ok(false);
+            }
+            let mut log = Log::with_pk(doc.uid, doc.id);
+            log.fill(cols);
+            log.updated_at = unix_ms() as i64;
+            doc.updated_at = log.updated_at;
+            logs.push(log);
+            Ok(true)
+        }
+
+        async fn put_external_id(
+            &self,
+            uid: xid::Id,
+            external_id: &str,
+            id: xid::Id,
+            if_absent: bool,
+        ) -> Result<bool, LogError> {
+            let mut external_ids = self.external_ids.lock().unwrap();
+            let key = (uid.0, external_id.to_string());
+            if if_absent && external_ids.contains_key(&key) {
+                return Ok(false);
+            }
+            external_ids.insert(key, id);
+            Ok(true)
+        }
+
+        async fn get_external_id(
+            &self,
+            uid: xid::Id,
+            external_id: &str,
+        ) -> Result<xid::Id, LogError> {
+            let external_ids = self.external_ids.lock().unwrap();
+            external_ids
+                .get(&(uid.0, external_id.to_string()))
+                .copied()
+                .ok_or(LogError::NotFound)
+        }
+
+        async fn release_external_id(
+            &self,
+            uid: xid::Id,
+            external_id: &str,
+            id: xid::Id,
+        ) -> Result<(), LogError> {
+            let mut external_ids = self.external_ids.lock().unwrap();
+            let key = (uid.0, external_id.to_string());
+            if external_ids.get(&key) == Some(&id) {
+                external_ids.remove(&key);
+            }
+            Ok(())
+        }
+
+        async fn append_status(
+            &self,
+            doc: &mut Log,
+            status: Status,
+            error: &str,
+        ) -> Result<(), LogError> {
+            let mut logs = self.logs.lock().unwrap();
+            let log = logs
+                .iter_mut()
+                .find(|r| r.uid == doc.uid && r.id == doc.id)
+                .ok_or(LogError::NotFound)?;
+            let mut statuses = self.statuses.lock().unwrap();
+            let history = statuses.entry((doc.uid.0, doc.id.0)).or_default();
+            let current = history.first().map_or(log.status, |e| e.status);
+            Log::check_status_change(current, status)?;
+
+            let entry = StatusEntry {
+                at: unix_ms() as i64,
+                status,
+                error: error.to_string(),
+            };
+            if log.status != status {
+                log.status = status;
+                log.updated_at = entry.at;
+            }
+            doc.status = status;
+            doc.error = entry.error.clone();
+            doc.updated_at = entry.at;
+            history.insert(0, entry);
+            Ok(())
+        }
+
+        async fn status_history(
+            &self,
+            uid: xid::Id,
+            id: xid::Id,
+            limit: u16,
+        ) -> Result<Vec<StatusEntry>, LogError> {
+            let statuses = self.statuses.lock().unwrap();
+            let mut res = statuses.get(&(uid.0, id.0)).cloned().unwrap_or_default();
+            res.truncate(limit as usize);
+            Ok(res)
+        }
+
+        async fn latest_statuses(
+            &self,
+            uid: xid::Id,
+            ids: &[xid::Id],
+        ) -> Result<HashMap<xid::Id, StatusEntry>, LogError> {
+            let statuses = self.statuses.lock().unwrap();
+            Ok(ids
+                .iter()
+                .filter_map(|id| {
+                    let entry = statuses.get(&(uid.0, id.0))?.first()?;
+                    Some((*id, entry.clone()))
+                })
+                .collect())
+        }
+
+        async fn list(
+            &self,
+            uid: xid::Id,
+            select_fields: Vec<String>,
+            page_size: u16,
+            page_token: Option<xid::Id>,
+            action: Option<i8>,
+            since: Option<u32>,
+            _timeout_ms: Option<u64>,
+        ) -> Result<Vec<Log>, LogError> {
+            let fields = Log::select_fields(select_fields, true)?;
+            let token = page_token.unwrap_or(MAX_ID);
+            let start = xid_from_unix(since.unwrap_or_default());
+
+            let logs = self.logs.lock().unwrap();
+            let mut res: Vec<Log> = logs
+                .iter()
+                .filter(|r| {
+                    r.uid == uid
+                        && r.id.0 >= start.0
+                        && r.id.0 < token.0
+                        && action.map_or(true, |a| r.action == a)
+                })
+                .cloned()
+                .collect();
+            res.sort_by_key(|doc| std::cmp::Reverse(doc.id.0));
+            res.truncate(page_size as usize);
+            for doc in res.iter_mut() {
+                doc._fields = fields.clone();
+            }
+            Ok(res)
+        }
+
+        async fn list_before(
+            &self,
+            uid: xid::Id,
+            select_fields: Vec<String>,
+            page_size: u16,
+            before: xid::Id,
+            since: Option<u32>,
+            _timeout_ms: Option<u64>,
+        ) -> Result<Vec<Log>, LogError> {
+            let fields = Log::select_fields(select_fields, true)?;
+            let start = xid_from_unix(since.unwrap_or_default());
+
+            let logs = self.logs.lock().unwrap();
+            let mut res: Vec<Log> = logs
+                .iter()
+                .filter(|r| r.uid == uid && r.id.0 >= start.0 && r.id.0 > before.0)
+                .cloned()
+                .collect();
+            res.sort_by_key(|doc| doc.id.0);
+            res.truncate(page_size as usize);
+            res.reverse();
+            for doc in res.iter_mut() {
+                doc._fields = fields.clone();
+            }
+            Ok(res)
+        }
+
+        async fn list_by_label(
+            &self,
+            uid: xid::Id,
+            select_fields: Vec<String>,
+            page_size: u16,
+            page_token: Option<xid::Id>,
+            action: Option<i8>,
+            label: (&str, &str),
+            since: Option<u32>,
+            timeout_ms: Option<u64>,
+        ) -> Result<Vec<Log>, LogError> {
+            // the logs are kept whole, so the labels are there whatever the fields
+            let res = self
+                .list(
+                    uid,
+                    select_fields,
+                    u16::MAX,
+                    page_token,
+                    action,
+                    since,
+                    timeout_ms,
+                )
+                .await?;
+            Ok(res
+                .into_iter()
+                .filter(|r| r.labels.get(label.0).map(|v| v.as_str()) == Some(label.1))
+                .take(page_size as usize)
+                .collect())
+        }
+
+        async fn import_batch(&self, logs: &[Log]) -> Result<(u64, Vec<xid::Id>), LogError> {
+            if self.fail_inserts.load(Ordering::Relaxed) {
+                return Err(LogError::Unavailable);
+            }
+            let now = (unix_ms() / 1000) as u32;
+            if let Some(doc) = logs.iter().find(|doc| xid_unix(&doc.id) > now) {
+                return Err(LogError::InvalidInput(format!(
+                    "log id {} is in the future",
+                    doc.id
+                )));
+            }
+
+            let mut stored = self.logs.lock().unwrap();
+            let mut skipped: Vec<xid::Id> = Vec::new();
+            for doc in logs {
+                if stored.iter().any(|r| r.uid == doc.uid && r.id == doc.id) {
+                    skipped.push(doc.id);
+                } else {
+                    stored.push(doc.clone());
+                }
+            }
+            Ok(((logs.len() - skipped.len()) as u64, skipped))
+        }
+
+        async fn sum_tokens(
+            &self,
+            uid: xid::Id,
+            action: Option<i8>,
+            since: u32,
+            until: u32,
+            _timeout_ms: Option<u64>,
+        ) -> Result<i64, LogError> {
+            let logs = self.window(uid, action, since, until);
+            Ok(logs.iter().map(|r| r.tokens as i64).sum())
+        }
+
+        async fn sum_tokens_streamed(
+            &self,
+            uid: xid::Id,
+            action: Option<i8>,
+            since: u32,
+            until: u32,
+            page_size: u16,
+            max_pages: usize,
+            _timeout_ms: Option<u64>,
+        ) -> Result<(i64, bool), LogError> {
+            let logs = self.window(uid, action, since, until);
+            let limit = page_size as usize * max_pages;
+            let total = logs.iter().take(limit).map(|r| r.tokens as i64).sum();
+            Ok((total, logs.len() >= limit))
+        }
+
+        async fn action_histogram(
+            &self,
+            uid: xid::Id,
+            action: Option<i8>,
+            bucket_seconds: u32,
+            since: u32,
+            until: u32,
+            _page_size: u16,
+            max_scan: usize,
+            _timeout_ms: Option<u64>,
+        ) -> Result<(Vec<u64>, bool), LogError> {
+            if bucket_seconds == 0 || since >= until {
+                return Err(LogError::InvalidInput(
+                    "Invalid histogram window".to_string(),
+                ));
+            }
+
+            let logs = self.window(uid, action, since, until);
+            let mut counts = vec![0u64; Log::histogram_buckets(since, until, bucket_seconds)];
+            for doc in logs.iter().take(max_scan) {
+                counts[((xid_unix(&doc.id) - since) / bucket_seconds) as usize] += 1;
+            }
+            Ok((counts, logs.len() >= max_scan))
+        }
+
+        async fn delete_before(
+            &self,
+            uid: xid::Id,
+            action: Option<i8>,
+            before: u32,
+            force: bool,
+            _page_size: u16,
+        ) -> Result<u64, LogError> {
+            let end = xid_from_unix(before);
+            let mut logs = self.logs.lock().unwrap();
+            let n = logs.len();
+            logs.retain(|r| {
+                r.uid != uid
+                    || r.id.0 >= end.0
+                    || action.map_or(false, |a| r.action != a)
+                    || (!force && r.status != Status::Open)
+            });
+            Ok((n - logs.len()) as u64)
+        }
+    }
+
+    impl MemoryStore {
+        // the logs of uid in [since, until), of the action if one is given,
+        // newest first.
+        fn window(&self, uid: xid::Id, action: Option<i8>, since: u32, until: u32) -> Vec<Log> {
+            let (start, end) = (xid_from_unix(since), xid_from_unix(until));
+            let logs = self.logs.lock().unwrap();
+            let mut res: Vec<Log> = logs
+                .iter()
+                .filter(|r| {
+                    r.uid == uid
+                        && r.id.0 >= start.0
+                        && r.id.0 < end.0
+                        && action.map_or(true, |a| r.action == a)
+                })
+                .cloned()
+                .collect();
+            res.sort_by_key(|doc| std::cmp::Reverse(doc.id.0));
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::db::xid_from_unix;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn memory_store_works() {
+        let store: Arc<dyn LogStore> = Arc::new(MemoryStore::default());
+        let uid = xid::new();
+
+        // create, as the create handler does
+        let mut ids: Vec<xid::Id> = Vec::new();
+        for (i, action) in [8i8, 40, 8].iter().enumerate() {
+            let mut doc = Log::with_pk(uid, xid_from_unix(1690000000 + i as u32));
+            let mut cols = ColumnsMap::with_capacity(3);
+            cols.set_as("action", action);
+            cols.set_as("status", &0i8);
+            cols.set_as("tokens", &(i as i32));
+            assert!(store.upsert_fields(&mut doc, cols, &[]).await.unwrap());
+            assert!(doc.updated_at > 0);
+            ids.push(doc.id);
+        }
+
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("uid", &uid);
+        let mut doc = Log::with_pk(uid, ids[0]);
+        assert!(matches!(
+            store.upsert_fields(&mut doc, cols, &[]).await,
+            Err(LogError::InvalidField(_))
+        ));
+
+        // get with a projection
+        let mut doc = Log::with_pk(uid, ids[1]);
+        store
+            .get_one(&mut doc, vec!["tokens".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(doc.action, 40);
+        assert_eq!(doc.tokens, 1);
+        assert!(doc._fields.contains(&"tokens".to_string()));
+        assert!(!doc._fields.contains(&"payload".to_string()));
+
+        let mut doc = Log::with_pk(uid, xid::new());
+        assert!(matches!(
+            store.get_one(&mut doc, vec![]).await,
+            Err(LogError::NotFound)
+        ));
+
+        // the action can't be changed
+        let mut doc = Log::with_pk(uid, ids[0]);
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("action", &40i8);
+        assert!(matches!(
+            store.upsert_fields(&mut doc, cols, &[]).await,
+            Err(LogError::InvalidInput(_))
+        ));
+
+        // update freezes the log
+        let mut doc = Log::with_pk(uid, ids[1]);
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("status", &1i8);
+        store.upsert_fields(&mut doc, cols, &[]).await.unwrap();
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("tokens", &100i32);
+        assert!(matches!(
+            store.upsert_fields(&mut doc, cols, &[]).await,
+            Err(LogError::Frozen)
+        ));
+
+        // error stays mutable if configured
+        let mutable = vec!["error".to_string()];
+        let mut cols = ColumnsMap::with_capacity(2);
+        cols.set_as("status", &1i8);
+        cols.set_as("error", &"late failure".to_string());
+        store.upsert_fields(&mut doc, cols, &mutable).await.unwrap();
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("tokens", &100i32);
+        assert!(matches!(
+            store.upsert_fields(&mut doc, cols, &mutable).await,
+            Err(LogError::Frozen)
+        ));
+        let mut doc = Log::with_pk(uid, ids[1]);
+        store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(doc.error, "late failure");
+        assert_eq!(doc.tokens, 1);
+
+        // later errors are appended, and the error can't be cleared
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"retry failed".to_string());
+        store.upsert_fields(&mut doc, cols, &mutable).await.unwrap();
+        let mut cols = ColumnsMap::with_capacity(1);
+        cols.set_as("error", &"".to_string());
+        assert!(matches!(
+            store.upsert_fields(&mut doc, cols, &mutable).await,
+            Err(LogError::InvalidInput(_))
+        ));
+        store.get_one(&mut doc, vec![]).await.unwrap();
+        assert_eq!(doc.error, "late failure\nretry failed");
+
+        // list pages newest first
+        let res = store
+            .list(uid, vec![], 2, None, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.iter().map(|r| r.id).collect::<Vec<xid::Id>>(),
+            vec![ids[2], ids[1]]
+        );
+        let res = store
+            .list(uid, vec![], 2, Some(ids[1]), None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id, ids[0]);
+
+        let res = store
+            .list(uid, vec![], 10, None, Some(8), Some(1690000001), None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id, ids[2]);
+        assert!(store
+            .list(xid::new(), vec![], 10, None, None, None, None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn memory_store_pages_back() {
+        let store: Arc<dyn LogStore> = Arc::new(MemoryStore::default());
+        let uid = xid::new();
+        for i in 0..7u32 {
+            let mut doc = Log::with_pk(uid, xid_from_unix(1690000000 + i));
+            let mut cols = ColumnsMap::with_capacity(1);
+            cols.set_as("action", &8i8);
+            store.insert(&mut doc, &cols).await.unwrap();
+        }
+        let ids = |res: &[Log]| res.iter().map(|r| r.id).collect::<Vec<xid::Id>>();
+
+        // forward two pages, then back one
+        let page1 = store
+            .list(uid, vec![], 3, None, None, None, None)
+            .await
+            .unwrap();
+        let page2 = store
+            .list(uid, vec![], 3, Some(page1[2].id), None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(page2.len(), 3);
+        let back = store
+            .list_before(uid, vec![], 3, page2[0].id, None, None)
+            .await
+            .unwrap();
+        assert_eq!(ids(&back), ids(&page1));
+
+        // a short page back reaches the newest log
+        let back = store
+            .list_before(uid, vec![], 3, page1[1].id, None, None)
+            .await
+            .unwrap();
+        assert_eq!(ids(&back), ids(&page1[..1]));
+    }
+}
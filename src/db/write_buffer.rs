@@ -0,0 +1,331 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+
+use crate::conf;
+use crate::db::{scylladb::ScyllaDB, Log, LogError};
+
+// called with each buffered log once it is written.
+pub type OnWritten = Arc<dyn Fn(&Log) + Send + Sync>;
+
+// accumulates created logs and writes them as unlogged batches every
+// flush_interval_ms or max_entries logs, whichever comes first. Pushes wait
+// while the buffer is full or a failed batch is retried.
+pub struct WriteBuffer {
+    tx: Mutex<Option<mpsc::Sender<Log>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl WriteBuffer {
+    pub fn new(db: Arc<ScyllaDB>, cfg: &conf::WriteBuffer, on_written: Option<OnWritten>) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Log>(cfg.capacity.max(1));
+        let max_entries = cfg.max_entries.max(1);
+        let interval = Duration::from_millis(cfg.flush_interval_ms.max(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let flusher = Flusher {
+            db,
+            max_entries,
+            max_bytes: cfg.max_batch_bytes.max(1),
+            max_retries: cfg.max_retries,
+            retry_backoff_ms: cfg.retry_backoff_ms,
+            dropped: dropped.clone(),
+            on_written,
+        };
+
+        let worker = tokio::spawn(async move {
+            let mut buf: Vec<Log> = Vec::with_capacity(max_entries);
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    doc = rx.recv() => match doc {
+                        Some(doc) => {
+                            buf.push(doc);
+                            if buf.len() >= max_entries {
+                                flusher.flush(&mut buf).await;
+                            }
+                        }
+                        // all senders dropped, flushes the rest and exits.
+                        None => {
+                            flusher.flush(&mut buf).await;
+                            return;
+                        }
+                    },
+                    _ = ticker.tick() => flusher.flush(&mut buf).await,
+                }
+            }
+        });
+
+        Self {
+            tx: Mutex::new(Some(tx)),
+            worker: Mutex::new(Some(worker)),
+            dropped,
+        }
+    }
+
+    // the number of acknowledged logs that could not be written.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub async fn push(&self, doc: Log) -> Result<(), LogError> {
+        let tx = self.tx.lock().await.clone();
+        match tx {
+            Some(tx) => tx.send(doc).await.map_err(|_| LogError::Unavailable),
+            None => Err(LogError::Unavailable),
+        }
+    }
+
+    // stops accepting logs and waits for the buffered ones to be written.
+    pub async fn shutdown(&self) {
+        self.tx.lock().await.take();
+        if let Some(worker) = self.worker.lock().await.take() {
+            let _ = worker.await;
+        }
+    }
+}
+
+struct Flusher {
+    db: Arc<ScyllaDB>,
+    max_entries: usize,
+    max_bytes: usize,
+    max_retries: u32,
+    retry_backoff_ms: u64,
+    dropped: Arc<AtomicU64>,
+    on_written: Option<OnWritten>,
+}
+
+impl Flusher {
+    // writes the buffered logs one partition batch at a time. A failed batch,
+    // e.g. while the breaker is open, is retried with an exponential backoff
+    // and only dropped, and counted, once the retries are spent.
+    async fn flush(&self, buf: &mut Vec<Log>) {
+        if buf.is_empty() {
+            return;
+        }
+
+        for batch in split_batches(std::mem::take(buf), self.max_entries, self.max_bytes) {
+            let mut attempt = 0u32;
+            loop {
+                let err = match Log::insert_unlogged(&self.db, &batch).await {
+                    Ok(_) => {
+                        if let Some(ref on_written) = self.on_written {
+                            batch.iter().for_each(|doc| on_written(doc));
+                        }
+                        break;
+                    }
+                    Err(err) => err,
+                };
+                if attempt >= self.max_retries {
+                    self.dropped
+                        .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    log::error!(
+                        "write buffer flush failed, {} logs dropped: {}",
+                        batch.len(),
+                        err
+                    );
+                    break;
+                }
+
+                let delay = retry_delay(self.retry_backoff_ms, attempt);
+                log::warn!(
+                    "write buffer flush failed, retrying {} logs in {:?}: {}",
+                    batch.len(),
+                    delay,
+                    err
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// the backoff before the given retry, doubling up to 64 times the base.
+fn retry_delay(backoff_ms: u64, attempt: u32) -> Duration {
+    Duration::from_millis(backoff_ms.saturating_mul(1 << attempt.min(6)))
+}
+
+// groups the logs by partition, a batch never spans two uids, and cuts each
+// group into batches of at most max_entries logs and max_bytes bytes. A log
+// larger than max_bytes goes alone. Logs keep their order within a uid.
+fn split_batches(logs: Vec<Log>, max_entries: usize, max_bytes: usize) -> Vec<Vec<Log>> {
+    let mut groups: Vec<Vec<Log>> = Vec::new();
+    let mut index: HashMap<xid::Id, usize> = HashMap::new();
+    for doc in logs {
+        match index.get(&doc.uid) {
+            Some(i) => groups[*i].push(doc),
+            None => {
+                index.insert(doc.uid, groups.len());
+                groups.push(vec![doc]);
+            }
+        }
+    }
+
+    let mut batches: Vec<Vec<Log>> = Vec::new();
+    for group in groups {
+        let mut batch: Vec<Log> = Vec::new();
+        let mut bytes = 0usize;
+        for doc in group {
+            let size = batch_size(&doc);
+            if !batch.is_empty() && (batch.len() >= max_entries || bytes + size > max_bytes) {
+                batches.push(std::mem::take(&mut batch));
+                bytes = 0;
+            }
+            bytes += size;
+            batch.push(doc);
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+    }
+    batches
+}
+
+// an estimate of the bytes a log adds to a batch.
+fn batch_size(doc: &Log) -> usize {
+    // uid, id and gid, action, status, tokens and updated_at
+    let fixed = 12 * 3 + 1 + 1 + 4 + 8;
+    let labels: usize = doc.labels.iter().map(|(k, v)| k.len() + v.len()).sum();
+    fixed + doc.ip.len() + doc.payload.len() + doc.error.len() + doc.trace_id.len() + labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Status;
+
+    async fn get_db() -> Arc<ScyllaDB> {
+        let cfg = conf::Conf::new().unwrap_or_else(|err| panic!("config error: {}", err));
+        Arc::new(ScyllaDB::new(cfg.scylla, "logbase_test").await.unwrap())
+    }
+
+    fn new_log(uid: xid::Id) -> Log {
+        let mut doc = Log::with_pk(uid, xid::new());
+        doc.action = 8;
+        doc.status = Status::Frozen;
+        doc.tokens = 3;
+        doc
+    }
+
+    #[test]
+    fn split_batches_works() {
+        let a = xid::new();
+        let b = xid::new();
+        let logs: Vec<Log> = [a, b, a, a, b].iter().map(|uid| new_log(*uid)).collect();
+        let ids: Vec<xid::Id> = logs.iter().map(|doc| doc.id).collect();
+
+        let res = split_batches(logs.clone(), 100, 1 << 20);
+        assert_eq!(res.len(), 2);
+        assert_eq!(
+            res[0].iter().map(|doc| doc.id).collect::<Vec<_>>(),
+            vec![ids[0], ids[2], ids[3]]
+        );
+        assert_eq!(
+            res[1].iter().map(|doc| doc.id).collect::<Vec<_>>(),
+            vec![ids[1], ids[4]]
+        );
+
+        let res = split_batches(logs.clone(), 2, 1 << 20);
+        assert_eq!(
+            res.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+            vec![2, 1, 2]
+        );
+        assert!(res
+            .iter()
+            .all(|batch| batch.iter().all(|doc| doc.uid == batch[0].uid)));
+
+        let mut logs = logs;
+        logs[2].payload = vec![0u8; 1000];
+        let res = split_batches(logs, 100, 500);
+        assert_eq!(
+            res.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+            vec![1, 1, 1, 2]
+        );
+        assert_eq!(res[1][0].id, ids[2]);
+
+        assert!(split_batches(vec![], 100, 500).is_empty());
+    }
+
+    #[test]
+    fn retry_delay_works() {
+        assert_eq!(retry_delay(100, 0), Duration::from_millis(100));
+        assert_eq!(retry_delay(100, 1), Duration::from_millis(200));
+        assert_eq!(retry_delay(100, 3), Duration::from_millis(800));
+        assert_eq!(retry_delay(100, 20), Duration::from_millis(6400));
+        assert_eq!(retry_delay(0, 2), Duration::ZERO);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn flush_works() {
+        let db = get_db().await;
+        let (tx, mut rx) = mpsc::unbounded_channel::<xid::Id>();
+        let buffer = WriteBuffer::new(
+            db.clone(),
+            &conf::WriteBuffer {
+                enabled: true,
+                flush_interval_ms: 10,
+                max_entries: 100,
+                capacity: 100,
+                ..Default::default()
+            },
+            Some(Arc::new(move |doc: &Log| {
+                tx.send(doc.id).unwrap();
+            })),
+        );
+
+        let doc = new_log(xid::new());
+        buffer.push(doc.clone()).await.unwrap();
+        // notified once written
+        assert_eq!(rx.recv().await.unwrap(), doc.id);
+
+        let mut res = Log::with_pk(doc.uid, doc.id);
+        res.get_one(&db, vec![]).await.unwrap();
+        assert_eq!(res.action, 8);
+        assert_eq!(res.tokens, 3);
+        buffer.shutdown().await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn shutdown_works() {
+        let db = get_db().await;
+        let buffer = WriteBuffer::new(
+            db.clone(),
+            &conf::WriteBuffer {
+                enabled: true,
+                flush_interval_ms: 3600 * 1000,
+                max_entries: 1000,
+                capacity: 1000,
+                ..Default::default()
+            },
+            None,
+        );
+
+        let uid = xid::new();
+        let docs: Vec<Log> = (0..10).map(|_| new_log(uid)).collect();
+        for doc in &docs {
+            buffer.push(doc.clone()).await.unwrap();
+        }
+        buffer.shutdown().await;
+        assert!(matches!(
+            buffer.push(new_log(uid)).await,
+            Err(LogError::Unavailable)
+        ));
+
+        for doc in docs {
+            let mut res = Log::with_pk(doc.uid, doc.id);
+            res.get_one(&db, vec![]).await.unwrap();
+            assert_eq!(res.status, Status::Frozen);
+        }
+    }
+}
@@ -1,13 +1,12 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use structured_logger::{async_json::new_writer, Builder};
-use tokio::{
-    io, signal,
-};
+use tokio::{io, signal};
 
 mod api;
 mod conf;
 mod db;
+mod otel;
 mod router;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
@@ -19,6 +18,7 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     log::debug!("{:?}", cfg);
+    otel::init(&cfg.otel)?;
 
     let server_cfg = cfg.server.clone();
     let server_env = cfg.env.clone();
@@ -33,9 +33,14 @@ async fn main() -> anyhow::Result<()> {
         &addr
     );
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal(app_state, server_cfg.graceful_shutdown))
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal(
+            app_state.clone(),
+            server_cfg.graceful_shutdown,
+        ))
         .await?;
+    app_state.shutdown().await;
+    otel::shutdown();
 
     Ok(())
 }
@@ -0,0 +1,215 @@
+use axum::{
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{
+    global::{self, BoxedSpan},
+    propagation::{Extractor, TextMapPropagator},
+    runtime,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    trace::{FutureExt, Span, SpanKind, Status, Tracer},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use std::{fmt::Display, future::Future};
+
+use crate::conf;
+
+// installs the OTLP span exporter, spans are dropped if no endpoint is set.
+pub fn init(cfg: &conf::Otel) -> anyhow::Result<()> {
+    if cfg.endpoint.is_empty() {
+        return Ok(());
+    }
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(cfg.endpoint.clone()),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                cfg.service_name.clone(),
+            )])),
+        )
+        .install_batch(runtime::Tokio)?;
+    Ok(())
+}
+
+// flushes the spans not exported yet.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+// runs the request in the trace context of its traceparent header, the DB
+// spans of the request are children of the caller's span.
+pub async fn middleware<B>(req: Request<B>, next: Next<B>) -> Response {
+    let cx = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+    next.run(req).with_context(cx).await
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+// a client span around a DB operation, a child of the span of the current
+// trace context if any. Ended when dropped.
+pub struct DbSpan(BoxedSpan);
+
+impl DbSpan {
+    pub fn start(op: &'static str, uid: Option<&xid::Id>, timeout_ms: u64) -> Self {
+        let tracer = global::tracer("logbase");
+        let mut attrs = vec![
+            KeyValue::new("db.system", "scylla"),
+            KeyValue::new("db.operation", op),
+            KeyValue::new("db.timeout_ms", timeout_ms as i64),
+        ];
+        if let Some(uid) = uid {
+            attrs.push(KeyValue::new("logbase.uid", uid.to_string()));
+        }
+        let span = tracer
+            .span_builder(op)
+            .with_kind(SpanKind::Client)
+            .with_attributes(attrs)
+            .start(&tracer);
+        Self(span)
+    }
+
+    // runs the operation in the span, an error sets the span status.
+    pub async fn run<T, E, F>(mut self, op: F) -> Result<T, E>
+    where
+        E: Display,
+        F: Future<Output = Result<T, E>>,
+    {
+        let res = op.await;
+        if let Err(err) = &res {
+            self.0.set_status(Status::error(err.to_string()));
+        }
+        res
+    }
+}
+
+impl Drop for DbSpan {
+    fn drop(&mut self) {
+        self.0.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing, Router};
+    use futures::future::BoxFuture;
+    use opentelemetry::{
+        sdk::export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::{SpanId, TraceId},
+        Key, Value,
+    };
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+
+    #[derive(Debug, Default, Clone)]
+    struct MemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for MemoryExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn db_span_works() {
+        let exporter = MemoryExporter::default();
+        let provider = trace::TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        global::set_tracer_provider(provider.clone());
+
+        let uid = xid::new();
+        let app = Router::new()
+            .route(
+                "/",
+                routing::get(move || async move {
+                    let _ = DbSpan::start("log.get_one", Some(&uid), 3000)
+                        .run(async { Ok::<(), String>(()) })
+                        .await;
+                    let _ = DbSpan::start("log.list", Some(&uid), 250)
+                        .run(async { Err::<(), String>("timed out".to_string()) })
+                        .await;
+                    "ok"
+                }),
+            )
+            .layer(middleware::from_fn(middleware));
+
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let req = Request::builder()
+            .uri("/")
+            .header("traceparent", traceparent)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(req).await.unwrap();
+        provider.force_flush();
+
+        let exported = exporter.0.lock().unwrap().clone();
+        let spans: Vec<&SpanData> = exported
+            .iter()
+            .filter(|s| {
+                s.attributes.get(&Key::from_static_str("logbase.uid"))
+                    == Some(&Value::from(uid.to_string()))
+            })
+            .collect();
+        assert_eq!(spans.len(), 2);
+        for span in &spans {
+            assert_eq!(
+                span.span_context.trace_id(),
+                TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+            );
+            assert_eq!(
+                span.parent_span_id,
+                SpanId::from_hex("00f067aa0ba902b7").unwrap()
+            );
+        }
+        assert_eq!(spans[0].name, "log.get_one");
+        assert_eq!(spans[0].status, Status::Unset);
+        assert_eq!(spans[0].span_kind, SpanKind::Client);
+        assert_eq!(
+            spans[0]
+                .attributes
+                .get(&Key::from_static_str("db.timeout_ms")),
+            Some(&Value::I64(3000))
+        );
+        assert_eq!(spans[1].name, "log.list");
+        assert_eq!(
+            spans[1]
+                .attributes
+                .get(&Key::from_static_str("db.timeout_ms")),
+            Some(&Value::I64(250))
+        );
+        assert_eq!(spans[1].status, Status::error("timed out"));
+
+        // a new trace without traceparent
+        exporter.0.lock().unwrap().clear();
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        app.oneshot(req).await.unwrap();
+        provider.force_flush();
+        let spans = exporter.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].parent_span_id, SpanId::INVALID);
+        assert_ne!(
+            spans[0].span_context.trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+    }
+}
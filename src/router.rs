@@ -1,29 +1,77 @@
-use axum::{middleware, routing, Router};
-use std::sync::Arc;
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header, HeaderName, HeaderValue, Method, Request},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing, Extension, Router,
+};
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tower::ServiceBuilder;
 use tower_http::{
     catch_panic::CatchPanicLayer,
-    compression::{predicate::SizeAbove, CompressionLayer},
+    compression::{
+        predicate::{And, NotForContentType, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    cors::{AllowOrigin, CorsLayer},
 };
 
-use axum_web::context;
+use axum_web::context::{self, ReqContext};
 use axum_web::encoding;
+use axum_web::erring::HTTPError;
 
 use crate::api;
 use crate::conf;
 use crate::db;
+use crate::otel;
 
 pub async fn new(cfg: conf::Conf) -> anyhow::Result<(Arc<api::AppState>, Router)> {
+    cors_layer(&cfg.cors)?;
+    api::log::check_sampling(&cfg.sampling)?;
+    api::log::check_freeze_bypass_actions(&cfg.freeze_bypass_actions)?;
     let app_state = Arc::new(new_app_state(cfg).await?);
+    let app = routes(app_state.clone())?;
+    Ok((app_state, app))
+}
 
+// the routes and middlewares over the app state, tests drive them with a
+// MemoryStore backed state.
+pub fn routes(app_state: Arc<api::AppState>) -> anyhow::Result<Router> {
+    let cors = cors_layer(&app_state.cfg.cors)?;
+    let compression = &app_state.cfg.compression;
     let mds = ServiceBuilder::new()
-        .layer(CatchPanicLayer::new())
+        .layer(middleware::from_fn(otel::middleware))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(app_state.cfg.api_keys.clone()),
+            authenticate,
+        ))
         .layer(middleware::from_fn(context::middleware))
-        .layer(CompressionLayer::new().compress_when(SizeAbove::new(encoding::MIN_ENCODING_SIZE)));
+        .layer(CatchPanicLayer::custom(panic_response))
+        .layer(middleware::from_fn_with_state(
+            app_state.in_flight.clone(),
+            track_in_flight,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.latency.clone(),
+            record_latency,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            negotiate_encoding,
+        ))
+        .layer(compression_layer(compression));
 
     let app = Router::new()
         .route("/", routing::get(api::version))
         .route("/healthz", routing::get(api::healthz))
+        .route("/readyz", routing::get(api::readyz))
+        .route("/metrics", routing::get(api::metrics::metrics))
+        .route("/v1/whoami", routing::get(api::whoami))
         .nest(
             "/v1/log",
             Router::new()
@@ -33,12 +81,274 @@ pub async fn new(cfg: conf::Conf) -> anyhow::Result<(Arc<api::AppState>, Router)
                         .get(api::log::get)
                         .patch(api::log::update),
                 )
-                .route("/list_recently", routing::post(api::log::list_recently)),
+                .route(
+                    "/tokens",
+                    routing::patch(api::log::add_tokens).get(api::log::token_usage),
+                )
+                .route("/by_external", routing::get(api::log::get_by_external))
+                .route("/present", routing::get(api::log::present))
+                .route("/import", routing::post(api::log::import))
+                .route("/validate", routing::post(api::log::validate))
+                .route("/list", routing::post(api::log::list))
+                .route("/list_recently", routing::post(api::log::list_recently))
+                .route(
+                    "/list_recently_multi",
+                    routing::post(api::log::list_recently_multi),
+                )
+                .route("/batch_get", routing::post(api::log::batch_get))
+                .route("/batch_upsert", routing::post(api::log::batch_upsert))
+                .route("/latest", routing::get(api::log::latest))
+                .route("/earliest", routing::get(api::log::earliest))
+                .route("/changed_since", routing::get(api::log::changed_since))
+                .route("/sync", routing::post(api::log::sync))
+                .route("/top_tokens", routing::post(api::log::top_tokens))
+                .route("/summary", routing::get(api::log::summary))
+                .route("/status_count", routing::get(api::log::status_count))
+                .route(
+                    "/status_history",
+                    routing::post(api::log::append_status).get(api::log::status_history),
+                )
+                .route("/histogram", routing::post(api::log::histogram))
+                .route("/purge", routing::delete(api::log::purge))
+                .route("/by_action", routing::delete(api::log::delete_by_action))
+                .route("/export_bin", routing::get(api::export::export_bin))
+                .route("/export_buckets", routing::get(api::export::export_buckets))
+                .route("/replay", routing::post(api::replay::replay)),
+        )
+        .nest(
+            "/v1/admin",
+            Router::new()
+                .route(
+                    "/read_only",
+                    routing::get(api::admin::get_read_only).put(api::admin::set_read_only),
+                )
+                .route_layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    require_admin,
+                )),
+        )
+        .route("/v1/actions", routing::get(api::action::source))
+        .nest(
+            "/v1/action",
+            Router::new().route("/:name", routing::get(api::action::get)),
         )
         .route_layer(mds)
-        .with_state(app_state.clone());
+        .with_state(app_state);
 
-    Ok((app_state, app))
+    // outside of the routes, so preflights are answered for every path
+    let app = match cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+    Ok(app)
+}
+
+// None when no origin is allowed, browsers then enforce same-origin.
+fn cors_layer(cfg: &conf::Cors) -> anyhow::Result<Option<CorsLayer>> {
+    if cfg.allowed_origins.is_empty() {
+        return Ok(None);
+    }
+
+    let origins = if cfg.allowed_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        let mut origins: Vec<HeaderValue> = Vec::with_capacity(cfg.allowed_origins.len());
+        for o in &cfg.allowed_origins {
+            origins.push(HeaderValue::from_str(o.trim_end_matches('/'))?);
+        }
+        AllowOrigin::list(origins)
+    };
+    let mut methods: Vec<Method> = Vec::with_capacity(cfg.allowed_methods.len());
+    for m in &cfg.allowed_methods {
+        methods.push(Method::from_bytes(m.to_ascii_uppercase().as_bytes())?);
+    }
+    let mut headers: Vec<HeaderName> = Vec::with_capacity(cfg.allowed_headers.len());
+    for h in &cfg.allowed_headers {
+        headers.push(HeaderName::from_bytes(h.as_bytes())?);
+    }
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .max_age(Duration::from_secs(cfg.max_age_secs)),
+    ))
+}
+
+// answers a panicked request with the standard JSON error. It runs inside
+// the context middleware, so the request id is known and the 500 is logged.
+fn panic_response(err: Box<dyn Any + Send + 'static>) -> Response {
+    let msg = if let Some(s) = err.downcast_ref::<String>() {
+        s.as_str()
+    } else if let Some(s) = err.downcast_ref::<&str>() {
+        s
+    } else {
+        "unknown panic"
+    };
+    log::error!(
+        "request {} panicked: {}",
+        context::request_id().unwrap_or_default(),
+        msg
+    );
+    HTTPError::new(500, "internal server error".to_string()).into_response()
+}
+
+// compresses responses above MIN_ENCODING_SIZE, except binary ones such as
+// the export, whose payloads are usually compressed already.
+fn compression_layer(
+    cfg: &conf::Compression,
+) -> CompressionLayer<And<SizeAbove, NotForContentType>> {
+    CompressionLayer::new()
+        .zstd(cfg.enabled("zstd"))
+        .br(cfg.enabled("br"))
+        .gzip(cfg.enabled("gzip"))
+        .compress_when(
+            SizeAbove::new(encoding::MIN_ENCODING_SIZE)
+                .and(NotForContentType::const_new("application/octet-stream")),
+        )
+}
+
+// rejects requests without a valid API key with 401, unless no key is
+// configured. It runs before the context middleware, so the tenant and role
+// pinned by the key are the ones the request context sees.
+async fn authenticate<B>(
+    State(keys): State<Arc<Vec<conf::ApiKey>>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if keys.is_empty() || api::auth::is_exempt(req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let key = match api::auth::find_key(&keys, authorization) {
+        Some(key) => key,
+        None => {
+            let msg = if authorization.is_empty() {
+                "missing API key"
+            } else {
+                "invalid API key"
+            };
+            let mut res = HTTPError::new(401, msg.to_string()).into_response();
+            res.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return res;
+        }
+    };
+
+    // the key decides the caller, tenant and role, what the request claims
+    // is dropped even if the key leaves them empty.
+    let headers = req.headers_mut();
+    for (name, value) in [
+        ("x-auth-app", &key.name),
+        ("x-tenant", &key.tenant),
+        ("x-auth-role", &key.role),
+    ] {
+        match HeaderValue::from_str(value) {
+            Ok(v) if !value.is_empty() => {
+                headers.insert(name, v);
+            }
+            _ => {
+                headers.remove(name);
+            }
+        }
+    }
+    next.run(req).await
+}
+
+// rejects requests of a role not in admin_roles with 403, see
+// AppState::check_admin. It runs after the context middleware.
+async fn require_admin<B>(
+    State(app): State<Arc<api::AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Err(err) = app.check_admin(&ctx) {
+        return err.into_response();
+    }
+    next.run(req).await
+}
+
+// records the latency of the request by its matched route.
+async fn record_latency<B>(
+    State(latency): State<Arc<api::metrics::RouteLatency>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+    let start = Instant::now();
+    let res = next.run(req).await;
+    latency.observe(&method, &route, start.elapsed());
+    res
+}
+
+// counts the request as in flight until its response is produced.
+async fn track_in_flight<B>(
+    State(in_flight): State<Arc<api::metrics::InFlight>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let _guard = in_flight.enter();
+    next.run(req).await
+}
+
+// rewrites Accept-Encoding to the single encoding preferred by the server,
+// CompressionLayer will then compress the response with it.
+async fn negotiate_encoding<B>(
+    State(app): State<Arc<api::AppState>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(accept) = req.headers().get(header::ACCEPT_ENCODING) {
+        let accept = accept.to_str().unwrap_or_default();
+        let value = encoding::negotiate(accept, &app.cfg.compression.preferred)
+            .unwrap_or_else(|| "identity".to_string());
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            req.headers_mut().insert(header::ACCEPT_ENCODING, value);
+        }
+    }
+    next.run(req).await
+}
+
+async fn new_scylla(
+    cfg: &conf::Conf,
+    keyspace: &str,
+) -> anyhow::Result<Arc<db::scylladb::ScyllaDB>> {
+    let mut scylla = db::scylladb::ScyllaDB::new(cfg.scylla.clone(), keyspace).await?;
+    if cfg.scylla.bootstrap {
+        db::bootstrap(&scylla, keyspace).await?;
+    }
+    if !scylla.table_exists(keyspace, "log").await? {
+        log::error!("table log not found in keyspace {}", keyspace);
+    }
+    if let Some(ref read_cfg) = cfg.scylla_read {
+        let reader = Arc::new(db::scylladb::ScyllaDB::new(read_cfg.clone(), keyspace).await?);
+        db::scylladb::ScyllaDB::spawn_health_check(
+            reader.clone(),
+            read_cfg.health_check_interval(),
+            read_cfg.health_check_failures,
+        );
+        scylla = scylla.with_reader(reader);
+    }
+
+    let scylla = Arc::new(scylla);
+    db::scylladb::ScyllaDB::spawn_health_check(
+        scylla.clone(),
+        cfg.scylla.health_check_interval(),
+        cfg.scylla.health_check_failures,
+    );
+    Ok(scylla)
 }
 
 async fn new_app_state(cfg: conf::Conf) -> anyhow::Result<api::AppState> {
@@ -47,8 +357,366 @@ async fn new_app_state(cfg: conf::Conf) -> anyhow::Result<api::AppState> {
     } else {
         "logbase"
     };
-    let scylla = db::scylladb::ScyllaDB::new(cfg.scylla, keyspace).await?;
+
+    let scylla = new_scylla(&cfg, keyspace).await?;
+    let mut tenants = HashMap::with_capacity(cfg.tenants.len());
+    for (tenant, keyspace) in &cfg.tenants {
+        tenants.insert(tenant.to_owned(), new_scylla(&cfg, keyspace).await?);
+    }
+
+    let notifier = Arc::new(api::notify::Notifier::new(
+        &cfg.notify,
+        cfg.notify_max_in_flight,
+    )?);
+    let on_written = if notifier.is_empty() {
+        None
+    } else {
+        Some(api::notify::on_written(notifier.clone()))
+    };
+    let mut buffers = HashMap::new();
+    if cfg.write_buffer.enabled {
+        buffers.insert(
+            "".to_string(),
+            Arc::new(db::WriteBuffer::new(
+                scylla.clone(),
+                &cfg.write_buffer,
+                on_written.clone(),
+            )),
+        );
+        for (tenant, db) in &tenants {
+            buffers.insert(
+                tenant.to_owned(),
+                Arc::new(db::WriteBuffer::new(
+                    db.clone(),
+                    &cfg.write_buffer,
+                    on_written.clone(),
+                )),
+            );
+        }
+    }
+
+    let mut stores: HashMap<String, Arc<dyn db::LogStore>> = HashMap::new();
+    stores.insert("".to_string(), scylla.clone());
+    for (tenant, db) in &tenants {
+        stores.insert(tenant.to_owned(), db.clone());
+    }
+
+    let quotas = Arc::new(api::quota::ActionQuotas::new(&cfg.quotas));
+    let read_only = Arc::new(api::admin::ReadOnly::new(cfg.read_only));
+    let scylla_window = Arc::new(api::metrics::ScyllaWindow::new(Duration::from_secs(
+        cfg.health.error_window_secs,
+    )));
     Ok(api::AppState {
-        scylla: Arc::new(scylla),
+        cfg: Arc::new(cfg),
+        scylla,
+        tenants,
+        stores,
+        buffers,
+        latency: Arc::new(api::metrics::RouteLatency::default()),
+        in_flight: Arc::new(api::metrics::InFlight::default()),
+        payload_sizes: Arc::new(api::metrics::PayloadSizes::default()),
+        scylla_window,
+        quotas,
+        read_only,
+        notifier,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn authenticate_works() {
+        let keys = vec![
+            conf::ApiKey {
+                key: "secret".to_string(),
+                name: "console".to_string(),
+                tenant: "a".to_string(),
+                role: "".to_string(),
+            },
+            conf::ApiKey {
+                key: "root".to_string(),
+                name: "".to_string(),
+                tenant: "".to_string(),
+                role: "admin".to_string(),
+            },
+        ];
+        let echo = |headers: axum::http::HeaderMap| async move {
+            let get = |k: &str| {
+                headers
+                    .get(k)
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .unwrap_or_default()
+            };
+            format!(
+                "{},{},{}",
+                get("x-auth-app"),
+                get("x-tenant"),
+                get("x-auth-role")
+            )
+        };
+        let app = |keys: Vec<conf::ApiKey>| {
+            Router::new()
+                .route("/", routing::get(|| async { "ok" }))
+                .route("/healthz", routing::get(|| async { "ok" }))
+                .route("/v1/whoami", routing::get(echo))
+                .route_layer(middleware::from_fn_with_state(Arc::new(keys), authenticate))
+        };
+        let req = |uri: &str, authorization: Option<&str>| {
+            let mut req = Request::builder()
+                .uri(uri)
+                .header("x-auth-app", "spoofed")
+                .header("x-tenant", "b")
+                .header("x-auth-role", "guest");
+            if let Some(v) = authorization {
+                req = req.header(header::AUTHORIZATION, v);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let text = |res: Response| async move {
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let res = app(keys.clone())
+            .oneshot(req("/v1/whoami", Some("Bearer secret")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        // the key pins the caller, tenant and role, an empty one is cleared
+        assert_eq!(text(res).await, "console,a,");
+        let res = app(keys.clone())
+            .oneshot(req("/v1/whoami", Some("Bearer root")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(text(res).await, ",,admin");
+
+        for authorization in [None, Some("Bearer wrong"), Some("secret")] {
+            let res = app(keys.clone())
+                .oneshot(req("/v1/whoami", authorization))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 401);
+            assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+            let body = text(res).await;
+            if authorization.is_none() {
+                assert!(body.contains("missing API key"));
+            } else {
+                assert!(body.contains("invalid API key"));
+            }
+        }
+
+        for uri in ["/", "/healthz"] {
+            let res = app(keys.clone()).oneshot(req(uri, None)).await.unwrap();
+            assert_eq!(res.status(), 200);
+        }
+
+        // no key configured, authentication is off
+        let res = app(vec![]).oneshot(req("/v1/whoami", None)).await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(text(res).await, "spoofed,b,guest");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn cors_layer_works() {
+        let mut cfg = conf::Cors::default();
+        assert!(cors_layer(&cfg).unwrap().is_none());
+
+        cfg.allowed_origins = vec!["https://admin.example.com".to_string()];
+        let app = Router::new()
+            .route("/v1/log", routing::post(|| async { "ok" }))
+            .layer(cors_layer(&cfg).unwrap().unwrap());
+
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/v1/log")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(
+                    header::ACCESS_CONTROL_REQUEST_HEADERS,
+                    "content-type,x-tenant,x-auth-role,x-request-deadline",
+                )
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app
+            .clone()
+            .oneshot(preflight("https://admin.example.com"))
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+        let headers = res.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://admin.example.com"
+        );
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .contains("POST"));
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        let allowed = headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap();
+        for h in ["x-tenant", "x-auth-role", "x-request-deadline"] {
+            assert!(allowed.contains(h), "{}", allowed);
+        }
+        // the default config allows the same headers
+        assert_eq!(
+            conf::Conf::new().unwrap().cors.allowed_headers,
+            cfg.allowed_headers
+        );
+
+        // browsers reject a preflight without the allow-origin header
+        let res = app
+            .oneshot(preflight("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        // header values can't carry control characters
+        cfg.allowed_origins = vec!["bad\norigin".to_string()];
+        assert!(cors_layer(&cfg).is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn record_latency_works() {
+        let latency = Arc::new(api::metrics::RouteLatency::default());
+        let app = Router::new()
+            .route("/v1/log/:id", routing::get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(
+                latency.clone(),
+                record_latency,
+            ));
+
+        let req = Request::builder()
+            .uri("/v1/log/abc")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap();
+
+        let mut out = String::new();
+        latency.render(&mut out);
+        assert!(out.contains(
+            "logbase_http_request_duration_seconds_count{method=\"GET\",route=\"/v1/log/:id\"} 1"
+        ));
+        assert!(out.contains("route=\"/v1/log/:id\",le=\"+Inf\"} 1"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn track_in_flight_works() {
+        let in_flight = Arc::new(api::metrics::InFlight::default());
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let waiting = release.clone();
+        let app = Router::new()
+            .route(
+                "/wait",
+                routing::get(move || {
+                    let waiting = waiting.clone();
+                    async move {
+                        let _ = waiting.acquire().await.unwrap();
+                        "ok"
+                    }
+                }),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                in_flight.clone(),
+                track_in_flight,
+            ));
+
+        let mut tasks = Vec::new();
+        for _ in 0..3 {
+            let req = Request::builder().uri("/wait").body(Body::empty()).unwrap();
+            tasks.push(tokio::spawn(app.clone().oneshot(req)));
+        }
+        while in_flight.get() < 3 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(in_flight.get(), 3);
+
+        release.add_permits(3);
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    async fn boom() -> &'static str {
+        panic!("boom")
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn panic_response_works() {
+        let app = Router::new()
+            .route("/panic", routing::get(boom))
+            .route_layer(
+                ServiceBuilder::new()
+                    .layer(middleware::from_fn(context::middleware))
+                    .layer(CatchPanicLayer::custom(panic_response)),
+            );
+
+        let req = Request::builder()
+            .uri("/panic")
+            .header("x-request-id", "rid-panic")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 500);
+        assert_eq!(res.headers().get("x-request-id").unwrap(), "rid-panic");
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["request_id"], "rid-panic");
+        assert_eq!(body["error"]["code"], 500);
+        assert_eq!(body["error"]["message"], "internal server error");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn compression_layer_works() {
+        let app = Router::new()
+            .route(
+                "/json",
+                routing::get(|| async { axum::Json(vec!["log"; 200]) }),
+            )
+            .route(
+                "/bin",
+                routing::get(|| async {
+                    (
+                        [(header::CONTENT_TYPE, "application/octet-stream")],
+                        vec![0u8; 1024],
+                    )
+                }),
+            )
+            .layer(compression_layer(&conf::Compression::default()));
+
+        for (uri, encoding) in [("/json", Some("gzip")), ("/bin", None)] {
+            let req = Request::builder()
+                .uri(uri)
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(
+                res.headers()
+                    .get(header::CONTENT_ENCODING)
+                    .map(|v| v.to_str().unwrap()),
+                encoding,
+                "{}",
+                uri
+            );
+        }
+    }
+}